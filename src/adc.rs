@@ -0,0 +1,187 @@
+//! Application Default Credentials, resolved the way Google's client
+//! libraries resolve them.
+//!
+//! The first of these that exists wins:
+//!
+//! 1. the file named by `GOOGLE_APPLICATION_CREDENTIALS`
+//! 2. the well-known file written by `gcloud auth application-default login`
+//!    (see [`creds_path`])
+//! 3. the metadata server, on Google Cloud
+//!
+//! Each file may hold an OAuth client (`authorized_user`), a service account
+//! key, or an `external_account` configuration. A user source serves cached
+//! and refreshed tokens, and only opens a browser when [`AdcOptions`] allows it.
+//!
+//! ```rust,no_run
+//! use gcloud_identity_token::adc::default_token_source;
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let source = default_token_source().await?;
+//! let token = source.token(&[]).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::auth::{get_token_for_service_account, get_token_with_options};
+use crate::config::{Creds, LoginOptions, ServiceAccountCreds, TokenOutput, creds_path};
+use crate::external::{ExternalAccountCreds, get_token_for_external_account};
+use crate::metadata::{get_token_from_metadata, metadata_server_available};
+use anyhow::{Context, Result, anyhow};
+use std::path::Path;
+
+/// Environment variable naming a credentials file, as in Google's client libraries.
+pub const APPLICATION_CREDENTIALS_ENV: &str = "GOOGLE_APPLICATION_CREDENTIALS";
+
+/// Where tokens come from.
+#[derive(Clone)]
+pub enum TokenSource {
+    /// A user's OAuth client, with tokens from the cache or a login
+    User {
+        /// The OAuth client
+        creds: Creds,
+        /// Whether a browser login may run when nothing usable is cached
+        allow_login: bool,
+    },
+    /// A service account key
+    ServiceAccount(ServiceAccountCreds),
+    /// Workload identity federation
+    ExternalAccount(ExternalAccountCreds),
+    /// The attached service account of a Google Cloud workload
+    MetadataServer,
+}
+
+/// Options of [`default_token_source_with_options`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AdcOptions {
+    /// Let a user source open a browser when nothing usable is cached;
+    /// otherwise it fails with
+    /// [`AuthError::LoginRequired`](crate::error::AuthError::LoginRequired)
+    pub allow_login: bool,
+}
+
+/// Resolve Application Default Credentials, never logging in interactively.
+///
+/// # Errors
+///
+/// Returns an error if `GOOGLE_APPLICATION_CREDENTIALS` names an unusable
+/// file, or if no credentials are found at all.
+pub async fn default_token_source() -> Result<TokenSource> {
+    default_token_source_with_options(AdcOptions::default()).await
+}
+
+/// Like [`default_token_source`], with control over interactive logins.
+pub async fn default_token_source_with_options(opts: AdcOptions) -> Result<TokenSource> {
+    if let Some(path) =
+        std::env::var_os(APPLICATION_CREDENTIALS_ENV).filter(|path| !path.is_empty())
+    {
+        let path = Path::new(&path);
+        return source_from_file(path, opts)
+            .with_context(|| format!("{APPLICATION_CREDENTIALS_ENV}={}", path.display()));
+    }
+
+    if let Ok(path) = creds_path() {
+        if path.exists() {
+            return source_from_file(&path, opts);
+        }
+    }
+
+    if metadata_server_available().await {
+        return Ok(TokenSource::MetadataServer);
+    }
+
+    Err(anyhow!(
+        "No Application Default Credentials found; set {APPLICATION_CREDENTIALS_ENV}, run \
+         `gcloud auth application-default login`, or run on Google Cloud"
+    ))
+}
+
+fn source_from_file(path: &Path, opts: AdcOptions) -> Result<TokenSource> {
+    let json = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    source_from_json(&json, opts)
+}
+
+/// The token source a credentials file describes, by its `type`.
+///
+/// Files without a `type`, as written by `init`, are OAuth clients.
+fn source_from_json(json: &str, opts: AdcOptions) -> Result<TokenSource> {
+    let value: serde_json::Value = serde_json::from_str(json)?;
+    match value.get("type").and_then(|kind| kind.as_str()) {
+        None | Some("authorized_user") => Ok(TokenSource::User {
+            creds: serde_json::from_value(value)?,
+            allow_login: opts.allow_login,
+        }),
+        Some("service_account") => Ok(TokenSource::ServiceAccount(ServiceAccountCreds::from_json(
+            json,
+        )?)),
+        Some("external_account") => Ok(TokenSource::ExternalAccount(
+            ExternalAccountCreds::from_json(json)?,
+        )),
+        Some(other) => Err(anyhow!("Unsupported credentials of type {other:?}")),
+    }
+}
+
+impl TokenSource {
+    /// An access token for `scopes`, with an ID token where the source
+    /// provides one.
+    ///
+    /// Empty `scopes` mean the source's defaults: `openid email` for users,
+    /// `cloud-platform` for service and external accounts, and the
+    /// instance's scopes on the metadata server.
+    pub async fn token(&self, scopes: &[&str]) -> Result<TokenOutput<'static>> {
+        match self {
+            Self::User { creds, allow_login } => {
+                let opts = LoginOptions {
+                    scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+                    no_login: !allow_login,
+                    ..LoginOptions::default()
+                };
+                get_token_with_options(creds, &opts).await
+            }
+            Self::ServiceAccount(sa) => get_token_for_service_account(sa, scopes).await,
+            Self::ExternalAccount(creds) => get_token_for_external_account(creds, scopes).await,
+            Self::MetadataServer => get_token_from_metadata(scopes, None).await,
+        }
+    }
+
+    /// Short name of the kind of source, e.g. for diagnostics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::User { .. } => "authorized_user",
+            Self::ServiceAccount(_) => "service_account",
+            Self::ExternalAccount(_) => "external_account",
+            Self::MetadataServer => "metadata_server",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_from_json_by_type() {
+        let kind = |json: &str| source_from_json(json, AdcOptions::default()).map(|s| s.kind());
+
+        assert_eq!(
+            kind(r#"{"client_id":"a","client_secret":"b"}"#).unwrap(),
+            "authorized_user"
+        );
+        assert_eq!(
+            kind(r#"{"type":"authorized_user","client_id":"a","client_secret":"b","refresh_token":"r"}"#)
+                .unwrap(),
+            "authorized_user"
+        );
+        assert_eq!(
+            kind(r#"{"type":"service_account","client_email":"sa@p.iam.gserviceaccount.com","private_key":"pem"}"#)
+                .unwrap(),
+            "service_account"
+        );
+        assert_eq!(
+            kind(r#"{"type":"external_account","audience":"a","subject_token_type":"t","credential_source":{"file":"/tmp/t"}}"#)
+                .unwrap(),
+            "external_account"
+        );
+        assert!(kind(r#"{"type":"impersonated_service_account"}"#).is_err());
+    }
+}
@@ -1,111 +1,1672 @@
 //! OAuth authentication logic for obtaining and refreshing Google tokens.
 
-use crate::browser::{build_auth_url, capture_auth_code, open_browser_or_print};
-use crate::cache::{load_cached_token, save_token};
-use crate::config::{Creds, SavedToken, TokenOutput, TokenResponse};
-use crate::shared::get_or_init_port;
-use anyhow::Result;
-use chrono::{Duration, Utc};
+use crate::browser::{
+    LoginSession, MANUAL_REDIRECT_URI, auth_nonce, auth_state, build_auth_url,
+    open_browser_or_print, read_pasted_auth_code, set_query_params,
+};
+use crate::cache::{
+    CACHE_PATH_ENV, cached_accounts, delete_token, file_cache_path, invalidate_memory_cache,
+    load_account, load_cached_token, load_scoped_account, load_scoped_token, lock_cache,
+    record_refresh, record_refresh_rejected, save_account_token, save_token, scope_set,
+    update_token,
+};
+use crate::config::{
+    Creds, LoginFlow, LoginOptions, OwnedToken, SavedToken, ServiceAccountCreds,
+    TokenErrorResponse, TokenOutput, TokenResponse,
+};
+use crate::debug;
+use crate::device::device_login;
+use crate::error::AuthError;
+use crate::gcloud;
+use crate::hooks::{Trigger, run_refresh_hook};
+use crate::iam::{get_id_tokens_as, impersonated_access_token};
+use crate::metadata::{get_id_token_from_metadata, metadata_server_available, metadata_token};
+use crate::stats::{self, Event};
+use crate::verify::decode_unverified;
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
 use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use url::Url;
+
+pub use tokio_util::sync::CancellationToken;
+
+/// OAuth scopes requested by a plain browser login.
+pub const DEFAULT_SCOPES: &[&str] = &["openid", "email"];
+
+/// `prompt` of [`select_account`]: the account chooser, then the consent screen.
+const SELECT_ACCOUNT_PROMPT: &str = "select_account consent";
+
+/// OAuth scope required for Cloud SQL IAM database authentication.
+pub const SQL_LOGIN_SCOPE: &str = "https://www.googleapis.com/auth/sqlservice.login";
+
+/// OAuth scope covering Google Cloud APIs, including IAM Credentials.
+pub const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+/// Environment variables that supply a ready-made access token, in order of precedence.
+pub const ACCESS_TOKEN_ENVS: &[&str] = &["GOOGLE_OAUTH_ACCESS_TOKEN", "CLOUDSDK_AUTH_ACCESS_TOKEN"];
+
+/// Longest lifetime Google gives access tokens, assumed for externally supplied ones.
+const MAX_ACCESS_TOKEN_LIFETIME: Duration = Duration::hours(1);
+
+/// Lifetime of refresh tokens issued to OAuth clients in "Testing" publishing status.
+const TESTING_REFRESH_TOKEN_LIFETIME: Duration = Duration::days(7);
+
+/// Google's OAuth 2.0 token revocation endpoint.
+const REVOKE_URL: &str = "https://oauth2.googleapis.com/revoke";
+
+/// Google's OAuth 2.0 token information endpoint.
+const TOKENINFO_URL: &str = "https://oauth2.googleapis.com/tokeninfo";
+
+/// Grant type of service account JWT assertions (RFC 7523).
+const JWT_BEARER_GRANT: &str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
+
+/// Lifetime of a signed assertion; Google accepts at most one hour.
+const ASSERTION_LIFETIME: Duration = Duration::hours(1);
+
+/// Token endpoint response carrying only an access token and its lifetime.
+#[derive(Deserialize)]
+struct AccessTokenResponse {
+    access_token: String,
+    expires_in: i64,
+}
+
+/// Claims of a service account's JWT bearer assertion.
+#[derive(Debug, Serialize)]
+struct AssertionClaims {
+    iss: String,
+    /// User impersonated through domain-wide delegation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<String>,
+    scope: String,
+    aud: String,
+    iat: i64,
+    exp: i64,
+}
+
+/// Claims of a self-signed JWT presented directly to a Google API.
+#[derive(Serialize)]
+struct SelfSignedClaims {
+    iss: String,
+    sub: String,
+    aud: String,
+    iat: i64,
+    exp: i64,
+}
 
 /// Obtain a fresh or cached Google access token and ID token.
 ///
-/// Handles refresh, browser login, and local secure caching.
+/// Handles refresh, browser login, and local secure caching. On Google Cloud,
+/// with nothing cached, the metadata server's service account token is
+/// returned instead of logging in, with an ID token for the OAuth client. When
+/// `GOOGLE_OAUTH_ACCESS_TOKEN` or `CLOUDSDK_AUTH_ACCESS_TOKEN` is set, that
+/// token is returned as is, with an empty ID token.
 pub async fn get_token(creds: &Creds) -> Result<TokenOutput<'static>> {
+    get_token_with_options(creds, &LoginOptions::default()).await
+}
+
+/// Like [`get_token`], but aborts when `cancel` is triggered.
+///
+/// Cancelling tears down the loopback server and any in-flight requests, and
+/// returns [`AuthError::Cancelled`]. Dropping the future has the same effect.
+pub async fn get_token_with_cancel(
+    creds: &Creds,
+    cancel: &CancellationToken,
+) -> Result<TokenOutput<'static>> {
+    let opts = LoginOptions {
+        cancel: cancel.clone(),
+        ..LoginOptions::default()
+    };
+    get_token_with_options(creds, &opts).await
+}
+
+/// Like [`get_token`], with control over the browser login and cancellation.
+pub async fn get_token_with_options(
+    creds: &Creds,
+    opts: &LoginOptions,
+) -> Result<TokenOutput<'static>> {
+    get_owned_token(creds, opts).await.map(OwnedToken::leak)
+}
+
+/// Like [`get_token_with_options`], returning owned strings.
+pub(crate) async fn get_owned_token(creds: &Creds, opts: &LoginOptions) -> Result<OwnedToken> {
+    cancellable(&opts.cancel, fetch_token(creds, opts))
+        .await
+        .inspect_err(|_| stats::record(Event::Failure))
+}
+
+/// Run `fut` to completion unless `cancel` fires first.
+pub(crate) async fn cancellable<T>(
+    cancel: &CancellationToken,
+    fut: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    tokio::select! {
+        res = fut => res,
+        _ = cancel.cancelled() => Err(AuthError::Cancelled.into()),
+    }
+}
+
+/// Cached, refreshed, or freshly logged-in token for the default scopes, of
+/// `opts.account` or else the current account.
+///
+/// Tokens from the cache or a refresh must meet `opts.assurance`, since a
+/// refreshed ID token carries the claims of the original login.
+async fn fetch_token(creds: &Creds, opts: &LoginOptions) -> Result<OwnedToken> {
+    if let Some(token) = external_access_token() {
+        stats::record(Event::Hit);
+        return Ok(token);
+    }
+
+    let token = match &opts.account {
+        Some(email) => fetch_account_token(creds, opts, email).await?,
+        None => fetch_stored_or_login(creds, opts).await?,
+    };
+    opts.assurance.check_id_token(token.id_token.as_deref())?;
+    Ok(token)
+}
+
+/// The cached token, else a refreshed one, else a metadata server token,
+/// else a browser login.
+async fn fetch_stored_or_login(creds: &Creds, opts: &LoginOptions) -> Result<OwnedToken> {
     // Try cache first
-    if let Some(saved) = load_cached_token() {
-        if saved.token_expiry > Utc::now() + Duration::seconds(60) {
-            return Ok(token_output_from_saved(saved));
+    let scopes = entry_scopes(opts);
+    if let Some(saved) = load_scoped_token(&creds.client_id, &scopes) {
+        let missing = missing_scopes(&saved.granted_scopes, &login_scopes(DEFAULT_SCOPES, opts));
+        if !missing.is_empty() {
+            stats::record(Event::Miss);
+            eprintln!(
+                "The cached grant does not cover {}; logging in to request it.",
+                missing.join(" ")
+            );
+            // Ask for the old grant too, so the new one replaces it whole
+            // even where include_granted_scopes is not honoured.
+            let granted = regrant_scopes(&saved.granted_scopes);
+            return perform_login(creds, &login_scopes(&granted, opts), opts).await;
+        }
+
+        if saved.token_expiry > Utc::now() + opts.expiry_margin() {
+            stats::record(Event::Hit);
+            return Ok(saved.into());
+        }
+        stats::record(Event::Miss);
+
+        if let Some(max_age) = opts.max_age {
+            if login_older_than(&saved, max_age, Utc::now()) {
+                eprintln!("The last login is older than the allowed maximum; logging in again.");
+                return perform_login(creds, DEFAULT_SCOPES, opts).await;
+            }
         }
 
         // Expired — attempt refresh
-        return refresh_token(creds, &saved).await;
+        return refresh_token(creds, &saved, opts).await;
+    }
+
+    // No cached token — use the refresh token of a gcloud ADC file, else the
+    // workload's service account on Google Cloud, else the full auth flow.
+    // A seed is cached for the default scopes, so other scope sets log in.
+    stats::record(Event::Miss);
+    if let Some(refresh_token) = creds.refresh_token.as_ref().filter(|_| scopes.is_empty()) {
+        match seed_from_refresh_token(creds, refresh_token).await {
+            Ok(saved) => return Ok(saved.into()),
+            Err(err) if matches!(err.downcast_ref(), Some(AuthError::LoginRequired)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    if metadata_server_available().await {
+        return metadata_token(&[], Some(&creds.client_id)).await;
+    }
+    perform_login(creds, DEFAULT_SCOPES, opts).await
+}
+
+/// The scopes in `requested` that `granted` does not cover.
+///
+/// An empty `granted` is a grant cached before scopes were tracked, taken to
+/// cover the default scopes only. Google reports `email` and `profile` by
+/// their full URLs, so those are compared under both names.
+fn missing_scopes<'a>(granted: &[String], requested: &[&'a str]) -> Vec<&'a str> {
+    let covered = |scope: &str| {
+        if granted.is_empty() {
+            return DEFAULT_SCOPES.contains(&scope);
+        }
+        granted
+            .iter()
+            .any(|granted| canonical_scope(granted) == canonical_scope(scope))
+    };
+    requested
+        .iter()
+        .copied()
+        .filter(|scope| !covered(scope))
+        .collect()
+}
+
+/// The scopes a login extending the grant `granted` asks for again: the
+/// default scopes, which an untracked (empty) grant stands for and which the
+/// ID token needs, plus the rest of `granted`.
+fn regrant_scopes(granted: &[String]) -> Vec<&str> {
+    let mut scopes = DEFAULT_SCOPES.to_vec();
+    for scope in granted {
+        if !scopes
+            .iter()
+            .any(|known| canonical_scope(known) == canonical_scope(scope))
+        {
+            scopes.push(scope);
+        }
+    }
+    scopes
+}
+
+/// The scope set naming the cache entry of logins with `opts`: the default
+/// scopes plus `opts.scopes`, in [`scope_set`] form.
+pub(crate) fn entry_scopes(opts: &LoginOptions) -> Vec<String> {
+    scope_set(&login_scopes(DEFAULT_SCOPES, opts))
+}
+
+/// The full URL of a scope Google also accepts by a short name.
+pub(crate) fn canonical_scope(scope: &str) -> &str {
+    match scope {
+        "email" => "https://www.googleapis.com/auth/userinfo.email",
+        "profile" => "https://www.googleapis.com/auth/userinfo.profile",
+        _ => scope,
+    }
+}
+
+/// The scopes of a token endpoint response's `scope` field.
+fn parse_scopes(scope: Option<&str>) -> Vec<String> {
+    scope
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(str::to_string)
+        .collect()
+}
+
+/// Whether the interactive login behind `saved` happened more than `max_age` ago.
+///
+/// The ID token's `auth_time` is preferred; without it the refresh token's
+/// issue time stands in. A login of unknown age counts as too old.
+fn login_older_than(saved: &SavedToken, max_age: Duration, now: DateTime<Utc>) -> bool {
+    let auth_time = saved
+        .id_token
+        .as_deref()
+        .and_then(|id_token| decode_unverified(id_token).ok())
+        .and_then(|claims| claims.get("auth_time")?.as_i64())
+        .and_then(|secs| DateTime::from_timestamp(secs, 0));
+    match auth_time.or(saved.refresh_token_issued_at) {
+        Some(logged_in_at) => now - logged_in_at > max_age,
+        None => true,
+    }
+}
+
+/// A token injected through [`ACCESS_TOKEN_ENVS`], passed through untouched.
+///
+/// Its real expiry is unknown, so the longest possible lifetime is reported,
+/// and there is no ID token.
+fn external_access_token() -> Option<OwnedToken> {
+    let token = ACCESS_TOKEN_ENVS
+        .iter()
+        .find_map(|name| std::env::var(name).ok().filter(|token| !token.is_empty()))?;
+    Some(OwnedToken {
+        access_token: token,
+        id_token: None,
+        token_expiry: Utc::now() + MAX_ACCESS_TOKEN_LIFETIME,
+    })
+}
+
+/// Mint an access token for use as a Cloud SQL IAM database password.
+///
+/// The token is restricted to the `sqlservice.login` scope and minted fresh on
+/// every call, so connection pools can call this once per new connection. If the
+/// cached grant does not cover Cloud SQL yet, a browser login requesting it is run.
+pub async fn get_sql_password(creds: &Creds) -> Result<String> {
+    get_sql_password_with_options(creds, &LoginOptions::default()).await
+}
+
+/// Like [`get_sql_password`], with control over the browser login and cancellation.
+pub async fn get_sql_password_with_options(creds: &Creds, opts: &LoginOptions) -> Result<String> {
+    cancellable(
+        &opts.cancel,
+        fetch_scoped_access_token(creds, opts, SQL_LOGIN_SCOPE),
+    )
+    .await
+    .inspect_err(|_| stats::record(Event::Failure))
+}
+
+/// An access token limited to `scopes`, without an ID token.
+///
+/// For callers that only talk to Google APIs, e.g. with just the
+/// `cloud-platform` scope. The token is minted fresh from the cached refresh
+/// token on every call, so reuse it until `token_expiry`. If the cached grant
+/// does not cover `scopes`, a login requesting them is run first; the login
+/// still asks for `openid email`, which the cache needs to tell accounts apart.
+pub async fn get_access_token(creds: &Creds, scopes: &[&str]) -> Result<TokenOutput<'static>> {
+    get_access_token_with_options(creds, &LoginOptions::default(), scopes).await
+}
+
+/// Like [`get_access_token`], with control over the browser login and cancellation.
+pub async fn get_access_token_with_options(
+    creds: &Creds,
+    opts: &LoginOptions,
+    scopes: &[&str],
+) -> Result<TokenOutput<'static>> {
+    let token = cancellable(&opts.cancel, fetch_scoped_token(creds, opts, scopes))
+        .await
+        .inspect_err(|_| stats::record(Event::Failure))?;
+    Ok(TokenOutput {
+        access_token: Box::leak(token.access_token.into_boxed_str()),
+        id_token: None,
+        token_expiry: Utc::now() + Duration::seconds(token.expires_in),
+    })
+}
+
+/// A fresh access token limited to `scope`, logging in to request the scope
+/// when the cached grant does not cover it.
+pub(crate) async fn fetch_scoped_access_token(
+    creds: &Creds,
+    opts: &LoginOptions,
+    scope: &str,
+) -> Result<String> {
+    Ok(fetch_scoped_token(creds, opts, &[scope])
+        .await?
+        .access_token)
+}
+
+/// A fresh access token limited to `scopes`, logging in to request them
+/// when the cached grant does not cover them.
+async fn fetch_scoped_token(
+    creds: &Creds,
+    opts: &LoginOptions,
+    scopes: &[&str],
+) -> Result<AccessTokenResponse> {
+    let mut opts = opts.clone();
+    opts.scopes
+        .extend(scopes.iter().map(|scope| scope.to_string()));
+    let entry = entry_scopes(&opts);
+
+    // The grant of an earlier login for `scopes`, else the default one,
+    // which may cover them too.
+    let saved = load_scoped_token(&creds.client_id, &entry)
+        .or_else(|| load_cached_token(&creds.client_id))
+        .filter(|saved| !saved.refresh_token.is_empty());
+    if let Some(saved) = saved {
+        match refresh_scoped(creds, &saved.refresh_token, scopes).await {
+            Ok(token) => return Ok(token),
+            Err(err) if !login_fixes(&err) => return Err(err),
+            Err(_) => {}
+        }
+    }
+
+    perform_login(creds, DEFAULT_SCOPES, &opts).await?;
+
+    let saved = load_scoped_token(&creds.client_id, &entry)
+        .filter(|saved| !saved.refresh_token.is_empty())
+        .ok_or_else(|| anyhow!("Login returned no refresh token"))?;
+    refresh_scoped(creds, &saved.refresh_token, scopes).await
+}
+
+/// Exchange a refresh token for an access token limited to `scopes`.
+async fn refresh_scoped(
+    creds: &Creds,
+    refresh_token: &str,
+    scopes: &[&str],
+) -> Result<AccessTokenResponse> {
+    let scope = scopes.join(" ");
+    let client = Client::new();
+    let res = debug::send(client.post("https://oauth2.googleapis.com/token").form(&[
+        ("client_id", creds.client_id.as_str()),
+        ("client_secret", creds.client_secret.as_str()),
+        ("refresh_token", refresh_token),
+        ("grant_type", "refresh_token"),
+        ("scope", scope.as_str()),
+    ]))
+    .await?;
+
+    if !res.status().is_success() {
+        let body = res.text().await.unwrap_or_default();
+        return Err(match serde_json::from_str::<TokenErrorResponse>(&body) {
+            Ok(err) => anyhow::Error::new(err).context(format!("Refresh for scope {scope} failed")),
+            Err(_) => anyhow!("Refresh for scope {scope} failed: {body}"),
+        });
+    }
+
+    let token = res.json::<AccessTokenResponse>().await?;
+    stats::record(Event::Refresh);
+    Ok(token)
+}
+
+/// Whether a login can cure a failed refresh: the grant was revoked or has
+/// expired, or does not cover the requested scopes.
+fn login_fixes(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<TokenErrorResponse>()
+        .is_some_and(|err| matches!(err.error.as_str(), "invalid_grant" | "invalid_scope"))
+}
+
+/// Obtain a short-lived access token for `target_service_account` by
+/// impersonating it with the user's credentials.
+///
+/// Calls the IAM Credentials `generateAccessToken` method, which needs
+/// `roles/iam.serviceAccountTokenCreator` on the target. `scopes` defaults to
+/// `cloud-platform` when empty. Minted tokens are reused within the process
+/// until shortly before they expire. There is no ID token.
+pub async fn impersonate(
+    creds: &Creds,
+    target_service_account: &str,
+    scopes: &[&str],
+) -> Result<TokenOutput<'static>> {
+    impersonate_with_options(
+        creds,
+        &LoginOptions::default(),
+        target_service_account,
+        scopes,
+    )
+    .await
+}
+
+/// Like [`impersonate`], with control over the browser login and cancellation.
+pub async fn impersonate_with_options(
+    creds: &Creds,
+    opts: &LoginOptions,
+    target_service_account: &str,
+    scopes: &[&str],
+) -> Result<TokenOutput<'static>> {
+    let (access_token, token_expiry) = cancellable(
+        &opts.cancel,
+        impersonated_access_token(creds, opts, target_service_account, scopes),
+    )
+    .await
+    .inspect_err(|_| stats::record(Event::Failure))?;
+    Ok(TokenOutput {
+        access_token: Box::leak(access_token.into_boxed_str()),
+        id_token: None,
+        token_expiry,
+    })
+}
+
+/// Mint an ID token whose `aud` is `audience`, such as a Cloud Run service
+/// URL or an IAP OAuth client ID.
+///
+/// The token belongs to the service account gcloud impersonates
+/// (`auth/impersonate_service_account`), or on Google Cloud to the metadata
+/// server's service account. Google does not issue user ID tokens for other
+/// audiences, so without either this fails.
+pub async fn get_id_token(creds: &Creds, audience: &str) -> Result<String> {
+    get_id_token_with_options(creds, &LoginOptions::default(), None, audience).await
+}
+
+/// Like [`get_id_token`], impersonating `service_account` when given.
+pub async fn get_id_token_with_options(
+    creds: &Creds,
+    opts: &LoginOptions,
+    service_account: Option<&str>,
+    audience: &str,
+) -> Result<String> {
+    cancellable(
+        &opts.cancel,
+        mint_id_token(creds, opts, service_account, audience),
+    )
+    .await
+    .inspect_err(|_| stats::record(Event::Failure))
+}
+
+async fn mint_id_token(
+    creds: &Creds,
+    opts: &LoginOptions,
+    service_account: Option<&str>,
+    audience: &str,
+) -> Result<String> {
+    let service_account = service_account
+        .map(str::to_string)
+        .or_else(gcloud::impersonated_service_account);
+    if let Some(service_account) = service_account {
+        return get_id_tokens_as(creds, opts, &service_account, &[audience])
+            .await?
+            .remove(audience)
+            .ok_or_else(|| anyhow!("No ID token was minted for {audience}"));
+    }
+    if metadata_server_available().await {
+        return get_id_token_from_metadata(audience).await;
+    }
+    Err(anyhow!(
+        "An ID token for {audience} needs a service account: impersonate one with \
+         `gcloud config set auth/impersonate_service_account SA_EMAIL`, or run on Google Cloud"
+    ))
+}
+
+/// Obtain an access token for a service account from its key file, by
+/// exchanging a JWT assertion signed with the key (RFC 7523).
+///
+/// `scopes` defaults to `cloud-platform` when empty. With a subject set by
+/// [`ServiceAccountCreds::with_subject`], the token acts as that Workspace
+/// user. No browser or cache is involved; each call mints a new token, so callers should reuse it until
+/// `token_expiry`. There is no ID token.
+pub async fn get_token_for_service_account(
+    sa: &ServiceAccountCreds,
+    scopes: &[&str],
+) -> Result<TokenOutput<'static>> {
+    exchange_assertion(sa, scopes)
+        .await
+        .inspect_err(|_| stats::record(Event::Failure))
+}
+
+async fn exchange_assertion(
+    sa: &ServiceAccountCreds,
+    scopes: &[&str],
+) -> Result<TokenOutput<'static>> {
+    let scopes = if scopes.is_empty() {
+        CLOUD_PLATFORM_SCOPE.to_string()
+    } else {
+        scopes.join(" ")
+    };
+    let assertion = sign_assertion(sa, &assertion_claims(sa, &scopes, Utc::now()))?;
+
+    let client = Client::new();
+    let res = debug::send(client.post(&sa.token_uri).form(&[
+        ("grant_type", JWT_BEARER_GRANT),
+        ("assertion", assertion.as_str()),
+    ]))
+    .await?;
+    if !res.status().is_success() {
+        let err = res.json::<TokenErrorResponse>().await?;
+        return Err(anyhow!(
+            "Service account {} token request failed: {err}",
+            sa.client_email
+        ));
+    }
+
+    let res = res.json::<AccessTokenResponse>().await?;
+    stats::record(Event::Refresh);
+    Ok(TokenOutput {
+        access_token: Box::leak(res.access_token.into_boxed_str()),
+        id_token: None,
+        token_expiry: Utc::now() + Duration::seconds(res.expires_in),
+    })
+}
+
+/// Claims of a JWT bearer assertion requesting `scopes`, issued at `now`.
+fn assertion_claims(sa: &ServiceAccountCreds, scopes: &str, now: DateTime<Utc>) -> AssertionClaims {
+    AssertionClaims {
+        iss: sa.client_email.clone(),
+        sub: sa.subject.clone(),
+        scope: scopes.to_string(),
+        aud: sa.token_uri.clone(),
+        iat: now.timestamp(),
+        exp: (now + ASSERTION_LIFETIME).timestamp(),
+    }
+}
+
+/// Mint a self-signed JWT for the Google API at `audience`, such as
+/// `https://pubsub.googleapis.com/`, locally and without a token request.
+///
+/// APIs that accept self-signed JWTs, including most gRPC APIs, take it as a
+/// bearer token in place of an access token. It is valid for an hour and
+/// returned as the access token, with an empty ID token.
+///
+/// # Errors
+///
+/// Fails when the key is unusable or a domain-wide delegation subject is
+/// set, which self-signed JWTs cannot carry.
+pub fn self_signed_jwt(sa: &ServiceAccountCreds, audience: &str) -> Result<TokenOutput<'static>> {
+    if sa.subject.is_some() {
+        return Err(anyhow!(
+            "Self-signed JWTs cannot act as a delegated user; use get_token_for_service_account"
+        ));
+    }
+    let now = Utc::now();
+    let claims = SelfSignedClaims {
+        iss: sa.client_email.clone(),
+        sub: sa.client_email.clone(),
+        aud: audience.to_string(),
+        iat: now.timestamp(),
+        exp: (now + ASSERTION_LIFETIME).timestamp(),
+    };
+    let jwt = sign_assertion(sa, &claims)?;
+    Ok(TokenOutput {
+        access_token: Box::leak(jwt.into_boxed_str()),
+        id_token: None,
+        token_expiry: now + ASSERTION_LIFETIME,
+    })
+}
+
+/// Sign `claims` with the service account's private key (RS256).
+fn sign_assertion(sa: &ServiceAccountCreds, claims: &impl Serialize) -> Result<String> {
+    let key = EncodingKey::from_rsa_pem(sa.private_key.as_bytes())
+        .map_err(|err| anyhow!("Service account private key is unusable: {err}"))?;
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(sa.private_key_id.clone()).filter(|kid| !kid.is_empty());
+    Ok(jsonwebtoken::encode(&header, claims, &key)?)
+}
+
+/// Obtain the tokens of the account `email`, whichever account is current.
+///
+/// Its cached token is returned or refreshed without making it the current
+/// or last logged-in account. With nothing usable cached, a login
+/// pre-filled with `email` runs, and is refused if another account is picked.
+/// Needs the keyring, since a `GCLOUD_IDENTITY_TOKEN_PATH` file holds a
+/// single account.
+pub async fn get_token_for_account(creds: &Creds, email: &str) -> Result<TokenOutput<'static>> {
+    get_token_for_account_with_options(creds, &LoginOptions::default(), email).await
+}
+
+/// Like [`get_token_for_account`], with control over the login and cancellation.
+pub async fn get_token_for_account_with_options(
+    creds: &Creds,
+    opts: &LoginOptions,
+    email: &str,
+) -> Result<TokenOutput<'static>> {
+    let opts = LoginOptions {
+        account: Some(email.to_string()),
+        ..opts.clone()
+    };
+    get_token_with_options(creds, &opts).await
+}
+
+async fn fetch_account_token(
+    creds: &Creds,
+    opts: &LoginOptions,
+    email: &str,
+) -> Result<OwnedToken> {
+    if file_cache_path().is_some() {
+        return Err(anyhow!(
+            "Tokens of a chosen account need the keyring; unset {CACHE_PATH_ENV}"
+        ));
+    }
+
+    if let Some(saved) = load_scoped_account(email, &creds.client_id, &entry_scopes(opts)) {
+        if saved.token_expiry > Utc::now() + opts.expiry_margin() {
+            stats::record(Event::Hit);
+            return Ok(saved.into());
+        }
+        stats::record(Event::Miss);
+        match refresh_account(creds, email, &saved).await {
+            Ok(updated) => return Ok(updated.into()),
+            Err(err) if matches!(err.downcast_ref(), Some(AuthError::LoginRequired)) => {}
+            Err(err) => return Err(err),
+        }
+    } else {
+        stats::record(Event::Miss);
+    }
+
+    let mut opts = opts.clone();
+    opts.login_hint.get_or_insert_with(|| email.to_string());
+    let saved = interactive_login(creds, DEFAULT_SCOPES, &opts).await?;
+    let logged_in = saved
+        .id_token
+        .as_deref()
+        .and_then(|id_token| decode_unverified(id_token).ok())
+        .and_then(|claims| Some(claims.get("email")?.as_str()?.to_string()));
+    if !logged_in
+        .as_deref()
+        .is_some_and(|logged_in| logged_in.eq_ignore_ascii_case(email))
+    {
+        return Err(anyhow!(
+            "Logged in as {}, not {email}",
+            logged_in
+                .as_deref()
+                .unwrap_or("an account without an email")
+        ));
+    }
+    save_account_token(email, &saved)?;
+    stats::record(Event::Login);
+    run_refresh_hook(Trigger::Login, &saved).await;
+    Ok(saved.into())
+}
+
+/// Run an interactive login now, whatever is cached, and cache its tokens.
+///
+/// In the keyring the tokens are stored under the account's email, next to
+/// those of other accounts, and the account becomes the last logged-in one.
+/// A `GCLOUD_IDENTITY_TOKEN_PATH` file holds a single entry, which is replaced.
+pub async fn login(creds: &Creds, opts: &LoginOptions) -> Result<TokenOutput<'static>> {
+    cancellable(&opts.cancel, perform_login(creds, DEFAULT_SCOPES, opts))
+        .await
+        .map(OwnedToken::leak)
+        .inspect_err(|_| stats::record(Event::Failure))
+}
+
+/// Like [`login`], showing Google's account chooser even when the browser is
+/// signed in to a single account.
+///
+/// Unless `opts.prompt` says otherwise, the consent screen follows the
+/// chooser, so a refresh token is issued for an account seen before too.
+pub async fn select_account(creds: &Creds, opts: &LoginOptions) -> Result<TokenOutput<'static>> {
+    login(creds, &select_account_options(opts)).await
+}
+
+/// `opts` with the account chooser prompt, unless they choose a prompt.
+///
+/// It goes first among the extra parameters so later ones still override it.
+fn select_account_options(opts: &LoginOptions) -> LoginOptions {
+    let mut opts = opts.clone();
+    if opts.prompt.is_none() {
+        opts.extra_auth_params
+            .insert(0, ("prompt".into(), SELECT_ACCOUNT_PROMPT.into()));
+    }
+    opts
+}
+
+/// Revoke the cached grant at Google, then remove it from the cache.
+///
+/// The refresh token is revoked, which also invalidates the access tokens
+/// issued with it; an entry without one has its access token revoked. A token
+/// Google no longer knows is only removed. Returns `false` when nothing is
+/// cached.
+pub async fn revoke_token(creds: &Creds) -> Result<bool> {
+    let Some(saved) = load_cached_token(&creds.client_id) else {
+        return Ok(false);
+    };
+    let token = if saved.refresh_token.is_empty() {
+        &saved.access_token
+    } else {
+        &saved.refresh_token
+    };
+
+    let res = debug::send(Client::new().post(REVOKE_URL).form(&[("token", token)])).await?;
+    if !res.status().is_success() {
+        let err = res.json::<TokenErrorResponse>().await?;
+        // Already revoked or expired, so nothing is left to revoke.
+        if err.error != "invalid_token" {
+            return Err(anyhow!("Token revocation failed: {err}"));
+        }
+    }
+    delete_token(&creds.client_id)?;
+    Ok(true)
+}
+
+/// What Google's `tokeninfo` endpoint reports for a valid access token.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TokenInfo {
+    /// Scopes the token grants
+    pub scopes: Vec<String>,
+    /// When the token expires
+    pub expiry: DateTime<Utc>,
+    /// OAuth client the token was issued to
+    pub audience: Option<String>,
+    /// Email of the account, when the `email` scope was granted
+    pub email: Option<String>,
+    /// Google account ID of the token's subject
+    pub subject: Option<String>,
+}
+
+impl TokenInfo {
+    /// Whether the token grants `scope`.
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|granted| granted == scope)
+    }
+}
+
+/// Outcome of [`introspect`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum TokenStatus {
+    /// Google accepts the token
+    Valid(TokenInfo),
+    /// Google rejects the token, e.g. because it expired or was revoked
+    Invalid {
+        /// Google's explanation
+        reason: String,
+    },
+}
+
+/// `tokeninfo` response, with numbers sent as strings.
+#[derive(Deserialize)]
+struct TokenInfoResponse {
+    #[serde(default)]
+    scope: String,
+    exp: String,
+    #[serde(default)]
+    aud: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    sub: Option<String>,
+}
+
+impl TryFrom<TokenInfoResponse> for TokenInfo {
+    type Error = anyhow::Error;
+
+    fn try_from(res: TokenInfoResponse) -> Result<Self> {
+        let expiry = res
+            .exp
+            .parse()
+            .ok()
+            .and_then(|secs| DateTime::from_timestamp(secs, 0))
+            .ok_or_else(|| anyhow!("tokeninfo returned an invalid exp: {:?}", res.exp))?;
+        Ok(Self {
+            scopes: res.scope.split_whitespace().map(str::to_string).collect(),
+            expiry,
+            audience: res.aud,
+            email: res.email,
+            subject: res.sub,
+        })
+    }
+}
+
+/// Ask Google whether `access_token` is valid, and for its scopes and expiry.
+///
+/// Meant for diagnosing rejected tokens: unlike the cached expiry, this
+/// reflects revocation and the scopes actually granted. An error means
+/// Google could not be asked, not that the token is invalid.
+pub async fn introspect(access_token: &str) -> Result<TokenStatus> {
+    let res = debug::send(
+        Client::new()
+            .post(TOKENINFO_URL)
+            .form(&[("access_token", access_token)]),
+    )
+    .await?;
+    if res.status().is_client_error() {
+        let err = res.json::<TokenErrorResponse>().await?;
+        return Ok(TokenStatus::Invalid {
+            reason: err.to_string(),
+        });
+    }
+    if !res.status().is_success() {
+        return Err(anyhow!("tokeninfo failed with HTTP {}", res.status()));
+    }
+    Ok(TokenStatus::Valid(
+        res.json::<TokenInfoResponse>().await?.try_into()?,
+    ))
+}
+
+/// Outcome of a successful [`renew`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Renewal {
+    /// The cached token is valid beyond the requested margin
+    StillFresh,
+    /// The cached token was refreshed and saved
+    Refreshed,
+}
+
+/// Refresh the cached token if it expires within `margin`, never opening a browser.
+///
+/// Meant for cron jobs and systemd timers that keep tokens warm.
+///
+/// # Errors
+///
+/// Returns [`AuthError::LoginRequired`] when nothing is cached or Google
+/// rejects the stored refresh token.
+pub async fn renew(creds: &Creds, margin: Duration) -> Result<Renewal> {
+    renew_with_options(creds, &LoginOptions::default(), margin).await
+}
+
+/// Like [`renew`], for the token [`get_token_with_options`] would return with
+/// `opts`: that of `opts.account`, else the current account, for the scopes
+/// of `opts`.
+pub async fn renew_with_options(
+    creds: &Creds,
+    opts: &LoginOptions,
+    margin: Duration,
+) -> Result<Renewal> {
+    renew_selected(creds, opts, margin)
+        .await
+        .inspect_err(|_| stats::record(Event::Failure))
+}
+
+async fn renew_selected(creds: &Creds, opts: &LoginOptions, margin: Duration) -> Result<Renewal> {
+    let saved = load_selected_token(creds, opts).ok_or(AuthError::LoginRequired)?;
+    if saved.token_expiry > Utc::now() + margin {
+        stats::record(Event::Hit);
+        return Ok(Renewal::StillFresh);
+    }
+    stats::record(Event::Miss);
+
+    match &opts.account {
+        Some(email) => refresh_account(creds, email, &saved).await?,
+        None => exchange_refresh_token(creds, &saved).await?,
+    };
+    Ok(Renewal::Refreshed)
+}
+
+/// The token [`fetch_token`] would return with `opts` without refreshing or
+/// logging in, if there is one.
+///
+/// Only a token that still meets `opts` is returned; otherwise the caller
+/// falls back to the full lookup, which decides what to do about it.
+pub(crate) fn cached_token(creds: &Creds, opts: &LoginOptions) -> Option<OwnedToken> {
+    if let Some(token) = external_access_token() {
+        stats::record(Event::Hit);
+        return Some(token);
+    }
+    let saved = load_selected_token(creds, opts)?;
+    let usable = saved.token_expiry > Utc::now() + opts.expiry_margin()
+        && missing_scopes(&saved.granted_scopes, &login_scopes(DEFAULT_SCOPES, opts)).is_empty()
+        && opts
+            .assurance
+            .check_id_token(saved.id_token.as_deref())
+            .is_ok();
+    usable.then(|| {
+        stats::record(Event::Hit);
+        saved.into()
+    })
+}
+
+/// The cached token [`get_token_with_options`] starts from with `opts`,
+/// without refreshing it.
+pub fn load_selected_token(creds: &Creds, opts: &LoginOptions) -> Option<SavedToken> {
+    let scopes = entry_scopes(opts);
+    match &opts.account {
+        Some(email) => load_scoped_account(email, &creds.client_id, &scopes),
+        None => load_scoped_token(&creds.client_id, &scopes),
+    }
+}
+
+/// Renewal of one cached account by [`renew_all`].
+#[derive(Debug)]
+pub struct AccountRenewal {
+    /// The account's email, or the name it is stored under
+    pub account: String,
+    /// What happened, or why the account could not be renewed
+    pub result: Result<Renewal>,
+}
+
+/// Refresh every cached account of `creds`' OAuth client whose token expires
+/// within `margin`, never opening a browser.
+///
+/// Accounts are renewed one after another; a failure of one does not stop
+/// the others. With a `GCLOUD_IDENTITY_TOKEN_PATH` file, its single token is
+/// renewed as by [`renew`]. Returns nothing when no account is cached.
+pub async fn renew_all(creds: &Creds, margin: Duration) -> Vec<AccountRenewal> {
+    if file_cache_path().is_some() {
+        let account = load_cached_token(&creds.client_id)
+            .and_then(|saved| decode_unverified(saved.id_token.as_deref()?).ok())
+            .and_then(|claims| Some(claims.get("email")?.as_str()?.to_string()))
+            .unwrap_or_else(|| "cached token".to_string());
+        return vec![AccountRenewal {
+            account,
+            result: renew(creds, margin).await,
+        }];
+    }
+
+    let mut renewals = Vec::new();
+    for account in cached_accounts() {
+        // Accounts known to hold only other clients' tokens are not ours to renew.
+        if !account.clients.is_empty() && !account.clients.contains(&creds.client_id) {
+            continue;
+        }
+        let result = renew_account(creds, &account.user, margin)
+            .await
+            .inspect_err(|_| stats::record(Event::Failure));
+        renewals.push(AccountRenewal {
+            account: account.display_name().to_string(),
+            result,
+        });
     }
+    renewals
+}
+
+async fn renew_account(creds: &Creds, user: &str, margin: Duration) -> Result<Renewal> {
+    let saved = load_account(user, &creds.client_id).ok_or(AuthError::LoginRequired)?;
+    if saved.token_expiry > Utc::now() + margin {
+        stats::record(Event::Hit);
+        return Ok(Renewal::StillFresh);
+    }
+    stats::record(Event::Miss);
+    refresh_account(creds, user, &saved).await?;
+    Ok(Renewal::Refreshed)
+}
+
+/// Refresh the cached token of `user` under the cache lock, unless another
+/// process already did, and save it.
+async fn refresh_account(creds: &Creds, user: &str, saved: &SavedToken) -> Result<SavedToken> {
+    let _lock = lock_cache().await?;
+    invalidate_memory_cache();
+    let current = load_scoped_account(user, &creds.client_id, &saved.requested_scopes)
+        .unwrap_or_else(|| saved.clone());
+    if refreshed_elsewhere(saved, &current, Utc::now()) {
+        return Ok(current);
+    }
+    let updated = request_refresh(creds, &current).await?;
+    save_account_token(user, &updated)?;
+    stats::record(Event::Refresh);
+    record_refresh(&updated);
+    run_refresh_hook(Trigger::Refresh, &updated).await;
+    Ok(updated)
+}
 
-    // No cached token — full auth flow
-    perform_login(creds).await
+/// Fill the empty cache by refreshing `refresh_token`, as found in an
+/// `authorized_user` credentials file.
+///
+/// A rejected refresh token returns [`AuthError::LoginRequired`].
+async fn seed_from_refresh_token(creds: &Creds, refresh_token: &str) -> Result<SavedToken> {
+    let seed = SavedToken {
+        refresh_token: refresh_token.to_string(),
+        access_token: String::new(),
+        id_token: None,
+        token_expiry: DateTime::UNIX_EPOCH,
+        refresh_token_issued_at: None,
+        granted_scopes: Vec::new(),
+        requested_scopes: Vec::new(),
+        client_id: creds.client_id.clone(),
+    };
+    // A new cache entry, so unlike other refreshes it becomes the last login.
+    let saved = request_refresh(creds, &seed).await?;
+    save_token(&saved)?;
+    stats::record(Event::Refresh);
+    record_refresh(&saved);
+    run_refresh_hook(Trigger::Refresh, &saved).await;
+    Ok(saved)
 }
 
 /// Refresh an expired token using the stored refresh token.
-async fn refresh_token(creds: &Creds, saved: &SavedToken) -> Result<TokenOutput<'static>> {
+///
+/// If Google rejects the refresh token, a fresh browser login is performed instead.
+async fn refresh_token(
+    creds: &Creds,
+    saved: &SavedToken,
+    opts: &LoginOptions,
+) -> Result<OwnedToken> {
+    match exchange_refresh_token(creds, saved).await {
+        Ok(updated) => Ok(updated.into()),
+        Err(err) if matches!(err.downcast_ref(), Some(AuthError::LoginRequired)) => {
+            eprintln!("Logging in again.");
+            perform_login(creds, DEFAULT_SCOPES, opts).await
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Exchange the stored refresh token for new tokens and save them.
+///
+/// This runs under the cross-process cache lock, reading the cache again
+/// once the lock is held. If another process refreshed the token meanwhile,
+/// its result is returned; otherwise the latest refresh token is used, so a
+/// rotated one is never replaced by its predecessor.
+async fn exchange_refresh_token(creds: &Creds, saved: &SavedToken) -> Result<SavedToken> {
+    let _lock = lock_cache().await?;
+    invalidate_memory_cache();
+    let current = load_scoped_token(&creds.client_id, &saved.requested_scopes)
+        .unwrap_or_else(|| saved.clone());
+    if refreshed_elsewhere(saved, &current, Utc::now()) {
+        return Ok(current);
+    }
+
+    let updated = request_refresh(creds, &current).await?;
+    update_token(&updated)?;
+    stats::record(Event::Refresh);
+    record_refresh(&updated);
+    run_refresh_hook(Trigger::Refresh, &updated).await;
+    Ok(updated)
+}
+
+/// Whether `current`, read under the cache lock, is a live token another
+/// process obtained since `stale` was read.
+fn refreshed_elsewhere(stale: &SavedToken, current: &SavedToken, now: DateTime<Utc>) -> bool {
+    current.access_token != stale.access_token
+        && !current.access_token.is_empty()
+        && current.token_expiry > now
+}
+
+/// Exchange the stored refresh token for new tokens without saving them.
+///
+/// An `invalid_grant` rejection prints a warning explaining the likely cause
+/// and returns [`AuthError::LoginRequired`], as does an entry without a
+/// refresh token.
+pub(crate) async fn request_refresh(creds: &Creds, saved: &SavedToken) -> Result<SavedToken> {
+    if saved.refresh_token.is_empty() {
+        return Err(AuthError::LoginRequired.into());
+    }
+
     let client = Client::new();
-    let res = client
-        .post("https://oauth2.googleapis.com/token")
-        .form(&[
-            ("client_id", &creds.client_id),
-            ("client_secret", &creds.client_secret),
-            ("refresh_token", &saved.refresh_token),
-            ("grant_type", &"refresh_token".to_string()),
-        ])
-        .send()
-        .await?
-        .json::<TokenResponse>()
-        .await?;
+    let res = debug::send(client.post("https://oauth2.googleapis.com/token").form(&[
+        ("client_id", &creds.client_id),
+        ("client_secret", &creds.client_secret),
+        ("refresh_token", &saved.refresh_token),
+        ("grant_type", &"refresh_token".to_string()),
+    ]))
+    .await?;
 
+    if !res.status().is_success() {
+        let err = res.json::<TokenErrorResponse>().await?;
+        if err.error == "invalid_grant" {
+            warn_invalid_grant(&err, saved.refresh_token_issued_at, Utc::now());
+            // Best effort: only `cache prune` reads this.
+            let _ = record_refresh_rejected(saved);
+            return Err(AuthError::LoginRequired.into());
+        }
+        return Err(anyhow!("Token refresh failed: {err}"));
+    }
+
+    let res = res.json::<TokenResponse>().await?;
     let expires_at = Utc::now() + Duration::seconds(res.expires_in);
-    let refresh_token = res
-        .refresh_token
-        .clone()
-        .unwrap_or_else(|| saved.refresh_token.clone());
+    let (refresh_token, refresh_token_issued_at) = match res.refresh_token.clone() {
+        Some(rotated) => (rotated, Some(Utc::now())),
+        None => (saved.refresh_token.clone(), saved.refresh_token_issued_at),
+    };
+    let granted_scopes = match parse_scopes(res.scope.as_deref()) {
+        scopes if scopes.is_empty() => saved.granted_scopes.clone(),
+        scopes => scopes,
+    };
 
-    let updated = SavedToken {
+    Ok(SavedToken {
         refresh_token,
         access_token: res.access_token.clone(),
         id_token: res.id_token.clone(),
         token_expiry: expires_at,
-    };
+        refresh_token_issued_at,
+        granted_scopes,
+        requested_scopes: saved.requested_scopes.clone(),
+        client_id: creds.client_id.clone(),
+    })
+}
+
+/// Explain an `invalid_grant` refresh failure on stderr.
+///
+/// Refresh tokens that die after about a week are the signature of an OAuth
+/// client whose consent screen is still in "Testing" publishing status.
+fn warn_invalid_grant(
+    err: &TokenErrorResponse,
+    issued_at: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) {
+    eprintln!("Stored refresh token was rejected ({err}).");
+    if let Some(age) = testing_client_expiry_age(issued_at, now) {
+        eprintln!(
+            "The refresh token was issued {} days ago. OAuth clients whose consent screen is in \
+             \"Testing\" publishing status get refresh tokens that expire after 7 days. To stop \
+             these weekly logins, set the publishing status to \"In production\" on the OAuth \
+             consent screen page of the Google Cloud console.",
+            age.num_days()
+        );
+    }
+}
+
+/// Returns the refresh token's age if it matches the 7-day "Testing" client expiry.
+fn testing_client_expiry_age(
+    issued_at: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> Option<Duration> {
+    let age = now - issued_at?;
+    (age >= TESTING_REFRESH_TOKEN_LIFETIME - Duration::hours(1)).then_some(age)
+}
+
+/// Perform the interactive login `opts` selects, requesting `scopes`, and
+/// cache the result.
+async fn perform_login(creds: &Creds, scopes: &[&str], opts: &LoginOptions) -> Result<OwnedToken> {
+    let saved = interactive_login(creds, scopes, opts).await?;
+    save_token(&saved)?;
+    stats::record(Event::Login);
+    run_refresh_hook(Trigger::Login, &saved).await;
+    Ok(saved.into())
+}
 
-    save_token(&updated)?;
-    Ok(token_output_from_saved(updated))
+/// Run the interactive login `opts` selects, requesting `scopes`, without caching.
+async fn interactive_login(
+    creds: &Creds,
+    scopes: &[&str],
+    opts: &LoginOptions,
+) -> Result<SavedToken> {
+    if opts.no_login {
+        return Err(AuthError::LoginRequired.into());
+    }
+    match opts.login_flow() {
+        LoginFlow::Browser => browser_login(creds, scopes, opts).await,
+        LoginFlow::Manual => manual_login(creds, scopes, opts).await,
+        LoginFlow::DeviceCode => device_login(creds, scopes, opts).await,
+    }
 }
 
-/// Perform full browser-based OAuth flow.
-async fn perform_login(creds: &Creds) -> Result<TokenOutput<'static>> {
-    let port = get_or_init_port();
-    let redirect_uri = format!("http://localhost:{port}");
-    let auth_url = build_auth_url(&creds.client_id, &redirect_uri);
-    open_browser_or_print(&auth_url);
-    let code = capture_auth_code()?;
+/// Run the browser-based OAuth flow requesting `scopes`, without caching.
+pub(crate) async fn browser_login(
+    creds: &Creds,
+    scopes: &[&str],
+    opts: &LoginOptions,
+) -> Result<SavedToken> {
+    let session = LoginSession::for_options(opts)?;
+    let redirect_uri = session.redirect_uri();
+    let auth_url = login_auth_url(creds, scopes, &redirect_uri, opts);
+    open_browser_or_print(&auth_url, opts);
+    let state = auth_state(&auth_url).unwrap_or_default();
+    let code = session.capture_auth_code(&state).await?;
+    let nonce = auth_nonce(&auth_url).unwrap_or_default();
+    exchange_auth_code(creds, &code, &redirect_uri, &nonce, opts).await
+}
+
+/// Run the authorization code flow without a local server, requesting
+/// `scopes`, without caching.
+///
+/// The URL is only printed, since the browser is usually on another machine,
+/// and the user pastes back the address the redirect ended on.
+async fn manual_login(creds: &Creds, scopes: &[&str], opts: &LoginOptions) -> Result<SavedToken> {
+    let auth_url = login_auth_url(creds, scopes, MANUAL_REDIRECT_URI, opts);
+    eprintln!("\nOpen this URL in a browser on any machine:\n\n{auth_url}\n");
+    let state = auth_state(&auth_url).unwrap_or_default();
+    let code = read_pasted_auth_code(&state).await?;
+    let nonce = auth_nonce(&auth_url).unwrap_or_default();
+    exchange_auth_code(creds, &code, MANUAL_REDIRECT_URI, &nonce, opts).await
+}
+
+/// The authorization URL of a login requesting `scopes`.
+fn login_auth_url(creds: &Creds, scopes: &[&str], redirect_uri: &str, opts: &LoginOptions) -> Url {
+    let scopes = login_scopes(scopes, opts);
+    let mut auth_url = build_auth_url(&creds.client_id, redirect_uri, &scopes);
+    set_query_params(&mut auth_url, &opts.auth_url_params());
+    auth_url
+}
+
+/// Exchange an authorization code for tokens, refusing an ID token that does
+/// not carry the authorization request's `nonce`.
+async fn exchange_auth_code(
+    creds: &Creds,
+    code: &str,
+    redirect_uri: &str,
+    nonce: &str,
+    opts: &LoginOptions,
+) -> Result<SavedToken> {
+    let mut form = vec![
+        ("code", code),
+        ("client_id", creds.client_id.as_str()),
+        ("client_secret", creds.client_secret.as_str()),
+        ("redirect_uri", redirect_uri),
+        ("grant_type", "authorization_code"),
+    ];
+    form.extend(
+        opts.extra_token_params
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str())),
+    );
 
     let client = Client::new();
-    let res = client
-        .post("https://oauth2.googleapis.com/token")
-        .form(&[
-            ("code", &code),
-            ("client_id", &creds.client_id),
-            ("client_secret", &creds.client_secret),
-            ("redirect_uri", &redirect_uri),
-            ("grant_type", &"authorization_code".to_string()),
-        ])
-        .send()
-        .await?
-        .json::<TokenResponse>()
-        .await?;
+    let res = debug::send(
+        client
+            .post("https://oauth2.googleapis.com/token")
+            .form(&form),
+    )
+    .await?
+    .json::<TokenResponse>()
+    .await?;
+    check_nonce(res.id_token.as_deref(), nonce)?;
+    saved_from_login(creds, res, opts)
+}
+
+/// `scopes` plus the extra scopes in `opts`, without duplicates.
+pub(crate) fn login_scopes<'a>(scopes: &[&'a str], opts: &'a LoginOptions) -> Vec<&'a str> {
+    let mut scopes = scopes.to_vec();
+    for scope in &opts.scopes {
+        if !scopes.contains(&scope.as_str()) {
+            scopes.push(scope);
+        }
+    }
+    scopes
+}
 
+/// Turn the token endpoint's response to an interactive login with `creds`
+/// into a [`SavedToken`], refusing it if it falls short of `opts.assurance`.
+pub(crate) fn saved_from_login(
+    creds: &Creds,
+    res: TokenResponse,
+    opts: &LoginOptions,
+) -> Result<SavedToken> {
     let expires_at = Utc::now() + Duration::seconds(res.expires_in);
 
-    if let Some(refresh_token) = &res.refresh_token {
+    // Without a refresh token the access token is still cached, so callers are
+    // not sent back to the browser on every call before it expires.
+    let (refresh_token, refresh_token_issued_at) = match &res.refresh_token {
+        Some(refresh_token) => (refresh_token.clone(), Some(Utc::now())),
+        None => {
+            warn_missing_refresh_token(expires_at);
+            (String::new(), None)
+        }
+    };
+    // A login that falls short of the required assurance or domain is never cached.
+    opts.assurance.check_id_token(res.id_token.as_deref())?;
+    check_hosted_domain(res.id_token.as_deref(), opts.hosted_domain.as_deref())?;
+    Ok(SavedToken {
+        granted_scopes: parse_scopes(res.scope.as_deref()),
+        refresh_token,
+        access_token: res.access_token,
+        id_token: res.id_token,
+        token_expiry: expires_at,
+        refresh_token_issued_at,
+        requested_scopes: entry_scopes(opts),
+        client_id: creds.client_id.clone(),
+    })
+}
+
+/// Refuse an ID token whose `hd` claim is not `required`, if one is required.
+fn check_hosted_domain(id_token: Option<&str>, required: Option<&str>) -> Result<()> {
+    let Some(required) = required else {
+        return Ok(());
+    };
+    let id_token =
+        id_token.ok_or_else(|| anyhow!("No ID token to check the hosted domain {required} in"))?;
+    let claims = decode_unverified(id_token)?;
+    match claims.get("hd").and_then(|hd| hd.as_str()) {
+        Some(hd) if hd.eq_ignore_ascii_case(required) => Ok(()),
+        Some(hd) => Err(anyhow!(
+            "Logged in to an account of {hd}, but {required} is required"
+        )),
+        None => Err(anyhow!(
+            "Logged in to a personal account, but one of {required} is required"
+        )),
+    }
+}
+
+/// Refuse an ID token whose `nonce` claim is not `expected`, e.g. one issued
+/// to another login and substituted into this one.
+fn check_nonce(id_token: Option<&str>, expected: &str) -> Result<()> {
+    let id_token = id_token.ok_or_else(|| anyhow!("Google returned no ID token for the login"))?;
+    let claims = decode_unverified(id_token)?;
+    match claims.get("nonce").and_then(|nonce| nonce.as_str()) {
+        Some(nonce) if nonce == expected => Ok(()),
+        Some(_) => Err(anyhow!("The ID token's nonce does not match this login")),
+        None => Err(anyhow!(
+            "The ID token has no nonce, though this login sent one"
+        )),
+    }
+}
+
+/// Explain on stderr why the next login will be needed so soon.
+///
+/// Google withholds refresh tokens when it considers offline access already
+/// granted to the client; revoking the grant resets that.
+fn warn_missing_refresh_token(expires_at: DateTime<Utc>) {
+    eprintln!(
+        "Warning: Google returned no refresh token, so this login expires at {expires_at}.\n\
+         Remove this app at https://myaccount.google.com/permissions and log in again \
+         to grant offline access."
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+
+    #[test]
+    fn test_testing_client_expiry_detected_after_a_week() {
+        let now = Utc::now();
+        let issued_at = now - Duration::days(7);
+        assert!(testing_client_expiry_age(Some(issued_at), now).is_some());
+    }
+
+    #[test]
+    fn test_refreshed_elsewhere() {
+        let now = Utc::now();
+        let stale = SavedToken {
+            refresh_token: "r1".into(),
+            access_token: "a1".into(),
+            id_token: None,
+            token_expiry: now - Duration::minutes(1),
+            refresh_token_issued_at: None,
+            granted_scopes: Vec::new(),
+            requested_scopes: Vec::new(),
+            client_id: String::new(),
+        };
+        let rotated = SavedToken {
+            refresh_token: "r2".into(),
+            access_token: "a2".into(),
+            token_expiry: now + Duration::hours(1),
+            ..stale.clone()
+        };
+        assert!(refreshed_elsewhere(&stale, &rotated, now));
+        assert!(!refreshed_elsewhere(&stale, &stale, now));
+
+        let expired = SavedToken {
+            token_expiry: now - Duration::seconds(1),
+            ..rotated
+        };
+        assert!(!refreshed_elsewhere(&stale, &expired, now));
+    }
+
+    #[tokio::test]
+    async fn test_cancellable_returns_cancelled_error() {
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let err = cancellable(&cancel, std::future::pending::<Result<()>>())
+            .await
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(AuthError::Cancelled)));
+    }
+
+    #[tokio::test]
+    async fn test_renew_with_options_picks_the_chosen_account() {
+        use crate::cache::{TokenCache, shared_test_cache};
+
+        let client_id = "renew.apps.googleusercontent.com";
+        let creds = Creds {
+            client_id: client_id.into(),
+            client_secret: String::new(),
+            refresh_token: None,
+        };
+        let token = |access_token: &str, token_expiry| SavedToken {
+            refresh_token: String::new(),
+            access_token: access_token.into(),
+            id_token: None,
+            token_expiry,
+            refresh_token_issued_at: None,
+            granted_scopes: Vec::new(),
+            requested_scopes: Vec::new(),
+            client_id: client_id.into(),
+        };
+        let cloud = "https://www.googleapis.com/auth/cloud-platform";
+        let cache = shared_test_cache();
+        // The current account's token has expired and cannot be refreshed.
+        let expired = token("current", Utc::now() - Duration::minutes(5));
+        cache
+            .save(&format!("default:{client_id}"), &expired)
+            .unwrap();
+        let fresh = Utc::now() + Duration::hours(1);
+        cache
+            .save(&format!("b@example.com:{client_id}"), &token("b", fresh))
+            .unwrap();
+        let opts = LoginOptions {
+            account: Some("b@example.com".into()),
+            ..LoginOptions::default()
+        };
+        let scoped = LoginOptions {
+            scopes: vec![cloud.into()],
+            ..opts.clone()
+        };
+        let scopes = entry_scopes(&scoped).join(" ");
+        cache
+            .save(
+                &format!("b@example.com:{client_id}:{scopes}"),
+                &token("b-cloud", fresh),
+            )
+            .unwrap();
+
+        let saved = load_selected_token(&creds, &opts).unwrap();
+        assert_eq!(saved.access_token, "b");
+        let renewal = renew_with_options(&creds, &opts, Duration::minutes(1)).await;
+        assert_eq!(renewal.unwrap(), Renewal::StillFresh);
+
+        let saved = load_selected_token(&creds, &scoped).unwrap();
+        assert_eq!(saved.access_token, "b-cloud");
+    }
+
+    #[test]
+    fn test_login_older_than_uses_auth_time_then_issue_time() {
+        let now = Utc::now();
+        let claims = format!(
+            r#"{{"auth_time":{}}}"#,
+            (now - Duration::days(3)).timestamp()
+        );
+        let saved = SavedToken {
+            refresh_token: "refresh".into(),
+            access_token: "access".into(),
+            id_token: Some(format!("e30.{}.sig", URL_SAFE_NO_PAD.encode(claims))),
+            token_expiry: now,
+            refresh_token_issued_at: Some(now - Duration::days(30)),
+            granted_scopes: Vec::new(),
+            requested_scopes: Vec::new(),
+            client_id: String::new(),
+        };
+        assert!(!login_older_than(&saved, Duration::days(7), now));
+        assert!(login_older_than(&saved, Duration::days(1), now));
+
+        let saved = SavedToken {
+            id_token: None,
+            ..saved
+        };
+        assert!(login_older_than(&saved, Duration::days(7), now));
+        assert!(!login_older_than(&saved, Duration::days(60), now));
         let saved = SavedToken {
-            refresh_token: refresh_token.clone(),
-            access_token: res.access_token.clone(),
-            id_token: res.id_token.clone(),
-            token_expiry: expires_at,
+            refresh_token_issued_at: None,
+            ..saved
         };
-        save_token(&saved)?;
+        assert!(login_older_than(&saved, Duration::days(60), now));
     }
 
-    Ok(TokenOutput {
-        access_token: Box::leak(res.access_token.into_boxed_str()),
-        id_token: Box::leak(res.id_token.into_boxed_str()),
-        token_expiry: expires_at,
-    })
-}
+    #[test]
+    fn test_missing_scopes() {
+        let granted = parse_scopes(Some(
+            "openid https://www.googleapis.com/auth/userinfo.email \
+             https://www.googleapis.com/auth/drive.readonly",
+        ));
+        assert!(missing_scopes(&granted, &["openid", "email"]).is_empty());
+        assert!(
+            missing_scopes(
+                &granted,
+                &["https://www.googleapis.com/auth/drive.readonly"]
+            )
+            .is_empty()
+        );
+        assert_eq!(
+            missing_scopes(&granted, &["openid", "email", "profile"]),
+            ["profile"]
+        );
+
+        // A grant cached before scopes were tracked covers the defaults only.
+        assert!(missing_scopes(&[], DEFAULT_SCOPES).is_empty());
+        assert_eq!(
+            missing_scopes(&[], &["openid", "email", "profile"]),
+            ["profile"]
+        );
+    }
+
+    #[test]
+    fn test_regrant_scopes_keeps_defaults() {
+        let opts = LoginOptions {
+            scopes: vec!["https://www.googleapis.com/auth/drive.readonly".into()],
+            ..LoginOptions::default()
+        };
+        // A grant cached before scopes were tracked still asks for openid
+        // and email, so the login returns an ID token.
+        let legacy = regrant_scopes(&[]);
+        assert_eq!(
+            login_scopes(&legacy, &opts),
+            [
+                "openid",
+                "email",
+                "https://www.googleapis.com/auth/drive.readonly"
+            ]
+        );
+
+        let granted = parse_scopes(Some(
+            "openid https://www.googleapis.com/auth/userinfo.email \
+             https://www.googleapis.com/auth/calendar",
+        ));
+        assert_eq!(
+            regrant_scopes(&granted),
+            [
+                "openid",
+                "email",
+                "https://www.googleapis.com/auth/calendar"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_login_fixes_only_grant_rejections() {
+        let rejected = |error: &str| {
+            anyhow::Error::new(TokenErrorResponse {
+                error: error.into(),
+                error_description: None,
+            })
+            .context("Refresh for scope s failed")
+        };
+        assert!(login_fixes(&rejected("invalid_grant")));
+        assert!(login_fixes(&rejected("invalid_scope")));
+        assert!(!login_fixes(&rejected("invalid_client")));
+        assert!(!login_fixes(&anyhow!("Refresh for scope s failed: <html>")));
+    }
+
+    #[test]
+    fn test_select_account_options_prompt() {
+        let prompt = |opts: &LoginOptions| {
+            let mut url = build_auth_url("client", "http://localhost:1", DEFAULT_SCOPES);
+            set_query_params(&mut url, &select_account_options(opts).auth_url_params());
+            url.query_pairs()
+                .find(|(key, _)| key == "prompt")
+                .map(|(_, value)| value.into_owned())
+        };
+        assert_eq!(
+            prompt(&LoginOptions::default()).as_deref(),
+            Some("select_account consent")
+        );
+
+        let opts = LoginOptions {
+            prompt: Some(crate::config::Prompt::SelectAccount),
+            ..LoginOptions::default()
+        };
+        assert_eq!(prompt(&opts).as_deref(), Some("select_account"));
+    }
+
+    #[test]
+    fn test_check_hosted_domain() {
+        let id_token = |claims: &str| format!("e30.{}.sig", URL_SAFE_NO_PAD.encode(claims));
+        let corporate = id_token(r#"{"hd":"example.com"}"#);
+        assert!(check_hosted_domain(Some(&corporate), None).is_ok());
+        assert!(check_hosted_domain(Some(&corporate), Some("example.com")).is_ok());
+        assert!(check_hosted_domain(Some(&corporate), Some("other.com")).is_err());
+        assert!(check_hosted_domain(Some(&id_token("{}")), Some("example.com")).is_err());
+        assert!(check_hosted_domain(None, Some("example.com")).is_err());
+    }
+
+    #[test]
+    fn test_check_nonce() {
+        let id_token = |claims: &str| format!("e30.{}.sig", URL_SAFE_NO_PAD.encode(claims));
+        assert!(check_nonce(Some(&id_token(r#"{"nonce":"n"}"#)), "n").is_ok());
+        assert!(check_nonce(Some(&id_token(r#"{"nonce":"other"}"#)), "n").is_err());
+        assert!(check_nonce(Some(&id_token("{}")), "n").is_err());
+        let err = check_nonce(None, "n").unwrap_err();
+        assert_eq!(err.to_string(), "Google returned no ID token for the login");
+    }
+
+    #[test]
+    fn test_token_info_from_tokeninfo_response() {
+        let body = r#"{"azp":"id.apps.googleusercontent.com","aud":"id.apps.googleusercontent.com","sub":"1234","scope":"openid https://www.googleapis.com/auth/userinfo.email","exp":"1700000000","expires_in":"3599","email":"a@example.com","email_verified":"true","access_type":"offline"}"#;
+        let res: TokenInfoResponse = serde_json::from_str(body).unwrap();
+        let info = TokenInfo::try_from(res).unwrap();
+        assert!(info.has_scope("openid"));
+        assert!(!info.has_scope("https://www.googleapis.com/auth/cloud-platform"));
+        assert_eq!(info.expiry.timestamp(), 1_700_000_000);
+        assert_eq!(info.email.as_deref(), Some("a@example.com"));
+        assert_eq!(info.subject.as_deref(), Some("1234"));
+    }
+
+    #[test]
+    fn test_assertion_claims_and_unusable_key() {
+        let sa = ServiceAccountCreds {
+            client_email: "ci@p.iam.gserviceaccount.com".into(),
+            private_key: "not a key".into(),
+            private_key_id: String::new(),
+            token_uri: crate::config::TOKEN_URI.into(),
+            subject: None,
+        };
+        let now = Utc::now();
+        let claims = assertion_claims(&sa, "scope-a scope-b", now);
+        assert_eq!(claims.iss, sa.client_email);
+        assert_eq!(claims.aud, crate::config::TOKEN_URI);
+        assert_eq!(claims.scope, "scope-a scope-b");
+        assert_eq!(claims.exp - claims.iat, 3600);
+        assert!(serde_json::to_value(&claims).unwrap().get("sub").is_none());
+
+        let delegated = sa.clone().with_subject("admin@example.com");
+        let claims = assertion_claims(&delegated, "scope-a", now);
+        assert_eq!(
+            serde_json::to_value(&claims).unwrap()["sub"],
+            "admin@example.com"
+        );
+
+        let err = sign_assertion(&sa, &claims).unwrap_err();
+        assert!(err.to_string().contains("private key is unusable"));
+        assert!(self_signed_jwt(&sa, "https://pubsub.googleapis.com/").is_err());
+    }
 
-/// Construct a `TokenOutput` from a `SavedToken`.
-fn token_output_from_saved(saved: SavedToken) -> TokenOutput<'static> {
-    TokenOutput {
-        access_token: Box::leak(saved.access_token.into_boxed_str()),
-        id_token: Box::leak(saved.id_token.into_boxed_str()),
-        token_expiry: saved.token_expiry,
+    #[test]
+    fn test_testing_client_expiry_not_flagged_for_young_or_unknown_tokens() {
+        let now = Utc::now();
+        assert!(testing_client_expiry_age(Some(now - Duration::days(2)), now).is_none());
+        assert!(testing_client_expiry_age(None, now).is_none());
     }
 }
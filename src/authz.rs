@@ -0,0 +1,110 @@
+//! Interactive user credentials for the [`google-authz`] tower layer.
+//!
+//! `google-authz` refreshes tokens itself once it holds a refresh token, but
+//! cannot run a browser login. [`credentials`] runs one when nothing usable is
+//! cached, then hands the cached grant over, so a tonic or hyper client can be
+//! wrapped without glue code:
+//!
+//! ```rust,no_run
+//! use gcloud_identity_token::{authz, config::load_creds};
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let credentials = authz::credentials(
+//!     &load_creds()?,
+//!     &["https://www.googleapis.com/auth/cloud-platform"],
+//! )
+//! .await?;
+//! // GoogleAuthz::builder(channel).credentials(credentials).build().await
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`google-authz`]: https://docs.rs/google-authz
+
+use crate::auth::{entry_scopes, get_owned_token};
+use crate::cache::{adc_json, load_scoped_account, load_scoped_token};
+use crate::config::{Creds, LoginOptions, SavedToken};
+use crate::error::AuthError;
+use anyhow::{Result, anyhow};
+use google_authz::Credentials;
+
+/// `google-authz` credentials of the cached user, with a grant covering `scopes`.
+///
+/// Logs in first when nothing is cached or the cached grant lacks a scope.
+/// `google-authz` attaches access tokens for `scopes` to each request.
+pub async fn credentials(creds: &Creds, scopes: &'static [&'static str]) -> Result<Credentials> {
+    credentials_with_options(creds, &LoginOptions::default(), scopes).await
+}
+
+/// Like [`credentials`], with control over the login, e.g. its account.
+///
+/// With `opts.no_login`, fails with [`AuthError::LoginRequired`] where a
+/// login would run.
+pub async fn credentials_with_options(
+    creds: &Creds,
+    opts: &LoginOptions,
+    scopes: &'static [&'static str],
+) -> Result<Credentials> {
+    let mut opts = opts.clone();
+    opts.scopes
+        .extend(scopes.iter().map(|scope| scope.to_string()));
+    get_owned_token(creds, &opts).await?;
+
+    let scopes_entry = entry_scopes(&opts);
+    let token = match &opts.account {
+        Some(account) => load_scoped_account(account, &creds.client_id, &scopes_entry),
+        None => load_scoped_token(&creds.client_id, &scopes_entry),
+    }
+    .ok_or(AuthError::LoginRequired)?;
+    user_credentials(creds, &token, scopes).await
+}
+
+/// The `authorized_user` credentials of `token`, as `google-authz` reads them.
+async fn user_credentials(
+    creds: &Creds,
+    token: &SavedToken,
+    scopes: &'static [&'static str],
+) -> Result<Credentials> {
+    if token.refresh_token.is_empty() {
+        return Err(anyhow!(
+            "The cached token has no refresh token for google-authz; log in again"
+        ));
+    }
+    let json = serde_json::to_vec(&adc_json(creds, token, None))?;
+    Ok(Credentials::builder()
+        .json(&json)
+        .scopes(scopes)
+        .build()
+        .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[tokio::test]
+    async fn test_user_credentials_from_cached_grant() {
+        let creds = Creds {
+            client_id: "client".into(),
+            client_secret: "secret".into(),
+            refresh_token: None,
+        };
+        let mut token = SavedToken {
+            refresh_token: "refresh".into(),
+            access_token: "access".into(),
+            id_token: None,
+            token_expiry: Utc::now(),
+            refresh_token_issued_at: None,
+            granted_scopes: Vec::new(),
+            requested_scopes: Vec::new(),
+            client_id: "client".into(),
+        };
+        let scopes = &["https://www.googleapis.com/auth/cloud-platform"];
+        let credentials = user_credentials(&creds, &token, scopes).await.unwrap();
+        assert!(matches!(credentials, Credentials::User(_)));
+
+        token.refresh_token.clear();
+        assert!(user_credentials(&creds, &token, scopes).await.is_err());
+    }
+}
@@ -0,0 +1,385 @@
+//! Subject tokens of AWS workloads for workload identity federation.
+//!
+//! The subject token is a GetCallerIdentity request signed with Signature
+//! Version 4 but never sent; Google's Security Token Service sends it on to
+//! AWS to learn who signed it. Credentials and region come from the standard
+//! AWS environment variables, the ECS container endpoint, or the EC2 instance
+//! metadata service, in that order.
+
+use crate::debug;
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Utc};
+use reqwest::{Client, RequestBuilder};
+use ring::{digest, hmac};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// Host of the ECS container credentials endpoint.
+const ECS_CREDENTIALS_HOST: &str = "http://169.254.170.2";
+
+/// Lifetime asked of an IMDSv2 session token.
+const IMDSV2_TOKEN_TTL_SECONDS: &str = "300";
+
+/// Header binding the signed request to the workload identity pool provider.
+const TARGET_RESOURCE_HEADER: &str = "x-goog-cloud-target-resource";
+
+/// An `aws` credential source.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AwsSource {
+    /// `aws1`, the only version defined
+    pub environment_id: String,
+    /// EC2 metadata URL answering the availability zone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region_url: Option<String>,
+    /// EC2 metadata URL of the instance role's security credentials
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// GetCallerIdentity URL with a `{region}` placeholder
+    pub regional_cred_verification_url: String,
+    /// IMDSv2 session token URL, required on instances enforcing IMDSv2
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub imdsv2_session_token_url: Option<String>,
+}
+
+/// AWS security credentials.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AwsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    #[serde(default)]
+    token: Option<String>,
+}
+
+/// The request Google's STS forwards to AWS, as it expects it.
+#[derive(Serialize)]
+struct SignedRequest {
+    url: String,
+    method: &'static str,
+    headers: Vec<Header>,
+}
+
+#[derive(Serialize)]
+struct Header {
+    key: String,
+    value: String,
+}
+
+/// Build the subject token for `audience` from `source`.
+pub(crate) async fn subject_token(
+    client: &Client,
+    source: &AwsSource,
+    audience: &str,
+) -> Result<String> {
+    if source.environment_id != "aws1" {
+        return Err(anyhow!(
+            "Unsupported AWS environment {:?}; only aws1 is known",
+            source.environment_id
+        ));
+    }
+    let imds = Imds::connect(client, source).await?;
+    let region = region(&imds, source).await?;
+    let credentials = credentials(&imds, source).await?;
+
+    let url = source
+        .regional_cred_verification_url
+        .replace("{region}", &region);
+    let request = sign_request(
+        "POST",
+        &url,
+        &[(TARGET_RESOURCE_HEADER, audience)],
+        &credentials,
+        &region,
+        "sts",
+        Utc::now(),
+    )?;
+    let json = serde_json::to_string(&request)?;
+    Ok(url::form_urlencoded::byte_serialize(json.as_bytes()).collect())
+}
+
+/// The EC2 instance metadata service, with an IMDSv2 session when configured.
+struct Imds<'a> {
+    client: &'a Client,
+    session_token: Option<String>,
+}
+
+impl<'a> Imds<'a> {
+    /// Open an IMDSv2 session if the configuration asks for one and the
+    /// environment does not already supply region and credentials.
+    async fn connect(client: &'a Client, source: &AwsSource) -> Result<Imds<'a>> {
+        let needed = env_region().is_none() || env_credentials().is_none();
+        let session_token = match &source.imdsv2_session_token_url {
+            Some(url) if needed => {
+                let res = debug::send(client.put(url).header(
+                    "X-aws-ec2-metadata-token-ttl-seconds",
+                    IMDSV2_TOKEN_TTL_SECONDS,
+                ))
+                .await?;
+                if !res.status().is_success() {
+                    return Err(anyhow!(
+                        "IMDSv2 session request answered HTTP {}",
+                        res.status()
+                    ));
+                }
+                Some(res.text().await?)
+            }
+            _ => None,
+        };
+        Ok(Self {
+            client,
+            session_token,
+        })
+    }
+
+    async fn get(&self, url: &str) -> Result<String> {
+        let mut req = self.client.get(url);
+        if let Some(token) = &self.session_token {
+            req = req.header("X-aws-ec2-metadata-token", token);
+        }
+        text(req, url).await
+    }
+}
+
+async fn text(req: RequestBuilder, url: &str) -> Result<String> {
+    let res = debug::send(req).await?;
+    if !res.status().is_success() {
+        return Err(anyhow!("{url} answered HTTP {}", res.status()));
+    }
+    Ok(res.text().await?)
+}
+
+fn env_region() -> Option<String> {
+    ["AWS_REGION", "AWS_DEFAULT_REGION"]
+        .iter()
+        .find_map(|name| std::env::var(name).ok().filter(|region| !region.is_empty()))
+}
+
+fn env_credentials() -> Option<AwsCredentials> {
+    let access_key_id = std::env::var("AWS_ACCESS_KEY_ID").ok()?;
+    let secret_access_key = std::env::var("AWS_SECRET_ACCESS_KEY").ok()?;
+    Some(AwsCredentials {
+        access_key_id,
+        secret_access_key,
+        token: std::env::var("AWS_SESSION_TOKEN").ok(),
+    })
+}
+
+/// The region from the environment, else the instance's availability zone
+/// without its zone letter.
+async fn region(imds: &Imds<'_>, source: &AwsSource) -> Result<String> {
+    if let Some(region) = env_region() {
+        return Ok(region);
+    }
+    let url = source
+        .region_url
+        .as_deref()
+        .ok_or_else(|| anyhow!("AWS_REGION is unset and the configuration has no region_url"))?;
+    let zone = imds.get(url).await?;
+    let zone = zone.trim();
+    zone.get(..zone.len().saturating_sub(1))
+        .filter(|region| !region.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("Unexpected availability zone {zone:?}"))
+}
+
+/// Credentials from the environment, the ECS container endpoint, or the
+/// instance role.
+async fn credentials(imds: &Imds<'_>, source: &AwsSource) -> Result<AwsCredentials> {
+    if let Some(credentials) = env_credentials() {
+        return Ok(credentials);
+    }
+
+    let container_url = match std::env::var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI") {
+        Ok(path) => Some(format!("{ECS_CREDENTIALS_HOST}{path}")),
+        Err(_) => std::env::var("AWS_CONTAINER_CREDENTIALS_FULL_URI").ok(),
+    };
+    if let Some(url) = container_url {
+        let mut req = imds.client.get(&url);
+        if let Ok(token) = std::env::var("AWS_CONTAINER_AUTHORIZATION_TOKEN") {
+            req = req.header(reqwest::header::AUTHORIZATION, token);
+        }
+        return serde_json::from_str(&text(req, &url).await?)
+            .context("Unexpected ECS container credentials response");
+    }
+
+    let url = source.url.as_deref().ok_or_else(|| {
+        anyhow!("No AWS credentials in the environment and the configuration has no url")
+    })?;
+    let role = imds.get(url).await?;
+    let role = role.trim();
+    serde_json::from_str(
+        &imds
+            .get(&format!("{}/{role}", url.trim_end_matches('/')))
+            .await?,
+    )
+    .context("Unexpected instance role credentials response")
+}
+
+/// Sign a request without a body with Signature Version 4, returning it in
+/// the form Google's STS expects.
+fn sign_request(
+    method: &'static str,
+    url: &str,
+    extra_headers: &[(&str, &str)],
+    credentials: &AwsCredentials,
+    region: &str,
+    service: &str,
+    now: DateTime<Utc>,
+) -> Result<SignedRequest> {
+    let parsed = Url::parse(url)?;
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| anyhow!("{url} has no host"))?;
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+
+    let mut headers: Vec<(String, String)> = vec![
+        ("host".into(), host.into()),
+        ("x-amz-date".into(), amz_date.clone()),
+    ];
+    if let Some(token) = &credentials.token {
+        headers.push(("x-amz-security-token".into(), token.clone()));
+    }
+    headers.extend(
+        extra_headers
+            .iter()
+            .map(|(key, value)| (key.to_ascii_lowercase(), value.trim().to_string())),
+    );
+    headers.sort();
+
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(key, value)| format!("{key}:{value}\n"))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(key, _)| key.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let path = match parsed.path() {
+        "" => "/",
+        path => path,
+    };
+    let canonical_request = format!(
+        "{method}\n{path}\n{}\n{canonical_headers}\n{signed_headers}\n{}",
+        canonical_query(&parsed),
+        sha256_hex(b"")
+    );
+
+    let scope = format!("{date}/{region}/{service}/aws4_request");
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+        sha256_hex(canonical_request.as_bytes())
+    );
+    let key = [date.as_str(), region, service, "aws4_request"]
+        .iter()
+        .fold(
+            format!("AWS4{}", credentials.secret_access_key).into_bytes(),
+            |key, part| hmac_sha256(&key, part.as_bytes()),
+        );
+    let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+        credentials.access_key_id
+    );
+
+    let mut signed = vec![Header {
+        key: "Authorization".into(),
+        value: authorization,
+    }];
+    signed.extend(
+        headers
+            .into_iter()
+            .map(|(key, value)| Header { key, value }),
+    );
+    Ok(SignedRequest {
+        url: url.to_string(),
+        method,
+        headers: signed,
+    })
+}
+
+/// The query string with parameters sorted and encoded as SigV4 requires.
+fn canonical_query(url: &Url) -> String {
+    let mut pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(key, value)| (uri_encode(&key), uri_encode(&value)))
+        .collect();
+    pairs.sort();
+    pairs
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Percent-encode everything but RFC 3986 unreserved characters.
+fn uri_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data)
+        .as_ref()
+        .to_vec()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex(digest::digest(&digest::SHA256, data).as_ref())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// The `get-vanilla` case of AWS's Signature Version 4 test suite.
+    #[test]
+    fn test_sign_request_matches_aws_test_suite() {
+        let credentials = AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".into(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".into(),
+            token: None,
+        };
+        let now = Utc.with_ymd_and_hms(2015, 8, 30, 12, 36, 0).unwrap();
+        let signed = sign_request(
+            "GET",
+            "https://example.amazonaws.com/",
+            &[],
+            &credentials,
+            "us-east-1",
+            "service",
+            now,
+        )
+        .unwrap();
+        assert_eq!(
+            signed.headers[0].value,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+        assert_eq!(signed.headers[2].value, "20150830T123600Z");
+    }
+
+    #[test]
+    fn test_canonical_query_sorts_and_encodes() {
+        let url =
+            Url::parse("https://sts.amazonaws.com/?Version=2011-06-15&Action=Get Caller").unwrap();
+        assert_eq!(
+            canonical_query(&url),
+            "Action=Get%20Caller&Version=2011-06-15"
+        );
+    }
+}
@@ -1,19 +1,71 @@
-use crate::shared::get_or_init_port;
-use anyhow::Result;
+use crate::config::{DEFAULT_LOGIN_TIMEOUT, LoginOptions};
+use crate::error::AuthError;
+use anyhow::{Result, anyhow};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use ring::rand::{SecureRandom, SystemRandom};
 use std::collections::HashMap;
-use tiny_http::{Response, Server};
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
 use url::Url;
 
+/// Environment variable listing loopback ports to try, e.g. `8085` or `8085-8095`.
+pub const PORT_RANGE_ENV: &str = "GCLOUD_IDENTITY_TOKEN_PORT_RANGE";
+
+/// Environment variable pinning the loopback port, e.g. `8085`.
+///
+/// Unlike [`PORT_RANGE_ENV`], no other port is tried when it is taken.
+pub const PORT_ENV: &str = "GCLOUD_IDENTITY_TOKEN_PORT";
+
+/// Environment variable holding a browser launcher template, e.g. `firefox --new-window %s`.
+///
+/// Takes precedence over the conventional `BROWSER` variable.
+pub const BROWSER_COMMAND_ENV: &str = "GCLOUD_IDENTITY_TOKEN_BROWSER";
+
+/// Environment variable naming the browser profile to open the login in, e.g. `Profile 2`.
+pub const BROWSER_PROFILE_ENV: &str = "GCLOUD_IDENTITY_TOKEN_BROWSER_PROFILE";
+
+/// Environment variable naming an HTML file shown after a successful login.
+pub const SUCCESS_PAGE_ENV: &str = "GCLOUD_IDENTITY_TOKEN_SUCCESS_PAGE";
+
+/// Environment variable naming an HTML file shown after a failed login.
+///
+/// `{error}` and `{error_description}` in the file are replaced with what
+/// Google reported.
+pub const ERROR_PAGE_ENV: &str = "GCLOUD_IDENTITY_TOKEN_ERROR_PAGE";
+
+/// Redirect URI of a manual login.
+///
+/// Nothing listens on port 1, so the browser stops at an error page whose
+/// address still carries the code, and no local server can intercept it.
+pub const MANUAL_REDIRECT_URI: &str = "http://localhost:1";
+
 pub fn is_headless_env() -> bool {
     std::env::var("DISPLAY").is_err() && std::env::var("WAYLAND_DISPLAY").is_err()
 }
 
-pub fn build_auth_url(client_id: &str, redirect_uri: &str) -> Url {
+/// The authorization URL of a login, with a fresh random `state` and `nonce`.
+///
+/// The redirect must carry the same `state` (see [`auth_state`]), so a code
+/// another local process sends to the loopback port is not accepted, and the
+/// ID token the code is exchanged for must carry the same `nonce` (see
+/// [`auth_nonce`]), so it cannot be a token issued to another login.
+pub fn build_auth_url(client_id: &str, redirect_uri: &str, scopes: &[&str]) -> Url {
     let mut url = Url::parse("https://accounts.google.com/o/oauth2/v2/auth").unwrap();
     url.query_pairs_mut()
         .append_pair("client_id", client_id)
+        .append_pair("state", &random_state())
+        .append_pair("nonce", &random_state())
         .append_pair("response_type", "code")
-        .append_pair("scope", "openid email")
+        .append_pair("scope", &scopes.join(" "))
         .append_pair("redirect_uri", redirect_uri)
         .append_pair("access_type", "offline")
         .append_pair("include_granted_scopes", "true")
@@ -21,34 +73,928 @@ pub fn build_auth_url(client_id: &str, redirect_uri: &str) -> Url {
     url
 }
 
-pub fn open_browser_or_print(url: &Url) {
-    if is_headless_env() {
-        println!("\nOpen this URL in your browser:\n\n{}\n", url);
+/// The `state` parameter of an authorization URL, which its redirect must echo.
+pub fn auth_state(url: &Url) -> Option<String> {
+    query_param(url, "state")
+}
+
+/// The `nonce` parameter of an authorization URL, which the ID token must carry.
+pub fn auth_nonce(url: &Url) -> Option<String> {
+    query_param(url, "nonce")
+}
+
+fn query_param(url: &Url, name: &str) -> Option<String> {
+    url.query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// 128 random bits, URL-safe, for `state` and `nonce`.
+fn random_state() -> String {
+    let mut bytes = [0; 16];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("the system random number generator failed");
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Add `params` to the query of `url`, replacing parameters with the same name.
+///
+/// When `params` names a parameter more than once, the last value wins.
+pub fn set_query_params(url: &mut Url, params: &[(String, String)]) {
+    if params.is_empty() {
+        return;
+    }
+    let params: Vec<&(String, String)> = params
+        .iter()
+        .enumerate()
+        .filter(|(i, (key, _))| !params[i + 1..].iter().any(|(name, _)| name == key))
+        .map(|(_, param)| param)
+        .collect();
+    let kept: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| !params.iter().any(|(name, _)| name == key))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(kept)
+        .extend_pairs(params);
+}
+
+/// Open `url` in a browser, or print it to stderr when no browser can be launched.
+///
+/// Nothing is written to stdout, which is reserved for token output so that
+/// `TOKEN=$(gcloud-identity-token)` keeps working in headless sessions.
+///
+/// A launcher from `GCLOUD_IDENTITY_TOKEN_BROWSER` or `BROWSER` is used when
+/// set, even in headless sessions, since remote-development setups point
+/// `BROWSER` at a helper that opens the URL on the user's machine. Options in
+/// `opts` take precedence over the environment.
+pub fn open_browser_or_print(url: &Url, opts: &LoginOptions) {
+    let profile = opts
+        .browser_profile
+        .clone()
+        .or_else(|| std::env::var(BROWSER_PROFILE_ENV).ok());
+    let launchers = match &opts.browser {
+        Some(browser) => vec![browser.clone()],
+        None => launcher_commands(),
+    };
+
+    let opened = if !launchers.is_empty() {
+        launchers.iter().any(|template| {
+            launch_with_template(template, url.as_str(), profile.as_deref()).is_ok()
+        })
+    } else if is_headless_env() {
+        eprintln!("\nOpen this URL in your browser:\n\n{}\n", url);
+        return;
     } else {
-        open::that(url.as_str()).unwrap_or_else(|_| {
-            println!(
-                "\nCouldn't open browser. Please open this URL manually:\n\n{}\n",
-                url
-            );
+        if profile.is_some() {
+            eprintln!("Ignoring browser profile: choosing one requires a browser command.");
+        }
+        open::that(url.as_str()).is_ok()
+    };
+
+    if !opened {
+        eprintln!(
+            "\nCouldn't open browser. Please open this URL manually:\n\n{}\n",
+            url
+        );
+    }
+}
+
+/// Launcher templates from the environment, in the order they should be tried.
+///
+/// `BROWSER` may list several commands separated by `:`, as in its convention.
+fn launcher_commands() -> Vec<String> {
+    if let Ok(template) = std::env::var(BROWSER_COMMAND_ENV) {
+        return vec![template];
+    }
+    std::env::var("BROWSER")
+        .map(|list| {
+            list.split(':')
+                .filter(|cmd| !cmd.trim().is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Spawn the command described by `template` for `url` without waiting on it,
+/// adding the flags that select `profile` when the browser is recognized.
+fn launch_with_template(template: &str, url: &str, profile: Option<&str>) -> Result<()> {
+    let mut args = launcher_args(template, url);
+    if let (Some(profile), Some(program)) = (profile, args.first()) {
+        match profile_args(program, profile) {
+            Some(extra) => {
+                args.splice(1..1, extra);
+            }
+            None => eprintln!("Don't know how to select a profile for {program}; ignoring it."),
+        }
+    }
+
+    let (program, args) = args
+        .split_first()
+        .ok_or_else(|| anyhow!("Empty browser command"))?;
+    Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    Ok(())
+}
+
+/// Arguments that make the browser run by `program` open `profile`.
+///
+/// Chromium-based browsers take a profile directory name, Firefox a profile name.
+fn profile_args(program: &str, profile: &str) -> Option<Vec<String>> {
+    let name = Path::new(program)
+        .file_name()?
+        .to_str()?
+        .to_ascii_lowercase();
+    let chromium = ["chrome", "chromium", "brave", "edge", "vivaldi", "opera"];
+    if chromium.iter().any(|browser| name.contains(browser)) {
+        Some(vec![format!("--profile-directory={profile}")])
+    } else if name.contains("firefox") {
+        Some(vec!["-P".to_string(), profile.to_string()])
+    } else {
+        None
+    }
+}
+
+/// Expand a launcher template: `%s` is replaced by the URL, or the URL is
+/// appended when the template has no placeholder.
+fn launcher_args(template: &str, url: &str) -> Vec<String> {
+    let mut args = split_command(template);
+    if args.iter().any(|arg| arg.contains("%s")) {
+        for arg in &mut args {
+            *arg = arg.replace("%s", url);
+        }
+    } else {
+        args.push(url.to_string());
+    }
+    args
+}
+
+/// Split a command line on whitespace, honoring single and double quotes.
+fn split_command(command: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+
+    for c in command.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => current.push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                in_arg = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            (None, c) => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if in_arg {
+        args.push(current);
+    }
+    args
+}
+
+/// Longest wait for a connection to the redirect server to send its request.
+///
+/// Browsers open speculative connections that never send one; each is
+/// served on its own task, so they only hold their own socket this long.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pause after a failed accept, so a lack of file descriptors, which fails
+/// every accept until one is freed, does not spin.
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Largest redirect request read; the code and state fit well within it.
+const MAX_REQUEST_LEN: usize = 16 * 1024;
+
+/// HTML the browser shows once the login's redirect arrives.
+///
+/// In an error page, `{error}` and `{error_description}` are replaced with
+/// what Google reported, HTML-escaped.
+#[derive(Debug, Clone)]
+pub enum RedirectPage {
+    /// The page itself
+    Html(String),
+    /// A file holding the page, read when the login starts
+    File(PathBuf),
+}
+
+impl RedirectPage {
+    fn load(&self) -> Result<String> {
+        match self {
+            Self::Html(html) => Ok(html.clone()),
+            Self::File(path) => std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Failed to read {}: {e}", path.display())),
+        }
+    }
+
+    fn from_env(var: &str) -> Option<Self> {
+        std::env::var_os(var)
+            .filter(|path| !path.is_empty())
+            .map(|path| Self::File(path.into()))
+    }
+}
+
+/// Pages answering the redirect; plain text when unset.
+#[derive(Debug, Default)]
+struct Pages {
+    success: Option<String>,
+    error: Option<String>,
+}
+
+impl Pages {
+    /// Status, content type, and body of the answer to a redirect with a code.
+    fn success(&self) -> (&'static str, &'static str, String) {
+        match &self.success {
+            Some(html) => ("200 OK", HTML, html.clone()),
+            None => (
+                "200 OK",
+                TEXT,
+                "You may now return to the application.".into(),
+            ),
+        }
+    }
+
+    /// Status, content type, and body of the answer to a redirect with `error`.
+    fn failure(
+        &self,
+        error: &str,
+        description: Option<&str>,
+    ) -> (&'static str, &'static str, String) {
+        if let Some(html) = &self.error {
+            let html = html
+                .replace(
+                    "{error_description}",
+                    &escape_html(description.unwrap_or("")),
+                )
+                .replace("{error}", &escape_html(error));
+            return ("200 OK", HTML, html);
+        }
+        let body = match (error, description) {
+            ("access_denied", _) => {
+                "Login cancelled. You may close this tab and log in again from the application."
+                    .to_string()
+            }
+            (error, Some(description)) => format!(
+                "Login failed: {error}\n\n{description}\n\nYou may close this tab and try again."
+            ),
+            (error, None) => {
+                format!("Login failed: {error}\n\nYou may close this tab and try again.")
+            }
+        };
+        ("200 OK", TEXT, body)
+    }
+}
+
+/// The error a redirect carries instead of a code, if any.
+fn redirect_error(params: &HashMap<String, String>) -> Option<AuthError> {
+    Some(AuthError::AuthorizationFailed {
+        error: params.get("error")?.clone(),
+        description: params
+            .get("error_description")
+            .filter(|description| !description.is_empty())
+            .cloned(),
+    })
+}
+
+const HTML: &str = "text/html; charset=utf-8";
+const TEXT: &str = "text/plain; charset=utf-8";
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// A single browser login attempt that owns its loopback redirect server.
+///
+/// Each session binds its own ephemeral port, so several logins (for example
+/// for different accounts) can run side by side or one after another. The
+/// port is released when the session is dropped.
+///
+/// The server listens on `127.0.0.1` and, where available, on `::1` with the
+/// same port, so the `localhost` redirect arrives however the browser
+/// resolves it.
+pub struct LoginSession {
+    listeners: Vec<std::net::TcpListener>,
+    port: u16,
+    pages: Arc<Pages>,
+    timeout: Option<Duration>,
+}
+
+impl LoginSession {
+    /// Bind a loopback redirect server on an ephemeral port.
+    pub fn new() -> Result<Self> {
+        // The port picked for IPv4 may be taken on IPv6; pick another then.
+        let mut session = Self::bind(0);
+        for _ in 0..8 {
+            if session.is_ok() {
+                break;
+            }
+            session = Self::bind(0);
+        }
+        session
+    }
+
+    /// Bind the first free port in `ports`, skipping ports already in use.
+    ///
+    /// The redirect URI follows whichever port was bound, which works with
+    /// OAuth desktop clients since they accept any localhost port.
+    pub fn bind_in_range(ports: RangeInclusive<u16>) -> Result<Self> {
+        let (first, last) = (*ports.start(), *ports.end());
+        ports
+            .into_iter()
+            .find_map(|port| Self::bind(port).ok())
+            .ok_or_else(|| anyhow!("No free port for the redirect server in {first}-{last}"))
+    }
+
+    /// Bind exactly `port`, for a redirect URI registered with it.
+    ///
+    /// Fails rather than moving to another port, since the redirect would
+    /// then no longer match.
+    pub fn bind_fixed(port: u16) -> Result<Self> {
+        Self::bind(port).map_err(|err| {
+            anyhow!("Redirect port {port} is unavailable ({err:#}); free it or choose another")
+        })
+    }
+
+    /// Bind the port of `opts`, else as [`from_env`](Self::from_env) does,
+    /// and answer the redirect with the pages of `opts` or
+    /// `GCLOUD_IDENTITY_TOKEN_SUCCESS_PAGE` and `GCLOUD_IDENTITY_TOKEN_ERROR_PAGE`.
+    pub fn for_options(opts: &LoginOptions) -> Result<Self> {
+        let session = match opts.redirect_port {
+            Some(port) => Self::bind_fixed(port)?,
+            None => Self::from_env()?,
+        };
+        let success = opts
+            .success_page
+            .clone()
+            .or_else(|| RedirectPage::from_env(SUCCESS_PAGE_ENV));
+        let error = opts
+            .error_page
+            .clone()
+            .or_else(|| RedirectPage::from_env(ERROR_PAGE_ENV));
+        Ok(session
+            .with_pages(success.as_ref(), error.as_ref())?
+            .with_timeout(opts.login_timeout()))
+    }
+
+    /// Give up waiting for the redirect after `timeout`, or never when `None`;
+    /// [`DEFAULT_LOGIN_TIMEOUT`] unless changed.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Answer the redirect with custom HTML instead of a plain text message.
+    ///
+    /// Files are read now, so a missing page fails before the browser opens.
+    pub fn with_pages(
+        mut self,
+        success: Option<&RedirectPage>,
+        error: Option<&RedirectPage>,
+    ) -> Result<Self> {
+        self.pages = Arc::new(Pages {
+            success: success.map(RedirectPage::load).transpose()?,
+            error: error.map(RedirectPage::load).transpose()?,
         });
+        Ok(self)
+    }
+
+    /// Bind the port in `GCLOUD_IDENTITY_TOKEN_PORT`, else the first free one
+    /// in `GCLOUD_IDENTITY_TOKEN_PORT_RANGE`, else an ephemeral port.
+    pub fn from_env() -> Result<Self> {
+        if let Ok(value) = std::env::var(PORT_ENV) {
+            let port = value
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid {PORT_ENV} value: {value}"))?;
+            return Self::bind_fixed(port);
+        }
+        match std::env::var(PORT_RANGE_ENV) {
+            Ok(value) => {
+                let ports = parse_port_range(&value)
+                    .ok_or_else(|| anyhow!("Invalid {PORT_RANGE_ENV} value: {value}"))?;
+                Self::bind_in_range(ports)
+            }
+            Err(_) => Self::new(),
+        }
+    }
+
+    /// Bound now, so the port is known before the browser opens; served
+    /// once [`capture_auth_code`](Self::capture_auth_code) runs on the runtime.
+    fn bind(port: u16) -> Result<Self> {
+        let v4 = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+            .map_err(|e| anyhow!("Failed to start redirect server: {e}"))?;
+        let port = v4.local_addr()?.port();
+        let mut listeners = vec![v4];
+        match std::net::TcpListener::bind((Ipv6Addr::LOCALHOST, port)) {
+            Ok(v6) => listeners.push(v6),
+            // Another server on [::1] would receive the redirect instead.
+            Err(e) if e.kind() == ErrorKind::AddrInUse => {
+                return Err(anyhow!("Failed to start redirect server on [::1]: {e}"));
+            }
+            // Without IPv6 loopback, `localhost` only resolves to 127.0.0.1.
+            Err(_) => {}
+        }
+        for listener in &listeners {
+            listener.set_nonblocking(true)?;
+        }
+        Ok(Self {
+            listeners,
+            port,
+            pages: Arc::default(),
+            timeout: Some(DEFAULT_LOGIN_TIMEOUT),
+        })
+    }
+
+    /// The port the redirect server listens on.
+    pub fn port(&self) -> u16 {
+        self.port
     }
+
+    /// The redirect URI to register in the authorization request.
+    pub fn redirect_uri(&self) -> String {
+        format!("http://localhost:{}", self.port)
+    }
+
+    /// Wait for the OAuth redirect carrying `state` and return its `code`.
+    ///
+    /// Redirects with another or no `state` are answered with an error page
+    /// and otherwise ignored, so a local process cannot inject its own code.
+    ///
+    /// Fails with [`AuthError::LoginTimedOut`] when the redirect does not
+    /// arrive within the session's timeout, e.g. because the tab was closed.
+    ///
+    /// The server runs on the caller's runtime without blocking a thread.
+    /// The returned future is cancel-safe: dropping it stops the wait, closes
+    /// any open connections, and releases the port, as does the timeout.
+    pub async fn capture_auth_code(self, state: &str) -> Result<String> {
+        let listeners = self
+            .listeners
+            .into_iter()
+            .map(TcpListener::from_std)
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let receive = receive_auth_code(listeners, self.pages, state.into());
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, receive)
+                .await
+                .map_err(|_| AuthError::LoginTimedOut)?,
+            None => receive.await,
+        }
+    }
+}
+
+/// Ask on the terminal for the address the login's redirect ended on, and
+/// return its `code`.
+///
+/// Reads from stdin, so stdout stays free for token output.
+/// A pasted address must carry `state`; a bare code cannot be checked.
+pub async fn read_pasted_auth_code(state: &str) -> Result<String> {
+    eprintln!(
+        "After approving, the browser shows an error page for localhost. Paste the full \
+         address of that page, or just its code, here:"
+    );
+    let line = tokio::task::spawn_blocking(|| {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line).map(|_| line)
+    })
+    .await??;
+    parse_pasted_auth_code(&line, state)
+}
+
+/// The `code` of a pasted redirect address, or the pasted code itself.
+fn parse_pasted_auth_code(input: &str, state: &str) -> Result<String> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err(anyhow!("Nothing was pasted"));
+    }
+    let Ok(url) = Url::parse(input) else {
+        return Ok(input.to_string());
+    };
+    let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+    if params.get("state").map(String::as_str) != Some(state) {
+        return Err(anyhow!("The pasted address is not from this login"));
+    }
+    if let Some(error) = redirect_error(&params) {
+        return Err(error.into());
+    }
+    params
+        .get("code")
+        .cloned()
+        .ok_or_else(|| anyhow!("The pasted address has no code parameter"))
+}
+
+/// Parse a single port (`8085`) or an inclusive range (`8085-8095`).
+fn parse_port_range(value: &str) -> Option<RangeInclusive<u16>> {
+    let (first, last) = match value.split_once('-') {
+        Some((first, last)) => (first.trim().parse().ok()?, last.trim().parse().ok()?),
+        None => {
+            let port = value.trim().parse().ok()?;
+            (port, port)
+        }
+    };
+    (first <= last).then_some(first..=last)
+}
+
+/// Serve redirect requests until one carries `state` and `code` or `error`.
+///
+/// Browsers also ask for `/favicon.ico` or probe the redirect target; those
+/// stray requests get a 404 and the wait continues. Connections are served
+/// concurrently, and dropping the future aborts them all. A failed accept
+/// only ends the wait when the listener itself is broken; see
+/// [`listener_failed`].
+async fn receive_auth_code(
+    listeners: Vec<TcpListener>,
+    pages: Arc<Pages>,
+    state: Arc<str>,
+) -> Result<String> {
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = accept_any(&listeners) => match accepted {
+                Ok(stream) => {
+                    connections.spawn(serve_redirect(stream, pages.clone(), state.clone()));
+                }
+                Err(err) if listener_failed(&err) => return Err(err.into()),
+                Err(err) => {
+                    eprintln!("Warning: could not accept a connection to the login redirect: {err}");
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                }
+            },
+            Some(served) = connections.join_next() => {
+                if let Ok(Some(outcome)) = served {
+                    return outcome;
+                }
+            }
+        }
+    }
+}
+
+/// Whether the accept error `err` means the listener itself is unusable.
+///
+/// Anything else, like a connection aborted before it was accepted or
+/// running out of file descriptors for now, concerns one connection or
+/// passes, so the wait goes on; the login timeout bounds it.
+fn listener_failed(err: &std::io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::InvalidInput | ErrorKind::Unsupported)
+}
+
+/// The next connection to any of `listeners`.
+async fn accept_any(listeners: &[TcpListener]) -> std::io::Result<TcpStream> {
+    std::future::poll_fn(|cx| {
+        for listener in listeners {
+            if let Poll::Ready(accepted) = listener.poll_accept(cx) {
+                return Poll::Ready(accepted.map(|(stream, _)| stream));
+            }
+        }
+        Poll::Pending
+    })
+    .await
 }
 
-pub fn capture_auth_code() -> Result<String> {
-    let port = get_or_init_port();
-    let server = Server::http(("127.0.0.1", port)).unwrap();
-    let request = server.recv()?;
-    let query = request.url().split('?').nth(1).unwrap_or("");
+/// Answer one connection, returning the login's outcome if its request
+/// carried one; stray and broken requests give `None`.
+async fn serve_redirect(
+    mut stream: TcpStream,
+    pages: Arc<Pages>,
+    state: Arc<str>,
+) -> Option<Result<String>> {
+    let target = tokio::time::timeout(REQUEST_TIMEOUT, read_request_target(&mut stream))
+        .await
+        .ok()??;
+    let query = target.split('?').nth(1).unwrap_or("");
     let params: HashMap<_, _> = url::form_urlencoded::parse(query.as_bytes())
         .into_owned()
         .collect();
-    let code = params
-        .get("code")
-        .ok_or("Missing ?code= param")
-        .map_err(|_| anyhow::anyhow!("Error capturing auth code"))?
-        .clone();
-    request.respond(Response::from_string(
-        "You may now return to the application.",
-    ))?;
-    Ok(code)
+
+    let forged = params.get("state").map(String::as_str) != Some(&*state);
+    let ((status, content_type, body), outcome) =
+        if forged && (params.contains_key("code") || params.contains_key("error")) {
+            eprintln!("Warning: ignoring a login redirect with the wrong state");
+            (("400 Bad Request", TEXT, "Invalid state".to_string()), None)
+        } else if let Some(code) = params.get("code") {
+            (pages.success(), Some(Ok(code.clone())))
+        } else if let Some(AuthError::AuthorizationFailed { error, description }) =
+            redirect_error(&params)
+        {
+            let page = pages.failure(&error, description.as_deref());
+            let error = AuthError::AuthorizationFailed { error, description };
+            (page, Some(Err(error.into())))
+        } else {
+            (("404 Not Found", TEXT, "Not found".to_string()), None)
+        };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    // The client may already have gone away; the outcome stands regardless.
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+    outcome
+}
+
+/// The request target of the HTTP request on `stream`, e.g. `/?code=...`,
+/// once its headers have arrived.
+async fn read_request_target(stream: &mut TcpStream) -> Option<String> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).await.ok()?;
+        if read == 0 || request.len() + read > MAX_REQUEST_LEN {
+            return None;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next()?.split_whitespace();
+    match (request_line.next()?, request_line.next()) {
+        ("GET", Some(target)) => Some(target.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    #[test]
+    fn test_sessions_bind_distinct_ports() {
+        let first = LoginSession::new().unwrap();
+        let second = LoginSession::new().unwrap();
+        assert_ne!(first.port(), second.port());
+    }
+
+    #[test]
+    fn test_build_auth_url_random_state() {
+        let state = || auth_state(&build_auth_url("client", "http://localhost:1", &[])).unwrap();
+        let first = state();
+        assert_eq!(first.len(), 22);
+        assert_ne!(first, state());
+
+        let url = build_auth_url("client", "http://localhost:1", &[]);
+        assert_ne!(auth_nonce(&url), auth_state(&url));
+    }
+
+    #[test]
+    fn test_set_query_params_replaces_and_appends() {
+        let mut url = build_auth_url("client", "http://localhost:1", &["openid"]);
+        set_query_params(
+            &mut url,
+            &[
+                ("prompt".into(), "select_account".into()),
+                ("hl".into(), "en".into()),
+                ("hl".into(), "de".into()),
+            ],
+        );
+        let params: Vec<_> = url.query_pairs().into_owned().collect();
+        assert_eq!(params.iter().filter(|(key, _)| key == "prompt").count(), 1);
+        assert!(params.contains(&("prompt".into(), "select_account".into())));
+        assert!(params.contains(&("hl".into(), "de".into())));
+        assert!(!params.contains(&("hl".into(), "en".into())));
+    }
+
+    #[test]
+    fn test_launcher_args_substitutes_or_appends_url() {
+        assert_eq!(
+            launcher_args("firefox --new-window %s", "https://x"),
+            ["firefox", "--new-window", "https://x"]
+        );
+        assert_eq!(
+            launcher_args("xdg-open", "https://x"),
+            ["xdg-open", "https://x"]
+        );
+    }
+
+    #[test]
+    fn test_profile_args_by_browser() {
+        assert_eq!(
+            profile_args("/usr/bin/google-chrome", "Profile 2"),
+            Some(vec!["--profile-directory=Profile 2".to_string()])
+        );
+        assert_eq!(
+            profile_args("firefox", "work"),
+            Some(vec!["-P".to_string(), "work".to_string()])
+        );
+        assert_eq!(profile_args("xdg-open", "work"), None);
+    }
+
+    #[test]
+    fn test_split_command_honors_quotes() {
+        assert_eq!(
+            split_command(r#"chrome --profile-directory="Profile 2" '%s'"#),
+            ["chrome", "--profile-directory=Profile 2", "%s"]
+        );
+    }
+
+    #[test]
+    fn test_parse_pasted_auth_code() {
+        assert_eq!(
+            parse_pasted_auth_code(
+                "http://localhost:1/?state=s&code=4/0Ab%2Bc&scope=email\n",
+                "s"
+            )
+            .unwrap(),
+            "4/0Ab+c"
+        );
+        assert_eq!(parse_pasted_auth_code("  4/0Abc  ", "s").unwrap(), "4/0Abc");
+        assert!(
+            parse_pasted_auth_code("http://localhost:1/?state=s&error=access_denied", "s").is_err()
+        );
+        assert!(parse_pasted_auth_code("http://localhost:1/?state=s", "s").is_err());
+        assert!(parse_pasted_auth_code("\n", "s").is_err());
+        assert!(parse_pasted_auth_code("http://localhost:1/?state=t&code=c", "s").is_err());
+        assert!(parse_pasted_auth_code("http://localhost:1/?code=c", "s").is_err());
+    }
+
+    #[test]
+    fn test_parse_port_range() {
+        assert_eq!(parse_port_range("8085"), Some(8085..=8085));
+        assert_eq!(parse_port_range("8085-8095"), Some(8085..=8095));
+        assert_eq!(parse_port_range("8095-8085"), None);
+        assert_eq!(parse_port_range("http"), None);
+    }
+
+    #[test]
+    fn test_bind_in_range_skips_taken_port() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let session = LoginSession::bind_in_range(port..=port.saturating_add(20)).unwrap();
+        assert_ne!(session.port(), port);
+    }
+
+    #[tokio::test]
+    async fn test_capture_auth_code_from_redirect() {
+        let session = LoginSession::new().unwrap();
+        let port = session.port();
+        let capture = tokio::spawn(session.capture_auth_code("s"));
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(
+            stream,
+            "GET /?state=s&code=abc123 HTTP/1.1\r\nHost: localhost\r\n\r\n"
+        )
+        .unwrap();
+
+        assert_eq!(capture.await.unwrap().unwrap(), "abc123");
+    }
+
+    #[tokio::test]
+    async fn test_capture_auth_code_over_ipv6() {
+        if std::net::TcpListener::bind("[::1]:0").is_err() {
+            return;
+        }
+        let session = LoginSession::new().unwrap();
+        let port = session.port();
+        let capture = tokio::spawn(session.capture_auth_code("s"));
+
+        let mut stream = TcpStream::connect(("::1", port)).unwrap();
+        write!(stream, "GET /?state=s&code=v6 HTTP/1.1\r\n\r\n").unwrap();
+
+        assert_eq!(capture.await.unwrap().unwrap(), "v6");
+    }
+
+    #[tokio::test]
+    async fn test_capture_auth_code_reports_redirect_error() {
+        let session = LoginSession::new().unwrap();
+        let port = session.port();
+        let capture = tokio::spawn(session.capture_auth_code("s"));
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(
+            stream,
+            "GET /?state=s&error=access_denied&error_description=User+cancelled HTTP/1.1\r\n\r\n"
+        )
+        .unwrap();
+
+        let err = capture.await.unwrap().unwrap_err();
+        match err.downcast_ref() {
+            Some(AuthError::AuthorizationFailed { error, description }) => {
+                assert_eq!(error, "access_denied");
+                assert_eq!(description.as_deref(), Some("User cancelled"));
+            }
+            _ => panic!("unexpected error: {err:#}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_capture_auth_code_survives_idle_connections() {
+        let session = LoginSession::new().unwrap();
+        let port = session.port();
+        let capture = tokio::spawn(session.capture_auth_code("s"));
+
+        // A speculative connection that never sends a request.
+        let _idle = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(
+            stream,
+            "GET /?state=s&code=late HTTP/1.1\r\nHost: localhost\r\n\r\n"
+        )
+        .unwrap();
+
+        let code = tokio::time::timeout(Duration::from_secs(5), capture).await;
+        assert_eq!(code.unwrap().unwrap().unwrap(), "late");
+    }
+
+    #[test]
+    fn test_only_broken_listeners_end_the_wait() {
+        let error = |kind| std::io::Error::from(kind);
+        assert!(!listener_failed(&error(ErrorKind::ConnectionAborted)));
+        assert!(!listener_failed(&error(ErrorKind::ConnectionReset)));
+        assert!(!listener_failed(&error(ErrorKind::Interrupted)));
+        assert!(!listener_failed(&std::io::Error::other(
+            "Too many open files"
+        )));
+        assert!(listener_failed(&error(ErrorKind::InvalidInput)));
+        assert!(listener_failed(&error(ErrorKind::Unsupported)));
+    }
+
+    #[test]
+    fn test_custom_pages() {
+        let pages = Pages {
+            success: Some("<h1>Done</h1>".into()),
+            error: Some("<p>Failed: {error}</p><p>{error_description}</p>".into()),
+        };
+        assert_eq!(pages.success(), ("200 OK", HTML, "<h1>Done</h1>".into()));
+        assert_eq!(
+            pages.failure("<access_denied>", Some("a & b")).2,
+            "<p>Failed: &lt;access_denied&gt;</p><p>a &amp; b</p>"
+        );
+        assert_eq!(Pages::default().success().1, TEXT);
+        assert!(
+            Pages::default()
+                .failure("access_denied", None)
+                .2
+                .starts_with("Login cancelled")
+        );
+
+        let missing = RedirectPage::File("/nonexistent/success.html".into());
+        let session = LoginSession::new().unwrap();
+        assert!(session.with_pages(Some(&missing), None).is_err());
+    }
+
+    #[test]
+    fn test_bind_fixed_does_not_move() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        assert!(LoginSession::bind_fixed(port).is_err());
+        drop(taken);
+        assert_eq!(LoginSession::bind_fixed(port).unwrap().port(), port);
+    }
+
+    #[tokio::test]
+    async fn test_capture_auth_code_times_out() {
+        let session = LoginSession::new()
+            .unwrap()
+            .with_timeout(Some(Duration::from_millis(50)));
+        let port = session.port();
+        let err = session.capture_auth_code("s").await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(AuthError::LoginTimedOut)));
+        std::net::TcpListener::bind(("127.0.0.1", port)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dropped_capture_releases_port() {
+        let session = LoginSession::new().unwrap();
+        let port = session.port();
+        let wait = tokio::time::timeout(Duration::from_millis(50), session.capture_auth_code("s"));
+        assert!(wait.await.is_err());
+        std::net::TcpListener::bind(("127.0.0.1", port)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_capture_auth_code_ignores_stray_requests() {
+        let session = LoginSession::new().unwrap();
+        let port = session.port();
+        let capture = tokio::spawn(session.capture_auth_code("s"));
+
+        for path in ["/favicon.ico", "/?code=forged", "/?state=s&code=xyz"] {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        }
+
+        assert_eq!(capture.await.unwrap().unwrap(), "xyz");
+    }
 }
@@ -2,110 +2,2416 @@
 //!
 //! This stores OAuth tokens securely using the system keyring (by default) or
 //! to a file if the `GCLOUD_IDENTITY_TOKEN_PATH` environment variable is set.
+//! Another [`TokenCache`], such as the process-local [`MemoryCache`], can
+//! replace the keyring.
 //!
-//! The keyring entry is namespaced under the service `gcloud-identity-token`
-//! and the keyring "username" is extracted from the ID token's email field.
+//! The keyring entry is namespaced under the service `gcloud-identity-token`,
+//! or the one set with [`configure_keyring`], and the keyring "username" is
+//! the ID token's `sub`, Google's stable user ID, followed by the OAuth client
+//! ID, so every account has its own entry for each client. Emails, which can
+//! change, are kept as hints to find and show accounts by. The account used
+//! is the one chosen with [`set_current_account`], else gcloud's active
+//! account, else the most recently logged-in one, whichever has a cached
+//! token first.
 
-use crate::config::SavedToken;
-use anyhow::Result;
+use crate::auth::{DEFAULT_SCOPES, canonical_scope, request_refresh};
+use crate::config::{Creds, SavedToken, load_creds};
+use crate::error::AuthError;
+use crate::gcloud;
+#[cfg(feature = "gcloud-store")]
+use crate::gcloud_store::GcloudCache;
+use crate::seal::{self, SealKey};
+use crate::verify::decode_unverified;
+use crate::watch::write_atomic;
+use anyhow::{Result, anyhow};
 use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
 use keyring::Entry;
-use serde::Deserialize;
-use std::{fs, path::PathBuf};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use std::{fmt, fs};
+use zeroize::Zeroize;
 
 const SERVICE: &str = env!("CARGO_PKG_NAME");
 
-/// Claims in a Google ID token. Used to extract the email address.
+/// Token most recently read from or written to the keyring by this process.
+///
+/// Repeated `get_token` calls are served from here until the token expires, so
+/// the keyring (and any macOS Keychain authorization check) is consulted once.
+static MEMO: Mutex<Option<Secret>> = Mutex::new(None);
+
+/// Disagreement between wall-clock and monotonic elapsed time beyond which
+/// the wall clock is taken to have jumped (suspend/resume, NTP step, manual change).
+const CLOCK_JUMP_THRESHOLD: chrono::Duration = chrono::Duration::minutes(2);
+
+/// A keyring token held in process memory.
+///
+/// Redacted from debug output and wiped from memory when dropped.
+struct Secret {
+    user: String,
+    token: SavedToken,
+    /// When this process first saw the token, on both clocks
+    seen: Instant,
+    seen_wall: DateTime<Utc>,
+}
+
+impl Secret {
+    /// The token with its expiry made robust to wall-clock changes.
+    ///
+    /// The expiry is the earlier of the stored one and the same remaining
+    /// lifetime counted on the monotonic clock, which wall-clock changes do
+    /// not affect but which may pause during suspend. After a clock jump the
+    /// ID token's `exp` claim, set by Google's clock, also bounds it.
+    fn view(&self) -> SavedToken {
+        let now = Utc::now();
+        let mono_elapsed =
+            chrono::Duration::from_std(self.seen.elapsed()).unwrap_or(chrono::Duration::MAX);
+        let wall_elapsed = now - self.seen_wall;
+        let remaining_at_seen = self.token.token_expiry - self.seen_wall;
+        let mut expiry = self
+            .token
+            .token_expiry
+            .min(now + (remaining_at_seen - mono_elapsed));
+
+        if (wall_elapsed - mono_elapsed).abs() > CLOCK_JUMP_THRESHOLD {
+            let exp = self
+                .token
+                .id_token
+                .as_deref()
+                .and_then(|id_token| decode_unverified(id_token).ok())
+                .and_then(|claims| claims.get("exp")?.as_i64())
+                .and_then(|exp| DateTime::from_timestamp(exp, 0));
+            if let Some(exp) = exp {
+                expiry = expiry.min(exp);
+            }
+        }
+
+        SavedToken {
+            token_expiry: expiry,
+            ..self.token.clone()
+        }
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret({}, <redacted>)", self.user)
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        wipe(&mut self.token);
+    }
+}
+
+/// The memoized token for `user`, if one is held and has not expired.
+fn memoized(user: &str) -> Option<SavedToken> {
+    let memo = MEMO.lock().ok()?;
+    memo.as_ref()
+        .filter(|secret| secret.user == user)
+        .map(Secret::view)
+        .filter(|token| token.token_expiry > Utc::now())
+}
+
+/// Hold `token` in memory and return it as [`Secret::view`] sees it.
+///
+/// Re-reading a token already held keeps the time it was first seen, so its
+/// monotonic expiry survives the round trip through the keyring.
+fn memoize(user: &str, token: &SavedToken) -> SavedToken {
+    let Ok(mut memo) = MEMO.lock() else {
+        return token.clone();
+    };
+    let (seen, seen_wall) = match memo.as_ref() {
+        Some(secret) if secret.user == user && secret.token.access_token == token.access_token => {
+            (secret.seen, secret.seen_wall)
+        }
+        _ => (Instant::now(), Utc::now()),
+    };
+    let secret = Secret {
+        user: user.to_string(),
+        token: token.clone(),
+        seen,
+        seen_wall,
+    };
+    let view = secret.view();
+    *memo = Some(secret);
+    view
+}
+
+/// Forget the token held in process memory, forcing the next load to read the keyring.
+pub fn invalidate_memory_cache() {
+    if let Ok(mut memo) = MEMO.lock() {
+        *memo = None;
+    }
+}
+
+/// Environment variable naming a file to cache tokens in instead of the keyring.
+pub const CACHE_PATH_ENV: &str = "GCLOUD_IDENTITY_TOKEN_PATH";
+
+static FILE_CACHE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Cache tokens in the file at `path` instead of the keyring, or go back to
+/// the keyring with `None`. `GCLOUD_IDENTITY_TOKEN_PATH` takes precedence.
+pub fn configure_file_cache(path: Option<PathBuf>) {
+    if let Ok(mut file_cache) = FILE_CACHE.lock() {
+        *file_cache = path;
+    }
+}
+
+/// The file tokens are cached in, or `None` when the keyring is used.
+pub fn file_cache_path() -> Option<PathBuf> {
+    std::env::var_os(CACHE_PATH_ENV)
+        .map(PathBuf::from)
+        .or_else(|| FILE_CACHE.lock().ok()?.clone())
+}
+
+/// Environment variable selecting the [`StoragePolicy`]: `full` or `refresh-token-only`.
+pub const STORAGE_POLICY_ENV: &str = "GCLOUD_IDENTITY_TOKEN_STORAGE";
+
+static REFRESH_TOKEN_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// What part of a token is written to the keyring.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StoragePolicy {
+    /// Refresh, access, and ID tokens
+    #[default]
+    Full,
+    /// Only the refresh token; access and ID tokens stay in process memory, so
+    /// each new process starts with a refresh
+    RefreshTokenOnly,
+}
+
+impl std::str::FromStr for StoragePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "full" => Ok(StoragePolicy::Full),
+            "refresh-token-only" => Ok(StoragePolicy::RefreshTokenOnly),
+            _ => Err(anyhow!(
+                "Unknown storage policy {s:?}; expected full or refresh-token-only"
+            )),
+        }
+    }
+}
+
+/// Apply `policy` to keyring writes from now on.
+///
+/// The file cache selected by `GCLOUD_IDENTITY_TOKEN_PATH` always holds the
+/// full token; point it at a tmpfs path to keep it off disk.
+pub fn configure_storage_policy(policy: StoragePolicy) {
+    REFRESH_TOKEN_ONLY.store(policy == StoragePolicy::RefreshTokenOnly, Ordering::Relaxed);
+}
+
+/// The policy set by [`configure_storage_policy`], else `GCLOUD_IDENTITY_TOKEN_STORAGE`.
+fn storage_policy() -> StoragePolicy {
+    if REFRESH_TOKEN_ONLY.load(Ordering::Relaxed) {
+        return StoragePolicy::RefreshTokenOnly;
+    }
+    std::env::var(STORAGE_POLICY_ENV)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_default()
+}
+
+/// Environment variable that binds the file cache to this machine when set to anything but `0`.
+pub const BIND_MACHINE_ENV: &str = "GCLOUD_IDENTITY_TOKEN_BIND_MACHINE";
+
+static BIND_MACHINE: AtomicBool = AtomicBool::new(false);
+
+/// Encrypt the `GCLOUD_IDENTITY_TOKEN_PATH` file with a key derived from this
+/// machine's identifier (`/etc/machine-id`, IOPlatformUUID, or `MachineGuid`).
+///
+/// A copy of the file, e.g. from a backup, then cannot be decrypted on any
+/// other host. Plaintext files written earlier are still read and are sealed
+/// on the next save.
+pub fn configure_machine_binding(enabled: bool) {
+    BIND_MACHINE.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether [`configure_machine_binding`] or `GCLOUD_IDENTITY_TOKEN_BIND_MACHINE` is on.
+fn machine_binding() -> bool {
+    BIND_MACHINE.load(Ordering::Relaxed)
+        || std::env::var(BIND_MACHINE_ENV).is_ok_and(|value| !value.is_empty() && value != "0")
+}
+
+/// Environment variable holding a secret that keys the file cache's integrity check.
+///
+/// Without it the file only carries a checksum, which catches truncated or
+/// corrupted writes but not deliberate edits.
+pub const CACHE_SECRET_ENV: &str = "GCLOUD_IDENTITY_TOKEN_CACHE_SECRET";
+
+/// Environment variable holding a passphrase the file cache is encrypted with.
+pub const CACHE_PASSPHRASE_ENV: &str = "GCLOUD_IDENTITY_TOKEN_CACHE_PASSPHRASE";
+
+static CACHE_PASSPHRASE: Mutex<Option<String>> = Mutex::new(None);
+
+/// Encrypt the `GCLOUD_IDENTITY_TOKEN_PATH` file with AES-256-GCM under a key
+/// stretched from `passphrase`, or stop doing so with `None`.
+///
+/// Unlike [`configure_machine_binding`], the file can be moved to another
+/// host; it takes precedence when both are on. Plaintext files written
+/// earlier are still read and are encrypted on the next save.
+pub fn configure_cache_passphrase(passphrase: Option<String>) {
+    if let Ok(mut configured) = CACHE_PASSPHRASE.lock() {
+        if let Some(old) = configured.as_mut() {
+            old.zeroize();
+        }
+        *configured = passphrase;
+    }
+}
+
+/// The passphrase set by [`configure_cache_passphrase`], else
+/// `GCLOUD_IDENTITY_TOKEN_CACHE_PASSPHRASE`.
+fn cache_passphrase() -> Option<Vec<u8>> {
+    CACHE_PASSPHRASE
+        .lock()
+        .ok()
+        .and_then(|passphrase| passphrase.clone())
+        .map(String::into_bytes)
+        .or_else(|| {
+            std::env::var_os(CACHE_PASSPHRASE_ENV).map(|passphrase| passphrase.into_encoded_bytes())
+        })
+        .filter(|passphrase| !passphrase.is_empty())
+}
+
+fn cache_secret() -> Option<Vec<u8>> {
+    std::env::var_os(CACHE_SECRET_ENV)
+        .filter(|secret| !secret.is_empty())
+        .map(|secret| secret.into_encoded_bytes())
+}
+
+/// Read the file cache, decrypting or verifying it as it was written.
+///
+/// A file that fails decryption, its integrity check, or parsing is reported
+/// and treated as missing. Plain JSON and older formats are still accepted.
+fn read_token_file(path: &Path) -> Option<SavedToken> {
+    let data = fs::read(path).ok()?;
+    let json = if seal::is_sealed(&data) {
+        SealKey::for_envelope(&data, cache_passphrase().as_deref())
+            .and_then(|key| seal::open(&data, &key))
+    } else if seal::is_protected(&data) {
+        seal::verify(&data, cache_secret().as_deref())
+    } else {
+        Ok(data)
+    };
+    match json.and_then(|json| SavedToken::from_json(&json)) {
+        Ok(token) => Some(token),
+        Err(err) => {
+            eprintln!("Ignoring token cache {}: {err:#}", path.display());
+            None
+        }
+    }
+}
+
+/// Write the file cache, sealed when a passphrase or machine binding is
+/// configured and with an integrity check otherwise.
+fn write_token_file(path: &Path, token: &SavedToken) -> Result<()> {
+    let json = token.to_json()?.into_bytes();
+    let data = if let Some(passphrase) = cache_passphrase() {
+        seal::seal(&json, &SealKey::passphrase(&passphrase)?)?
+    } else if machine_binding() {
+        seal::seal(&json, &SealKey::machine()?)?
+    } else {
+        seal::protect(&json, cache_secret().as_deref())?
+    };
+    write_atomic(path, &data)
+}
+
+/// The part of `token` that `policy` allows to be persisted.
+///
+/// A refresh-token-only entry reads back as long expired, so the first load
+/// in a new process goes straight to a refresh.
+fn persisted(token: &SavedToken, policy: StoragePolicy) -> SavedToken {
+    match policy {
+        StoragePolicy::Full => token.clone(),
+        StoragePolicy::RefreshTokenOnly => SavedToken {
+            access_token: String::new(),
+            id_token: None,
+            token_expiry: chrono::DateTime::UNIX_EPOCH,
+            ..token.clone()
+        },
+    }
+}
+
+/// Storage of cached tokens, one entry per account.
+///
+/// The keyring is used unless another store is chosen with
+/// [`configure_token_cache`] or `GCLOUD_IDENTITY_TOKEN_CACHE`, falling back
+/// to a [`DirectoryCache`] where there is no usable keyring (see
+/// [`keyring_fallback`]). A `GCLOUD_IDENTITY_TOKEN_PATH` file takes
+/// precedence over any store.
+pub trait TokenCache: Send + Sync {
+    /// The token stored for `user`, if any.
+    fn load(&self, user: &str) -> Result<Option<SavedToken>>;
+
+    /// Store `token` for `user`, replacing any earlier one.
+    fn save(&self, user: &str, token: &SavedToken) -> Result<()>;
+
+    /// Remove the token of `user`; a missing entry is not an error.
+    fn delete(&self, user: &str) -> Result<()>;
+
+    /// Names of the stored entries, if the store can list them.
+    fn users(&self) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Whether entries outlive the process. Without that, nothing about the
+    /// accounts (last login, usage, the cross-process lock) touches the disk.
+    fn is_persistent(&self) -> bool {
+        true
+    }
+}
+
+/// The OS keyring: the macOS Keychain, Windows Credential Manager, or the
+/// Secret Service on Linux.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KeyringCache;
+
+/// Where in the keyring entries are kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyringOptions {
+    /// Service name of the entries; the crate name, `gcloud-identity-token`,
+    /// when `None`
+    pub service: Option<String>,
+    /// The Secret Service collection on Linux, created if needed, or the
+    /// suffix of the credential target names on Windows; ignored on macOS
+    pub target: Option<String>,
+    /// Which keyring to use on Linux; the Secret Service when `None`
+    pub backend: Option<KeyringBackend>,
+    /// How long to wait for the keyring to answer, e.g. while it shows an
+    /// unlock prompt nobody will see on a headless server; [`KEYRING_TIMEOUT`]
+    /// when `None`, and no limit when zero
+    pub timeout: Option<Duration>,
+}
+
+/// Keyring implementations available on Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyringBackend {
+    /// The Secret Service over D-Bus, as served by GNOME Keyring (libsecret)
+    /// or KeePassXC, in its default collection
+    Libsecret,
+    /// KWallet through its Secret Service interface, in the `kdewallet`
+    /// collection unless [`KeyringOptions::target`] names another
+    Kwallet,
+    /// The kernel keyring, which needs no D-Bus session or desktop; entries
+    /// are lost on reboot
+    Keyutils,
+}
+
+impl std::str::FromStr for KeyringBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "libsecret" | "secret-service" | "gnome-keyring" => Ok(Self::Libsecret),
+            "kwallet" => Ok(Self::Kwallet),
+            "keyutils" => Ok(Self::Keyutils),
+            other => Err(anyhow!(
+                "Unknown keyring backend {other:?}; expected libsecret, kwallet, or keyutils"
+            )),
+        }
+    }
+}
+
+/// How long a keyring call may take before the keyring counts as unavailable.
+pub const KEYRING_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Environment variable overriding the keyring service name.
+pub const KEYRING_SERVICE_ENV: &str = "GCLOUD_IDENTITY_TOKEN_KEYRING_SERVICE";
+
+/// Environment variable choosing the keyring target, see [`KeyringOptions::target`].
+pub const KEYRING_TARGET_ENV: &str = "GCLOUD_IDENTITY_TOKEN_KEYRING_TARGET";
+
+/// Environment variable choosing the Linux keyring: `libsecret`, `kwallet`, or `keyutils`.
+pub const KEYRING_BACKEND_ENV: &str = "GCLOUD_IDENTITY_TOKEN_KEYRING_BACKEND";
+
+/// Environment variable with the keyring timeout in seconds, `0` for none.
+pub const KEYRING_TIMEOUT_ENV: &str = "GCLOUD_IDENTITY_TOKEN_KEYRING_TIMEOUT";
+
+static KEYRING_OPTIONS: Mutex<Option<KeyringOptions>> = Mutex::new(None);
+
+/// Keep keyring entries under `opts` from now on, so applications embedding
+/// this crate do not share entries with each other or with the CLI.
+pub fn configure_keyring(opts: KeyringOptions) {
+    invalidate_memory_cache();
+    if let Ok(mut configured) = KEYRING_OPTIONS.lock() {
+        *configured = Some(opts);
+    }
+}
+
+/// The options set by [`configure_keyring`], else those of the
+/// `GCLOUD_IDENTITY_TOKEN_KEYRING_*` variables.
+fn keyring_options() -> KeyringOptions {
+    if let Some(opts) = KEYRING_OPTIONS.lock().ok().and_then(|opts| opts.clone()) {
+        return opts;
+    }
+    let var = |name| std::env::var(name).ok().filter(|value| !value.is_empty());
+    KeyringOptions {
+        service: var(KEYRING_SERVICE_ENV),
+        target: var(KEYRING_TARGET_ENV),
+        backend: var(KEYRING_BACKEND_ENV).and_then(|name| match name.parse() {
+            Ok(backend) => Some(backend),
+            Err(err) => {
+                eprintln!("Warning: ignoring {KEYRING_BACKEND_ENV}: {err}");
+                None
+            }
+        }),
+        timeout: var(KEYRING_TIMEOUT_ENV).and_then(|secs| match secs.parse() {
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            Err(_) => {
+                eprintln!(
+                    "Warning: ignoring {KEYRING_TIMEOUT_ENV}={secs:?}: not a number of seconds"
+                );
+                None
+            }
+        }),
+    }
+}
+
+/// The keyring entry of `user` under `opts`, in the chosen Linux backend.
+#[cfg(target_os = "linux")]
+fn keyring_entry(user: &str, opts: &KeyringOptions) -> keyring::Result<Entry> {
+    use keyring::keyutils::KeyutilsCredential;
+    use keyring::secret_service::SsCredential;
+
+    let service = opts.service.as_deref().unwrap_or(SERVICE);
+    let target = opts.target.as_deref();
+    Ok(match opts.backend {
+        Some(KeyringBackend::Keyutils) => Entry::new_with_credential(Box::new(
+            KeyutilsCredential::new_with_target(target, service, user)?,
+        )),
+        backend => {
+            check_dbus_session(
+                std::env::var_os("DBUS_SESSION_BUS_ADDRESS").as_deref(),
+                std::env::var_os("XDG_RUNTIME_DIR").as_deref(),
+            )?;
+            let target = match backend {
+                Some(KeyringBackend::Kwallet) => target.or(Some("kdewallet")),
+                _ => target,
+            };
+            Entry::new_with_credential(Box::new(SsCredential::new_with_target(
+                target, service, user,
+            )?))
+        }
+    })
+}
+
+/// The keyring entry of `user` under `opts`.
+#[cfg(not(target_os = "linux"))]
+fn keyring_entry(user: &str, opts: &KeyringOptions) -> keyring::Result<Entry> {
+    let service = opts.service.as_deref().unwrap_or(SERVICE);
+    match opts.target.as_deref() {
+        None => Entry::new(service, user),
+        // Windows target names identify single credentials, so each user
+        // needs its own.
+        Some(target) if cfg!(windows) => {
+            Entry::new_with_target(&format!("{user}.{target}"), service, user)
+        }
+        Some(target) => Entry::new_with_target(target, service, user),
+    }
+}
+
+/// Fail at once, rather than after the timeout, when the Secret Service
+/// cannot be reached because there is no D-Bus session bus: neither a bus
+/// `address` (`DBUS_SESSION_BUS_ADDRESS`) nor a socket in `runtime_dir`
+/// (`XDG_RUNTIME_DIR`).
+#[cfg(target_os = "linux")]
+fn check_dbus_session(
+    address: Option<&std::ffi::OsStr>,
+    runtime_dir: Option<&std::ffi::OsStr>,
+) -> keyring::Result<()> {
+    let has_address = address.is_some_and(|address| !address.is_empty());
+    let has_socket = runtime_dir.is_some_and(|dir| Path::new(dir).join("bus").exists());
+    if has_address || has_socket {
+        return Ok(());
+    }
+    Err(keyring::Error::NoStorageAccess(
+        format!(
+            "no D-Bus session bus for the Secret Service (DBUS_SESSION_BUS_ADDRESS is unset); \
+             run inside a desktop session or `dbus-run-session`, or set \
+             {KEYRING_BACKEND_ENV}=keyutils"
+        )
+        .into(),
+    ))
+}
+
+/// Run `op` on the keyring entry of `user`, giving up once the configured
+/// timeout passes.
+///
+/// The call runs on its own thread, which is abandoned if it never returns;
+/// a prompt-blocked D-Bus call cannot be cancelled.
+fn keyring_call<T: Send + 'static>(
+    user: &str,
+    op: impl FnOnce(Entry) -> keyring::Result<T> + Send + 'static,
+) -> keyring::Result<T> {
+    let opts = keyring_options();
+    let timeout = opts.timeout.unwrap_or(KEYRING_TIMEOUT);
+    let user = user.to_string();
+    let call = move || keyring_entry(&user, &opts).and_then(op);
+    if timeout.is_zero() {
+        return call();
+    }
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = tx.send(call());
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(res) => res,
+        Err(_) => Err(keyring::Error::NoStorageAccess(
+            format!(
+                "the keyring did not answer within {}s, perhaps waiting on an unlock \
+                 prompt; set {KEYRING_TIMEOUT_ENV} to wait longer",
+                timeout.as_secs()
+            )
+            .into(),
+        )),
+    }
+}
+
+impl TokenCache for KeyringCache {
+    fn load(&self, user: &str) -> Result<Option<SavedToken>> {
+        match keyring_call(user, |entry| entry.get_password()) {
+            Ok(json) => Ok(Some(SavedToken::from_json(json.as_bytes())?)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn save(&self, user: &str, token: &SavedToken) -> Result<()> {
+        let json = token.to_json()?;
+        keyring_call(user, move |entry| entry.set_password(&json))?;
+        Ok(())
+    }
+
+    fn delete(&self, user: &str) -> Result<()> {
+        match keyring_call(user, |entry| entry.delete_password()) {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Tokens held in process memory only, for containers and tests that want
+/// neither the keyring nor a file.
+///
+/// Every process starts without a token. Entries are wiped from memory when
+/// replaced or deleted.
+#[derive(Default)]
+pub struct MemoryCache {
+    entries: Mutex<HashMap<String, SavedToken>>,
+}
+
+impl MemoryCache {
+    /// An empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl fmt::Debug for MemoryCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let users: Vec<String> = self
+            .entries
+            .lock()
+            .map(|entries| entries.keys().cloned().collect())
+            .unwrap_or_default();
+        write!(f, "MemoryCache({users:?}, <redacted>)")
+    }
+}
+
+impl TokenCache for MemoryCache {
+    fn load(&self, user: &str) -> Result<Option<SavedToken>> {
+        let entries = self
+            .entries
+            .lock()
+            .map_err(|_| anyhow!("Memory token cache is poisoned"))?;
+        Ok(entries.get(user).cloned())
+    }
+
+    fn save(&self, user: &str, token: &SavedToken) -> Result<()> {
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|_| anyhow!("Memory token cache is poisoned"))?;
+        if let Some(mut old) = entries.insert(user.to_string(), token.clone()) {
+            wipe(&mut old);
+        }
+        Ok(())
+    }
+
+    fn delete(&self, user: &str) -> Result<()> {
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|_| anyhow!("Memory token cache is poisoned"))?;
+        if let Some(mut old) = entries.remove(user) {
+            wipe(&mut old);
+        }
+        Ok(())
+    }
+
+    fn users(&self) -> Option<Vec<String>> {
+        let entries = self.entries.lock().ok()?;
+        Some(entries.keys().cloned().collect())
+    }
+
+    fn is_persistent(&self) -> bool {
+        false
+    }
+}
+
+/// Overwrite the secrets of `token` in memory.
+fn wipe(token: &mut SavedToken) {
+    token.refresh_token.zeroize();
+    token.access_token.zeroize();
+    token.id_token.zeroize();
+}
+
+/// One file per entry in a directory, for hosts without a keyring.
+///
+/// Files are readable by the owner only, inside a directory only the owner
+/// can enter, and carry an integrity check. They are encrypted when a cache
+/// passphrase or machine binding is configured, like the
+/// `GCLOUD_IDENTITY_TOKEN_PATH` file.
+#[derive(Debug, Clone)]
+pub struct DirectoryCache {
+    dir: PathBuf,
+}
+
+impl DirectoryCache {
+    /// A store keeping its files in `dir`, created on the first save.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The file of the entry `user`, named so any user name is a valid file name.
+    fn path(&self, user: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.json", URL_SAFE_NO_PAD.encode(user)))
+    }
+}
+
+impl TokenCache for DirectoryCache {
+    fn load(&self, user: &str) -> Result<Option<SavedToken>> {
+        let path = self.path(user);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(read_token_file(&path))
+    }
+
+    fn save(&self, user: &str, token: &SavedToken) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&self.dir, fs::Permissions::from_mode(0o700))?;
+        }
+        write_token_file(&self.path(user), token)
+    }
+
+    fn delete(&self, user: &str) -> Result<()> {
+        match fs::remove_file(self.path(user)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn users(&self) -> Option<Vec<String>> {
+        let entries = fs::read_dir(&self.dir).ok()?;
+        Some(
+            entries
+                .filter_map(|entry| {
+                    let name = entry.ok()?.file_name();
+                    let name = name.to_str()?.strip_suffix(".json")?;
+                    String::from_utf8(URL_SAFE_NO_PAD.decode(name).ok()?).ok()
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Environment variable that, set to `0`, makes keyring failures errors
+/// instead of falling back to a [`DirectoryCache`].
+pub const KEYRING_FALLBACK_ENV: &str = "GCLOUD_IDENTITY_TOKEN_KEYRING_FALLBACK";
+
+/// Why and where tokens are cached since the keyring proved unusable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyringFallback {
+    /// The keyring's error
+    pub reason: String,
+    /// Directory of the [`DirectoryCache`] used instead
+    pub dir: PathBuf,
+}
+
+static KEYRING_FALLBACK: OnceLock<KeyringFallback> = OnceLock::new();
+
+/// Where tokens are cached instead of the keyring, if this process found the
+/// keyring unusable.
+pub fn keyring_fallback() -> Option<KeyringFallback> {
+    KEYRING_FALLBACK.get().cloned()
+}
+
+/// The keyring, switching for the rest of the process to a [`DirectoryCache`]
+/// under [`state_dir`] once the keyring turns out to be missing or locked
+/// away, e.g. without a Secret Service on headless Linux.
+struct KeyringWithFallback;
+
+impl KeyringWithFallback {
+    fn run<T>(&self, op: impl Fn(&dyn TokenCache) -> Result<T>) -> Result<T> {
+        if let Some(fallback) = KEYRING_FALLBACK.get() {
+            return op(&DirectoryCache::new(&fallback.dir));
+        }
+        let err = match op(&KeyringCache) {
+            Err(err) if keyring_unavailable(&err) => err,
+            res => return res,
+        };
+        let Some(dir) = fallback_dir() else {
+            return Err(err);
+        };
+        let fallback = KEYRING_FALLBACK.get_or_init(|| {
+            let fallback = KeyringFallback {
+                reason: format!("{err:#}"),
+                dir,
+            };
+            eprintln!(
+                "Warning: keyring unavailable ({}); caching tokens in {} instead. \
+                 Set {KEYRING_FALLBACK_ENV}=0 to fail instead.",
+                fallback.reason,
+                fallback.dir.display()
+            );
+            fallback
+        });
+        op(&DirectoryCache::new(&fallback.dir))
+    }
+}
+
+impl TokenCache for KeyringWithFallback {
+    fn load(&self, user: &str) -> Result<Option<SavedToken>> {
+        self.run(|cache| cache.load(user))
+    }
+
+    fn save(&self, user: &str, token: &SavedToken) -> Result<()> {
+        self.run(|cache| cache.save(user, token))
+    }
+
+    fn delete(&self, user: &str) -> Result<()> {
+        self.run(|cache| cache.delete(user))
+    }
+
+    fn users(&self) -> Option<Vec<String>> {
+        let fallback = KEYRING_FALLBACK.get()?;
+        DirectoryCache::new(&fallback.dir).users()
+    }
+}
+
+/// Where tokens go when the keyring is unusable, unless the fallback is
+/// turned off or there is no state directory.
+pub(crate) fn fallback_dir() -> Option<PathBuf> {
+    if std::env::var(KEYRING_FALLBACK_ENV).is_ok_and(|value| value == "0") {
+        return None;
+    }
+    Some(state_dir()?.join("tokens"))
+}
+
+/// Whether `err` means there is no usable keyring at all, rather than a
+/// problem with one entry.
+fn keyring_unavailable(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<keyring::Error>(),
+        Some(keyring::Error::PlatformFailure(_) | keyring::Error::NoStorageAccess(_))
+    )
+}
+
+/// Environment variable choosing the token store: `keyring` (the default),
+/// `memory`, or `gcloud` for gcloud's credential databases (see
+/// `gcloud_store::GcloudCache`) with the `gcloud-store` feature.
+pub const CACHE_BACKEND_ENV: &str = "GCLOUD_IDENTITY_TOKEN_CACHE";
+
+static TOKEN_CACHE: Mutex<Option<Arc<dyn TokenCache>>> = Mutex::new(None);
+
+/// The memory cache chosen through `GCLOUD_IDENTITY_TOKEN_CACHE=memory`.
+static ENV_MEMORY_CACHE: OnceLock<Arc<MemoryCache>> = OnceLock::new();
+
+/// The gcloud store chosen through `GCLOUD_IDENTITY_TOKEN_CACHE=gcloud`,
+/// recording the secret of the application default OAuth client.
+#[cfg(feature = "gcloud-store")]
+static ENV_GCLOUD_CACHE: OnceLock<Option<Arc<GcloudCache>>> = OnceLock::new();
+
+/// Keep tokens in `cache` from now on instead of the keyring.
+///
+/// ```rust
+/// use gcloud_identity_token::cache::{MemoryCache, configure_token_cache};
+/// use std::sync::Arc;
+///
+/// configure_token_cache(Arc::new(MemoryCache::new()));
+/// ```
+pub fn configure_token_cache(cache: Arc<dyn TokenCache>) {
+    invalidate_memory_cache();
+    if let Ok(mut configured) = TOKEN_CACHE.lock() {
+        *configured = Some(cache);
+    }
+}
+
+/// A memory cache configured as the store for every test that needs one, so
+/// tests running at once do not replace each other's; each uses its own keys.
+#[cfg(test)]
+pub(crate) fn shared_test_cache() -> Arc<MemoryCache> {
+    static CACHE: OnceLock<Arc<MemoryCache>> = OnceLock::new();
+    CACHE
+        .get_or_init(|| {
+            let cache = Arc::new(MemoryCache::new());
+            configure_token_cache(cache.clone());
+            cache
+        })
+        .clone()
+}
+
+/// The store set by [`configure_token_cache`], else the one
+/// `GCLOUD_IDENTITY_TOKEN_CACHE` names, else the keyring.
+fn token_cache() -> Arc<dyn TokenCache> {
+    if let Some(cache) = TOKEN_CACHE.lock().ok().and_then(|cache| cache.clone()) {
+        return cache;
+    }
+    match std::env::var(CACHE_BACKEND_ENV).as_deref() {
+        Ok("memory") => ENV_MEMORY_CACHE.get_or_init(Default::default).clone(),
+        #[cfg(feature = "gcloud-store")]
+        Ok("gcloud") => {
+            let cache = ENV_GCLOUD_CACHE.get_or_init(|| {
+                let cache = GcloudCache::from_config_dir()?;
+                Some(Arc::new(match load_creds() {
+                    Ok(creds) => cache.with_client(&creds),
+                    Err(_) => cache,
+                }))
+            });
+            match cache {
+                Some(cache) => cache.clone(),
+                None => Arc::new(KeyringWithFallback),
+            }
+        }
+        _ => Arc::new(KeyringWithFallback),
+    }
+}
+
+/// Access restrictions for the macOS Keychain entry that stores the token.
+///
+/// Either option moves the entry into the data protection keychain, which
+/// ties it to the binary's code signature. That requires a signed binary with
+/// a `keychain-access-groups` entitlement.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeychainAccess {
+    /// Require Touch ID or the login password each time the token is read
+    pub require_user_presence: bool,
+    /// Only the binary that created the entry may read it
+    pub restrict_to_creating_app: bool,
+}
+
+/// Apply `access` to keychain entries written or read from now on.
+///
+/// # Errors
+///
+/// Returns an error if any restriction is requested on a platform other than macOS.
+pub fn configure_keychain_access(access: KeychainAccess) -> Result<()> {
+    if access == KeychainAccess::default() {
+        return Ok(());
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        keyring::set_default_credential_builder(Box::new(
+            crate::keychain::ProtectedKeychainBuilder { access },
+        ));
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    Err(anyhow!(
+        "Keychain access control is only available on macOS"
+    ))
+}
+
+/// Check that the keyring is reachable by looking up an entry that need not exist.
+pub fn check_keyring() -> Result<()> {
+    match keyring_call("doctor", |entry| entry.get_password()) {
+        Ok(_) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Claims in a Google ID token that identify the account.
 #[derive(Debug, Deserialize)]
 struct IdTokenClaims {
-    email: String,
+    /// Google's stable user ID
+    #[serde(default)]
+    sub: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+}
+
+/// The identifying claims of a Google-provided ID token, `None` if it is malformed.
+fn id_token_claims(id_token: Option<&str>) -> Option<IdTokenClaims> {
+    let parts: Vec<&str> = id_token?.split('.').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+
+    let payload = URL_SAFE_NO_PAD.decode(parts[1]).ok()?;
+    serde_json::from_slice(&payload).ok()
 }
 
 /// Extracts the email address from a Google-provided ID token.
 ///
-/// Returns `None` if the token is malformed or does not include `email`.
-fn extract_email_from_id_token(id_token: &str) -> Option<String> {
-    let parts: Vec<&str> = id_token.split('.').collect();
-    if parts.len() != 3 {
-        return None;
+/// Returns `None` if the token is malformed or does not include `email`.
+fn extract_email_from_id_token(id_token: Option<&str>) -> Option<String> {
+    id_token_claims(id_token)?.email
+}
+
+/// The name `token`'s account is stored under: the ID token's `sub`, which
+/// stays the same when the account's email changes, else its email, else
+/// `fallback`.
+fn account_key(token: &SavedToken, fallback: &str) -> String {
+    id_token_claims(token.id_token.as_deref())
+        .and_then(|claims| claims.sub.or(claims.email))
+        .unwrap_or_else(|| fallback.to_string())
+}
+
+/// The names the account `name`, a `sub` or an email, may be stored under:
+/// its `sub` first, then its email, under which entries were kept before
+/// `sub` was.
+fn account_keys(name: &str) -> Vec<String> {
+    let index = read_account_index();
+    let by_email = index.iter().find(|(_, record)| {
+        record
+            .email
+            .as_deref()
+            .is_some_and(|email| email.eq_ignore_ascii_case(name))
+    });
+    let email = index.get(name).and_then(|record| record.email.clone());
+    distinct_users([
+        by_email.map(|(key, _)| key.clone()),
+        Some(name.to_string()),
+        email,
+    ])
+}
+
+/// Loads a cached token from either a file or the system keyring.
+///
+/// - If `GCLOUD_IDENTITY_TOKEN_PATH` is set, the token will be loaded from the specified file.
+/// - Otherwise, it attempts to read from the OS keyring using a fixed service name,
+///   preferring gcloud's default account (`CLOUDSDK_CORE_ACCOUNT` or the active
+///   configuration) and falling back to the last logged-in user. After the first
+///   successful read the token is kept in process memory until it expires.
+///
+/// # Returns
+///
+/// An optional `SavedToken` if a token issued to the OAuth client `client_id`
+/// for the default scopes was found and deserialized.
+pub fn load_cached_token(client_id: &str) -> Option<SavedToken> {
+    load_scoped_token(client_id, &[])
+}
+
+/// Like [`load_cached_token`], for the entry of the [`scope_set`] `scopes`.
+///
+/// The `GCLOUD_IDENTITY_TOKEN_PATH` file holds a single entry, which is
+/// returned whatever its scopes.
+pub(crate) fn load_scoped_token(client_id: &str, scopes: &[String]) -> Option<SavedToken> {
+    if let Some(path) = file_cache_path() {
+        let token = read_token_file(&path).filter(|token| issued_to(token, client_id))?;
+        emit(CacheEventKind::Load, None, &token);
+        return Some(token);
+    }
+
+    candidate_users().into_iter().find_map(|user| {
+        let token = load_entry(&user, client_id, scopes)?;
+        // Best effort: a failure to record usage must not hide a valid token.
+        let _ = record_account_use(&account_key(&token, &user), &token);
+        emit(CacheEventKind::Load, Some(&user), &token);
+        Some(token)
+    })
+}
+
+/// Load the keyring token of a specific account from the OAuth client
+/// `client_id`, bypassing account selection.
+///
+/// Like [`load_cached_token`], the token is kept in process memory after the
+/// first read.
+pub fn load_account(user: &str, client_id: &str) -> Option<SavedToken> {
+    load_scoped_account(user, client_id, &[])
+}
+
+/// The keyring token of `user` from `client_id` for the default scopes, else
+/// one for another scope set.
+pub(crate) fn load_any_account(user: &str, client_id: &str) -> Option<SavedToken> {
+    load_account(user, client_id).or_else(|| {
+        scoped_entries(user)
+            .iter()
+            .filter(|key| split_entry_key(key).1 == client_id)
+            .find_map(|key| read_entry(key).filter(|token| issued_to(token, client_id)))
+    })
+}
+
+/// Like [`load_account`], for the entry of the [`scope_set`] `scopes`.
+pub(crate) fn load_scoped_account(
+    user: &str,
+    client_id: &str,
+    scopes: &[String],
+) -> Option<SavedToken> {
+    let token = load_entry(user, client_id, scopes)?;
+    emit(CacheEventKind::Load, Some(user), &token);
+    Some(token)
+}
+
+/// What happened to a cached session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheEventKind {
+    /// Tokens were stored, after a login or a refresh
+    Save,
+    /// Tokens were read from the cache
+    Load,
+    /// Tokens were removed
+    Delete,
+    /// A refresh token was exchanged for new tokens
+    Refresh,
+}
+
+/// A session change reported to the callbacks registered with [`on_save`],
+/// [`on_load`], [`on_delete`], and [`on_refresh`].
+///
+/// Events describe a session without its secrets: no token ever reaches a
+/// callback.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheEvent {
+    /// What happened
+    pub kind: CacheEventKind,
+    /// Email of the account, else the name it is stored under; `None` if
+    /// the token does not say
+    pub account: Option<String>,
+    /// OAuth client of the tokens, empty if unknown
+    pub client_id: String,
+    /// When the access token expires; `None` for deletions
+    pub expiry: Option<DateTime<Utc>>,
+    /// Whether the session can be refreshed without a login
+    pub has_refresh_token: bool,
+    /// Scopes the grant covers, empty if unknown
+    pub granted_scopes: Vec<String>,
+}
+
+type Callback = Arc<dyn Fn(&CacheEvent) + Send + Sync>;
+
+static CALLBACKS: Mutex<Vec<(CacheEventKind, Callback)>> = Mutex::new(Vec::new());
+
+/// Call `callback` whenever tokens are stored, e.g. to emit audit events.
+pub fn on_save(callback: impl Fn(&CacheEvent) + Send + Sync + 'static) {
+    register(CacheEventKind::Save, Arc::new(callback));
+}
+
+/// Call `callback` whenever tokens are read from the cache, including from
+/// this process's memory.
+pub fn on_load(callback: impl Fn(&CacheEvent) + Send + Sync + 'static) {
+    register(CacheEventKind::Load, Arc::new(callback));
+}
+
+/// Call `callback` whenever tokens are removed, by a logout, `cache delete`,
+/// or pruning.
+pub fn on_delete(callback: impl Fn(&CacheEvent) + Send + Sync + 'static) {
+    register(CacheEventKind::Delete, Arc::new(callback));
+}
+
+/// Call `callback` whenever a refresh token is exchanged for new tokens,
+/// before they are handed out.
+pub fn on_refresh(callback: impl Fn(&CacheEvent) + Send + Sync + 'static) {
+    register(CacheEventKind::Refresh, Arc::new(callback));
+}
+
+/// Remove every callback registered with [`on_save`] and friends.
+pub fn clear_event_callbacks() {
+    if let Ok(mut callbacks) = CALLBACKS.lock() {
+        callbacks.clear();
+    }
+}
+
+fn register(kind: CacheEventKind, callback: Callback) {
+    if let Ok(mut callbacks) = CALLBACKS.lock() {
+        callbacks.push((kind, callback));
+    }
+}
+
+/// Report a `kind` event about `token` of `account` (the token's email when
+/// `None`) to the registered callbacks.
+fn emit(kind: CacheEventKind, account: Option<&str>, token: &SavedToken) {
+    dispatch(kind, || CacheEvent {
+        kind,
+        account: extract_email_from_id_token(token.id_token.as_deref())
+            .or(account.map(str::to_string)),
+        client_id: token.client_id.clone(),
+        expiry: Some(token.token_expiry),
+        has_refresh_token: !token.refresh_token.is_empty(),
+        granted_scopes: token.granted_scopes.clone(),
+    });
+}
+
+/// Report the deletion of the tokens of `account` from `client_id`, or from
+/// every client when empty.
+fn emit_delete(account: Option<&str>, client_id: &str) {
+    dispatch(CacheEventKind::Delete, || CacheEvent {
+        kind: CacheEventKind::Delete,
+        account: account.map(str::to_string),
+        client_id: client_id.to_string(),
+        expiry: None,
+        has_refresh_token: false,
+        granted_scopes: Vec::new(),
+    });
+}
+
+/// Pass the event `event` builds to the callbacks of `kind`, if there are any.
+fn dispatch(kind: CacheEventKind, event: impl FnOnce() -> CacheEvent) {
+    let callbacks: Vec<Callback> = match CALLBACKS.lock() {
+        Ok(callbacks) => callbacks
+            .iter()
+            .filter(|(registered, _)| *registered == kind)
+            .map(|(_, callback)| callback.clone())
+            .collect(),
+        Err(_) => return,
+    };
+    if callbacks.is_empty() {
+        return;
+    }
+    // Called without the lock held, so callbacks may register others.
+    let event = event();
+    for callback in callbacks {
+        callback(&event);
+    }
+}
+
+/// Report that `token` came from a refresh.
+pub(crate) fn record_refresh(token: &SavedToken) {
+    emit(CacheEventKind::Refresh, None, token);
+}
+
+/// The token of `user` from `client_id` for the scope set `scopes`, from
+/// memory or the store.
+///
+/// An entry written before tokens recorded their client is stored under the
+/// bare user name, and is taken to belong to any client, for the default
+/// scopes.
+fn load_entry(user: &str, client_id: &str, scopes: &[String]) -> Option<SavedToken> {
+    let keys = distinct_users(account_keys(user).into_iter().flat_map(|name| {
+        let bare = scopes.is_empty().then(|| name.clone());
+        [Some(entry_key(&name, client_id, scopes)), bare]
+    }));
+    keys.iter().find_map(|key| {
+        if let Some(token) = memoized(key) {
+            return Some(token);
+        }
+        let token = read_entry(key).filter(|token| issued_to(token, client_id))?;
+        Some(memoize(key, &token))
+    })
+}
+
+/// Name of the store entry holding `user`'s token from the OAuth client
+/// `client_id` for the [`scope_set`] `scopes`.
+///
+/// Tokens of different clients are kept apart, since a refresh token only
+/// works with the client it was issued to, and so are tokens of different
+/// scope sets, so a caller is never served an access token minted for
+/// others. The default scopes keep the `user:client_id` name entries had
+/// before scope sets were told apart.
+fn entry_key(user: &str, client_id: &str, scopes: &[String]) -> String {
+    match (client_id, scopes) {
+        ("", []) => user.to_string(),
+        (_, []) => format!("{user}:{client_id}"),
+        _ => format!("{user}:{client_id}:{}", scopes.join(" ")),
+    }
+}
+
+/// The user, client and space-separated scope set an entry is named by, as
+/// [`entry_key`] names it; missing parts are empty.
+pub(crate) fn split_entry_key(key: &str) -> (&str, &str, &str) {
+    let (user, rest) = key.split_once(':').unwrap_or((key, ""));
+    let (client_id, scopes) = rest.split_once(':').unwrap_or((rest, ""));
+    (user, client_id, scopes)
+}
+
+/// The canonical form of the scopes `scopes`, which names cache entries:
+/// full scope URLs, sorted and without duplicates. Empty for the default
+/// scopes, so their entries keep their names.
+pub(crate) fn scope_set(scopes: &[&str]) -> Vec<String> {
+    let canonical = |scopes: &[&str]| {
+        scopes
+            .iter()
+            .map(|scope| canonical_scope(scope).to_string())
+            .collect::<BTreeSet<_>>()
+    };
+    let set = canonical(scopes);
+    if set == canonical(DEFAULT_SCOPES) {
+        return Vec::new();
+    }
+    set.into_iter().collect()
+}
+
+/// Whether `token` was issued to `client_id`, or predates recording that.
+fn issued_to(token: &SavedToken, client_id: &str) -> bool {
+    token.client_id.is_empty() || token.client_id == client_id
+}
+
+/// A keyring account this crate has stored a token for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CachedAccount {
+    /// Name the account is stored under: Google's stable user ID (`sub`),
+    /// or the email for entries cached before that was used
+    pub user: String,
+    /// Email of the account, if known
+    pub email: Option<String>,
+    /// When the token was last read from or written to the keyring, if known
+    pub last_used: Option<DateTime<Utc>>,
+    /// OAuth clients holding a token of the account, empty if unknown
+    pub clients: Vec<String>,
+    /// When Google last rejected the account's refresh token with
+    /// `invalid_grant`, if that happened since its last login
+    pub refresh_rejected: Option<DateTime<Utc>>,
+}
+
+impl CachedAccount {
+    /// The email of the account if known, else the name it is stored under.
+    pub fn display_name(&self) -> &str {
+        self.email.as_deref().unwrap_or(&self.user)
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct AccountRecord {
+    /// Email of the account when it was last saved, for display and lookup
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    last_used: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    clients: BTreeSet<String>,
+    /// Names of the account's entries for scope sets other than the default
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    scoped: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    refresh_rejected: Option<DateTime<Utc>>,
+}
+
+/// Delete cached accounts whose refresh tokens were revoked or rejected, or
+/// that went unused, as [`PruneOptions`](crate::prune::PruneOptions) asks.
+pub use crate::prune::prune_cache as prune;
+
+/// Accounts with a cached token: the one in the `GCLOUD_IDENTITY_TOKEN_PATH`
+/// file when that is configured, else those of [`cached_accounts`].
+pub fn list_accounts() -> Vec<CachedAccount> {
+    match file_cache_path() {
+        Some(path) => file_account(&path).into_iter().collect(),
+        None => cached_accounts(),
+    }
+}
+
+/// The account of the token in the file cache at `path`, last used when the
+/// file was last written.
+fn file_account(path: &Path) -> Option<CachedAccount> {
+    let token = read_token_file(path)?;
+    let last_used = fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .map(DateTime::<Utc>::from);
+    Some(CachedAccount {
+        user: account_key(&token, "default"),
+        email: extract_email_from_id_token(token.id_token.as_deref()),
+        last_used,
+        clients: Some(token.client_id)
+            .filter(|client_id| !client_id.is_empty())
+            .into_iter()
+            .collect(),
+        refresh_rejected: None,
+    })
+}
+
+/// Keyring accounts known to hold tokens.
+///
+/// Keyrings cannot be enumerated portably, so this comes from an index the
+/// crate maintains next to the email hint; accounts cached before the index
+/// existed appear once they are used again. Stores that can list their
+/// entries, like [`MemoryCache`], are listed directly.
+pub fn cached_accounts() -> Vec<CachedAccount> {
+    let mut index = read_account_index();
+    if let Some(user) = last_login() {
+        index.entry(user).or_default();
+    }
+    for key in token_cache().users().unwrap_or_default() {
+        let (name, client_id, scopes) = split_entry_key(&key);
+        // Stores keyed by email, like gcloud's, list accounts the index knows by `sub`.
+        let user = index
+            .iter()
+            .find(|(_, record)| record.email.as_deref() == Some(name))
+            .map_or(name, |(user, _)| user.as_str())
+            .to_string();
+        let record = index.entry(user).or_default();
+        if !scopes.is_empty() {
+            record.scoped.insert(key.clone());
+        } else if !client_id.is_empty() {
+            record.clients.insert(client_id.to_string());
+        }
+    }
+    index
+        .into_iter()
+        .map(|(user, mut record)| {
+            let scoped_clients = record.scoped.iter().map(|key| split_entry_key(key).1);
+            record.clients.extend(
+                scoped_clients
+                    .filter(|client_id| !client_id.is_empty())
+                    .map(str::to_string),
+            );
+            CachedAccount {
+                email: record
+                    .email
+                    .or_else(|| user.contains('@').then(|| user.clone())),
+                user,
+                last_used: record.last_used,
+                clients: record.clients.into_iter().collect(),
+                refresh_rejected: record.refresh_rejected,
+            }
+        })
+        .collect()
+}
+
+/// An account whose gcloud login [`import_from_gcloud`] copied into the cache.
+#[derive(Clone)]
+pub struct GcloudImport {
+    /// Email of the account
+    pub account: String,
+    /// The OAuth client the imported tokens belong to, to pass to
+    /// [`get_token`](crate::auth::get_token) and friends so they find them
+    pub creds: Creds,
+}
+
+/// Seed the cache with the refresh token gcloud already holds for `account`,
+/// so no browser login is needed on machines where `gcloud auth login` ran.
+///
+/// Without `account`, gcloud's active account is imported. Its credential
+/// comes from gcloud's `credentials.db` (with the `gcloud-store` feature),
+/// else from the application default credentials file of `gcloud auth
+/// application-default login`. The token is
+/// refreshed once to check it and learn the account, then saved as the last
+/// login.
+///
+/// # Errors
+///
+/// Returns an error if gcloud holds no refresh token for the account, if
+/// Google rejects it, or if the application default credentials belong to
+/// another account.
+pub async fn import_from_gcloud(account: Option<&str>) -> Result<GcloudImport> {
+    let account = account.map(str::to_string).or_else(gcloud::default_account);
+    #[cfg(feature = "gcloud-store")]
+    let from_store = match (&account, GcloudCache::from_config_dir()) {
+        (Some(account), Some(store)) => store.user_creds(account)?,
+        _ => None,
+    };
+    #[cfg(not(feature = "gcloud-store"))]
+    let from_store = None;
+    let creds = match from_store {
+        Some(creds) => creds,
+        None => load_creds().map_err(|err| {
+            anyhow!(
+                "gcloud has no credential for {}, and no application default \
+                 credentials were found: {err}",
+                account.as_deref().unwrap_or("an active account")
+            )
+        })?,
+    };
+    let Some(refresh_token) = creds.refresh_token.clone() else {
+        return Err(anyhow!(
+            "gcloud's credential has no refresh token to import"
+        ));
+    };
+
+    let seed = SavedToken {
+        refresh_token,
+        access_token: String::new(),
+        id_token: None,
+        token_expiry: DateTime::UNIX_EPOCH,
+        refresh_token_issued_at: None,
+        granted_scopes: Vec::new(),
+        requested_scopes: Vec::new(),
+        client_id: creds.client_id.clone(),
+    };
+    let saved = match request_refresh(&creds, &seed).await {
+        Err(err) if matches!(err.downcast_ref(), Some(AuthError::LoginRequired)) => {
+            return Err(anyhow!(
+                "Google rejected gcloud's refresh token; run `gcloud auth login` again"
+            ));
+        }
+        res => res?,
+    };
+    let email = extract_email_from_id_token(saved.id_token.as_deref())
+        .ok_or_else(|| anyhow!("gcloud's credential does not identify its account"))?;
+    if let Some(account) = account.filter(|account| !account.eq_ignore_ascii_case(&email)) {
+        return Err(anyhow!(
+            "gcloud's credential belongs to {email}, not {account}"
+        ));
+    }
+    save_token(&saved)?;
+    Ok(GcloudImport {
+        account: email,
+        creds,
+    })
+}
+
+/// Write the cached refresh token of `creds`' client to `path` as an
+/// `authorized_user` credentials file, the format of `gcloud auth
+/// application-default login`, returning the account it belongs to.
+///
+/// Google's SDKs for Python, Go, and others, and tools like Terraform, use
+/// the file through `GOOGLE_APPLICATION_CREDENTIALS`. It is replaced
+/// atomically and, on Unix, readable only by the owner.
+///
+/// # Errors
+///
+/// Returns an error if nothing is cached for the client, if the cached entry
+/// has no refresh token, or if the file cannot be written.
+pub fn export_adc(creds: &Creds, path: &Path) -> Result<Option<String>> {
+    let token = load_cached_token(&creds.client_id).ok_or(AuthError::LoginRequired)?;
+    if token.refresh_token.is_empty() {
+        return Err(anyhow!(
+            "The cached token has no refresh token to export; log in again"
+        ));
     }
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let account = extract_email_from_id_token(token.id_token.as_deref());
+    let json = adc_json(creds, &token, account.as_deref());
+    write_atomic(path, &serde_json::to_vec_pretty(&json)?)?;
+    Ok(account)
+}
 
-    let payload = URL_SAFE_NO_PAD.decode(parts[1]).ok()?;
-    let claims: IdTokenClaims = serde_json::from_slice(&payload).ok()?;
-    Some(claims.email)
+/// The `authorized_user` credentials of `token`, as gcloud writes them.
+pub(crate) fn adc_json(
+    creds: &Creds,
+    token: &SavedToken,
+    account: Option<&str>,
+) -> serde_json::Value {
+    serde_json::json!({
+        "account": account.unwrap_or_default(),
+        "client_id": creds.client_id,
+        "client_secret": creds.client_secret,
+        "refresh_token": token.refresh_token,
+        "type": "authorized_user",
+        "universe_domain": "googleapis.com",
+    })
 }
 
-/// Loads a cached token from either a file or the system keyring.
+/// Delete the cached tokens of `user`, from every OAuth client, and forget
+/// the account.
 ///
-/// - If `GCLOUD_IDENTITY_TOKEN_PATH` is set, the token will be loaded from the specified file.
-/// - Otherwise, it attempts to read from the OS keyring using a fixed service name
-///   and the default user identifier `"default"`.
+/// With a `GCLOUD_IDENTITY_TOKEN_PATH` file configured, the file is removed
+/// if it holds the token of `user`.
 ///
-/// # Returns
+/// # Errors
 ///
-/// An optional `SavedToken` if the token was found and deserialized.
-pub fn load_cached_token() -> Option<SavedToken> {
-    if let Ok(env_path) = std::env::var("GCLOUD_IDENTITY_TOKEN_PATH") {
-        let path = PathBuf::from(env_path);
-        let data = fs::read_to_string(path).ok()?;
-        return serde_json::from_str(&data).ok();
+/// Returns an error if the store refuses the deletion, or if the token file
+/// holds another account's token.
+pub fn delete_account(user: &str) -> Result<()> {
+    invalidate_memory_cache();
+    if let Some(path) = file_cache_path() {
+        return match file_account(&path) {
+            Some(account) if account.user == user || account.email.as_deref() == Some(user) => {
+                fs::remove_file(&path)?;
+                emit_delete(Some(user), "");
+                Ok(())
+            }
+            Some(_) => Err(anyhow!("{} holds another account's token", path.display())),
+            None => Ok(()),
+        };
+    }
+    delete_stored_account(user)
+}
+
+/// Delete the tokens of `user` from the keyring or the configured store,
+/// even while a token file is in use.
+pub(crate) fn delete_stored_account(user: &str) -> Result<()> {
+    invalidate_memory_cache();
+    let cache = token_cache();
+    let names = account_keys(user);
+    let accounts = cached_accounts();
+    for name in &names {
+        cache.delete(name)?;
+        let clients = accounts
+            .iter()
+            .filter(|account| account.user == *name)
+            .flat_map(|account| account.clients.iter());
+        for client_id in clients {
+            cache.delete(&entry_key(name, client_id, &[]))?;
+        }
+        for key in scoped_entries(name) {
+            cache.delete(&key)?;
+        }
+    }
+    emit_delete(Some(user), "");
+    forget_account(&names)
+}
+
+/// Names of the entries `name` is stored under for scope sets other than
+/// the default, as recorded when saved or listed by the store.
+fn scoped_entries(name: &str) -> Vec<String> {
+    let recorded = read_account_index()
+        .remove(name)
+        .map(|record| record.scoped)
+        .unwrap_or_default();
+    let listed = token_cache()
+        .users()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|key| {
+            let (user, _, scopes) = split_entry_key(key);
+            user == name && !scopes.is_empty()
+        });
+    recorded
+        .into_iter()
+        .chain(listed)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+fn forget_account(names: &[String]) -> Result<()> {
+    if !token_cache().is_persistent() {
+        return Ok(());
+    }
+    let mut index = read_account_index();
+    let before = index.len();
+    index.retain(|user, _| !names.contains(user));
+    if index.len() != before {
+        write_account_index(&index)?;
+    }
+    Ok(())
+}
+
+impl AccountRecord {
+    /// Mark the account used now with `token`.
+    ///
+    /// Only default-scope tokens list their client; entries of other scope
+    /// sets are recorded by name when saved.
+    fn touch(&mut self, token: &SavedToken) {
+        self.last_used = Some(Utc::now());
+        if !token.client_id.is_empty() && token.requested_scopes.is_empty() {
+            self.clients.insert(token.client_id.clone());
+        }
+        if let Some(email) = extract_email_from_id_token(token.id_token.as_deref()) {
+            self.email = Some(email);
+        }
+    }
+}
+
+fn record_account_use(user: &str, token: &SavedToken) -> Result<()> {
+    update_account_record(user, |record| record.touch(token))
+}
+
+/// Note that Google rejected the refresh token of `token`'s account, so
+/// [`prune`] can remove the account without asking again.
+pub(crate) fn record_refresh_rejected(token: &SavedToken) -> Result<()> {
+    if file_cache_path().is_some() {
+        return Ok(());
+    }
+    let Some(claims) = id_token_claims(token.id_token.as_deref()) else {
+        return Ok(());
+    };
+    let Some(user) = claims.sub.or(claims.email) else {
+        return Ok(());
+    };
+    update_account_record(&user, |record| record.refresh_rejected = Some(Utc::now()))
+}
+
+fn update_account_record(user: &str, update: impl FnOnce(&mut AccountRecord)) -> Result<()> {
+    if !token_cache().is_persistent() {
+        return Ok(());
+    }
+    let mut index = read_account_index();
+    update(index.entry(user.to_string()).or_default());
+    write_account_index(&index)
+}
+
+fn read_account_index() -> BTreeMap<String, AccountRecord> {
+    if !token_cache().is_persistent() {
+        return BTreeMap::new();
+    }
+    read_state(ACCOUNTS_FILE)
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+fn write_account_index(index: &BTreeMap<String, AccountRecord>) -> Result<()> {
+    write_state(ACCOUNTS_FILE, &serde_json::to_vec_pretty(index)?)
+}
+
+/// The stored token of `user`; unreadable entries are reported and count as
+/// missing.
+fn read_entry(user: &str) -> Option<SavedToken> {
+    token_cache()
+        .load(user)
+        .inspect_err(|err| eprintln!("Ignoring cached token of {user}: {err:#}"))
+        .ok()
+        .flatten()
+}
+
+/// Keyring users to try, in order: the current account, gcloud's default
+/// account, then the last login.
+fn candidate_users() -> Vec<String> {
+    distinct_users([
+        current_account(),
+        gcloud::default_account(),
+        Some(last_login().unwrap_or_else(|| "default".to_string())),
+    ])
+}
+
+/// The account saved last by [`save_token`] in this process, for stores
+/// that do not persist.
+static LAST_LOGIN: Mutex<Option<String>> = Mutex::new(None);
+
+/// The account logged in last.
+fn last_login() -> Option<String> {
+    if !token_cache().is_persistent() {
+        return LAST_LOGIN.lock().ok()?.clone();
+    }
+    String::from_utf8(read_state(LAST_LOGIN_FILE)?).ok()
+}
+
+fn set_last_login(user: &str) -> Result<()> {
+    if !token_cache().is_persistent() {
+        if let Ok(mut last_login) = LAST_LOGIN.lock() {
+            *last_login = Some(user.to_string());
+        }
+        return Ok(());
+    }
+    write_state(LAST_LOGIN_FILE, user.as_bytes())
+}
+
+/// The given users in order, without the unset ones and repeats.
+fn distinct_users(users: impl IntoIterator<Item = Option<String>>) -> Vec<String> {
+    let mut distinct = Vec::new();
+    for user in users.into_iter().flatten() {
+        if !distinct.contains(&user) {
+            distinct.push(user);
+        }
     }
+    distinct
+}
+
+/// The account chosen with [`set_current_account`], if any.
+pub fn current_account() -> Option<String> {
+    String::from_utf8(read_state(CURRENT_ACCOUNT_FILE)?)
+        .ok()
+        .map(|user| user.trim().to_string())
+        .filter(|user| !user.is_empty())
+}
 
-    let user = fs::read_to_string(email_hint_path()).unwrap_or_else(|_| "default".to_string());
-    let entry = Entry::new(SERVICE, &user).ok()?;
-    let json = entry.get_password().ok()?;
-    serde_json::from_str(&json).ok()
+/// Make `user` the account [`load_cached_token`] returns, ahead of gcloud's
+/// active account and the last login; `None` goes back to those.
+///
+/// # Errors
+///
+/// Returns an error if no token of `user` is cached in the keyring.
+pub fn set_current_account(user: Option<&str>) -> Result<()> {
+    invalidate_memory_cache();
+    let Some(user) = user else {
+        return remove_state(CURRENT_ACCOUNT_FILE);
+    };
+    let index = read_account_index();
+    let cached = account_keys(user).iter().any(|name| {
+        let clients = index.get(name).map(|record| &record.clients);
+        std::iter::once(name.clone())
+            .chain(
+                clients
+                    .into_iter()
+                    .flatten()
+                    .map(|client_id| entry_key(name, client_id, &[])),
+            )
+            .chain(scoped_entries(name))
+            .any(|key| read_entry(&key).is_some())
+    });
+    if !cached {
+        return Err(anyhow!("No token of {user} is cached; log in to it first"));
+    }
+    write_state(CURRENT_ACCOUNT_FILE, user.as_bytes())
 }
 
 /// Saves a token to either a file or the system keyring.
 ///
 /// - If `GCLOUD_IDENTITY_TOKEN_PATH` is set, the token will be saved to that file path.
-/// - Otherwise, it saves to the keyring using the `email` field in the ID token as the user ID.
-///   If the email cannot be extracted, it falls back to `"default"`.
+/// - Otherwise, it saves to the keyring using the `sub` claim of the ID token
+///   as the user ID, else its `email`, else `"default"`. Under
+///   [`StoragePolicy::RefreshTokenOnly`] only the refresh token is written there.
 ///
 /// # Errors
 ///
 /// Returns an error if the token cannot be serialized or stored.
 pub fn save_token(token: &SavedToken) -> Result<()> {
-    if let Ok(env_path) = std::env::var("GCLOUD_IDENTITY_TOKEN_PATH") {
-        let path = PathBuf::from(env_path);
+    if let Some(path) = file_cache_path() {
         fs::create_dir_all(path.parent().unwrap())?;
-        fs::write(path, serde_json::to_string_pretty(token)?)?;
+        write_token_file(&path, token)?;
+        emit(CacheEventKind::Save, None, token);
         return Ok(());
     }
 
-    let user =
-        extract_email_from_id_token(&token.id_token).unwrap_or_else(|| "default".to_string());
-    fs::write(email_hint_path(), &user)?;
+    let user = account_key(token, "default");
+    set_last_login(&user)?;
+    save_account_token(&user, token)
+}
 
-    let json = serde_json::to_string(token)?;
-    let entry = Entry::new(SERVICE, &user)?;
-    entry.set_password(&json)?;
-    Ok(())
+/// Save a refreshed token over the entry it was loaded from, without making
+/// its account the last login.
+pub(crate) fn update_token(token: &SavedToken) -> Result<()> {
+    if let Some(path) = file_cache_path() {
+        fs::create_dir_all(path.parent().unwrap())?;
+        write_token_file(&path, token)?;
+        emit(CacheEventKind::Save, None, token);
+        return Ok(());
+    }
+    save_account_token(&account_key(token, "default"), token)
 }
 
-/// Deletes a token from the system keyring.
+/// Save the keyring token of `user`, without making it the last login.
 ///
-/// Only applies if you're using the default `"default"` user ID. For more dynamic handling,
-/// you'd want to extract the appropriate email-based user name from the current context.
-pub fn delete_token() -> Result<()> {
-    let user = fs::read_to_string(email_hint_path()).unwrap_or_else(|_| "default".to_string());
-    let entry = Entry::new(SERVICE, &user)?;
-    entry.delete_password()?;
-    Ok(())
+/// Used to refresh the tokens of an account other than the one in use. The
+/// entry is named by the token's `sub` when it has one, whatever `user` says,
+/// and replaces any entry kept under the account's email before.
+///
+/// # Errors
+///
+/// Returns an error if a file cache is configured, since it holds a single
+/// token, or if the token cannot be serialized or stored.
+pub fn save_account_token(user: &str, token: &SavedToken) -> Result<()> {
+    if file_cache_path().is_some() {
+        return Err(anyhow!(
+            "A token file holds a single account; unset {CACHE_PATH_ENV} to cache several"
+        ));
+    }
+    let cache = token_cache();
+    let user = account_key(token, user);
+    let key = entry_key(&user, &token.client_id, &token.requested_scopes);
+    // Entries named by email are superseded. They go first, since a store
+    // naming entries by email itself, like gcloud's, holds the new one there.
+    let email =
+        extract_email_from_id_token(token.id_token.as_deref()).filter(|email| *email != user);
+    if let Some(email) = &email {
+        cache.delete(&entry_key(email, &token.client_id, &token.requested_scopes))?;
+    }
+    cache.save(&key, &persisted(token, storage_policy()))?;
+    for superseded in distinct_users([Some(user.clone()), email.clone()]) {
+        // So is a bare entry from before clients were recorded.
+        if superseded != key {
+            cache.delete(&superseded)?;
+        }
+    }
+    memoize(&key, token);
+    emit(CacheEventKind::Save, Some(&user), token);
+    if !cache.is_persistent() {
+        return Ok(());
+    }
+    let mut index = read_account_index();
+    let mut record = email
+        .and_then(|email| index.remove(&email))
+        .unwrap_or_default();
+    if let Some(current) = index.remove(&user) {
+        record.clients.extend(current.clients);
+        record.scoped.extend(current.scoped);
+    }
+    record.touch(token);
+    if !token.requested_scopes.is_empty() {
+        record.scoped.insert(key);
+    }
+    // A new token, from a refresh or a login, clears an earlier rejection.
+    record.refresh_rejected = None;
+    index.insert(user, record);
+    write_account_index(&index)
+}
+
+/// Deletes a token from the system keyring, or the token file when one is
+/// configured.
+///
+/// Removes the entry [`load_cached_token`] would return for `client_id`:
+/// gcloud's default account if it has a cached token, otherwise the last
+/// logged-in user, along with the account's entries for other scope sets.
+/// Tokens of the account from other clients are kept.
+pub fn delete_token(client_id: &str) -> Result<()> {
+    invalidate_memory_cache();
+    if let Some(path) = file_cache_path() {
+        return match fs::remove_file(&path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            Err(_) => Ok(()),
+            Ok(()) => {
+                emit_delete(None, client_id);
+                Ok(())
+            }
+        };
+    }
+    let users = candidate_users();
+    let user = users
+        .iter()
+        .find(|user| load_entry(user, client_id, &[]).is_some())
+        .unwrap_or(users.last().expect("at least one candidate user"));
+    invalidate_memory_cache();
+    let cache = token_cache();
+    let names = account_keys(user);
+    let of_client = |key: &String| split_entry_key(key).1 == client_id;
+    for name in &names {
+        cache.delete(&entry_key(name, client_id, &[]))?;
+        cache.delete(name)?;
+        for key in scoped_entries(name).iter().filter(|key| of_client(key)) {
+            cache.delete(key)?;
+        }
+    }
+    emit_delete(Some(user), client_id);
+
+    let mut index = read_account_index();
+    let mut changed = false;
+    for name in &names {
+        let Some(record) = index.get_mut(name.as_str()) else {
+            continue;
+        };
+        record.clients.remove(client_id);
+        record.scoped.retain(|key| !of_client(key));
+        if record.clients.is_empty() && record.scoped.is_empty() {
+            index.remove(name.as_str());
+        }
+        changed = true;
+    }
+    if !changed {
+        return Ok(());
+    }
+    write_account_index(&index)
+}
+
+/// Longest wait for another process to release the cache lock.
+const LOCK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Pause between attempts to take the cache lock.
+const LOCK_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// An exclusive advisory lock on the token cache, released when dropped.
+///
+/// Every process using the same cache takes it around a refresh, so a
+/// rotated refresh token is never overwritten with the one it replaced. The
+/// operating system drops it when its holder exits, so it cannot go stale.
+pub(crate) struct CacheLock {
+    _file: Option<fs::File>,
+}
+
+/// Take the cache lock, waiting while another process holds it.
+///
+/// # Errors
+///
+/// Returns an error if the lock file cannot be opened, or if the lock is
+/// still held after 30 seconds.
+pub(crate) async fn lock_cache() -> Result<CacheLock> {
+    if file_cache_path().is_none() && !token_cache().is_persistent() {
+        return Ok(CacheLock { _file: None });
+    }
+    lock_file(&lock_path()?, LOCK_TIMEOUT).await
+}
+
+async fn lock_file(path: &Path, timeout: std::time::Duration) -> Result<CacheLock> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        match file.try_lock() {
+            Ok(()) => return Ok(CacheLock { _file: Some(file) }),
+            Err(fs::TryLockError::WouldBlock) if Instant::now() < deadline => {
+                tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+            }
+            Err(fs::TryLockError::WouldBlock) => {
+                return Err(anyhow!(
+                    "Timed out waiting for another process to release {}",
+                    path.display()
+                ));
+            }
+            Err(fs::TryLockError::Error(err)) => return Err(err.into()),
+        }
+    }
+}
+
+/// The lock file of the file cache, else of the keyring.
+fn lock_path() -> Result<PathBuf> {
+    match file_cache_path() {
+        Some(path) => {
+            let mut lock = path.into_os_string();
+            lock.push(".lock");
+            Ok(PathBuf::from(lock))
+        }
+        None => state_file(LOCK_FILE),
+    }
+}
+
+/// Environment variable naming the directory of the crate's state files.
+pub const STATE_DIR_ENV: &str = "GCLOUD_IDENTITY_TOKEN_STATE_DIR";
+
+/// State file holding the account logged in last.
+const LAST_LOGIN_FILE: &str = "email";
+
+/// State file holding the account chosen with [`set_current_account`].
+const CURRENT_ACCOUNT_FILE: &str = "current";
+
+/// State file indexing the cached accounts.
+const ACCOUNTS_FILE: &str = "accounts.json";
+
+/// Lock file of the keyring cache.
+const LOCK_FILE: &str = "lock";
+
+/// Directory of the last-login hint, the current account, the account index,
+/// and the cache lock; never the tokens themselves.
+///
+/// `GCLOUD_IDENTITY_TOKEN_STATE_DIR` when set, else `gcloud-identity-token`
+/// in `$XDG_STATE_HOME` (`~/.local/state`) on Linux, `~/Library/Application
+/// Support` on macOS, and `%LOCALAPPDATA%` on Windows. `None` when there is
+/// no home directory to derive it from.
+pub fn state_dir() -> Option<PathBuf> {
+    state_dir_from(std::env::var_os(STATE_DIR_ENV))
+}
+
+/// [`state_dir`], with `configured` as the value of
+/// `GCLOUD_IDENTITY_TOKEN_STATE_DIR`.
+fn state_dir_from(configured: Option<std::ffi::OsString>) -> Option<PathBuf> {
+    if let Some(dir) = configured.filter(|dir| !dir.is_empty()) {
+        return Some(PathBuf::from(dir));
+    }
+    Some(
+        dirs::state_dir()
+            .or_else(dirs::data_local_dir)?
+            .join(env!("CARGO_PKG_NAME")),
+    )
+}
+
+/// The path of the state file `name`, for writing.
+fn state_file(name: &str) -> Result<PathBuf> {
+    state_file_in(state_dir(), name)
+}
+
+/// The path of the state file `name` in the state directory `dir`.
+fn state_file_in(dir: Option<PathBuf>, name: &str) -> Result<PathBuf> {
+    let dir =
+        dir.ok_or_else(|| anyhow!("No home directory to keep state in; set {STATE_DIR_ENV}"))?;
+    Ok(dir.join(name))
+}
+
+/// Where versions before [`state_dir`] kept the state file `name`.
+fn legacy_state_file(name: &str) -> Option<PathBuf> {
+    Some(
+        dirs::home_dir()?
+            .join(".cache")
+            .join(format!("{}.{name}", env!("CARGO_PKG_NAME"))),
+    )
+}
+
+/// Read the state file `name`, falling back to where older versions kept it.
+fn read_state(name: &str) -> Option<Vec<u8>> {
+    read_state_in(state_dir(), name)
+}
+
+/// [`read_state`] with the state directory `dir`.
+fn read_state_in(dir: Option<PathBuf>, name: &str) -> Option<Vec<u8>> {
+    dir.map(|dir| dir.join(name))
+        .into_iter()
+        .chain(legacy_state_file(name))
+        .find_map(|path| fs::read(path).ok())
 }
 
-fn email_hint_path() -> PathBuf {
-    dirs::home_dir()
-        .expect("no home dir")
-        .join(".cache")
-        .join(format!("{}.email", env!("CARGO_PKG_NAME")))
+/// Replace the state file `name`, creating the state directory if needed.
+fn write_state(name: &str, contents: &[u8]) -> Result<()> {
+    write_state_in(state_dir(), name, contents)
+}
+
+/// [`write_state`] with the state directory `dir`.
+fn write_state_in(dir: Option<PathBuf>, name: &str, contents: &[u8]) -> Result<()> {
+    let path = state_file_in(dir, name)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    write_atomic(&path, contents)
+}
+
+/// Remove the state file `name`, wherever a version of the crate put it.
+fn remove_state(name: &str) -> Result<()> {
+    remove_state_in(state_dir(), name)
+}
+
+/// [`remove_state`] with the state directory `dir`.
+fn remove_state_in(dir: Option<PathBuf>, name: &str) -> Result<()> {
+    for path in dir
+        .map(|dir| dir.join(name))
+        .into_iter()
+        .chain(legacy_state_file(name))
+    {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_event_callbacks_get_metadata_of_their_kind() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let record = |seen: &Arc<Mutex<Vec<CacheEvent>>>| {
+            let seen = seen.clone();
+            move |event: &CacheEvent| {
+                // Other tests save tokens concurrently.
+                if event.client_id == "events-client" {
+                    seen.lock().unwrap().push(event.clone());
+                }
+            }
+        };
+        on_save(record(&seen));
+        on_delete(record(&seen));
+
+        let token = SavedToken {
+            refresh_token: "refresh".into(),
+            access_token: "access".into(),
+            id_token: None,
+            token_expiry: Utc::now(),
+            refresh_token_issued_at: None,
+            granted_scopes: vec!["openid".into()],
+            requested_scopes: Vec::new(),
+            client_id: "events-client".into(),
+        };
+        emit(CacheEventKind::Save, Some("me@example.com"), &token);
+        emit(CacheEventKind::Load, Some("me@example.com"), &token);
+        emit_delete(Some("me@example.com"), "events-client");
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 2);
+        assert_eq!(seen[0].kind, CacheEventKind::Save);
+        assert_eq!(seen[0].account.as_deref(), Some("me@example.com"));
+        assert_eq!(seen[0].expiry, Some(token.token_expiry));
+        assert!(seen[0].has_refresh_token);
+        assert_eq!(seen[1].kind, CacheEventKind::Delete);
+        assert_eq!(seen[1].expiry, None);
+    }
+
+    #[test]
+    fn test_adc_json_is_authorized_user() {
+        let creds = Creds {
+            client_id: "client".into(),
+            client_secret: "secret".into(),
+            refresh_token: None,
+        };
+        let token = SavedToken {
+            refresh_token: "refresh".into(),
+            access_token: "access".into(),
+            id_token: None,
+            token_expiry: Utc::now(),
+            refresh_token_issued_at: None,
+            granted_scopes: Vec::new(),
+            requested_scopes: Vec::new(),
+            client_id: "client".into(),
+        };
+        let json = adc_json(&creds, &token, Some("me@example.com"));
+        assert_eq!(json["type"], "authorized_user");
+        assert_eq!(json["account"], "me@example.com");
+        assert_eq!(json["refresh_token"], "refresh");
+        assert!(json.get("access_token").is_none());
+
+        // The crate reads its own export back as an OAuth client.
+        let read: Creds = serde_json::from_value(json).unwrap();
+        assert_eq!(read.refresh_token.as_deref(), Some("refresh"));
+        assert_eq!(read.client_secret, "secret");
+    }
+
+    #[test]
+    fn test_keyring_backend_names() {
+        assert_eq!(
+            "libsecret".parse::<KeyringBackend>().unwrap(),
+            KeyringBackend::Libsecret
+        );
+        assert_eq!(
+            "secret-service".parse::<KeyringBackend>().unwrap(),
+            KeyringBackend::Libsecret
+        );
+        assert_eq!(
+            "kwallet".parse::<KeyringBackend>().unwrap(),
+            KeyringBackend::Kwallet
+        );
+        assert_eq!(
+            "keyutils".parse::<KeyringBackend>().unwrap(),
+            KeyringBackend::Keyutils
+        );
+        assert!("pass".parse::<KeyringBackend>().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_missing_dbus_session_is_unavailable() {
+        use std::ffi::OsStr;
+
+        let dir = tempfile::tempdir().unwrap();
+        let runtime_dir = Some(dir.path().as_os_str());
+        let err = anyhow::Error::from(check_dbus_session(None, runtime_dir).unwrap_err());
+        assert!(keyring_unavailable(&err));
+        assert!(format!("{err:#}").contains("D-Bus"));
+        assert!(check_dbus_session(Some(OsStr::new("")), None).is_err());
+
+        assert!(check_dbus_session(Some(OsStr::new("unix:path=/run/bus")), None).is_ok());
+        fs::write(dir.path().join("bus"), "").unwrap();
+        assert!(check_dbus_session(None, runtime_dir).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_lock_file_is_exclusive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.lock");
+        let held = lock_file(&path, LOCK_TIMEOUT).await.unwrap();
+        assert!(
+            lock_file(&path, std::time::Duration::from_millis(100))
+                .await
+                .is_err()
+        );
+
+        drop(held);
+        lock_file(&path, std::time::Duration::ZERO).await.unwrap();
+    }
+
+    #[test]
+    fn test_memory_cache_round_trip() {
+        let cache = MemoryCache::new();
+        let token = SavedToken {
+            refresh_token: "r".into(),
+            access_token: "a".into(),
+            id_token: None,
+            token_expiry: "2025-01-01T00:00:00Z".parse().unwrap(),
+            refresh_token_issued_at: None,
+            granted_scopes: Vec::new(),
+            requested_scopes: Vec::new(),
+            client_id: String::new(),
+        };
+        assert!(cache.load("me@example.com").unwrap().is_none());
+
+        cache.save("me@example.com", &token).unwrap();
+        let loaded = cache.load("me@example.com").unwrap().unwrap();
+        assert_eq!(loaded.refresh_token, "r");
+        assert!(cache.load("other@example.com").unwrap().is_none());
+        assert!(!format!("{cache:?}").contains("\"r\""));
+
+        cache.delete("me@example.com").unwrap();
+        cache.delete("me@example.com").unwrap();
+        assert!(cache.load("me@example.com").unwrap().is_none());
+        assert!(!cache.is_persistent());
+    }
+
+    #[test]
+    fn test_entries_are_keyed_by_client_and_scopes() {
+        assert_eq!(entry_key("me@example.com", "", &[]), "me@example.com");
+        assert_eq!(
+            entry_key("me@example.com", "1.apps.googleusercontent.com", &[]),
+            "me@example.com:1.apps.googleusercontent.com"
+        );
+
+        // Scope sets are compared in canonical form; the default set keeps
+        // the name entries had before.
+        assert!(scope_set(&["email", "openid"]).is_empty());
+        assert!(
+            scope_set(&["openid", "https://www.googleapis.com/auth/userinfo.email"]).is_empty()
+        );
+        let cloud = scope_set(&[
+            "openid",
+            "email",
+            "https://www.googleapis.com/auth/cloud-platform",
+            "openid",
+        ]);
+        assert_eq!(
+            cloud,
+            [
+                "https://www.googleapis.com/auth/cloud-platform",
+                "https://www.googleapis.com/auth/userinfo.email",
+                "openid"
+            ]
+        );
+        let key = entry_key("me@example.com", "1.apps.googleusercontent.com", &cloud);
+        assert_ne!(
+            key,
+            entry_key("me@example.com", "1.apps.googleusercontent.com", &[])
+        );
+        assert_eq!(
+            split_entry_key(&key),
+            (
+                "me@example.com",
+                "1.apps.googleusercontent.com",
+                "https://www.googleapis.com/auth/cloud-platform \
+                 https://www.googleapis.com/auth/userinfo.email openid"
+            )
+        );
+        assert_eq!(
+            split_entry_key("me@example.com:1.apps.googleusercontent.com"),
+            ("me@example.com", "1.apps.googleusercontent.com", "")
+        );
+        assert_eq!(
+            split_entry_key("me@example.com"),
+            ("me@example.com", "", "")
+        );
+
+        let mut token = SavedToken {
+            refresh_token: "r".into(),
+            access_token: "a".into(),
+            id_token: None,
+            token_expiry: Utc::now(),
+            refresh_token_issued_at: None,
+            granted_scopes: Vec::new(),
+            requested_scopes: Vec::new(),
+            client_id: String::new(),
+        };
+        assert!(issued_to(&token, "1.apps.googleusercontent.com"));
+        token.client_id = "2.apps.googleusercontent.com".into();
+        assert!(!issued_to(&token, "1.apps.googleusercontent.com"));
+        assert!(issued_to(&token, "2.apps.googleusercontent.com"));
+    }
+
+    #[test]
+    fn test_account_key_prefers_sub() {
+        let token = |claims: &str| SavedToken {
+            refresh_token: "r".into(),
+            access_token: "a".into(),
+            id_token: Some(format!(
+                "{}.{}.",
+                URL_SAFE_NO_PAD.encode(r#"{"alg":"none"}"#),
+                URL_SAFE_NO_PAD.encode(claims)
+            )),
+            token_expiry: Utc::now(),
+            refresh_token_issued_at: None,
+            granted_scopes: Vec::new(),
+            requested_scopes: Vec::new(),
+            client_id: String::new(),
+        };
+        assert_eq!(
+            account_key(
+                &token(r#"{"sub":"1234","email":"me@example.com"}"#),
+                "default"
+            ),
+            "1234"
+        );
+        assert_eq!(
+            account_key(&token(r#"{"email":"me@example.com"}"#), "default"),
+            "me@example.com"
+        );
+        // Two accounts without an email no longer share one entry.
+        assert_eq!(account_key(&token(r#"{"sub":"5678"}"#), "default"), "5678");
+        assert_eq!(account_key(&token("{}"), "default"), "default");
+    }
+
+    #[test]
+    fn test_state_files_in_configured_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let configured = state_dir_from(Some(dir.path().into()));
+        assert_eq!(configured.as_deref(), Some(dir.path()));
+        assert_ne!(state_dir_from(Some("".into())), configured);
+        assert_eq!(
+            state_file_in(configured.clone(), "probe").unwrap(),
+            dir.path().join("probe")
+        );
+
+        write_state_in(configured.clone(), "probe", b"me@example.com").unwrap();
+        assert_eq!(
+            read_state_in(configured.clone(), "probe").unwrap(),
+            b"me@example.com"
+        );
+        remove_state_in(configured.clone(), "probe").unwrap();
+        assert!(read_state_in(configured.clone(), "probe").is_none());
+        remove_state_in(configured, "probe").unwrap();
+    }
+
+    #[test]
+    fn test_file_account() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token.json");
+        assert!(file_account(&path).is_none());
+
+        let token = SavedToken {
+            refresh_token: "r".into(),
+            access_token: "a".into(),
+            id_token: Some(encode_dummy_id_token_with_email("me@example.com")),
+            token_expiry: Utc::now(),
+            refresh_token_issued_at: None,
+            granted_scopes: Vec::new(),
+            requested_scopes: Vec::new(),
+            client_id: "1.apps.googleusercontent.com".into(),
+        };
+        write_token_file(&path, &token).unwrap();
+        let account = file_account(&path).unwrap();
+        assert_eq!(account.user, "me@example.com");
+        assert_eq!(account.clients, ["1.apps.googleusercontent.com"]);
+        assert!(account.last_used.is_some());
+    }
+
+    #[test]
+    fn test_directory_cache_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DirectoryCache::new(dir.path().join("tokens"));
+        let user = "me@example.com:1.apps.googleusercontent.com";
+        assert!(cache.load(user).unwrap().is_none());
+        assert_eq!(cache.users(), None);
+
+        let token = SavedToken {
+            refresh_token: "r".into(),
+            access_token: "a".into(),
+            id_token: None,
+            token_expiry: "2025-01-01T00:00:00Z".parse().unwrap(),
+            refresh_token_issued_at: None,
+            granted_scopes: Vec::new(),
+            requested_scopes: Vec::new(),
+            client_id: String::new(),
+        };
+        cache.save(user, &token).unwrap();
+        assert_eq!(cache.load(user).unwrap().unwrap().refresh_token, "r");
+        assert_eq!(cache.users().unwrap(), [user]);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(cache.path(user)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        cache.delete(user).unwrap();
+        cache.delete(user).unwrap();
+        assert!(cache.load(user).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_keyring_unavailable() {
+        let missing = anyhow::Error::from(keyring::Error::NoStorageAccess("no dbus".into()));
+        assert!(keyring_unavailable(&missing));
+        assert!(!keyring_unavailable(&anyhow::Error::from(
+            keyring::Error::NoEntry
+        )));
+        assert!(!keyring_unavailable(&anyhow!("other")));
+    }
+
+    #[test]
+    fn test_distinct_users_keeps_first_occurrence() {
+        let users = distinct_users([
+            Some("b@example.com".to_string()),
+            None,
+            Some("a@example.com".to_string()),
+            Some("b@example.com".to_string()),
+        ]);
+        assert_eq!(users, ["b@example.com", "a@example.com"]);
+    }
+
     #[test]
     fn test_load_save_with_file_cache() {
         unsafe {
@@ -115,14 +2421,102 @@ mod tests {
         let token = SavedToken {
             refresh_token: "r".into(),
             access_token: "a".into(),
-            id_token: encode_dummy_id_token_with_email("test@example.com"),
-            token_expiry: "2025-01-01T00:00:00Z".into(),
+            id_token: Some(encode_dummy_id_token_with_email("test@example.com")),
+            token_expiry: "2025-01-01T00:00:00Z".parse().unwrap(),
+            refresh_token_issued_at: None,
+            granted_scopes: Vec::new(),
+            requested_scopes: Vec::new(),
+            client_id: String::new(),
         };
 
         save_token(&token).unwrap();
-        let loaded = load_cached_token().unwrap();
+        let loaded = load_cached_token("").unwrap();
         assert_eq!(loaded.refresh_token, "r");
         assert_eq!(loaded.id_token, token.id_token);
+
+        delete_token("").unwrap();
+        assert!(load_cached_token("").is_none());
+        delete_token("").unwrap();
+    }
+
+    #[test]
+    fn test_memoized_token_served_until_expiry() {
+        let mut token = SavedToken {
+            refresh_token: "r".into(),
+            access_token: "a".into(),
+            id_token: Some("i".into()),
+            token_expiry: Utc::now() + chrono::Duration::minutes(5),
+            refresh_token_issued_at: None,
+            granted_scopes: Vec::new(),
+            requested_scopes: Vec::new(),
+            client_id: String::new(),
+        };
+
+        memoize("memo@example.com", &token);
+        assert!(memoized("memo@example.com").is_some());
+        assert!(memoized("other@example.com").is_none());
+
+        token.token_expiry = Utc::now() - chrono::Duration::minutes(5);
+        memoize("memo@example.com", &token);
+        assert!(memoized("memo@example.com").is_none());
+    }
+
+    #[test]
+    fn test_view_survives_wall_clock_jumping_back() {
+        let now = Utc::now();
+        let claims = format!(
+            r#"{{"exp":{}}}"#,
+            (now + chrono::Duration::minutes(5)).timestamp()
+        );
+        // Seen a moment ago with ten minutes left, but the wall clock has
+        // since been set back an hour.
+        let secret = Secret {
+            user: "jump@example.com".into(),
+            token: SavedToken {
+                refresh_token: "r".into(),
+                access_token: "a".into(),
+                id_token: None,
+                token_expiry: now + chrono::Duration::minutes(70),
+                refresh_token_issued_at: None,
+                granted_scopes: Vec::new(),
+                requested_scopes: Vec::new(),
+                client_id: String::new(),
+            },
+            seen: Instant::now(),
+            seen_wall: now + chrono::Duration::minutes(60),
+        };
+        assert!(secret.view().token_expiry <= now + chrono::Duration::minutes(11));
+
+        let secret = Secret {
+            user: secret.user.clone(),
+            token: SavedToken {
+                id_token: Some(format!("e30.{}.sig", URL_SAFE_NO_PAD.encode(claims))),
+                ..secret.token.clone()
+            },
+            seen: secret.seen,
+            seen_wall: secret.seen_wall,
+        };
+        assert!(secret.view().token_expiry <= now + chrono::Duration::minutes(5));
+    }
+
+    #[test]
+    fn test_refresh_token_only_policy_strips_short_lived_tokens() {
+        let token = SavedToken {
+            refresh_token: "r".into(),
+            access_token: "a".into(),
+            id_token: Some("i".into()),
+            token_expiry: Utc::now() + chrono::Duration::minutes(5),
+            refresh_token_issued_at: None,
+            granted_scopes: Vec::new(),
+            requested_scopes: Vec::new(),
+            client_id: String::new(),
+        };
+
+        let stored = persisted(&token, StoragePolicy::RefreshTokenOnly);
+        assert_eq!(stored.refresh_token, "r");
+        assert!(stored.access_token.is_empty() && stored.id_token.is_none());
+        assert!(stored.token_expiry < Utc::now());
+        assert_eq!(persisted(&token, StoragePolicy::Full).access_token, "a");
     }
 
     /// Creates a fake but structurally valid JWT with an email field in the payload.
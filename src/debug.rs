@@ -0,0 +1,183 @@
+//! Opt-in logging of OAuth HTTP exchanges with credentials redacted.
+//!
+//! When enabled, every request to Google is logged to stderr with its method,
+//! URL, form body, response status, and timing, plus the body of failed
+//! responses. Tokens, authorization codes, and client secrets are replaced by
+//! `[REDACTED]` so the output can be attached to bug reports as is.
+
+use crate::user_agent::user_agent_header;
+use anyhow::Result;
+use reqwest::header::USER_AGENT;
+use reqwest::{RequestBuilder, Response};
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+/// Environment variable that enables debug logging when set to anything but `0`.
+pub const DEBUG_ENV: &str = "GCLOUD_IDENTITY_TOKEN_DEBUG";
+
+/// Parameter and field names whose values are never logged.
+const SENSITIVE_KEYS: &[&str] = &[
+    "access_token",
+    "assertion",
+    "client_secret",
+    "code",
+    "code_verifier",
+    "id_token",
+    "password",
+    "refresh_token",
+    "subject_token",
+    "token",
+];
+
+const REDACTED: &str = "[REDACTED]";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turn debug logging on or off for this process, e.g. from a `--debug` flag.
+pub fn set_debug(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether debug logging is on, via [`set_debug`] or `GCLOUD_IDENTITY_TOKEN_DEBUG`.
+pub fn debug_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+        || std::env::var(DEBUG_ENV).is_ok_and(|value| !value.is_empty() && value != "0")
+}
+
+/// Send `req` with the crate's `User-Agent`, logging the exchange when debug
+/// logging is on.
+///
+/// The body of a failed response is read for logging and handed back to the
+/// caller in a rebuilt [`Response`], so callers can still parse it.
+pub(crate) async fn send(req: RequestBuilder) -> Result<Response> {
+    let req = req.header(USER_AGENT, user_agent_header());
+    if !debug_enabled() {
+        return Ok(req.send().await?);
+    }
+
+    let (client, req) = req.build_split();
+    let req = req?;
+    let (method, url) = (req.method().clone(), redact_url(req.url()));
+    match req.body().and_then(|body| body.as_bytes()) {
+        Some(body) => eprintln!("[debug] {method} {url} {}", redact_form(body)),
+        None => eprintln!("[debug] {method} {url}"),
+    }
+
+    let started = Instant::now();
+    let res = client.execute(req).await.inspect_err(|err| {
+        eprintln!(
+            "[debug] {method} {url} failed after {:?}: {err}",
+            started.elapsed()
+        );
+    })?;
+    let status = res.status();
+    eprintln!(
+        "[debug] {method} {url} -> {status} in {:?}",
+        started.elapsed()
+    );
+    if status.is_success() {
+        return Ok(res);
+    }
+
+    let headers = res.headers().clone();
+    let body = res.bytes().await?;
+    eprintln!("[debug] response body: {}", redact_body(&body));
+
+    let mut rebuilt = http::Response::new(body.to_vec());
+    *rebuilt.status_mut() = status;
+    *rebuilt.headers_mut() = headers;
+    Ok(rebuilt.into())
+}
+
+fn is_sensitive(key: &str) -> bool {
+    SENSITIVE_KEYS.contains(&key)
+}
+
+/// Render `url` with sensitive query parameters redacted.
+fn redact_url(url: &url::Url) -> String {
+    if url.query().is_none() {
+        return url.to_string();
+    }
+    let mut redacted = url.clone();
+    redacted
+        .query_pairs_mut()
+        .clear()
+        .extend_pairs(url.query_pairs().map(|(key, value)| {
+            let value = if is_sensitive(&key) {
+                REDACTED.into()
+            } else {
+                value
+            };
+            (key, value)
+        }));
+    redacted.to_string()
+}
+
+/// Render a form-encoded body with sensitive fields redacted.
+fn redact_form(body: &[u8]) -> String {
+    url::form_urlencoded::parse(body)
+        .map(|(key, value)| {
+            let value = if is_sensitive(&key) { REDACTED } else { &value };
+            format!("{key}={value}")
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Render a response body, redacting sensitive fields when it is JSON.
+fn redact_body(body: &[u8]) -> String {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(mut json) => {
+            redact_json(&mut json);
+            json.to_string()
+        }
+        Err(_) => String::from_utf8_lossy(body).into_owned(),
+    }
+}
+
+fn redact_json(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                if is_sensitive(key) {
+                    *value = Value::String(REDACTED.into());
+                } else {
+                    redact_json(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_json),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_form() {
+        let body =
+            b"client_id=abc&client_secret=s3cret&refresh_token=1%2F2&grant_type=refresh_token";
+        assert_eq!(
+            redact_form(body),
+            "client_id=abc&client_secret=[REDACTED]&refresh_token=[REDACTED]&grant_type=refresh_token"
+        );
+    }
+
+    #[test]
+    fn test_redact_url_and_json_body() {
+        let url = url::Url::parse("https://example.com/cb?code=abc&state=xyz").unwrap();
+        assert_eq!(
+            redact_url(&url),
+            "https://example.com/cb?code=%5BREDACTED%5D&state=xyz"
+        );
+
+        let body = br#"{"error":"invalid_grant","nested":{"id_token":"jwt"}}"#;
+        assert_eq!(
+            redact_body(body),
+            r#"{"error":"invalid_grant","nested":{"id_token":"[REDACTED]"}}"#
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! OAuth 2.0 device authorization grant (RFC 8628) for machines without a browser.
+//!
+//! The user opens a short URL on any device and types the code shown here
+//! while this machine polls the token endpoint. Nothing has to reach back to
+//! this machine, so the flow works over plain SSH. Google only offers it to
+//! OAuth clients of type "TVs and Limited Input devices", for a limited set of
+//! scopes that includes `openid` and `email`.
+
+use crate::auth::{login_scopes, saved_from_login};
+use crate::config::{Creds, LoginOptions, SavedToken, TokenErrorResponse, TokenResponse};
+use crate::debug;
+use anyhow::{Result, anyhow};
+use reqwest::Client;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+/// Google's device authorization endpoint.
+const DEVICE_CODE_URL: &str = "https://oauth2.googleapis.com/device/code";
+
+/// Grant type of the device code polls.
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// Polling interval used when the server suggests none (RFC 8628 §3.2).
+const DEFAULT_POLL_INTERVAL: u64 = 5;
+
+/// How much a `slow_down` answer lengthens the polling interval (RFC 8628 §3.5).
+const SLOW_DOWN_STEP: Duration = Duration::from_secs(5);
+
+/// Response of the device authorization endpoint.
+#[derive(Debug, Deserialize)]
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    /// Google's name for RFC 8628's `verification_uri`
+    #[serde(alias = "verification_uri")]
+    verification_url: String,
+    expires_in: u64,
+    #[serde(default = "default_poll_interval")]
+    interval: u64,
+}
+
+fn default_poll_interval() -> u64 {
+    DEFAULT_POLL_INTERVAL
+}
+
+/// Run the device flow requesting `scopes`, without caching.
+///
+/// Prints the verification URL and user code to stderr, then polls until the
+/// user approves or declines, or the code expires.
+pub(crate) async fn device_login(
+    creds: &Creds,
+    scopes: &[&str],
+    opts: &LoginOptions,
+) -> Result<SavedToken> {
+    let scopes = login_scopes(scopes, opts);
+    let client = Client::new();
+    let res = debug::send(client.post(DEVICE_CODE_URL).form(&[
+        ("client_id", creds.client_id.as_str()),
+        ("scope", scopes.join(" ").as_str()),
+    ]))
+    .await?;
+    if !res.status().is_success() {
+        let err = res.json::<TokenErrorResponse>().await?;
+        let hint = if matches!(err.error.as_str(), "invalid_client" | "unauthorized_client") {
+            "; the device flow needs an OAuth client of type \"TVs and Limited Input devices\""
+        } else {
+            ""
+        };
+        return Err(anyhow!("Device authorization failed: {err}{hint}"));
+    }
+    let authorization = res.json::<DeviceAuthorization>().await?;
+
+    eprintln!(
+        "\nTo log in, open {} on any device and enter the code:\n\n    {}\n",
+        authorization.verification_url, authorization.user_code
+    );
+
+    let deadline = Instant::now() + Duration::from_secs(authorization.expires_in);
+    let mut interval = Duration::from_secs(authorization.interval.max(1));
+    loop {
+        tokio::time::sleep(interval).await;
+        if Instant::now() >= deadline {
+            return Err(anyhow!("The code expired before the login was approved"));
+        }
+
+        let res = debug::send(client.post("https://oauth2.googleapis.com/token").form(&[
+            ("client_id", creds.client_id.as_str()),
+            ("client_secret", creds.client_secret.as_str()),
+            ("device_code", authorization.device_code.as_str()),
+            ("grant_type", DEVICE_CODE_GRANT),
+        ]))
+        .await?;
+        if res.status().is_success() {
+            return saved_from_login(creds, res.json::<TokenResponse>().await?, opts);
+        }
+        interval = next_interval(&res.json::<TokenErrorResponse>().await?, interval)?;
+    }
+}
+
+/// The interval to wait before polling again after `err`, or the error that
+/// ends the login.
+fn next_interval(err: &TokenErrorResponse, interval: Duration) -> Result<Duration> {
+    match err.error.as_str() {
+        "authorization_pending" => Ok(interval),
+        "slow_down" => Ok(interval + SLOW_DOWN_STEP),
+        "access_denied" => Err(anyhow!("The login was declined")),
+        "expired_token" => Err(anyhow!("The code expired before the login was approved")),
+        _ => Err(anyhow!("Device login failed: {err}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_authorization_accepts_google_field_names() {
+        let body = r#"{"device_code":"d","user_code":"ABC-DEF-GHI","expires_in":1800,"verification_url":"https://www.google.com/device"}"#;
+        let authorization: DeviceAuthorization = serde_json::from_str(body).unwrap();
+        assert_eq!(
+            authorization.verification_url,
+            "https://www.google.com/device"
+        );
+        assert_eq!(authorization.interval, DEFAULT_POLL_INTERVAL);
+
+        let body = r#"{"device_code":"d","user_code":"u","expires_in":600,"interval":2,"verification_uri":"https://example.com/device"}"#;
+        let authorization: DeviceAuthorization = serde_json::from_str(body).unwrap();
+        assert_eq!(authorization.verification_url, "https://example.com/device");
+        assert_eq!(authorization.interval, 2);
+    }
+
+    #[test]
+    fn test_next_interval_follows_poll_errors() {
+        let err = |error: &str| TokenErrorResponse {
+            error: error.into(),
+            error_description: None,
+        };
+        let interval = Duration::from_secs(5);
+        assert_eq!(
+            next_interval(&err("authorization_pending"), interval).unwrap(),
+            interval
+        );
+        assert_eq!(
+            next_interval(&err("slow_down"), interval).unwrap(),
+            Duration::from_secs(10)
+        );
+        assert!(next_interval(&err("access_denied"), interval).is_err());
+        assert!(next_interval(&err("expired_token"), interval).is_err());
+    }
+}
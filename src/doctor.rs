@@ -0,0 +1,274 @@
+//! Environment diagnostics behind the `doctor` command.
+//!
+//! Each check reports pass, warning, or failure along with a hint on how to
+//! fix it, so login problems can be diagnosed without reading the source.
+
+use crate::auth::{TokenStatus, introspect};
+use crate::browser::{LoginSession, is_headless_env};
+use crate::cache::{check_keyring, fallback_dir, file_cache_path, load_cached_token};
+use crate::config::{creds_path, load_creds};
+use crate::debug;
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use reqwest::header::DATE;
+use std::fmt;
+
+/// Token endpoint probed for connectivity and clock skew.
+const TOKEN_ENDPOINT: &str = "https://oauth2.googleapis.com/token";
+
+/// Clock difference beyond which tokens may be rejected as not yet valid or expired.
+const MAX_CLOCK_SKEW_SECS: i64 = 60;
+
+/// Result of a single check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Everything is in order
+    Pass,
+    /// Works, but may cause surprises
+    Warn,
+    /// Will prevent logins or refreshes
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        })
+    }
+}
+
+/// One diagnostic and its outcome.
+#[derive(Debug, Clone)]
+pub struct Check {
+    /// What was checked
+    pub name: &'static str,
+    /// Outcome of the check
+    pub status: Status,
+    /// What was found
+    pub detail: String,
+    /// How to fix a warning or failure
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn warn(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            status: Status::Warn,
+            hint: Some(hint.into()),
+            ..Self::pass(name, detail)
+        }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            status: Status::Fail,
+            ..Self::warn(name, detail, hint)
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}: {}", self.status, self.name, self.detail)?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\n       hint: {hint}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Run every check in order.
+pub async fn run_checks() -> Vec<Check> {
+    let mut checks = vec![check_creds(), check_cache(), check_display(), check_port()];
+    checks.extend(check_token_endpoint().await);
+    checks.extend(check_cached_token().await);
+    checks
+}
+
+fn check_creds() -> Check {
+    const NAME: &str = "Credentials file";
+    let path = match creds_path() {
+        Ok(path) => path,
+        Err(err) => return Check::fail(NAME, err.to_string(), "Set HOME to your home directory."),
+    };
+    match load_creds() {
+        Ok(_) => Check::pass(NAME, path.display().to_string()),
+        Err(err) => Check::fail(
+            NAME,
+            format!("{}: {err}", path.display()),
+            "Run `gcloud auth application-default login`, or place an OAuth client's \
+             client_id and client_secret in that file.",
+        ),
+    }
+}
+
+fn check_cache() -> Check {
+    const NAME: &str = "Token cache";
+    if let Some(path) = file_cache_path() {
+        return Check::pass(NAME, format!("file {}", path.display()));
+    }
+    match (check_keyring(), fallback_dir()) {
+        (Ok(()), _) => Check::pass(NAME, "system keyring reachable"),
+        (Err(err), Some(dir)) => Check::warn(
+            NAME,
+            format!(
+                "system keyring unavailable ({err}); tokens are cached in {} instead",
+                dir.display()
+            ),
+            "Start a Secret Service provider (e.g. gnome-keyring) to use the keyring.",
+        ),
+        (Err(err), None) => Check::fail(
+            NAME,
+            format!("system keyring unavailable: {err}"),
+            "Start a Secret Service provider (e.g. gnome-keyring), or set \
+             GCLOUD_IDENTITY_TOKEN_PATH to use a file cache.",
+        ),
+    }
+}
+
+fn check_display() -> Check {
+    const NAME: &str = "Browser";
+    if is_wsl() {
+        return Check::warn(
+            NAME,
+            "running under WSL",
+            "Set BROWSER=wslview (from wslu) so logins open in the Windows browser.",
+        );
+    }
+    if cfg!(target_os = "linux") && is_headless_env() {
+        if std::env::var("BROWSER").is_ok() {
+            return Check::pass(NAME, "no display, but BROWSER is set");
+        }
+        return Check::warn(
+            NAME,
+            "no DISPLAY or WAYLAND_DISPLAY; the login URL will be printed instead",
+            "Open the printed URL on a machine with a browser, or set BROWSER.",
+        );
+    }
+    Check::pass(NAME, "display available")
+}
+
+fn is_wsl() -> bool {
+    std::env::var("WSL_DISTRO_NAME").is_ok()
+        || std::fs::read_to_string("/proc/sys/kernel/osrelease")
+            .is_ok_and(|release| release.to_ascii_lowercase().contains("microsoft"))
+}
+
+fn check_port() -> Check {
+    const NAME: &str = "Redirect port";
+    match LoginSession::from_env() {
+        Ok(session) => Check::pass(NAME, format!("can listen on port {}", session.port())),
+        Err(err) => Check::fail(
+            NAME,
+            err.to_string(),
+            "Free the port, or change GCLOUD_IDENTITY_TOKEN_PORT or \
+             GCLOUD_IDENTITY_TOKEN_PORT_RANGE.",
+        ),
+    }
+}
+
+/// Ask Google about the cached access token, if there is an unexpired one.
+async fn check_cached_token() -> Option<Check> {
+    const NAME: &str = "Cached token";
+    let client_id = load_creds().ok()?.client_id;
+    let saved = load_cached_token(&client_id).filter(|saved| saved.token_expiry > Utc::now())?;
+    Some(match introspect(&saved.access_token).await {
+        Ok(TokenStatus::Valid(info)) => Check::pass(
+            NAME,
+            format!(
+                "accepted by Google until {}, scopes: {}",
+                info.expiry,
+                info.scopes.join(" ")
+            ),
+        ),
+        Ok(TokenStatus::Invalid { reason }) => Check::fail(
+            NAME,
+            format!(
+                "rejected by Google ({reason}) although cached until {}",
+                saved.token_expiry
+            ),
+            "The grant was probably revoked; run `gcloud-identity-token logout` and log in again.",
+        ),
+        Err(err) => Check::warn(
+            NAME,
+            format!("could not be checked: {}", err.root_cause()),
+            "Check network access and HTTPS_PROXY settings.",
+        ),
+    })
+}
+
+/// Check that the token endpoint answers, and compare clocks using its `Date` header.
+async fn check_token_endpoint() -> Vec<Check> {
+    const NAME: &str = "Token endpoint";
+    let res = match debug::send(Client::new().get(TOKEN_ENDPOINT)).await {
+        Ok(res) => res,
+        Err(err) => {
+            return vec![Check::fail(
+                NAME,
+                format!("{TOKEN_ENDPOINT} unreachable: {}", err.root_cause()),
+                "Check network access and HTTPS_PROXY settings.",
+            )];
+        }
+    };
+
+    let connectivity = Check::pass(NAME, format!("{TOKEN_ENDPOINT} reachable"));
+    let server_time = res
+        .headers()
+        .get(DATE)
+        .and_then(|date| date.to_str().ok())
+        .and_then(|date| DateTime::parse_from_rfc2822(date).ok());
+    vec![
+        connectivity,
+        clock_check(server_time.map(|t| t.with_timezone(&Utc)), Utc::now()),
+    ]
+}
+
+fn clock_check(server_time: Option<DateTime<Utc>>, now: DateTime<Utc>) -> Check {
+    const NAME: &str = "Clock skew";
+    let Some(server_time) = server_time else {
+        return Check::warn(
+            NAME,
+            "server sent no Date header",
+            "Compare your clock with an NTP server.",
+        );
+    };
+    let skew = (now - server_time).num_seconds();
+    if skew.abs() > MAX_CLOCK_SKEW_SECS {
+        Check::fail(
+            NAME,
+            format!("local clock is {skew}s off from Google"),
+            "Enable time synchronization (e.g. `timedatectl set-ntp true`).",
+        )
+    } else {
+        Check::pass(NAME, format!("{skew}s"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_clock_check_flags_large_skew() {
+        let now = Utc::now();
+        assert_eq!(clock_check(Some(now), now).status, Status::Pass);
+        assert_eq!(
+            clock_check(Some(now - Duration::minutes(5)), now).status,
+            Status::Fail
+        );
+        assert_eq!(clock_check(None, now).status, Status::Warn);
+    }
+}
@@ -0,0 +1,59 @@
+//! Typed errors for conditions callers may want to handle.
+//!
+//! Functions in this crate return `anyhow::Result`; errors listed here can be
+//! recovered with `err.downcast_ref::<AuthError>()`.
+
+use std::fmt;
+
+/// An authentication failure with a well-defined cause.
+#[derive(Debug)]
+pub enum AuthError {
+    /// The login was cancelled before it completed
+    Cancelled,
+    /// No usable refresh token is cached, so an interactive login is needed
+    LoginRequired,
+    /// The browser login's redirect did not arrive in time, e.g. because the
+    /// tab was closed
+    LoginTimedOut,
+    /// Google redirected back with an error instead of an authorization code
+    AuthorizationFailed {
+        /// OAuth error code, e.g. `access_denied` when the user clicked Cancel
+        error: String,
+        /// Google's explanation, when it gave one
+        description: Option<String>,
+    },
+}
+
+impl AuthError {
+    /// Stable snake_case identifier for machine-readable output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AuthError::Cancelled => "cancelled",
+            AuthError::LoginRequired => "login_required",
+            AuthError::LoginTimedOut => "login_timed_out",
+            AuthError::AuthorizationFailed { .. } => "authorization_failed",
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Cancelled => write!(f, "Login cancelled"),
+            AuthError::LoginRequired => write!(f, "Interactive login required"),
+            AuthError::LoginTimedOut => write!(f, "Timed out waiting for the browser login"),
+            AuthError::AuthorizationFailed { error, .. } if error == "access_denied" => {
+                write!(f, "Login was cancelled in the browser")
+            }
+            AuthError::AuthorizationFailed { error, description } => {
+                write!(f, "Authorization failed: {error}")?;
+                match description {
+                    Some(description) => write!(f, " ({description})"),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
@@ -0,0 +1,444 @@
+//! Workload identity federation through `external_account` credentials.
+//!
+//! A token issued by another identity provider, such as a GitHub Actions OIDC
+//! token, is read from the configured credential source and exchanged at
+//! Google's Security Token Service for a federated access token. When the
+//! configuration names a service account, that token is then traded for one
+//! of the service account's through the IAM Credentials API.
+
+use crate::auth::CLOUD_PLATFORM_SCOPE;
+use crate::aws;
+use crate::config::TokenOutput;
+use crate::debug;
+use crate::iam::generate_access_token_at;
+use crate::output::{EXECUTABLE_INTERACTIVE_ENV, EXECUTABLE_OUTPUT_FILE_ENV};
+use crate::stats::{self, Event};
+use crate::sts::{self, ExchangeOptions};
+use anyhow::{Context, Result, anyhow};
+use chrono::{Duration, Utc};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+pub use crate::aws::AwsSource;
+pub use crate::sts::STS_TOKEN_URL;
+
+/// Environment variable that must be `1` before an executable source may run.
+pub const ALLOW_EXECUTABLES_ENV: &str = "GOOGLE_EXTERNAL_ACCOUNT_ALLOW_EXECUTABLES";
+
+/// Time an executable source gets when its configuration sets no timeout.
+const DEFAULT_EXECUTABLE_TIMEOUT_MILLIS: u64 = 30_000;
+
+/// An `external_account` credential configuration, as written by
+/// `gcloud iam workload-identity-pools create-cred-config`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ExternalAccountCreds {
+    /// Full resource name of the workload identity pool provider
+    pub audience: String,
+    /// Type of the token the credential source yields, e.g.
+    /// `urn:ietf:params:oauth:token-type:jwt`
+    pub subject_token_type: String,
+    /// STS endpoint the subject token is exchanged at
+    #[serde(default = "default_token_url")]
+    pub token_url: String,
+    /// Where the subject token comes from
+    pub credential_source: CredentialSource,
+    /// `generateAccessToken` URL of a service account to impersonate
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_account_impersonation_url: Option<String>,
+    /// Project billed for workforce pool requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workforce_pool_user_project: Option<String>,
+}
+
+fn default_token_url() -> String {
+    STS_TOKEN_URL.to_string()
+}
+
+/// Source of the external subject token.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum CredentialSource {
+    /// The signed GetCallerIdentity request of an AWS workload
+    Aws(AwsSource),
+    /// A file rewritten by some other process, e.g. a projected Kubernetes token
+    File {
+        /// Path of the token file
+        file: PathBuf,
+        /// How the token is stored in the file
+        #[serde(default)]
+        format: SubjectTokenFormat,
+    },
+    /// A URL answering GET requests with the token
+    Url {
+        /// Token URL
+        url: String,
+        /// Headers sent with the request
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        headers: BTreeMap<String, String>,
+        /// How the token is stored in the response body
+        #[serde(default)]
+        format: SubjectTokenFormat,
+    },
+    /// A command printing an executable response, run only when
+    /// `GOOGLE_EXTERNAL_ACCOUNT_ALLOW_EXECUTABLES=1`
+    Executable {
+        /// Command and timeout
+        executable: ExecutableSource,
+    },
+}
+
+/// How a subject token is stored in a file or response body.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SubjectTokenFormat {
+    /// The whole content, trimmed, is the token
+    #[default]
+    Text,
+    /// The content is a JSON object holding the token in a field
+    Json {
+        /// Name of the field holding the token
+        subject_token_field_name: String,
+    },
+}
+
+/// An executable credential source.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ExecutableSource {
+    /// Command line, split on whitespace
+    pub command: String,
+    /// Milliseconds the command may run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_millis: Option<u64>,
+    /// File the command caches its response in, read before running it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_file: Option<PathBuf>,
+}
+
+/// Response printed by an executable source.
+#[derive(Deserialize)]
+struct ExecutableOutput {
+    success: bool,
+    #[serde(default)]
+    id_token: Option<String>,
+    #[serde(default)]
+    saml_response: Option<String>,
+    #[serde(default)]
+    expiration_time: Option<i64>,
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+impl ExternalAccountCreds {
+    /// Parse an `external_account` configuration's JSON.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the JSON is invalid or has a `type` other than
+    /// `external_account`.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(json)?;
+        match value.get("type").and_then(|kind| kind.as_str()) {
+            None | Some("external_account") => Ok(serde_json::from_value(value)?),
+            Some(other) => Err(anyhow!(
+                "Expected an external_account configuration, got credentials of type {other:?}"
+            )),
+        }
+    }
+}
+
+/// Load an `external_account` configuration file.
+pub fn load_external_account_creds(path: &Path) -> Result<ExternalAccountCreds> {
+    ExternalAccountCreds::from_json(&std::fs::read_to_string(path)?)
+}
+
+/// Obtain an access token through workload identity federation.
+///
+/// `scopes` defaults to `cloud-platform` when empty. Each call reads a fresh
+/// subject token and exchanges it; callers should reuse the result until
+/// `token_expiry`. There is no ID token.
+pub async fn get_token_for_external_account(
+    creds: &ExternalAccountCreds,
+    scopes: &[&str],
+) -> Result<TokenOutput<'static>> {
+    exchange(creds, scopes)
+        .await
+        .inspect_err(|_| stats::record(Event::Failure))
+}
+
+async fn exchange(creds: &ExternalAccountCreds, scopes: &[&str]) -> Result<TokenOutput<'static>> {
+    let scopes = if scopes.is_empty() {
+        vec![CLOUD_PLATFORM_SCOPE]
+    } else {
+        scopes.to_vec()
+    };
+    let client = Client::new();
+    let subject_token = subject_token(&client, creds)
+        .await
+        .context("Failed to read the external subject token")?;
+
+    // An impersonating exchange needs cloud-platform; the requested scopes
+    // apply to the service account's token instead.
+    let sts_scopes = match creds.service_account_impersonation_url {
+        Some(_) => vec![CLOUD_PLATFORM_SCOPE.to_string()],
+        None => scopes.iter().map(|scope| scope.to_string()).collect(),
+    };
+    let opts = ExchangeOptions {
+        subject_token_type: creds.subject_token_type.clone(),
+        scopes: sts_scopes,
+        token_url: creds.token_url.clone(),
+        options: creds
+            .workforce_pool_user_project
+            .as_ref()
+            .map(|project| serde_json::json!({ "userProject": project })),
+        ..ExchangeOptions::default()
+    };
+    let sts = sts::exchange(&subject_token, &creds.audience, &opts).await?;
+    stats::record(Event::Refresh);
+
+    let (access_token, token_expiry) = match &creds.service_account_impersonation_url {
+        Some(url) => generate_access_token_at(&client, url, &sts.access_token, &scopes).await?,
+        None => {
+            let expiry = sts
+                .expiry()
+                .unwrap_or_else(|| Utc::now() + Duration::hours(1));
+            (sts.access_token, expiry)
+        }
+    };
+    Ok(TokenOutput {
+        access_token: Box::leak(access_token.into_boxed_str()),
+        id_token: None,
+        token_expiry,
+    })
+}
+
+/// Read the subject token from the configured source.
+async fn subject_token(client: &Client, creds: &ExternalAccountCreds) -> Result<String> {
+    match &creds.credential_source {
+        CredentialSource::Aws(source) => aws::subject_token(client, source, &creds.audience).await,
+        CredentialSource::File { file, format } => {
+            let content = tokio::fs::read_to_string(file)
+                .await
+                .with_context(|| format!("Failed to read {}", file.display()))?;
+            format.extract(&content)
+        }
+        CredentialSource::Url {
+            url,
+            headers,
+            format,
+        } => {
+            let mut req = client.get(url);
+            for (name, value) in headers {
+                req = req.header(name, value);
+            }
+            let res = debug::send(req).await?;
+            if !res.status().is_success() {
+                return Err(anyhow!("{url} answered HTTP {}", res.status()));
+            }
+            format.extract(&res.text().await?)
+        }
+        CredentialSource::Executable { executable } => run_executable(executable, creds).await,
+    }
+}
+
+impl SubjectTokenFormat {
+    /// Pull the token out of `content`.
+    fn extract(&self, content: &str) -> Result<String> {
+        let token = match self {
+            Self::Text => content.trim().to_string(),
+            Self::Json {
+                subject_token_field_name,
+            } => serde_json::from_str::<serde_json::Value>(content)?
+                .get(subject_token_field_name)
+                .and_then(|token| token.as_str())
+                .ok_or_else(|| anyhow!("No {subject_token_field_name:?} string in the JSON"))?
+                .to_string(),
+        };
+        if token.is_empty() {
+            return Err(anyhow!("The subject token is empty"));
+        }
+        Ok(token)
+    }
+}
+
+/// Obtain a subject token from an executable source, reusing its cached
+/// output file while the token there is unexpired.
+async fn run_executable(source: &ExecutableSource, creds: &ExternalAccountCreds) -> Result<String> {
+    if std::env::var(ALLOW_EXECUTABLES_ENV).as_deref() != Ok("1") {
+        return Err(anyhow!(
+            "Executable credential sources only run when {ALLOW_EXECUTABLES_ENV}=1"
+        ));
+    }
+    if let Some(cached) = source
+        .output_file
+        .as_ref()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|json| serde_json::from_str::<ExecutableOutput>(&json).ok())
+        .and_then(|output| output.token(Utc::now().timestamp()).ok())
+    {
+        return Ok(cached);
+    }
+
+    let mut parts = source.command.split_whitespace();
+    let program = parts
+        .next()
+        .ok_or_else(|| anyhow!("The executable source has an empty command"))?;
+    let mut cmd = tokio::process::Command::new(program);
+    cmd.args(parts)
+        .env("GOOGLE_EXTERNAL_ACCOUNT_AUDIENCE", &creds.audience)
+        .env(
+            "GOOGLE_EXTERNAL_ACCOUNT_TOKEN_TYPE",
+            &creds.subject_token_type,
+        )
+        .env(EXECUTABLE_INTERACTIVE_ENV, "0")
+        .stderr(std::process::Stdio::inherit())
+        .kill_on_drop(true);
+    if let Some(path) = &source.output_file {
+        cmd.env(EXECUTABLE_OUTPUT_FILE_ENV, path);
+    }
+    if let Some(email) = creds
+        .service_account_impersonation_url
+        .as_deref()
+        .and_then(impersonated_email)
+    {
+        cmd.env("GOOGLE_EXTERNAL_ACCOUNT_IMPERSONATED_EMAIL", email);
+    }
+
+    let timeout = std::time::Duration::from_millis(
+        source
+            .timeout_millis
+            .unwrap_or(DEFAULT_EXECUTABLE_TIMEOUT_MILLIS),
+    );
+    let output = tokio::time::timeout(timeout, cmd.output())
+        .await
+        .map_err(|_| anyhow!("{program} did not finish within {timeout:?}"))?
+        .with_context(|| format!("Failed to run {program}"))?;
+    if !output.status.success() {
+        return Err(anyhow!("{program} exited with {}", output.status));
+    }
+    serde_json::from_slice::<ExecutableOutput>(&output.stdout)
+        .with_context(|| format!("{program} printed no executable response"))?
+        .token(Utc::now().timestamp())
+}
+
+impl ExecutableOutput {
+    /// The subject token, unless the response reports failure or expired
+    /// before `now` (seconds since the Unix epoch).
+    fn token(self, now: i64) -> Result<String> {
+        if !self.success {
+            return Err(anyhow!(
+                "The executable reported {}: {}",
+                self.code.as_deref().unwrap_or("an error"),
+                self.message.as_deref().unwrap_or("no message")
+            ));
+        }
+        if self.expiration_time.is_some_and(|expiry| expiry <= now) {
+            return Err(anyhow!("The executable's token has expired"));
+        }
+        self.id_token
+            .or(self.saml_response)
+            .ok_or_else(|| anyhow!("The executable response carries no token"))
+    }
+}
+
+/// The service account email in a `generateAccessToken` URL.
+fn impersonated_email(url: &str) -> Option<&str> {
+    url.rsplit_once("/serviceAccounts/")?
+        .1
+        .strip_suffix(":generateAccessToken")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_github_actions_configuration() {
+        let json = r#"{
+            "type": "external_account",
+            "audience": "//iam.googleapis.com/projects/1/locations/global/workloadIdentityPools/gh/providers/gh",
+            "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+            "token_url": "https://sts.googleapis.com/v1/token",
+            "service_account_impersonation_url": "https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/ci@p.iam.gserviceaccount.com:generateAccessToken",
+            "credential_source": {
+                "url": "https://token.actions.githubusercontent.com/?audience=x",
+                "headers": {"Authorization": "Bearer t"},
+                "format": {"type": "json", "subject_token_field_name": "value"}
+            }
+        }"#;
+        let creds = ExternalAccountCreds::from_json(json).unwrap();
+        let CredentialSource::Url {
+            headers, format, ..
+        } = &creds.credential_source
+        else {
+            panic!("expected a URL source");
+        };
+        assert_eq!(headers["Authorization"], "Bearer t");
+        assert_eq!(format.extract(r#"{"value":"oidc"}"#).unwrap(), "oidc");
+        assert_eq!(
+            impersonated_email(creds.service_account_impersonation_url.as_deref().unwrap()),
+            Some("ci@p.iam.gserviceaccount.com")
+        );
+
+        let user = r#"{"type":"authorized_user","client_id":"a","client_secret":"b"}"#;
+        assert!(ExternalAccountCreds::from_json(user).is_err());
+    }
+
+    #[test]
+    fn test_parse_aws_configuration() {
+        let json = r#"{
+            "type": "external_account",
+            "audience": "//iam.googleapis.com/projects/1/locations/global/workloadIdentityPools/aws/providers/aws",
+            "subject_token_type": "urn:ietf:params:aws:token-type:aws4_request",
+            "credential_source": {
+                "environment_id": "aws1",
+                "region_url": "http://169.254.169.254/latest/meta-data/placement/availability-zone",
+                "url": "http://169.254.169.254/latest/meta-data/iam/security-credentials",
+                "regional_cred_verification_url": "https://sts.{region}.amazonaws.com?Action=GetCallerIdentity&Version=2011-06-15"
+            }
+        }"#;
+        let creds = ExternalAccountCreds::from_json(json).unwrap();
+        let CredentialSource::Aws(source) = &creds.credential_source else {
+            panic!("expected an AWS source");
+        };
+        assert_eq!(source.environment_id, "aws1");
+        assert!(source.imdsv2_session_token_url.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_file_source_reads_text_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("token");
+        std::fs::write(&file, "k8s-token\n").unwrap();
+        let creds = ExternalAccountCreds {
+            audience: "aud".into(),
+            subject_token_type: "urn:ietf:params:oauth:token-type:jwt".into(),
+            token_url: STS_TOKEN_URL.into(),
+            credential_source: CredentialSource::File {
+                file,
+                format: SubjectTokenFormat::Text,
+            },
+            service_account_impersonation_url: None,
+            workforce_pool_user_project: None,
+        };
+        assert_eq!(
+            subject_token(&Client::new(), &creds).await.unwrap(),
+            "k8s-token"
+        );
+    }
+
+    #[test]
+    fn test_executable_output_token() {
+        let ok = r#"{"version":1,"success":true,"token_type":"urn:ietf:params:oauth:token-type:id_token","id_token":"jwt","expiration_time":2000}"#;
+        let output = |json| serde_json::from_str::<ExecutableOutput>(json).unwrap();
+        assert_eq!(output(ok).token(1000).unwrap(), "jwt");
+        assert!(output(ok).token(3000).is_err());
+
+        let failed = r#"{"version":1,"success":false,"code":"401","message":"Login required"}"#;
+        let err = output(failed).token(0).unwrap_err();
+        assert!(err.to_string().contains("401: Login required"));
+    }
+}
@@ -0,0 +1,164 @@
+//! C ABI for embedding the login and cache machinery in non-Rust programs.
+//!
+//! Enabled by the `ffi` feature. Build a shared library with
+//! `cargo rustc --lib --release --features ffi --crate-type cdylib`.
+//!
+//! ```c
+//! char *token = NULL;
+//! if (git_get_access_token(&token) == GIT_OK) {
+//!     use(token);
+//!     git_free_string(token);
+//! } else {
+//!     char *msg = git_last_error();
+//!     fprintf(stderr, "%s\n", msg);
+//!     git_free_string(msg);
+//! }
+//! ```
+//!
+//! Credentials come from [`load_creds`] and the cache behaves as in the CLI,
+//! including the environment variables documented at the crate root.
+
+use crate::auth::get_owned_token;
+use crate::config::{LoginOptions, OwnedToken, load_creds};
+use crate::error::AuthError;
+use anyhow::{Result, anyhow};
+use std::cell::RefCell;
+use std::ffi::{CString, c_char, c_int};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::OnceLock;
+use tokio::runtime::Runtime;
+
+/// The token was written to the output pointer.
+pub const GIT_OK: c_int = 0;
+/// Any failure not covered by a more specific code; see [`git_last_error`].
+pub const GIT_ERROR: c_int = 1;
+/// The output pointer was null.
+pub const GIT_INVALID_ARGUMENT: c_int = 2;
+/// An interactive login is needed but could not be completed.
+pub const GIT_LOGIN_REQUIRED: c_int = 3;
+/// The login was cancelled.
+pub const GIT_CANCELLED: c_int = 4;
+/// The browser login's redirect did not arrive in time.
+pub const GIT_LOGIN_TIMED_OUT: c_int = 5;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Runtime shared by all calls, so in-memory caches survive between them.
+fn runtime() -> Result<&'static Runtime> {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    if let Some(runtime) = RUNTIME.get() {
+        return Ok(runtime);
+    }
+    let runtime = Runtime::new()?;
+    Ok(RUNTIME.get_or_init(|| runtime))
+}
+
+/// Fetch a token, pick one string out of it, and hand it to C through `out`.
+///
+/// # Safety
+///
+/// `out` must be null or valid for writing a pointer.
+unsafe fn export_token(out: *mut *mut c_char, pick: fn(&OwnedToken) -> Result<&str>) -> c_int {
+    if out.is_null() {
+        return GIT_INVALID_ARGUMENT;
+    }
+    let res = catch_unwind(AssertUnwindSafe(|| -> Result<CString> {
+        let creds = load_creds()?;
+        let token = runtime()?.block_on(get_owned_token(&creds, &LoginOptions::default()))?;
+        Ok(CString::new(pick(&token)?)?)
+    }))
+    .unwrap_or_else(|_| Err(anyhow!("panic while obtaining a token")));
+
+    match res {
+        Ok(token) => {
+            // SAFETY: `out` is non-null and the caller guarantees it is writable.
+            unsafe { *out = token.into_raw() };
+            GIT_OK
+        }
+        Err(err) => {
+            let code = match err.downcast_ref() {
+                Some(AuthError::LoginRequired) => GIT_LOGIN_REQUIRED,
+                Some(AuthError::Cancelled) => GIT_CANCELLED,
+                Some(AuthError::LoginTimedOut) => GIT_LOGIN_TIMED_OUT,
+                Some(AuthError::AuthorizationFailed { .. }) | None => GIT_ERROR,
+            };
+            LAST_ERROR.with(|last| *last.borrow_mut() = Some(format!("{err:#}")));
+            code
+        }
+    }
+}
+
+/// Store a fresh or cached access token in `*out`, logging in if needed.
+///
+/// Returns [`GIT_OK`] or an error code. Free the token with [`git_free_string`].
+///
+/// # Safety
+///
+/// `out` must be null or valid for writing a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn git_get_access_token(out: *mut *mut c_char) -> c_int {
+    // SAFETY: forwarded from the caller.
+    unsafe { export_token(out, |token| Ok(&token.access_token)) }
+}
+
+/// Store a fresh or cached ID token in `*out`, logging in if needed.
+///
+/// Returns [`GIT_OK`] or an error code. Free the token with [`git_free_string`].
+///
+/// # Safety
+///
+/// `out` must be null or valid for writing a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn git_get_id_token(out: *mut *mut c_char) -> c_int {
+    // SAFETY: forwarded from the caller.
+    unsafe { export_token(out, OwnedToken::require_id_token) }
+}
+
+/// The message of the last error on this thread, or null if there was none.
+///
+/// Free the message with [`git_free_string`].
+#[no_mangle]
+pub extern "C" fn git_last_error() -> *mut c_char {
+    LAST_ERROR
+        .with(|last| last.borrow().clone())
+        .and_then(|msg| CString::new(msg).ok())
+        .map_or(std::ptr::null_mut(), CString::into_raw)
+}
+
+/// Free a string returned by this library. Null is ignored.
+///
+/// # Safety
+///
+/// `s` must be null or a pointer returned by this library that has not been
+/// freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn git_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        // SAFETY: the caller guarantees `s` came from `CString::into_raw` here.
+        drop(unsafe { CString::from_raw(s) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_null_output_pointer_is_rejected() {
+        let code = unsafe { git_get_access_token(std::ptr::null_mut()) };
+        assert_eq!(code, GIT_INVALID_ARGUMENT);
+    }
+
+    #[test]
+    fn test_last_error_round_trip() {
+        LAST_ERROR.with(|last| *last.borrow_mut() = Some("boom".into()));
+        let msg = git_last_error();
+        assert_eq!(
+            unsafe { std::ffi::CStr::from_ptr(msg) }.to_str().unwrap(),
+            "boom"
+        );
+        unsafe { git_free_string(msg) };
+    }
+}
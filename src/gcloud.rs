@@ -0,0 +1,136 @@
+//! Read-only access to the Cloud SDK's local configuration.
+//!
+//! Files are parsed directly rather than by shelling out to `gcloud`, so this
+//! works on machines without the SDK installed and costs no subprocess.
+
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+
+/// Environment variable relocating the gcloud configuration directory.
+pub const CONFIG_DIR_ENV: &str = "CLOUDSDK_CONFIG";
+
+/// Environment variable overriding the account in the active configuration.
+pub const CORE_ACCOUNT_ENV: &str = "CLOUDSDK_CORE_ACCOUNT";
+
+/// Environment variable overriding the service account gcloud impersonates.
+pub const IMPERSONATE_ENV: &str = "CLOUDSDK_AUTH_IMPERSONATE_SERVICE_ACCOUNT";
+
+/// Environment variable overriding which named configuration is active.
+pub const ACTIVE_CONFIG_ENV: &str = "CLOUDSDK_ACTIVE_CONFIG_NAME";
+
+/// The gcloud configuration directory.
+///
+/// Like gcloud, this is `CLOUDSDK_CONFIG` when set, `%APPDATA%\gcloud` on
+/// Windows, and `~/.config/gcloud` elsewhere.
+pub fn config_dir() -> Option<PathBuf> {
+    config_dir_from(std::env::var_os(CONFIG_DIR_ENV))
+}
+
+/// [`config_dir`], with `configured` as the value of `CLOUDSDK_CONFIG`.
+fn config_dir_from(configured: Option<OsString>) -> Option<PathBuf> {
+    if let Some(dir) = configured.filter(|dir| !dir.is_empty()) {
+        return Some(PathBuf::from(dir));
+    }
+    if cfg!(windows) {
+        if let Some(appdata) = std::env::var_os("APPDATA") {
+            return Some(PathBuf::from(appdata).join("gcloud"));
+        }
+    }
+    Some(dirs::home_dir()?.join(".config").join("gcloud"))
+}
+
+/// Name of the active gcloud configuration, `default` when none is recorded.
+pub fn active_config_name() -> String {
+    std::env::var(ACTIVE_CONFIG_ENV)
+        .ok()
+        .or_else(|| fs::read_to_string(config_dir()?.join("active_config")).ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "default".to_string())
+}
+
+/// The account gcloud would use, as `gcloud config get-value account` reports it.
+///
+/// `CLOUDSDK_CORE_ACCOUNT` wins over the `[core] account` property of the
+/// active configuration.
+pub fn default_account() -> Option<String> {
+    property(CORE_ACCOUNT_ENV, "core", "account")
+}
+
+/// The service account gcloud impersonates, as set by
+/// `gcloud config set auth/impersonate_service_account`.
+///
+/// `CLOUDSDK_AUTH_IMPERSONATE_SERVICE_ACCOUNT` wins over the active configuration.
+pub fn impersonated_service_account() -> Option<String> {
+    property(IMPERSONATE_ENV, "auth", "impersonate_service_account")
+}
+
+/// A gcloud property: the environment variable `env` if set, else `key` in
+/// `[section]` of the active configuration.
+fn property(env: &str, section: &str, key: &str) -> Option<String> {
+    if let Ok(value) = std::env::var(env) {
+        return Some(value).filter(|value| !value.is_empty());
+    }
+
+    let path = config_dir()?
+        .join("configurations")
+        .join(format!("config_{}", active_config_name()));
+    ini_value(&fs::read_to_string(path).ok()?, section, key)
+}
+
+/// Look up `key` in `[section]` of an INI file as gcloud writes them.
+fn ini_value(contents: &str, section: &str, key: &str) -> Option<String> {
+    let mut in_section = false;
+    for line in contents.lines().map(str::trim) {
+        if line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            in_section = name.trim() == section;
+        } else if in_section {
+            if let Some((k, v)) = line.split_once('=') {
+                if k.trim() == key {
+                    return Some(v.trim().to_string()).filter(|v| !v.is_empty());
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_dir_honors_cloudsdk_config() {
+        assert_eq!(
+            config_dir_from(Some("/srv/gcloud".into())),
+            Some(PathBuf::from("/srv/gcloud"))
+        );
+        // An empty CLOUDSDK_CONFIG counts as unset.
+        assert_eq!(
+            config_dir_from(Some(OsString::new())),
+            config_dir_from(None)
+        );
+    }
+
+    #[test]
+    fn test_ini_value_reads_core_account() {
+        let config = "\
+[compute]
+account = wrong@example.com
+
+[core]
+# comment
+project = my-project
+account = me@example.com
+";
+        assert_eq!(
+            ini_value(config, "core", "account").as_deref(),
+            Some("me@example.com")
+        );
+        assert_eq!(ini_value(config, "core", "zone"), None);
+    }
+}
@@ -0,0 +1,499 @@
+//! A [`TokenCache`] kept in gcloud's own credential databases, so accounts
+//! logged in with either tool are visible to the other.
+//!
+//! gcloud stores one credential per account in `credentials.db` and its
+//! latest access token in `access_tokens.db`, both SQLite databases in the
+//! gcloud configuration directory. The databases are read and written with
+//! the `sqlite3` command-line shell, which must be on `PATH`.
+//!
+//! A refresh token only works with the OAuth client it was issued to, so this
+//! crate uses a token gcloud obtained only when it is configured with the
+//! same client. gcloud, which refreshes with the client stored alongside each
+//! token, can use every token this crate stores. Each account has a single
+//! credential, so a login through this crate replaces gcloud's own
+//! credential for that account.
+//!
+//! ```rust,no_run
+//! use gcloud_identity_token::cache::configure_token_cache;
+//! use gcloud_identity_token::config::load_creds;
+//! use gcloud_identity_token::gcloud_store::GcloudCache;
+//! use std::sync::Arc;
+//!
+//! # fn run() -> anyhow::Result<()> {
+//! let creds = load_creds()?;
+//! let cache = GcloudCache::from_config_dir().expect("no home directory");
+//! configure_token_cache(Arc::new(cache.with_client(&creds)));
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::cache::{TokenCache, split_entry_key};
+use crate::config::{Creds, SavedToken, TOKEN_URI};
+use crate::gcloud;
+use crate::verify::decode_unverified;
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde_json::{Map, Value};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// File holding gcloud's refresh tokens, in the `credentials` table.
+const CREDENTIALS_DB: &str = "credentials.db";
+
+/// File holding gcloud's access tokens, in the `access_tokens` table.
+const ACCESS_TOKENS_DB: &str = "access_tokens.db";
+
+/// Schema of the `credentials` table, as gcloud creates it.
+const CREATE_CREDENTIALS: &str =
+    r#"CREATE TABLE IF NOT EXISTS "credentials" (account_id TEXT PRIMARY KEY, value BLOB);"#;
+
+/// Schema of the `access_tokens` table, as gcloud creates it.
+const CREATE_ACCESS_TOKENS: &str = r#"CREATE TABLE IF NOT EXISTS "access_tokens" (account_id TEXT PRIMARY KEY, access_token TEXT, token_expiry TIMESTAMP, rapt_token TEXT, id_token TEXT);"#;
+
+/// How gcloud's timestamps are read, in UTC: Python's `sqlite3` module
+/// omits the fraction of whole seconds.
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+
+/// How Python's `sqlite3` module, and so gcloud, writes timestamps.
+const TIMESTAMP_WRITE_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.6f";
+
+/// How long to wait for gcloud to release a locked database, in milliseconds.
+const BUSY_TIMEOUT_MS: u32 = 5000;
+
+/// Revocation endpoint gcloud records with each credential.
+const REVOKE_URI: &str = "https://oauth2.googleapis.com/revoke";
+
+/// Tokens stored in gcloud's `credentials.db` and `access_tokens.db`.
+///
+/// Entries are named `{account}:{client_id}` like the crate's other stores,
+/// where the account is an email or the `sub` of an ID token gcloud holds;
+/// loading an entry whose client differs from gcloud's stored one finds
+/// nothing.
+#[derive(Clone)]
+pub struct GcloudCache {
+    dir: PathBuf,
+    client: Option<(String, String)>,
+}
+
+impl GcloudCache {
+    /// A store using the databases in the gcloud configuration directory `dir`.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            client: None,
+        }
+    }
+
+    /// A store using the databases of the gcloud installation, in
+    /// [`gcloud::config_dir`]; `None` without a home directory.
+    pub fn from_config_dir() -> Option<Self> {
+        Some(Self::new(gcloud::config_dir()?))
+    }
+
+    /// Record the client secret of `creds` with the tokens saved from its
+    /// client, which gcloud needs to refresh them.
+    ///
+    /// Without it, a token can only be saved over a credential of the same
+    /// client, whose secret is kept.
+    pub fn with_client(mut self, creds: &Creds) -> Self {
+        self.client = Some((creds.client_id.clone(), creds.client_secret.clone()));
+        self
+    }
+
+    fn credentials_db(&self) -> PathBuf {
+        self.dir.join(CREDENTIALS_DB)
+    }
+
+    fn access_tokens_db(&self) -> PathBuf {
+        self.dir.join(ACCESS_TOKENS_DB)
+    }
+
+    /// The OAuth client and refresh token gcloud holds for `account`.
+    pub(crate) fn user_creds(&self, account: &str) -> Result<Option<Creds>> {
+        let Some(credential) = self.credential(account)? else {
+            return Ok(None);
+        };
+        let string = |name| Some(credential.get(name)?.as_str()?.to_string());
+        Ok(Some(Creds {
+            client_id: string("client_id").unwrap_or_default(),
+            client_secret: string("client_secret").unwrap_or_default(),
+            refresh_token: string("refresh_token").filter(|token| !token.is_empty()),
+        }))
+    }
+
+    /// gcloud's name for the account `name`, which is an email or the `sub`
+    /// of the account's stored ID token.
+    fn account_id(&self, name: &str) -> Result<Option<String>> {
+        if name.contains('@') {
+            return Ok(Some(name.to_string()));
+        }
+        let rows = query(
+            &self.access_tokens_db(),
+            r#"SELECT account_id, id_token FROM "access_tokens";"#,
+        )?;
+        Ok(rows.iter().find_map(|row| {
+            let claims = decode_unverified(row.get("id_token")?.as_str()?).ok()?;
+            (claims.get("sub")?.as_str()? == name)
+                .then(|| Some(row.get("account_id")?.as_str()?.to_string()))?
+        }))
+    }
+
+    /// The stored credential of `account`, if it is an `authorized_user` one.
+    fn credential(&self, account: &str) -> Result<Option<Map<String, Value>>> {
+        let rows = query(
+            &self.credentials_db(),
+            &format!(
+                r#"SELECT CAST(value AS TEXT) AS value FROM "credentials" WHERE account_id = {};"#,
+                quote(account)
+            ),
+        )?;
+        let Some(value) = rows.first().and_then(|row| row.get("value")?.as_str()) else {
+            return Ok(None);
+        };
+        let credential: Map<String, Value> =
+            serde_json::from_str(value).context("Malformed gcloud credential")?;
+        Ok(Some(credential).filter(is_user_credential))
+    }
+}
+
+/// Whether a gcloud credential is a user's, rather than e.g. a service account key.
+fn is_user_credential(credential: &Map<String, Value>) -> bool {
+    matches!(
+        credential.get("type").and_then(Value::as_str),
+        None | Some("authorized_user")
+    )
+}
+
+/// The account and client of the entry `user`, as named by the cache.
+///
+/// gcloud keeps one grant per account, so entries of every scope set share it.
+fn split_entry(user: &str) -> (&str, &str) {
+    let (account, client_id, _) = split_entry_key(user);
+    (account, client_id)
+}
+
+/// The client ID recorded in a gcloud credential.
+fn client_of(credential: &Map<String, Value>) -> &str {
+    credential
+        .get("client_id")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+}
+
+impl TokenCache for GcloudCache {
+    fn load(&self, user: &str) -> Result<Option<SavedToken>> {
+        let (name, client_id) = split_entry(user);
+        let Some(account) = self.account_id(name)? else {
+            return Ok(None);
+        };
+        let account = account.as_str();
+        let Some(credential) = self.credential(account)? else {
+            return Ok(None);
+        };
+        if client_of(&credential) != client_id {
+            return Ok(None);
+        }
+
+        let rows = query(
+            &self.access_tokens_db(),
+            &format!(
+                r#"SELECT access_token, token_expiry, id_token FROM "access_tokens" WHERE account_id = {};"#,
+                quote(account)
+            ),
+        )?;
+        let row = rows.first();
+        let column = |name| {
+            row.and_then(|row| row.get(name)?.as_str())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let string = |name| {
+            credential
+                .get(name)
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+        Ok(Some(SavedToken {
+            refresh_token: string("refresh_token"),
+            access_token: column("access_token"),
+            id_token: Some(column("id_token")).filter(|id_token| !id_token.is_empty()),
+            token_expiry: parse_timestamp(&column("token_expiry")).unwrap_or(DateTime::UNIX_EPOCH),
+            refresh_token_issued_at: None,
+            granted_scopes: credential
+                .get("scopes")
+                .and_then(Value::as_array)
+                .map(|scopes| {
+                    scopes
+                        .iter()
+                        .filter_map(|scope| Some(scope.as_str()?.to_string()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            requested_scopes: Vec::new(),
+            client_id: client_id.to_string(),
+        }))
+    }
+
+    fn save(&self, user: &str, token: &SavedToken) -> Result<()> {
+        let email = token
+            .id_token
+            .as_deref()
+            .and_then(|id_token| decode_unverified(id_token).ok())
+            .and_then(|claims| Some(claims.get("email")?.as_str()?.to_string()));
+        let account = match email {
+            Some(email) => email,
+            None => split_entry(user).0.to_string(),
+        };
+        let account = account.as_str();
+        let mut credential = self
+            .credential(account)?
+            .filter(|credential| client_of(credential) == token.client_id)
+            .unwrap_or_default();
+        if let Some((client_id, secret)) = &self.client {
+            if *client_id == token.client_id {
+                credential.insert("client_secret".into(), secret.clone().into());
+            }
+        }
+        credential.insert("type".into(), "authorized_user".into());
+        credential.insert("client_id".into(), token.client_id.clone().into());
+        credential.insert("refresh_token".into(), token.refresh_token.clone().into());
+        credential.insert("token_uri".into(), TOKEN_URI.into());
+        credential
+            .entry("revoke_uri")
+            .or_insert_with(|| REVOKE_URI.into());
+        if !token.granted_scopes.is_empty() {
+            credential.insert("scopes".into(), token.granted_scopes.clone().into());
+        }
+
+        execute(
+            &self.credentials_db(),
+            &format!(
+                r#"{CREATE_CREDENTIALS}
+REPLACE INTO "credentials" (account_id, value) VALUES ({}, {});"#,
+                quote(account),
+                quote(&Value::Object(credential).to_string())
+            ),
+        )?;
+
+        let access_token = if token.access_token.is_empty() {
+            format!(
+                r#"DELETE FROM "access_tokens" WHERE account_id = {};"#,
+                quote(account)
+            )
+        } else {
+            // An upsert rather than REPLACE keeps gcloud's reauth token.
+            format!(
+                r#"INSERT INTO "access_tokens" (account_id, access_token, token_expiry, id_token)
+VALUES ({}, {}, {}, {})
+ON CONFLICT(account_id) DO UPDATE SET access_token = excluded.access_token,
+    token_expiry = excluded.token_expiry, id_token = excluded.id_token;"#,
+                quote(account),
+                quote(&token.access_token),
+                quote(
+                    &token
+                        .token_expiry
+                        .naive_utc()
+                        .format(TIMESTAMP_WRITE_FORMAT)
+                        .to_string()
+                ),
+                token.id_token.as_deref().map_or("NULL".to_string(), quote)
+            )
+        };
+        execute(
+            &self.access_tokens_db(),
+            &format!("{CREATE_ACCESS_TOKENS}\n{access_token}"),
+        )
+    }
+
+    fn delete(&self, user: &str) -> Result<()> {
+        let (name, client_id) = split_entry(user);
+        let Some(account) = self.account_id(name)? else {
+            return Ok(());
+        };
+        let account = account.as_str();
+        match self.credential(account)? {
+            Some(credential) if client_of(&credential) == client_id => {}
+            _ => return Ok(()),
+        }
+        for (db, table) in [
+            (self.credentials_db(), "credentials"),
+            (self.access_tokens_db(), "access_tokens"),
+        ] {
+            execute(
+                &db,
+                &format!(
+                    r#"DELETE FROM "{table}" WHERE account_id = {};"#,
+                    quote(account)
+                ),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn users(&self) -> Option<Vec<String>> {
+        let rows = query(
+            &self.credentials_db(),
+            r#"SELECT account_id, CAST(value AS TEXT) AS value FROM "credentials";"#,
+        )
+        .ok()?;
+        Some(
+            rows.iter()
+                .filter_map(|row| {
+                    let account = row.get("account_id")?.as_str()?;
+                    let credential: Map<String, Value> =
+                        serde_json::from_str(row.get("value")?.as_str()?).ok()?;
+                    is_user_credential(&credential)
+                        .then(|| format!("{account}:{}", client_of(&credential)))
+                })
+                .collect(),
+        )
+    }
+}
+
+/// A timestamp as gcloud writes them, naive and in UTC, with or without
+/// fractional seconds.
+fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    Some(
+        NaiveDateTime::parse_from_str(value, TIMESTAMP_FORMAT)
+            .ok()?
+            .and_utc(),
+    )
+}
+
+/// `value` as an SQL string literal.
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// The rows `sql` selects from the database `db`; none if it does not exist.
+fn query(db: &Path, sql: &str) -> Result<Vec<Map<String, Value>>> {
+    if !db.exists() {
+        return Ok(Vec::new());
+    }
+    let output = sqlite(db, sql)?;
+    if output.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    Ok(serde_json::from_str(&output)?)
+}
+
+/// Run the statements `sql` on the database `db`, creating it readable by
+/// the owner only.
+fn execute(db: &Path, sql: &str) -> Result<()> {
+    let created = !db.exists();
+    if created {
+        if let Some(parent) = db.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+    }
+    sqlite(db, &format!("BEGIN IMMEDIATE;\n{sql}\nCOMMIT;"))?;
+    #[cfg(unix)]
+    if created {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(db, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+/// Run `sql` through the `sqlite3` shell on `db`, returning its JSON output.
+///
+/// Statements go through standard input, so no value reaches a command line.
+fn sqlite(db: &Path, sql: &str) -> Result<String> {
+    let mut child = Command::new("sqlite3")
+        .arg("-bail")
+        .arg("-json")
+        .arg(db)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run sqlite3, needed to use gcloud's credential databases")?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(format!(".timeout {BUSY_TIMEOUT_MS}\n{sql}\n").as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "sqlite3 failed on {}: {}",
+            db.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_round_trip_through_gcloud_schema() {
+        Command::new("sqlite3")
+            .arg("-version")
+            .output()
+            .expect("the gcloud-store feature needs the sqlite3 shell on PATH");
+        let dir = tempfile::tempdir().unwrap();
+        let creds = Creds {
+            client_id: "client".into(),
+            client_secret: "secret".into(),
+            refresh_token: None,
+        };
+        let cache = GcloudCache::new(dir.path()).with_client(&creds);
+        assert!(cache.load("me@example.com:client").unwrap().is_none());
+
+        let token = SavedToken {
+            refresh_token: "refresh'quoted".into(),
+            access_token: "access".into(),
+            id_token: Some("id".into()),
+            token_expiry: parse_timestamp("2030-01-02 03:04:05.123456").unwrap(),
+            refresh_token_issued_at: None,
+            granted_scopes: vec!["openid".into(), "email".into()],
+            requested_scopes: Vec::new(),
+            client_id: "client".into(),
+        };
+        cache.save("me@example.com:client", &token).unwrap();
+        let loaded = cache.load("me@example.com:client").unwrap().unwrap();
+        assert_eq!(loaded.refresh_token, token.refresh_token);
+        assert_eq!(loaded.access_token, "access");
+        assert_eq!(loaded.token_expiry, token.token_expiry);
+        assert_eq!(loaded.granted_scopes, token.granted_scopes);
+        let imported = cache.user_creds("me@example.com").unwrap().unwrap();
+        assert_eq!(imported.client_secret, "secret");
+        assert_eq!(imported.refresh_token.as_deref(), Some("refresh'quoted"));
+        assert_eq!(
+            cache.users().unwrap(),
+            vec!["me@example.com:client".to_string()]
+        );
+
+        // Another client's entry is not this one, and its deletion leaves it be.
+        assert!(cache.load("me@example.com:other").unwrap().is_none());
+        cache.delete("me@example.com").unwrap();
+        assert!(cache.load("me@example.com:client").unwrap().is_some());
+
+        // Without an access token only the refresh token stays.
+        let refresh_only = SavedToken {
+            access_token: String::new(),
+            token_expiry: Utc::now() - Duration::hours(1),
+            ..token
+        };
+        cache.save("me@example.com:client", &refresh_only).unwrap();
+        let loaded = cache.load("me@example.com:client").unwrap().unwrap();
+        assert_eq!(loaded.access_token, "");
+        assert_eq!(loaded.token_expiry, DateTime::UNIX_EPOCH);
+
+        cache.delete("me@example.com:client").unwrap();
+        assert!(cache.load("me@example.com:client").unwrap().is_none());
+        assert_eq!(cache.users().unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        let parsed = parse_timestamp("2024-05-06 07:08:09").unwrap();
+        assert_eq!(parsed.to_rfc3339(), "2024-05-06T07:08:09+00:00");
+        assert!(parse_timestamp("2024-05-06 07:08:09.5").is_some());
+        assert_eq!(parse_timestamp(""), None);
+    }
+}
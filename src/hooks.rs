@@ -0,0 +1,124 @@
+//! A user command run whenever new tokens are obtained.
+//!
+//! Lets long-running modes such as `watch` push fresh tokens into places this
+//! crate knows nothing about: kubeconfigs, `.netrc` files, tmux environments.
+
+use crate::config::SavedToken;
+use std::sync::Mutex;
+use tokio::process::Command;
+
+/// Environment variable holding the command to run after a refresh or login.
+pub const ON_REFRESH_ENV: &str = "GCLOUD_IDENTITY_TOKEN_ON_REFRESH";
+
+static ON_REFRESH: Mutex<Option<String>> = Mutex::new(None);
+
+/// What produced the new tokens.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Trigger {
+    Refresh,
+    Login,
+}
+
+/// Run `command` through the shell after every successful refresh or login,
+/// overriding `GCLOUD_IDENTITY_TOKEN_ON_REFRESH`.
+///
+/// The command sees these environment variables:
+///
+/// - `GCLOUD_IDENTITY_TOKEN_EVENT` — `refresh` or `login`
+/// - `GCLOUD_IDENTITY_TOKEN_EXPIRY` — expiry in RFC 3339
+/// - `GCLOUD_IDENTITY_TOKEN_EXPIRY_UNIX` — expiry in seconds since the Unix epoch
+/// - `GCLOUD_IDENTITY_TOKEN_ACCESS_TOKEN`, `GCLOUD_IDENTITY_TOKEN_ID_TOKEN` — the new tokens
+///
+/// It runs before the token is handed to the caller; its failure is reported
+/// on stderr but does not fail the token request.
+pub fn set_refresh_hook(command: Option<String>) {
+    if let Ok(mut hook) = ON_REFRESH.lock() {
+        *hook = command;
+    }
+}
+
+fn refresh_hook() -> Option<String> {
+    ON_REFRESH
+        .lock()
+        .ok()
+        .and_then(|hook| hook.clone())
+        .or_else(|| std::env::var(ON_REFRESH_ENV).ok())
+        .filter(|command| !command.trim().is_empty())
+}
+
+/// Run the configured hook, if any, for the freshly obtained `token`.
+pub(crate) async fn run_refresh_hook(trigger: Trigger, token: &SavedToken) {
+    let Some(command) = refresh_hook() else {
+        return;
+    };
+    let event = match trigger {
+        Trigger::Refresh => "refresh",
+        Trigger::Login => "login",
+    };
+
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+    let status = cmd
+        .arg(&command)
+        .env("GCLOUD_IDENTITY_TOKEN_EVENT", event)
+        .env(
+            "GCLOUD_IDENTITY_TOKEN_EXPIRY",
+            token.token_expiry.to_rfc3339(),
+        )
+        .env(
+            "GCLOUD_IDENTITY_TOKEN_EXPIRY_UNIX",
+            token.token_expiry.timestamp().to_string(),
+        )
+        .env("GCLOUD_IDENTITY_TOKEN_ACCESS_TOKEN", &token.access_token)
+        .env(
+            "GCLOUD_IDENTITY_TOKEN_ID_TOKEN",
+            token.id_token.as_deref().unwrap_or_default(),
+        )
+        // Keep stdout for token output.
+        .stdout(std::io::stderr())
+        .status()
+        .await;
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("Warning: {ON_REFRESH_ENV} hook exited with {status}"),
+        Err(err) => eprintln!("Warning: failed to run {ON_REFRESH_ENV} hook: {err}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_hook_sees_event_and_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("hook.out");
+        set_refresh_hook(Some(format!(
+            "printf '%s %s' \"$GCLOUD_IDENTITY_TOKEN_EVENT\" \"$GCLOUD_IDENTITY_TOKEN_EXPIRY_UNIX\" > {}",
+            out.display()
+        )));
+        let token = SavedToken {
+            refresh_token: "refresh".into(),
+            access_token: "access".into(),
+            id_token: Some("id".into()),
+            token_expiry: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            refresh_token_issued_at: None,
+            granted_scopes: Vec::new(),
+            requested_scopes: Vec::new(),
+            client_id: String::new(),
+        };
+        run_refresh_hook(Trigger::Login, &token).await;
+        set_refresh_hook(None);
+
+        assert_eq!(std::fs::read_to_string(out).unwrap(), "login 1700000000");
+    }
+}
@@ -0,0 +1,248 @@
+//! Service account tokens minted through the IAM Credentials API.
+//!
+//! The user's own access token authorizes each call, so the account needs
+//! `roles/iam.serviceAccountTokenCreator` on the target service account.
+
+use crate::auth::{CLOUD_PLATFORM_SCOPE, cancellable, fetch_scoped_access_token};
+use crate::config::{Creds, LoginOptions};
+use crate::debug;
+use crate::gcloud;
+use crate::stats::{self, Event};
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/// Base URL of the IAM Credentials service account resources.
+const IAM_CREDENTIALS_URL: &str =
+    "https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts";
+
+/// Most `generateIdToken` calls [`get_id_tokens`] keeps in flight at once.
+pub const MAX_CONCURRENT_MINTS: usize = 8;
+
+/// Remaining lifetime below which a remembered impersonated token is replaced.
+const IMPERSONATED_REUSE_MARGIN: chrono::Duration = chrono::Duration::seconds(60);
+
+/// Impersonated access tokens by service account and sorted scopes.
+type ImpersonatedTokens = BTreeMap<(String, Vec<String>), (String, DateTime<Utc>)>;
+
+static IMPERSONATED: Mutex<ImpersonatedTokens> = Mutex::new(BTreeMap::new());
+
+#[derive(Deserialize)]
+struct GenerateIdTokenResponse {
+    token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateAccessTokenResponse {
+    access_token: String,
+    expire_time: DateTime<Utc>,
+}
+
+/// Error body returned by Google APIs.
+#[derive(Deserialize)]
+struct ApiErrorResponse {
+    error: ApiError,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    message: String,
+    #[serde(default)]
+    status: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.status.is_empty() {
+            f.write_str(&self.message)
+        } else {
+            write!(f, "{}: {}", self.status, self.message)
+        }
+    }
+}
+
+/// Mint ID tokens for several audiences at once, as the service account
+/// gcloud impersonates (`auth/impersonate_service_account`).
+///
+/// Returns a map from audience to ID token. See [`get_id_tokens_as`].
+pub async fn get_id_tokens(creds: &Creds, audiences: &[&str]) -> Result<BTreeMap<String, String>> {
+    let service_account = gcloud::impersonated_service_account().ok_or_else(|| {
+        anyhow!(
+            "No service account to mint ID tokens as; run `gcloud config set \
+             auth/impersonate_service_account SA_EMAIL` or set {}",
+            gcloud::IMPERSONATE_ENV
+        )
+    })?;
+    get_id_tokens_as(creds, &LoginOptions::default(), &service_account, audiences).await
+}
+
+/// Mint ID tokens for several audiences as `service_account`.
+///
+/// The calls run concurrently, at most [`MAX_CONCURRENT_MINTS`] at a time,
+/// and the first failure cancels the rest. If the cached grant lacks the
+/// `cloud-platform` scope, a browser login requesting it runs first.
+pub async fn get_id_tokens_as(
+    creds: &Creds,
+    opts: &LoginOptions,
+    service_account: &str,
+    audiences: &[&str],
+) -> Result<BTreeMap<String, String>> {
+    let access_token = cancellable(
+        &opts.cancel,
+        fetch_scoped_access_token(creds, opts, CLOUD_PLATFORM_SCOPE),
+    )
+    .await?;
+    let access_token = Arc::<str>::from(access_token);
+    let service_account = Arc::<str>::from(service_account);
+    let client = Client::new();
+    let limit = Arc::new(Semaphore::new(MAX_CONCURRENT_MINTS));
+
+    let mut tasks = JoinSet::new();
+    for audience in audiences.iter().copied().collect::<BTreeSet<_>>() {
+        let (client, access_token, service_account, limit) = (
+            client.clone(),
+            access_token.clone(),
+            service_account.clone(),
+            limit.clone(),
+        );
+        let audience = audience.to_string();
+        tasks.spawn(async move {
+            let _permit = limit.acquire_owned().await?;
+            let id_token = generate_id_token(&client, &access_token, &service_account, &audience)
+                .await
+                .with_context(|| format!("Failed to mint an ID token for {audience}"))?;
+            Ok::<_, anyhow::Error>((audience, id_token))
+        });
+    }
+
+    let mut tokens = BTreeMap::new();
+    while let Some(minted) = tasks.join_next().await {
+        let (audience, id_token) = minted??;
+        tokens.insert(audience, id_token);
+    }
+    Ok(tokens)
+}
+
+/// A short-lived access token for `service_account` with `scopes`, and its
+/// expiry, minted with `generateAccessToken`.
+///
+/// Tokens are remembered for the life of the process and reused until shortly
+/// before they expire. `scopes` defaults to `cloud-platform` when empty.
+pub(crate) async fn impersonated_access_token(
+    creds: &Creds,
+    opts: &LoginOptions,
+    service_account: &str,
+    scopes: &[&str],
+) -> Result<(String, DateTime<Utc>)> {
+    let mut scopes: Vec<String> = if scopes.is_empty() {
+        vec![CLOUD_PLATFORM_SCOPE.to_string()]
+    } else {
+        scopes.iter().map(|scope| scope.to_string()).collect()
+    };
+    scopes.sort();
+    scopes.dedup();
+    let key = (service_account.to_string(), scopes);
+
+    let remembered = IMPERSONATED.lock().ok().and_then(|tokens| {
+        tokens
+            .get(&key)
+            .filter(|(_, expiry)| *expiry > Utc::now() + IMPERSONATED_REUSE_MARGIN)
+            .cloned()
+    });
+    if let Some(token) = remembered {
+        stats::record(Event::Hit);
+        return Ok(token);
+    }
+
+    let access_token = fetch_scoped_access_token(creds, opts, CLOUD_PLATFORM_SCOPE).await?;
+    let url = format!("{IAM_CREDENTIALS_URL}/{service_account}:generateAccessToken");
+    let scopes: Vec<&str> = key.1.iter().map(String::as_str).collect();
+    let token = generate_access_token_at(&Client::new(), &url, &access_token, &scopes)
+        .await
+        .with_context(|| format!("Failed to impersonate {service_account}"))?;
+    if let Ok(mut tokens) = IMPERSONATED.lock() {
+        tokens.insert(key, token.clone());
+    }
+    Ok(token)
+}
+
+/// Call the `generateAccessToken` method at `url`, authorized by
+/// `access_token`, returning the service account's token and its expiry.
+pub(crate) async fn generate_access_token_at(
+    client: &Client,
+    url: &str,
+    access_token: &str,
+    scopes: &[&str],
+) -> Result<(String, DateTime<Utc>)> {
+    let res = debug::send(
+        client
+            .post(url)
+            .bearer_auth(access_token)
+            .json(&serde_json::json!({ "scope": scopes })),
+    )
+    .await?;
+    if !res.status().is_success() {
+        let status = res.status();
+        return Err(match res.json::<ApiErrorResponse>().await {
+            Ok(body) => anyhow!("generateAccessToken failed: {}", body.error),
+            Err(_) => anyhow!("generateAccessToken failed with HTTP {status}"),
+        });
+    }
+    let res = res.json::<GenerateAccessTokenResponse>().await?;
+    Ok((res.access_token, res.expire_time))
+}
+
+/// Call `generateIdToken` for one audience.
+async fn generate_id_token(
+    client: &Client,
+    access_token: &str,
+    service_account: &str,
+    audience: &str,
+) -> Result<String> {
+    let url = format!("{IAM_CREDENTIALS_URL}/{service_account}:generateIdToken");
+    let res = debug::send(
+        client
+            .post(url)
+            .bearer_auth(access_token)
+            .json(&serde_json::json!({ "audience": audience, "includeEmail": true })),
+    )
+    .await?;
+    if !res.status().is_success() {
+        let status = res.status();
+        return Err(match res.json::<ApiErrorResponse>().await {
+            Ok(body) => anyhow!("generateIdToken failed: {}", body.error),
+            Err(_) => anyhow!("generateIdToken failed with HTTP {status}"),
+        });
+    }
+    Ok(res.json::<GenerateIdTokenResponse>().await?.token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_access_token_response() {
+        let body = r#"{"accessToken":"ya29.sa","expireTime":"2030-01-01T00:00:00Z"}"#;
+        let res: GenerateAccessTokenResponse = serde_json::from_str(body).unwrap();
+        assert_eq!(res.access_token, "ya29.sa");
+        assert_eq!(res.expire_time.timestamp(), 1_893_456_000);
+    }
+
+    #[test]
+    fn test_api_error_display() {
+        let body = r#"{"error":{"code":403,"message":"Permission 'iam.serviceAccounts.getOpenIdToken' denied","status":"PERMISSION_DENIED"}}"#;
+        let err: ApiErrorResponse = serde_json::from_str(body).unwrap();
+        assert_eq!(
+            err.error.to_string(),
+            "PERMISSION_DENIED: Permission 'iam.serviceAccounts.getOpenIdToken' denied"
+        );
+    }
+}
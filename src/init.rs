@@ -0,0 +1,183 @@
+//! Interactive first-time setup for the `init` command.
+//!
+//! Walks through the three things a new user needs: an OAuth client, a place
+//! to cache tokens, and the scopes to request. Prompts go to stderr and
+//! answers are read from the given input, normally stdin.
+
+use crate::cache::check_keyring;
+use crate::config::{Creds, Settings, creds_path, load_creds, save_creds, save_settings};
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+/// Where to create an OAuth client for the wizard's instructions.
+const CREDENTIALS_CONSOLE_URL: &str = "https://console.cloud.google.com/apis/credentials";
+
+/// Choices made in the wizard.
+#[derive(Debug, Clone)]
+pub struct Setup {
+    /// Settings written to the settings file
+    pub settings: Settings,
+    /// Whether the user asked for a first login right away
+    pub login_now: bool,
+}
+
+/// Ask the setup questions, write the OAuth client and settings files, and
+/// return the choices.
+pub fn run_wizard(input: &mut impl BufRead) -> Result<Setup> {
+    eprintln!("Step 1 of 3: OAuth client");
+    match load_creds() {
+        Ok(creds) => eprintln!(
+            "Using OAuth client {} from {}.",
+            creds.client_id,
+            creds_path()?.display()
+        ),
+        Err(_) => import_client(input)?,
+    }
+
+    eprintln!("\nStep 2 of 3: token cache");
+    let keyring_works = check_keyring().is_ok();
+    if !keyring_works {
+        eprintln!("The system keyring is not reachable here, so a file cache is suggested.");
+    }
+    let default_backend = if keyring_works { "keyring" } else { "file" };
+    let cache_file = loop {
+        let answer = ask(
+            input,
+            &format!("Cache tokens in the keyring or a file? [{default_backend}] "),
+        )?;
+        match answer.as_str() {
+            "" if keyring_works => break None,
+            "keyring" | "k" => break None,
+            "" | "file" | "f" => {
+                let default = default_cache_file()?;
+                let path = ask(input, &format!("Token file [{}] ", default.display()))?;
+                break Some(if path.is_empty() {
+                    default
+                } else {
+                    PathBuf::from(path)
+                });
+            }
+            _ => eprintln!("Please answer keyring or file."),
+        }
+    };
+
+    eprintln!("\nStep 3 of 3: scopes");
+    eprintln!("Logins always request openid and email.");
+    let scopes = ask(
+        input,
+        "Extra scopes, space separated, e.g. https://www.googleapis.com/auth/cloud-platform [none] ",
+    )?
+    .split_whitespace()
+    .map(str::to_string)
+    .collect();
+
+    let settings = Settings { cache_file, scopes };
+    let path = save_settings(&settings)?;
+    eprintln!("\nWrote {}.", path.display());
+
+    let login_now = !matches!(
+        ask(input, "Log in now? [Y/n] ")?.to_lowercase().as_str(),
+        "n" | "no"
+    );
+    Ok(Setup {
+        settings,
+        login_now,
+    })
+}
+
+/// Prompt for a downloaded OAuth client file until one is imported.
+fn import_client(input: &mut impl BufRead) -> Result<()> {
+    eprintln!(
+        "No OAuth client found at {}.\n\
+         Either run `gcloud auth application-default login`, or create an OAuth \
+         client of type \"Desktop app\" at {CREDENTIALS_CONSOLE_URL}, download its JSON, \
+         and enter the file's path here.",
+        creds_path()?.display()
+    );
+    loop {
+        let path = ask(input, "Client JSON file: ")?;
+        if path.is_empty() {
+            continue;
+        }
+        let imported = std::fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| parse_client_json(&json));
+        match imported {
+            Ok(creds) => {
+                let written = creds_path()?;
+                save_creds(&written, &creds)?;
+                eprintln!(
+                    "Saved OAuth client {} to {}.",
+                    creds.client_id,
+                    written.display()
+                );
+                return Ok(());
+            }
+            Err(err) => eprintln!("Could not use {path}: {err:#}"),
+        }
+    }
+}
+
+/// Read an OAuth client from a console download (`{"installed": {...}}`) or
+/// a file that already has `client_id` and `client_secret` at the top level.
+fn parse_client_json(json: &str) -> Result<Creds> {
+    #[derive(Deserialize)]
+    struct Download {
+        installed: Option<Creds>,
+        web: Option<Creds>,
+    }
+
+    if let Ok(Download { installed, web }) = serde_json::from_str(json) {
+        if let Some(creds) = installed.or(web) {
+            return Ok(creds);
+        }
+    }
+    serde_json::from_str(json)
+        .map_err(|_| anyhow!("expected an OAuth client JSON with a client_id and client_secret"))
+}
+
+fn default_cache_file() -> Result<PathBuf> {
+    Ok(dirs::config_dir()
+        .ok_or_else(|| anyhow!("Configuration directory not found"))?
+        .join(env!("CARGO_PKG_NAME"))
+        .join("token.json"))
+}
+
+/// Print `question` and read a trimmed answer, failing at end of input.
+fn ask(input: &mut impl BufRead, question: &str) -> Result<String> {
+    eprint!("{question}");
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    if input.read_line(&mut answer)? == 0 {
+        return Err(anyhow!("Setup aborted"));
+    }
+    Ok(answer.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_client_json_formats() {
+        let download = r#"{"installed":{"client_id":"id.apps.googleusercontent.com","client_secret":"s","redirect_uris":["http://localhost"]}}"#;
+        assert_eq!(
+            parse_client_json(download).unwrap().client_id,
+            "id.apps.googleusercontent.com"
+        );
+
+        let adc = r#"{"client_id":"id","client_secret":"s","refresh_token":"r","type":"authorized_user"}"#;
+        assert_eq!(parse_client_json(adc).unwrap().client_secret, "s");
+
+        assert!(parse_client_json(r#"{"type":"service_account"}"#).is_err());
+    }
+
+    #[test]
+    fn test_ask_fails_at_end_of_input() {
+        let mut input = std::io::Cursor::new("answer\n");
+        assert_eq!(ask(&mut input, "").unwrap(), "answer");
+        assert!(ask(&mut input, "").is_err());
+    }
+}
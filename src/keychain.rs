@@ -0,0 +1,87 @@
+//! macOS Keychain backend with access-control options.
+//!
+//! Installed as keyring's default credential builder by
+//! [`configure_keychain_access`](crate::cache::configure_keychain_access).
+//! Entries live in the data protection keychain, which scopes them to the
+//! binary's code-signing identity and supports `SecAccessControl` flags.
+
+use crate::cache::KeychainAccess;
+use keyring::credential::{Credential, CredentialApi, CredentialBuilderApi};
+use keyring::{Error, Result};
+use security_framework::passwords::{
+    delete_generic_password_options, generic_password, set_generic_password_options,
+};
+use security_framework::passwords_options::{AccessControlOptions, PasswordOptions};
+use std::any::Any;
+
+/// `errSecItemNotFound`
+const ERR_SEC_ITEM_NOT_FOUND: i32 = -25300;
+
+/// Builds [`ProtectedKeychainCredential`]s carrying the configured access rules.
+#[derive(Debug)]
+pub(crate) struct ProtectedKeychainBuilder {
+    pub(crate) access: KeychainAccess,
+}
+
+impl CredentialBuilderApi for ProtectedKeychainBuilder {
+    fn build(&self, _target: Option<&str>, service: &str, user: &str) -> Result<Box<Credential>> {
+        Ok(Box::new(ProtectedKeychainCredential {
+            service: service.to_string(),
+            user: user.to_string(),
+            access: self.access,
+        }))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// A generic password in the data protection keychain.
+#[derive(Debug)]
+struct ProtectedKeychainCredential {
+    service: String,
+    user: String,
+    access: KeychainAccess,
+}
+
+impl ProtectedKeychainCredential {
+    fn options(&self) -> PasswordOptions {
+        let mut options = PasswordOptions::new_generic_password(&self.service, &self.user);
+        options.use_protected_keychain();
+        options
+    }
+}
+
+impl CredentialApi for ProtectedKeychainCredential {
+    fn set_password(&self, password: &str) -> Result<()> {
+        // Access control is fixed when an item is created, so replace the item.
+        let _ = delete_generic_password_options(self.options());
+
+        let mut options = self.options();
+        if self.access.require_user_presence {
+            options.set_access_control_options(AccessControlOptions::USER_PRESENCE);
+        }
+        set_generic_password_options(password.as_bytes(), options).map_err(decode_error)
+    }
+
+    fn get_password(&self) -> Result<String> {
+        let bytes = generic_password(self.options()).map_err(decode_error)?;
+        String::from_utf8(bytes).map_err(|e| Error::BadEncoding(e.into_bytes()))
+    }
+
+    fn delete_password(&self) -> Result<()> {
+        delete_generic_password_options(self.options()).map_err(decode_error)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+fn decode_error(err: security_framework::base::Error) -> Error {
+    match err.code() {
+        ERR_SEC_ITEM_NOT_FOUND => Error::NoEntry,
+        _ => Error::PlatformFailure(Box::new(err)),
+    }
+}
@@ -14,6 +14,7 @@
 //! - File-based cache via `GCLOUD_IDENTITY_TOKEN_PATH` override
 //! - Fully async support with `reqwest` + `tokio`
 //! - Intelligent refresh with expiry tracking
+//! - ID token verification against Google's cached signing keys
 //!
 //! ## Example
 //!
@@ -26,20 +27,62 @@
 //!     let creds = load_creds()?;
 //!     let token = get_token(&creds).await?;
 //!     println!("Access Token: {}", token.access_token);
-//!     println!("ID Token: {}", token.id_token);
+//!     println!("ID Token: {}", token.require_id_token()?);
 //!     Ok(())
 //! }
 //! ```
 //!
 //! ## Environment Variables
-//! - `GCLOUD_IDENTITY_TOKEN_PATH` — path to file-based token cache
+//! - `GCLOUD_IDENTITY_TOKEN_PATH` — path to file-based token cache, overriding
+//!   the `init` settings file
+//! - `GCLOUD_IDENTITY_TOKEN_CACHE` — `memory` keeps tokens in process memory instead of the keyring, `gcloud` in gcloud's `credentials.db` and `access_tokens.db`, shared with gcloud (`gcloud-store` feature)
+//! - `GCLOUD_IDENTITY_TOKEN_STATE_DIR` — directory of the last-login hint, current account, and account index, overriding the platform's state directory
+//! - `GCLOUD_IDENTITY_TOKEN_KEYRING_FALLBACK` — `0` fails when the keyring is unusable instead of caching tokens in files under the state directory
+//! - `GCLOUD_IDENTITY_TOKEN_KEYRING_SERVICE` — keyring service name of the entries, `gcloud-identity-token` by default
+//! - `GCLOUD_IDENTITY_TOKEN_KEYRING_TARGET` — Secret Service collection on Linux, or target name suffix on Windows
+//! - `GCLOUD_IDENTITY_TOKEN_KEYRING_BACKEND` — Linux keyring: `libsecret` (the default), `kwallet`, or `keyutils` for headless machines without D-Bus
+//! - `GCLOUD_IDENTITY_TOKEN_KEYRING_TIMEOUT` — seconds to wait for the keyring before treating it as unavailable, 20 by default, `0` for no limit
+//! - `GCLOUD_IDENTITY_TOKEN_PORT` — exact loopback port for the login redirect, e.g. `8085`, for OAuth clients registered with a fixed redirect URI
+//! - `GCLOUD_IDENTITY_TOKEN_LOGIN_TIMEOUT` — seconds a browser login waits for its redirect before failing (default 300, `0` for no limit)
+//! - `GCLOUD_IDENTITY_TOKEN_SUCCESS_PAGE` — HTML file the browser shows after a successful login
+//! - `GCLOUD_IDENTITY_TOKEN_ERROR_PAGE` — HTML file the browser shows after a failed login; `{error}` and `{error_description}` are replaced with what Google reported
+//! - `GCLOUD_IDENTITY_TOKEN_PORT_RANGE` — loopback ports to try for the login redirect, e.g. `8085-8095`
+//! - `GCLOUD_IDENTITY_TOKEN_BROWSER` — browser launcher template, e.g. `firefox --new-window %s`
+//! - `GCLOUD_IDENTITY_TOKEN_BROWSER_PROFILE` — browser profile for the login, e.g. `Profile 2`
+//! - `GCLOUD_IDENTITY_TOKEN_LOGIN_FLOW` — `device-code` logs in by entering a code on another device, `manual` by pasting the redirect address, instead of a loopback redirect
+//! - `GCLOUD_IDENTITY_TOKEN_EXPIRY_MARGIN` — seconds of remaining lifetime below which a cached token is refreshed, 300 by default
+//! - `GCLOUD_IDENTITY_TOKEN_DEBUG` — log OAuth HTTP exchanges to stderr, with credentials redacted
+//! - `GCLOUD_IDENTITY_TOKEN_BIND_MACHINE` — `1` encrypts the token file so it only decrypts on this machine
+//! - `GCLOUD_IDENTITY_TOKEN_CACHE_PASSPHRASE` — passphrase the token file is encrypted with (AES-256-GCM, PBKDF2 key)
+//! - `GCLOUD_IDENTITY_TOKEN_CACHE_SECRET` — secret keying the token file's HMAC, so edits are detected
+//! - `GCLOUD_IDENTITY_TOKEN_ON_REFRESH` — shell command run after each refresh or login, with the new tokens in its environment
+//! - `GCLOUD_IDENTITY_TOKEN_USER_AGENT_PRODUCT` — product identifier appended to the `User-Agent`, e.g. `deploy-bot/2.3`
+//! - `GCLOUD_IDENTITY_TOKEN_STORAGE` — `refresh-token-only` keeps access and ID tokens out of the keyring
+//! - `GOOGLE_APPLICATION_CREDENTIALS` — credentials file `adc::default_token_source` tries first
+//! - `GOOGLE_OAUTH_ACCESS_TOKEN`, `CLOUDSDK_AUTH_ACCESS_TOKEN` — access token returned as is, skipping every flow
+//! - `CLOUDSDK_CONFIG` — gcloud configuration directory holding the credentials file
+//! - `CLOUDSDK_CORE_ACCOUNT` — account whose cached token is preferred, as in gcloud
+//! - `CLOUDSDK_AUTH_IMPERSONATE_SERVICE_ACCOUNT` — service account `iam::get_id_tokens` mints tokens as
+//! - `GOOGLE_EXTERNAL_ACCOUNT_ALLOW_EXECUTABLES` — `1` lets `external_account` executable sources run
+//! - `GCE_METADATA_HOST` — metadata server host; `NO_GCE_CHECK=true` never uses the metadata server
+//! - `BROWSER` — conventional browser command, used when no launcher template is set
 //! - `DISPLAY` / `WAYLAND_DISPLAY` — if unset, triggers headless login flow
 //!
 //! ## Modules
 
+/// Application Default Credentials resolution.
+pub mod adc;
+
 /// Authorization flow and token refresh logic.
 pub mod auth;
 
+/// Interactive user credentials for the `google-authz` tower layer.
+#[cfg(feature = "google-authz")]
+pub mod authz;
+
+// AWS credential source of `external_account` configurations.
+mod aws;
+
 /// Browser launching and redirect capture logic.
 pub mod browser;
 
@@ -49,5 +92,77 @@ pub mod cache;
 /// Configuration structures and token types.
 pub mod config;
 
-/// Shared utilities like port picking.
-pub mod shared;
+/// Opt-in HTTP debug logging with credentials redacted.
+pub mod debug;
+
+// Device authorization grant used by `LoginFlow::DeviceCode`.
+mod device;
+
+/// Environment diagnostics for the `doctor` command.
+pub mod doctor;
+
+/// Typed errors that callers can match on.
+pub mod error;
+
+/// Workload identity federation through `external_account` credentials.
+pub mod external;
+
+/// C ABI for non-Rust applications.
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
+/// Read-only access to gcloud's configuration files.
+pub mod gcloud;
+
+/// A token cache shared with gcloud through its credential databases.
+#[cfg(feature = "gcloud-store")]
+pub mod gcloud_store;
+
+/// User command run after each refresh or login.
+pub mod hooks;
+
+/// Service account access and ID tokens minted through the IAM Credentials API.
+pub mod iam;
+
+/// Interactive first-time setup for the `init` command.
+pub mod init;
+
+// macOS Keychain backend used for access-controlled entries.
+#[cfg(target_os = "macos")]
+mod keychain;
+
+/// Shareable token manager for long-running applications.
+pub mod manager;
+
+/// Service account tokens from the Google Cloud metadata server.
+pub mod metadata;
+
+/// JSON output formats for external credential consumers.
+pub mod output;
+
+/// Common re-exports: `use gcloud_identity_token::prelude::*;`.
+pub mod prelude;
+
+/// Removal of revoked, expired, and idle cached accounts.
+pub mod prune;
+
+/// Browser logins relayed to headless machines, end-to-end encrypted.
+pub mod relay;
+
+// Authenticated encryption of the file cache.
+mod seal;
+
+/// Counters of cache hits, refreshes, and logins.
+pub mod stats;
+
+/// OAuth 2.0 token exchange at Google's Security Token Service.
+pub mod sts;
+
+/// `User-Agent` sent with every request, with an optional application product.
+pub mod user_agent;
+
+/// ID token verification with cached Google signing keys.
+pub mod verify;
+
+/// Continuously refreshed token files for sidecar consumers.
+pub mod watch;
@@ -1,10 +1,862 @@
 use anyhow::Result;
-use gcloud_identity_token::{auth::get_token, config::load_creds};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use gcloud_identity_token::{
+    auth::{
+        AccountRenewal, CancellationToken, Renewal, get_id_token_with_options,
+        get_sql_password_with_options, get_token_with_options, impersonate_with_options,
+        load_selected_token, login, renew_all, renew_with_options, revoke_token, select_account,
+    },
+    browser::RedirectPage,
+    cache::{
+        KeychainAccess, StoragePolicy, configure_file_cache, configure_keychain_access,
+        configure_machine_binding, configure_storage_policy, current_account, delete_account,
+        export_adc, import_from_gcloud, list_accounts, set_current_account,
+    },
+    config::{Creds, LoginFlow, LoginOptions, Prompt, load_creds, load_settings},
+    debug::set_debug,
+    doctor::{Status, run_checks},
+    error::AuthError,
+    hooks::set_refresh_hook,
+    init::run_wizard,
+    output::{
+        CredentialProcessOutput, ExecutableResponse, TOKEN_OUTPUT_VERSION, VersionedTokenOutput,
+        executable_interactive, token_output_schema,
+    },
+    prune::{PruneOptions, prune_cache},
+    relay::{Receiver, login_for_remote},
+    stats::stats,
+    verify::{Assurance, VerifyOptions, decode, decode_unverified, verify_id_token},
+    watch::{FileFormat, TokenKind, WatchOptions, watch},
+};
+use std::path::PathBuf;
+
+/// Remaining lifetime below which printed tokens come with a warning.
+const DEFAULT_WARN_WITHIN_MINUTES: i64 = 10;
+
+/// Exit code used when an interactive login is needed but not allowed.
+const EXIT_LOGIN_REQUIRED: i32 = 3;
+
+/// Exit code used when the browser login's redirect never arrives.
+const EXIT_LOGIN_TIMED_OUT: i32 = 4;
+
+/// Exit code used when the user cancels a login with Ctrl-C.
+const EXIT_CANCELLED: i32 = 130;
+
+/// Obtain Google OAuth tokens, printing them as JSON by default.
+///
+/// Only token output goes to stdout; prompts and diagnostics go to stderr.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Add the decoded ID token claims to the JSON output
+    #[arg(long)]
+    include_claims: bool,
+
+    /// Verify the ID token before adding its claims (implies --include-claims)
+    #[arg(long)]
+    verify_claims: bool,
+
+    /// JSON output layout version to write
+    #[arg(long, default_value_t = TOKEN_OUTPUT_VERSION)]
+    output_version: u32,
+
+    /// Print the JSON Schema of the output and exit
+    #[arg(long)]
+    print_schema: bool,
+
+    /// Browser command used for login, e.g. "firefox --new-window %s"
+    #[arg(long, global = true)]
+    browser: Option<String>,
+
+    /// Browser profile to open the login in, e.g. "Profile 2"
+    #[arg(long, global = true)]
+    browser_profile: Option<String>,
+
+    /// Loopback port of the login redirect, for OAuth clients registered
+    /// with an exact redirect URI or forwarded ports [env: GCLOUD_IDENTITY_TOKEN_PORT]
+    #[arg(long, global = true)]
+    port: Option<u16>,
+
+    /// Seconds the browser login waits for its redirect, 0 for no limit
+    /// [default: 300] [env: GCLOUD_IDENTITY_TOKEN_LOGIN_TIMEOUT]
+    #[arg(long, global = true, value_name = "SECONDS")]
+    login_timeout: Option<u64>,
+
+    /// HTML file the browser shows after a successful login
+    /// [env: GCLOUD_IDENTITY_TOKEN_SUCCESS_PAGE]
+    #[arg(long, global = true, value_name = "PATH")]
+    success_page: Option<PathBuf>,
+
+    /// HTML file the browser shows after a failed login, with {error} replaced
+    /// by the error [env: GCLOUD_IDENTITY_TOKEN_ERROR_PAGE]
+    #[arg(long, global = true, value_name = "PATH")]
+    error_page: Option<PathBuf>,
+
+    /// Print access tokens, or ID tokens with --audience, of this service
+    /// account, impersonated with the user's credentials (needs
+    /// roles/iam.serviceAccountTokenCreator)
+    #[arg(long, global = true, value_name = "SA_EMAIL")]
+    impersonate_service_account: Option<String>,
+
+    /// Use the cached tokens of this account instead of the current one,
+    /// logging in to it if needed
+    #[arg(long, global = true, value_name = "EMAIL")]
+    account: Option<String>,
+
+    /// Log in by entering a code on another device instead of a local browser
+    /// (needs a "TVs and Limited Input devices" OAuth client)
+    #[arg(long, global = true, conflicts_with = "no_launch_browser")]
+    device_code: bool,
+
+    /// Print the login URL to open on any machine, then read the address the
+    /// browser was redirected to from the terminal
+    #[arg(long, global = true)]
+    no_launch_browser: bool,
+
+    /// Require Touch ID or the login password to read the cached token (macOS)
+    #[arg(long, global = true)]
+    keychain_require_presence: bool,
+
+    /// Only let this binary read the cached token from the keychain (macOS)
+    #[arg(long, global = true)]
+    keychain_app_only: bool,
+
+    /// Preselect a signed-in browser account by index or email
+    #[arg(long, global = true)]
+    authuser: Option<String>,
+
+    /// Email address to pre-fill on the sign-in page
+    #[arg(long, global = true, value_name = "EMAIL")]
+    login_hint: Option<String>,
+
+    /// Only accept logins to accounts of this Workspace domain
+    #[arg(long, global = true, value_name = "DOMAIN")]
+    hd: Option<String>,
+
+    /// Pages Google shows during login: none, consent (default), or select_account
+    #[arg(long, global = true)]
+    prompt: Option<Prompt>,
+
+    /// Consent screen language, e.g. "de" or "pt-BR"
+    #[arg(long, global = true)]
+    hl: Option<String>,
+
+    /// Require a browser login when the last one is older than this many days
+    #[arg(long, global = true)]
+    max_login_age_days: Option<i64>,
+
+    /// Refuse ID tokens whose acr claim is not this value (repeatable; any one matches)
+    #[arg(long, global = true, value_name = "ACR")]
+    require_acr: Vec<String>,
+
+    /// Refuse ID tokens whose amr claim lacks this method, e.g. "mfa" (repeatable)
+    #[arg(long, global = true, value_name = "AMR")]
+    require_amr: Vec<String>,
+
+    /// Keep only the refresh token in the keyring; access and ID tokens stay in memory
+    #[arg(long, global = true)]
+    store_refresh_token_only: bool,
+
+    /// Encrypt the GCLOUD_IDENTITY_TOKEN_PATH file so it only decrypts on this machine
+    #[arg(long, global = true)]
+    bind_to_machine: bool,
+
+    /// Extra authorization URL parameter, e.g. "hl=de" (repeatable)
+    #[arg(long = "auth-param", global = true, value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    auth_params: Vec<(String, String)>,
+
+    /// Extra form field for the authorization code exchange (repeatable)
+    #[arg(long = "token-param", global = true, value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    token_params: Vec<(String, String)>,
+
+    /// Shell command to run after each refresh or login, with the new expiry and
+    /// tokens in GCLOUD_IDENTITY_TOKEN_* environment variables
+    #[arg(long, global = true, value_name = "COMMAND")]
+    on_refresh_exec: Option<String>,
+
+    /// Log OAuth HTTP exchanges to stderr, with tokens and secrets redacted
+    #[arg(long, global = true)]
+    debug: bool,
+
+    /// Print cache hit, refresh, and login counts to stderr on exit
+    #[arg(long, global = true)]
+    stats: bool,
+
+    /// Output format for `whoami` and errors: text, or JSON on stdout
+    #[arg(long, global = true, value_enum, default_value_t = Format::Text)]
+    format: Format,
+}
+
+/// Near-expiry handling for printed tokens.
+#[derive(Args)]
+struct ExpiryArgs {
+    /// Warn on stderr when the token expires within this many minutes
+    #[arg(long, default_value_t = DEFAULT_WARN_WITHIN_MINUTES)]
+    warn_within_minutes: i64,
+
+    /// Refresh first if the token expires within the warning window
+    #[arg(long)]
+    refresh: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print just the access token
+    PrintAccessToken(ExpiryArgs),
+
+    /// Print just the ID token
+    PrintIdentityToken {
+        #[command(flatten)]
+        expiry: ExpiryArgs,
+
+        /// Mint the token for this audience, e.g. a Cloud Run URL or an IAP
+        /// client ID, as the impersonated or metadata server service account
+        #[arg(long)]
+        audience: Option<String>,
+    },
+
+    /// Print a Cloud SQL IAM database password (a `sqlservice.login` access token)
+    SqlPassword,
+
+    /// Refresh the cached tokens of all accounts close to expiry, without opening a browser
+    ///
+    /// Intended for cron or systemd timers. Exits 0 when every token is fresh or
+    /// was refreshed, 3 when nothing is cached or every failed account needs an
+    /// interactive login, and 1 on other errors.
+    Renew {
+        /// Refresh tokens expiring within this many minutes
+        #[arg(long, default_value_t = 15)]
+        within_minutes: i64,
+    },
+
+    /// Print a token as `credential_process` JSON (Version, AccessToken, Expiration)
+    CredentialProcess {
+        /// Token to print: access or id
+        #[arg(long, default_value = "access")]
+        token: TokenKind,
+    },
+
+    /// Print an ID token as an executable-sourced credential response
+    ///
+    /// For use as `credential_source.executable.command` in an external_account
+    /// configuration. Errors are reported in the response JSON, with exit code 1.
+    /// A browser login only happens when GOOGLE_EXTERNAL_ACCOUNT_INTERACTIVE=1.
+    ExecutableCredential,
+
+    /// Show which account the cached token belongs to, without logging in
+    Whoami,
+
+    /// Check the environment for common login problems
+    ///
+    /// Exits 1 if any check fails.
+    Doctor,
+
+    /// Set up credentials, the token cache, and default scopes interactively
+    ///
+    /// Writes the settings file and, unless declined, logs in once at the end.
+    Init,
+
+    /// Keep a file updated with a fresh token until interrupted
+    Watch {
+        /// File to write the token to (replaced atomically)
+        #[arg(long)]
+        output: PathBuf,
+
+        /// Token to write: access or id
+        #[arg(long, default_value = "access")]
+        token: TokenKind,
+
+        /// File format: raw or json
+        #[arg(long, default_value = "raw")]
+        file_format: FileFormat,
+
+        /// Refresh the token this many minutes before it expires
+        #[arg(long, default_value_t = 5)]
+        refresh_minutes: i64,
+    },
+
+    /// Log in now, even if a token is cached, or for a machine running `receive`
+    ///
+    /// Each account is cached separately, and the one logged in last is used.
+    /// With --remote, prints an encrypted reply to paste into `receive`
+    /// instead; nothing is cached here.
+    Login {
+        /// Pairing code shown by `receive` on the other machine
+        #[arg(long, value_name = "PAIRING_CODE")]
+        remote: Option<String>,
+
+        /// Show Google's account chooser to pick the account to log in with
+        #[arg(long, conflicts_with = "remote")]
+        select_account: bool,
+    },
+
+    /// Revoke the cached login at Google and remove it from the cache
+    Logout,
+
+    /// Accept a login performed on another machine with `login --remote`
+    ///
+    /// For machines without a browser: shows a pairing code, then reads the
+    /// reply from stdin and caches the tokens.
+    Receive,
+
+    /// Manage cached tokens
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// List the accounts with a cached token, marking the current one
+    List,
+
+    /// Delete the cached tokens of an account, from every OAuth client,
+    /// without revoking them at Google
+    Delete {
+        /// Account to delete
+        email: String,
+    },
+
+    /// Copy an account's refresh token from gcloud into the cache, so no
+    /// browser login is needed
+    ///
+    /// Reads gcloud's credentials.db, else the application default
+    /// credentials file. The imported tokens belong to gcloud's OAuth client.
+    Import {
+        /// Account to import; gcloud's active account by default
+        email: Option<String>,
+    },
+
+    /// Write the cached refresh token as an authorized_user credentials file,
+    /// for Google's SDKs and tools that read GOOGLE_APPLICATION_CREDENTIALS
+    Export {
+        /// File to write, replaced if it exists
+        path: PathBuf,
+    },
+
+    /// Make a cached account the current one, used ahead of gcloud's active
+    /// account and the last login
+    Use {
+        /// Account to use
+        #[arg(required_unless_present = "clear")]
+        email: Option<String>,
+
+        /// Go back to gcloud's active account or the last login
+        #[arg(long, conflicts_with = "email")]
+        clear: bool,
+    },
+
+    /// Remove cached accounts whose tokens are unusable or unused, listing them
+    ///
+    /// Entries without a refresh token are removed once they expire, and
+    /// accounts whose last refresh was rejected right away. Only the
+    /// keyring is pruned, not a GCLOUD_IDENTITY_TOKEN_PATH file.
+    Prune {
+        /// Also remove accounts not used for this many days
+        #[arg(long)]
+        older_than_days: Option<i64>,
+
+        /// Ask Google whether each refresh token is still valid
+        #[arg(long)]
+        check_refresh: bool,
+    },
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    set_debug(cli.debug);
+    let (format, print_stats) = (cli.format, cli.stats);
+
+    if cli.print_schema {
+        println!("{}", serde_json::to_string_pretty(&token_output_schema())?);
+        return Ok(());
+    }
+
+    let res = match cli.command {
+        Some(Command::Doctor) => doctor().await,
+        Some(Command::Init) => init(cli).await,
+        _ => start(cli).await,
+    };
+    if print_stats {
+        eprintln!("{}", stats());
+    }
+
+    let Err(err) = res else {
+        return Ok(());
+    };
+    let kind = err.downcast_ref::<AuthError>();
+    let code = match kind {
+        Some(AuthError::Cancelled) => EXIT_CANCELLED,
+        Some(AuthError::LoginRequired) => EXIT_LOGIN_REQUIRED,
+        Some(AuthError::LoginTimedOut) => EXIT_LOGIN_TIMED_OUT,
+        Some(AuthError::AuthorizationFailed { .. }) | None => 1,
+    };
+
+    match (format, kind) {
+        (Format::Json, _) => {
+            let error = serde_json::json!({
+                "error": {
+                    "kind": kind.map_or("error", AuthError::kind),
+                    "message": format!("{err:#}"),
+                }
+            });
+            println!("{error}");
+        }
+        (Format::Text, Some(AuthError::Cancelled)) => eprintln!("Login cancelled."),
+        (Format::Text, Some(AuthError::LoginRequired)) => {
+            eprintln!("{err}: run gcloud-identity-token to log in.");
+        }
+        (Format::Text, Some(AuthError::LoginTimedOut)) => {
+            eprintln!("{err}; run it again, or raise --login-timeout.");
+        }
+        (Format::Text, Some(AuthError::AuthorizationFailed { .. })) => eprintln!("{err}."),
+        (Format::Text, None) => return Err(err),
+    }
+    std::process::exit(code);
+}
+
+/// Load credentials, apply global options, and run the requested command.
+async fn start(cli: Cli) -> Result<()> {
     let creds = load_creds()?;
-    let token = get_token(&creds).await?;
-    println!("{}", serde_json::to_string_pretty(&token)?);
+    let settings = load_settings()?;
+    if settings.cache_file.is_some() {
+        configure_file_cache(settings.cache_file);
+    }
+    configure_keychain_access(KeychainAccess {
+        require_user_presence: cli.keychain_require_presence,
+        restrict_to_creating_app: cli.keychain_app_only,
+    })?;
+    if cli.store_refresh_token_only {
+        configure_storage_policy(StoragePolicy::RefreshTokenOnly);
+    }
+    if cli.bind_to_machine {
+        configure_machine_binding(true);
+    }
+    if cli.on_refresh_exec.is_some() {
+        set_refresh_hook(cli.on_refresh_exec.clone());
+    }
+
+    let opts = LoginOptions {
+        browser: cli.browser.clone(),
+        browser_profile: cli.browser_profile.clone(),
+        authuser: cli.authuser.clone(),
+        locale: cli.hl.clone(),
+        login_hint: cli.login_hint.clone(),
+        hosted_domain: cli.hd.clone(),
+        prompt: cli.prompt,
+        scopes: settings.scopes,
+        account: cli.account.clone(),
+        flow: if cli.device_code {
+            Some(LoginFlow::DeviceCode)
+        } else {
+            cli.no_launch_browser.then_some(LoginFlow::Manual)
+        },
+        no_login: false,
+        expiry_margin: None,
+        max_age: cli.max_login_age_days.map(chrono::Duration::days),
+        assurance: Assurance {
+            acr_values: cli.require_acr.clone(),
+            amr_values: cli.require_amr.clone(),
+        },
+        extra_auth_params: cli.auth_params.clone(),
+        extra_token_params: cli.token_params.clone(),
+        cancel: CancellationToken::new(),
+        redirect_port: cli.port,
+        success_page: cli.success_page.clone().map(RedirectPage::File),
+        error_page: cli.error_page.clone().map(RedirectPage::File),
+        login_timeout: cli.login_timeout.map(std::time::Duration::from_secs),
+    };
+    tokio::spawn(cancel_on_ctrl_c(opts.cancel.clone()));
+
+    run(cli, &creds, &opts).await
+}
+
+async fn run(cli: Cli, creds: &Creds, opts: &LoginOptions) -> Result<()> {
+    match cli.command {
+        Some(Command::PrintAccessToken(expiry)) => match &cli.impersonate_service_account {
+            Some(sa) => println!(
+                "{}",
+                impersonate_with_options(creds, opts, sa, &[])
+                    .await?
+                    .access_token
+            ),
+            None => print_token(creds, opts, TokenKind::Access, &expiry).await?,
+        },
+        Some(Command::PrintIdentityToken { expiry, audience }) => {
+            match (audience, &cli.impersonate_service_account) {
+                (Some(audience), sa) => println!(
+                    "{}",
+                    get_id_token_with_options(creds, opts, sa.as_deref(), &audience).await?
+                ),
+                (None, Some(_)) => {
+                    return Err(anyhow::anyhow!("An impersonated ID token needs --audience"));
+                }
+                (None, None) => print_token(creds, opts, TokenKind::Id, &expiry).await?,
+            }
+        }
+        Some(Command::SqlPassword) => {
+            println!("{}", get_sql_password_with_options(creds, opts).await?);
+        }
+        Some(Command::Renew { within_minutes }) => {
+            renew_accounts(creds, chrono::Duration::minutes(within_minutes)).await?;
+        }
+        Some(Command::CredentialProcess { token: kind }) => {
+            let token = get_token_with_options(creds, opts).await?;
+            let output = CredentialProcessOutput::new(&token, kind)?;
+            println!("{}", serde_json::to_string(&output)?);
+        }
+        Some(Command::ExecutableCredential) => {
+            if let Err(err) = executable_credential(creds, opts).await {
+                let code = match err.downcast_ref() {
+                    Some(AuthError::LoginRequired) => "401",
+                    Some(AuthError::Cancelled) => "CANCELLED",
+                    Some(AuthError::LoginTimedOut) => "TIMEOUT",
+                    Some(AuthError::AuthorizationFailed { error, .. }) => error,
+                    None => "ERROR",
+                };
+                ExecutableResponse::error(code, format!("{err:#}")).emit()?;
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Whoami) => whoami(creds, opts, cli.format).await?,
+        Some(Command::Doctor) => unreachable!("doctor runs before credentials are loaded"),
+        Some(Command::Init) => {
+            let token = get_token_with_options(creds, opts).await?;
+            let claims = decode_unverified(token.require_id_token()?)?;
+            let email = claims["email"]
+                .as_str()
+                .unwrap_or("an account without an email");
+            eprintln!("Logged in as {email}.");
+        }
+        Some(Command::Watch {
+            output,
+            token,
+            file_format,
+            refresh_minutes,
+        }) => {
+            let watch_opts = WatchOptions {
+                output,
+                token,
+                format: file_format,
+                refresh_margin: chrono::Duration::minutes(refresh_minutes),
+            };
+            watch(creds, opts, &watch_opts).await?;
+        }
+        Some(Command::Login {
+            remote: Some(remote),
+            ..
+        }) => {
+            println!("{}", login_for_remote(creds, opts, &remote).await?);
+            eprintln!("Paste the line above into `receive` on the other machine.");
+        }
+        Some(Command::Login {
+            remote: None,
+            select_account: choose,
+        }) => {
+            let token = if choose {
+                select_account(creds, opts).await?
+            } else {
+                login(creds, opts).await?
+            };
+            let claims = decode_unverified(token.require_id_token()?)?;
+            match claims["email"].as_str() {
+                Some(email) => eprintln!("Logged in as {email}."),
+                None => eprintln!("Logged in."),
+            }
+        }
+        Some(Command::Receive) => receive(creds)?,
+        Some(Command::Logout) => {
+            if revoke_token(creds).await? {
+                eprintln!("Logged out; the grant was revoked at Google.");
+            } else {
+                eprintln!("Not logged in.");
+            }
+        }
+        Some(Command::Cache {
+            command: CacheCommand::Use { email, .. },
+        }) => {
+            set_current_account(email.as_deref())?;
+            match email {
+                Some(email) => eprintln!("Now using {email}."),
+                None => eprintln!("Using gcloud's active account or the last login."),
+            }
+        }
+        Some(Command::Cache {
+            command: CacheCommand::List,
+        }) => {
+            let accounts = list_accounts();
+            match cli.format {
+                Format::Json => println!("{}", serde_json::to_string_pretty(&accounts)?),
+                Format::Text => {
+                    let current = current_account();
+                    for account in &accounts {
+                        let is_current = current.as_deref().is_some_and(|current| {
+                            current == account.user || Some(current) == account.email.as_deref()
+                        });
+                        let marker = if is_current { '*' } else { ' ' };
+                        let last_used = account
+                            .last_used
+                            .map_or_else(|| "unknown".to_string(), |at| at.to_rfc3339());
+                        println!("{marker} {}  last used {last_used}", account.display_name());
+                    }
+                }
+            }
+        }
+        Some(Command::Cache {
+            command: CacheCommand::Delete { email },
+        }) => {
+            delete_account(&email)?;
+            eprintln!("Deleted the cached tokens of {email}.");
+        }
+        Some(Command::Cache {
+            command: CacheCommand::Export { path },
+        }) => {
+            let account = export_adc(creds, &path)?;
+            eprintln!(
+                "Wrote the credentials of {} to {}.",
+                account.as_deref().unwrap_or("the cached account"),
+                path.display()
+            );
+        }
+        Some(Command::Cache {
+            command: CacheCommand::Import { email },
+        }) => {
+            let imported = import_from_gcloud(email.as_deref()).await?;
+            eprintln!("Imported {} from gcloud.", imported.account);
+            if imported.creds.client_id != creds.client_id {
+                eprintln!(
+                    "Warning: its tokens belong to OAuth client {}, not the configured {}.",
+                    imported.creds.client_id, creds.client_id
+                );
+            }
+        }
+        Some(Command::Cache {
+            command:
+                CacheCommand::Prune {
+                    older_than_days,
+                    check_refresh,
+                },
+        }) => {
+            let prune_opts = PruneOptions {
+                max_idle: older_than_days.map(chrono::Duration::days),
+                check_refresh,
+            };
+            let pruned = prune_cache(creds, &prune_opts).await?;
+            match cli.format {
+                Format::Json => println!("{}", serde_json::to_string_pretty(&pruned)?),
+                Format::Text => {
+                    for account in &pruned {
+                        println!(
+                            "removed {} ({})",
+                            account.email.as_deref().unwrap_or(&account.user),
+                            account.reason
+                        );
+                    }
+                }
+            }
+        }
+        None => {
+            let token = get_token_with_options(creds, opts).await?;
+            let claims = if cli.verify_claims {
+                let audience = VerifyOptions::for_audience(creds.client_id.clone());
+                Some(decode(token.require_id_token()?, &audience).await?)
+            } else if cli.include_claims {
+                Some(decode_unverified(token.require_id_token()?)?)
+            } else {
+                None
+            };
+            let output = VersionedTokenOutput::new(&token, claims, cli.output_version)?;
+            println!("{}", serde_json::to_string_pretty(&output)?);
+        }
+    }
     Ok(())
 }
+
+/// Renew every cached account, reporting each on stderr, and fail if any failed.
+async fn renew_accounts(creds: &Creds, margin: chrono::Duration) -> Result<()> {
+    let renewals = renew_all(creds, margin).await;
+    if renewals.is_empty() {
+        return Err(AuthError::LoginRequired.into());
+    }
+    let mut failed = 0;
+    let mut login_required = 0;
+    for AccountRenewal { account, result } in &renewals {
+        match result {
+            Ok(Renewal::StillFresh) => eprintln!("{account}: still fresh"),
+            Ok(Renewal::Refreshed) => eprintln!("{account}: refreshed"),
+            Err(err) => {
+                failed += 1;
+                if matches!(err.downcast_ref(), Some(AuthError::LoginRequired)) {
+                    login_required += 1;
+                }
+                eprintln!("{account}: {err:#}");
+            }
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        _ if failed == login_required => Err(AuthError::LoginRequired.into()),
+        _ => Err(anyhow::anyhow!(
+            "{failed} of {} accounts could not be renewed",
+            renewals.len()
+        )),
+    }
+}
+
+/// Print a single token, warning when it is about to expire.
+async fn print_token(
+    creds: &Creds,
+    opts: &LoginOptions,
+    kind: TokenKind,
+    expiry: &ExpiryArgs,
+) -> Result<()> {
+    let window = chrono::Duration::minutes(expiry.warn_within_minutes);
+    if expiry.refresh {
+        match renew_with_options(creds, opts, window).await {
+            // With nothing cached, get_token below runs a login instead.
+            Err(err) if !matches!(err.downcast_ref(), Some(AuthError::LoginRequired)) => {
+                return Err(err);
+            }
+            _ => {}
+        }
+    }
+
+    let token = get_token_with_options(creds, opts).await?;
+    let remaining = token.token_expiry - chrono::Utc::now();
+    if remaining < window {
+        eprintln!(
+            "Warning: this token expires in {} minutes, at {}. Pass --refresh to get a fresh one.",
+            remaining.num_minutes(),
+            token.token_expiry
+        );
+    }
+
+    match kind {
+        TokenKind::Access => println!("{}", token.access_token),
+        TokenKind::Id => println!("{}", token.require_id_token()?),
+    }
+    Ok(())
+}
+
+/// Print the identity of the cached ID token after verifying it.
+///
+/// An expired token is refreshed first, but a browser login is never started.
+async fn whoami(creds: &Creds, opts: &LoginOptions, format: Format) -> Result<()> {
+    renew_with_options(creds, opts, chrono::Duration::minutes(1)).await?;
+    let saved = load_selected_token(creds, opts).ok_or(AuthError::LoginRequired)?;
+    let id_token = saved
+        .id_token
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("The cached token has no ID token; log in again"))?;
+    let claims = verify_id_token(
+        id_token,
+        &VerifyOptions::for_audience(creds.client_id.clone()),
+    )
+    .await?;
+    let remaining = (saved.token_expiry - chrono::Utc::now()).num_minutes();
+
+    match format {
+        Format::Json => {
+            let identity = serde_json::json!({
+                "email": claims.email,
+                "sub": claims.sub,
+                "hd": claims.hd,
+                "token_expiry": saved.token_expiry,
+            });
+            println!("{}", serde_json::to_string_pretty(&identity)?);
+        }
+        Format::Text => {
+            println!("email:   {}", claims.email.as_deref().unwrap_or("(none)"));
+            println!("subject: {}", claims.sub);
+            println!("domain:  {}", claims.hd.as_deref().unwrap_or("(none)"));
+            println!("expires: {} ({remaining} minutes left)", saved.token_expiry);
+        }
+    }
+    Ok(())
+}
+
+/// Show a pairing code, then cache the tokens from the pasted reply.
+fn receive(creds: &Creds) -> Result<()> {
+    let receiver = Receiver::new()?;
+    eprintln!("On a machine with a browser, run:\n");
+    eprintln!(
+        "    gcloud-identity-token login --remote {}\n",
+        receiver.pairing_code()
+    );
+    eprintln!("Then paste the reply here:");
+    let mut reply = String::new();
+    std::io::stdin().read_line(&mut reply)?;
+    let token = receiver.accept(&reply, creds)?;
+    eprintln!("Login received; tokens valid until {}.", token.token_expiry);
+    Ok(())
+}
+
+/// Print every diagnostic and fail if any check failed.
+async fn doctor() -> Result<()> {
+    let checks = run_checks().await;
+    for check in &checks {
+        println!("{check}");
+    }
+    if checks.iter().any(|check| check.status == Status::Fail) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Run the setup wizard, then log in through the new settings if asked to.
+async fn init(cli: Cli) -> Result<()> {
+    let setup = run_wizard(&mut std::io::stdin().lock())?;
+    if setup.login_now {
+        start(cli).await?;
+    }
+    eprintln!("Setup complete. Run `gcloud-identity-token doctor` to check it.");
+    Ok(())
+}
+
+/// Print a successful executable response, never opening a browser unless
+/// the calling library allows interaction.
+async fn executable_credential(creds: &Creds, opts: &LoginOptions) -> Result<()> {
+    let opts = LoginOptions {
+        no_login: !executable_interactive(),
+        ..opts.clone()
+    };
+    let token = get_token_with_options(creds, &opts).await?;
+    ExecutableResponse::success(&token)?.emit()
+}
+
+/// Parse a `KEY=VALUE` command-line parameter.
+fn parse_key_value(arg: &str) -> Result<(String, String), String> {
+    arg.split_once('=')
+        .filter(|(key, _)| !key.is_empty())
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got {arg:?}"))
+}
+
+/// Trap SIGINT so an interrupted login shuts the loopback server down cleanly.
+async fn cancel_on_ctrl_c(cancel: CancellationToken) {
+    if tokio::signal::ctrl_c().await.is_ok() {
+        cancel.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_definition_is_consistent() {
+        Cli::command().debug_assert();
+    }
+}
@@ -0,0 +1,167 @@
+//! A shareable handle for applications that need tokens from many tasks.
+
+use crate::auth::{cached_token, get_owned_token};
+use crate::config::{Creds, LoginOptions, OwnedToken};
+use crate::stats::{self, Stats};
+use anyhow::Result;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Minimum remaining lifetime [`TokenManager::warm`] leaves the cached token with.
+const WARM_MARGIN: chrono::Duration = chrono::Duration::minutes(10);
+
+/// Cheaply cloneable token source for application state.
+///
+/// Clones share one set of credentials and one refresh lock, so when many
+/// requests find the token expired at once only the first refreshes (or logs
+/// in) and the rest are served the result from the cache. Requests that find
+/// a valid token are served without taking the lock.
+///
+/// ```rust,no_run
+/// use gcloud_identity_token::{config::load_creds, manager::TokenManager};
+///
+/// # async fn run() -> anyhow::Result<()> {
+/// let manager = TokenManager::new(load_creds()?);
+/// let handle = manager.clone();
+/// tokio::spawn(async move { handle.access_token().await });
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct TokenManager {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    creds: Creds,
+    opts: LoginOptions,
+    refresh: Mutex<()>,
+}
+
+impl TokenManager {
+    /// Create a manager with default login options.
+    pub fn new(creds: Creds) -> Self {
+        Self::with_options(creds, LoginOptions::default())
+    }
+
+    /// Create a manager that logs in with `opts`.
+    pub fn with_options(creds: Creds, opts: LoginOptions) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                creds,
+                opts,
+                refresh: Mutex::new(()),
+            }),
+        }
+    }
+
+    /// A cached, refreshed, or freshly logged-in token.
+    ///
+    /// Unlike the free functions in [`crate::auth`], the token owns its
+    /// strings, so calling this for every request does not leak memory.
+    pub async fn token(&self) -> Result<OwnedToken> {
+        let Inner { creds, opts, .. } = &*self.inner;
+        if let Some(token) = cached_token(creds, opts) {
+            return Ok(token);
+        }
+        let _guard = self.inner.refresh.lock().await;
+        // The task that held the lock may have just refreshed the token.
+        if let Some(token) = cached_token(creds, opts) {
+            return Ok(token);
+        }
+        get_owned_token(creds, opts).await
+    }
+
+    /// Make sure a token is cached and has at least ten minutes left, refreshing
+    /// or logging in now rather than on the first real request.
+    ///
+    /// Call at application startup or before a batch job so credential
+    /// problems surface early.
+    pub async fn warm(&self) -> Result<()> {
+        // The token of the usual lookup, only treated as expired earlier, so
+        // it is refreshed, or a login run, just as a request would.
+        let opts = LoginOptions {
+            expiry_margin: Some(self.inner.opts.expiry_margin().max(WARM_MARGIN)),
+            ..self.inner.opts.clone()
+        };
+        let _guard = self.inner.refresh.lock().await;
+        get_owned_token(&self.inner.creds, &opts).await.map(drop)
+    }
+
+    /// The current access token.
+    pub async fn access_token(&self) -> Result<String> {
+        Ok(self.token().await?.access_token)
+    }
+
+    /// The current ID token.
+    pub async fn id_token(&self) -> Result<String> {
+        Ok(self.token().await?.require_id_token()?.to_string())
+    }
+
+    /// Cache and token endpoint counters.
+    ///
+    /// The counters are process-wide, so they include activity from every
+    /// manager and from the free functions in [`crate::auth`].
+    pub fn stats(&self) -> Stats {
+        stats::stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_manager_is_send_sync_clone() {
+        fn assert_shareable<T: Clone + Send + Sync + 'static>() {}
+        assert_shareable::<TokenManager>();
+    }
+
+    #[tokio::test]
+    async fn test_cache_hits_do_not_wait_for_a_refresh() {
+        use crate::cache::{TokenCache, shared_test_cache};
+        use crate::config::SavedToken;
+
+        let client_id = "manager.apps.googleusercontent.com";
+        let token = SavedToken {
+            refresh_token: String::new(),
+            access_token: "cached".into(),
+            id_token: None,
+            token_expiry: chrono::Utc::now() + chrono::Duration::hours(1),
+            refresh_token_issued_at: None,
+            granted_scopes: Vec::new(),
+            requested_scopes: Vec::new(),
+            client_id: client_id.into(),
+        };
+        shared_test_cache()
+            .save(&format!("c@example.com:{client_id}"), &token)
+            .unwrap();
+        let creds = Creds {
+            client_id: client_id.into(),
+            client_secret: String::new(),
+            refresh_token: None,
+        };
+        let manager = TokenManager::with_options(
+            creds,
+            LoginOptions {
+                account: Some("c@example.com".into()),
+                ..LoginOptions::default()
+            },
+        );
+
+        // A login in progress elsewhere holds the lock throughout.
+        let _refreshing = manager.inner.refresh.lock().await;
+        let hits: Vec<_> = (0..4)
+            .map(|_| {
+                let manager = manager.clone();
+                tokio::spawn(async move { manager.access_token().await })
+            })
+            .collect();
+        for hit in hits {
+            let token = tokio::time::timeout(std::time::Duration::from_secs(5), hit)
+                .await
+                .expect("a cache hit waited for the refresh lock");
+            assert_eq!(token.unwrap().unwrap(), "cached");
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! Tokens from the metadata server on GCE, GKE, Cloud Run, and Cloud Functions.
+//!
+//! The metadata server hands out tokens of the workload's attached service
+//! account without any login. It is only probed when the environment looks
+//! like Google Cloud, so runs on a workstation never wait on it.
+
+use crate::config::{OwnedToken, TokenOutput};
+use crate::debug;
+use crate::stats::{self, Event};
+use anyhow::{Result, anyhow};
+use chrono::{Duration, Utc};
+use reqwest::Client;
+use serde::Deserialize;
+use tokio::sync::OnceCell;
+
+/// Environment variable overriding the metadata server's host, as in
+/// Google's client libraries.
+pub const METADATA_HOST_ENV: &str = "GCE_METADATA_HOST";
+
+/// Environment variable that disables the metadata server when `true`.
+pub const NO_GCE_CHECK_ENV: &str = "NO_GCE_CHECK";
+
+const DEFAULT_METADATA_HOST: &str = "169.254.169.254";
+
+/// Header every metadata request and response carries.
+const METADATA_FLAVOR: (&str, &str) = ("Metadata-Flavor", "Google");
+
+/// How long the detection probe may take.
+const PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Environment variables set by Google's serverless runtimes.
+const SERVERLESS_ENVS: &[&str] = &["K_SERVICE", "CLOUD_RUN_JOB", "FUNCTION_TARGET"];
+
+/// Where GCE machines, including GKE nodes, name their product.
+const DMI_PRODUCT_NAME: &str = "/sys/class/dmi/id/product_name";
+
+static AVAILABLE: OnceCell<bool> = OnceCell::const_new();
+
+#[derive(Deserialize)]
+struct MetadataTokenResponse {
+    access_token: String,
+    expires_in: i64,
+}
+
+/// Whether a metadata server is reachable, probed once per process.
+///
+/// Only probed with `GCE_METADATA_HOST` set or on a machine that identifies
+/// as Google Cloud; `NO_GCE_CHECK=true` turns it off.
+pub async fn metadata_server_available() -> bool {
+    *AVAILABLE
+        .get_or_init(|| async {
+            if std::env::var(NO_GCE_CHECK_ENV).is_ok_and(|value| value.eq_ignore_ascii_case("true"))
+            {
+                return false;
+            }
+            if std::env::var(METADATA_HOST_ENV).is_err() && !looks_like_google_cloud() {
+                return false;
+            }
+            probe().await
+        })
+        .await
+}
+
+/// Serverless runtime variables, or a GCE product name in the DMI tables.
+fn looks_like_google_cloud() -> bool {
+    SERVERLESS_ENVS
+        .iter()
+        .any(|name| std::env::var_os(name).is_some())
+        || std::fs::read_to_string(DMI_PRODUCT_NAME)
+            .is_ok_and(|name| name.trim().starts_with("Google"))
+}
+
+async fn probe() -> bool {
+    let req = client()
+        .get(base_url())
+        .header(METADATA_FLAVOR.0, METADATA_FLAVOR.1);
+    match tokio::time::timeout(PROBE_TIMEOUT, debug::send(req)).await {
+        Ok(Ok(res)) => res
+            .headers()
+            .get(METADATA_FLAVOR.0)
+            .is_some_and(|flavor| flavor == METADATA_FLAVOR.1),
+        _ => false,
+    }
+}
+
+/// Fetch the attached service account's access token, and an ID token for
+/// `audience` when one is given.
+///
+/// `scopes` may be empty for the instance's default scopes; Cloud Run and
+/// GKE honour requested scopes, classic GCE instances ignore them.
+pub async fn get_token_from_metadata(
+    scopes: &[&str],
+    audience: Option<&str>,
+) -> Result<TokenOutput<'static>> {
+    metadata_token(scopes, audience).await.map(OwnedToken::leak)
+}
+
+/// Like [`get_token_from_metadata`], returning owned strings.
+pub(crate) async fn metadata_token(scopes: &[&str], audience: Option<&str>) -> Result<OwnedToken> {
+    fetch(&base_url(), scopes, audience)
+        .await
+        .inspect_err(|_| stats::record(Event::Failure))
+}
+
+/// Fetch the tokens from the metadata server at `base`.
+async fn fetch(base: &str, scopes: &[&str], audience: Option<&str>) -> Result<OwnedToken> {
+    let client = client();
+    let account = format!("{base}/instance/service-accounts/default");
+
+    let mut req = client.get(format!("{account}/token"));
+    if !scopes.is_empty() {
+        req = req.query(&[("scopes", scopes.join(","))]);
+    }
+    let res = debug::send(req.header(METADATA_FLAVOR.0, METADATA_FLAVOR.1)).await?;
+    if !res.status().is_success() {
+        return Err(anyhow!(
+            "Metadata server token request failed with HTTP {}",
+            res.status()
+        ));
+    }
+    let token = res.json::<MetadataTokenResponse>().await?;
+
+    let id_token = match audience {
+        Some(audience) => Some(fetch_id_token(&client, base, audience).await?),
+        None => None,
+    };
+
+    stats::record(Event::Refresh);
+    Ok(OwnedToken {
+        access_token: token.access_token,
+        id_token,
+        token_expiry: Utc::now() + Duration::seconds(token.expires_in),
+    })
+}
+
+/// Fetch an ID token for `audience` of the attached service account.
+pub async fn get_id_token_from_metadata(audience: &str) -> Result<String> {
+    fetch_id_token(&client(), &base_url(), audience)
+        .await
+        .inspect_err(|_| stats::record(Event::Failure))
+}
+
+async fn fetch_id_token(client: &Client, base: &str, audience: &str) -> Result<String> {
+    let res = debug::send(
+        client
+            .get(format!("{base}/instance/service-accounts/default/identity"))
+            .query(&[("audience", audience), ("format", "full")])
+            .header(METADATA_FLAVOR.0, METADATA_FLAVOR.1),
+    )
+    .await?;
+    if !res.status().is_success() {
+        return Err(anyhow!(
+            "Metadata server identity request failed with HTTP {}",
+            res.status()
+        ));
+    }
+    Ok(res.text().await?)
+}
+
+/// The metadata server's URL, on the host `GCE_METADATA_HOST` names.
+fn base_url() -> String {
+    base_url_from(std::env::var(METADATA_HOST_ENV).ok())
+}
+
+/// [`base_url`], with `host` as the value of `GCE_METADATA_HOST`.
+fn base_url_from(host: Option<String>) -> String {
+    let host = host
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| DEFAULT_METADATA_HOST.to_string());
+    format!("http://{host}/computeMetadata/v1")
+}
+
+/// A client that never sends metadata requests through a proxy.
+fn client() -> Client {
+    Client::builder()
+        .no_proxy()
+        .build()
+        .unwrap_or_else(|_| Client::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    /// Answer each connection with the next canned response, returning the
+    /// request lines seen.
+    fn fake_server(responses: Vec<&'static str>) -> (String, std::thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        let handle = std::thread::spawn(move || {
+            let mut seen = Vec::new();
+            for body in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                seen.push(line.trim().to_string());
+                while {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    header != "\r\n"
+                } {}
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nMetadata-Flavor: Google\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+            }
+            seen
+        });
+        (host, handle)
+    }
+
+    #[tokio::test]
+    async fn test_fetch_reads_token_and_identity() {
+        let (host, server) = fake_server(vec![
+            r#"{"access_token":"ya29.sa","expires_in":3599,"token_type":"Bearer"}"#,
+            "header.claims.sig",
+        ]);
+        let token = fetch(&base_url_from(Some(host)), &[], Some("client-id"))
+            .await
+            .unwrap();
+
+        assert_eq!(token.access_token, "ya29.sa");
+        assert_eq!(token.id_token.as_deref(), Some("header.claims.sig"));
+        let seen = server.join().unwrap();
+        assert!(seen[0].contains("/service-accounts/default/token"));
+        assert!(seen[1].contains("audience=client-id"));
+    }
+
+    #[test]
+    fn test_base_url_defaults_to_link_local_host() {
+        assert_eq!(
+            base_url_from(None),
+            "http://169.254.169.254/computeMetadata/v1"
+        );
+        assert_eq!(base_url_from(Some(String::new())), base_url_from(None));
+        assert_eq!(
+            base_url_from(Some("metadata.google.internal".into())),
+            "http://metadata.google.internal/computeMetadata/v1"
+        );
+    }
+}
@@ -0,0 +1,274 @@
+//! Token output in the JSON shapes other tools expect from a credential helper.
+
+use crate::config::TokenOutput;
+use crate::watch::{TokenKind, write_atomic};
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::path::Path;
+
+/// Current version of the CLI's default JSON output.
+///
+/// Fields may be added within a version; removing a field or changing its
+/// meaning bumps the version.
+pub const TOKEN_OUTPUT_VERSION: u32 = 1;
+
+/// Token type for OIDC ID tokens in executable-sourced credentials.
+pub const ID_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:id_token";
+
+/// Token type for generic JWTs in executable-sourced credentials.
+pub const JWT_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:jwt";
+
+/// Environment variable through which Google's auth libraries request a token type.
+pub const EXECUTABLE_TOKEN_TYPE_ENV: &str = "GOOGLE_EXTERNAL_ACCOUNT_TOKEN_TYPE";
+
+/// Environment variable naming the file an executable response is also written to.
+pub const EXECUTABLE_OUTPUT_FILE_ENV: &str = "GOOGLE_EXTERNAL_ACCOUNT_OUTPUT_FILE";
+
+/// Environment variable set to `1` when the executable may interact with the user.
+pub const EXECUTABLE_INTERACTIVE_ENV: &str = "GOOGLE_EXTERNAL_ACCOUNT_INTERACTIVE";
+
+/// The CLI's default JSON output.
+#[derive(Serialize)]
+pub struct VersionedTokenOutput<'a> {
+    /// Version of this layout, see [`TOKEN_OUTPUT_VERSION`]
+    pub schema_version: u32,
+    /// The tokens and their expiry
+    #[serde(flatten)]
+    pub token: &'a TokenOutput<'a>,
+    /// Decoded ID token claims, when requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub claims: Option<Value>,
+}
+
+impl<'a> VersionedTokenOutput<'a> {
+    /// Lay out `token` as schema `version`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error for versions this build cannot produce.
+    pub fn new(token: &'a TokenOutput<'a>, claims: Option<Value>, version: u32) -> Result<Self> {
+        if version != TOKEN_OUTPUT_VERSION {
+            return Err(anyhow!(
+                "Unsupported output version {version}; this build writes version {TOKEN_OUTPUT_VERSION}"
+            ));
+        }
+        Ok(Self {
+            schema_version: version,
+            token,
+            claims,
+        })
+    }
+}
+
+/// JSON Schema describing [`VersionedTokenOutput`].
+pub fn token_output_schema() -> Value {
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "gcloud-identity-token output",
+        "type": "object",
+        "required": ["schema_version", "access_token", "id_token", "token_expiry"],
+        "properties": {
+            "schema_version": {
+                "const": TOKEN_OUTPUT_VERSION,
+                "description": "Layout version; bumped only when a field is removed or changes meaning"
+            },
+            "access_token": {
+                "type": "string",
+                "description": "OAuth 2.0 access token for Google APIs"
+            },
+            "id_token": {
+                "type": ["string", "null"],
+                "description": "OpenID Connect ID token (JWT) identifying the user; null when none was issued"
+            },
+            "token_expiry": {
+                "type": "string",
+                "format": "date-time",
+                "description": "RFC 3339 UTC time at which both tokens expire"
+            },
+            "claims": {
+                "type": "object",
+                "description": "ID token claims, present with --include-claims or --verify-claims"
+            }
+        }
+    })
+}
+
+/// A response following the `credential_process` contract: a versioned JSON
+/// object carrying the token and its expiry, printed on stdout.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct CredentialProcessOutput<'a> {
+    /// Schema version, always `1`
+    pub version: u8,
+    /// The bearer token
+    pub access_token: &'a str,
+    /// RFC 3339 time after which the caller should run the process again
+    pub expiration: DateTime<Utc>,
+}
+
+impl<'a> CredentialProcessOutput<'a> {
+    /// Wrap the requested token from `token`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when the ID token is requested but `token` has none.
+    pub fn new(token: &TokenOutput<'a>, kind: TokenKind) -> Result<Self> {
+        Ok(Self {
+            version: 1,
+            access_token: match kind {
+                TokenKind::Access => token.access_token,
+                TokenKind::Id => token.require_id_token()?,
+            },
+            expiration: token.token_expiry,
+        })
+    }
+}
+
+/// A response for Google's executable-sourced workload identity federation,
+/// letting this binary serve as `credential_source.executable.command`.
+#[derive(Debug, Serialize)]
+pub struct ExecutableResponse<'a> {
+    /// Schema version, always `1`
+    pub version: u8,
+    /// Whether a token was obtained
+    pub success: bool,
+    /// [`ID_TOKEN_TYPE`] or [`JWT_TOKEN_TYPE`] on success
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<&'a str>,
+    /// The subject token on success
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<&'a str>,
+    /// Token expiry as seconds since the Unix epoch on success
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expiration_time: Option<i64>,
+    /// Error code on failure
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<&'a str>,
+    /// Human-readable error on failure
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl<'a> ExecutableResponse<'a> {
+    /// A successful response carrying the ID token from `token`.
+    ///
+    /// The token type follows `GOOGLE_EXTERNAL_ACCOUNT_TOKEN_TYPE` when it
+    /// asks for a JWT and defaults to an ID token otherwise.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when `token` has no ID token.
+    pub fn success(token: &TokenOutput<'a>) -> Result<Self> {
+        let token_type = match std::env::var(EXECUTABLE_TOKEN_TYPE_ENV).as_deref() {
+            Ok(JWT_TOKEN_TYPE) => JWT_TOKEN_TYPE,
+            _ => ID_TOKEN_TYPE,
+        };
+        Ok(Self {
+            version: 1,
+            success: true,
+            token_type: Some(token_type),
+            id_token: Some(token.require_id_token()?),
+            expiration_time: Some(token.token_expiry.timestamp()),
+            code: None,
+            message: None,
+        })
+    }
+
+    /// A failure response with a short `code` and a descriptive `message`.
+    pub fn error(code: &'a str, message: impl Into<String>) -> Self {
+        Self {
+            version: 1,
+            success: false,
+            token_type: None,
+            id_token: None,
+            expiration_time: None,
+            code: Some(code),
+            message: Some(message.into()),
+        }
+    }
+
+    /// Print the response on stdout, and also write it to the file named by
+    /// `GOOGLE_EXTERNAL_ACCOUNT_OUTPUT_FILE` so callers can reuse it until expiry.
+    pub fn emit(&self) -> Result<()> {
+        let json = serde_json::to_string(self)?;
+        if let Ok(path) = std::env::var(EXECUTABLE_OUTPUT_FILE_ENV) {
+            write_atomic(Path::new(&path), json.as_bytes())?;
+        }
+        println!("{json}");
+        Ok(())
+    }
+}
+
+/// Whether the calling auth library allows user interaction.
+pub fn executable_interactive() -> bool {
+    std::env::var(EXECUTABLE_INTERACTIVE_ENV).as_deref() == Ok("1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_versioned_output_matches_schema() {
+        let token = TokenOutput {
+            access_token: "access",
+            id_token: Some("id"),
+            token_expiry: Utc::now(),
+        };
+        let output = serde_json::to_value(
+            VersionedTokenOutput::new(&token, None, TOKEN_OUTPUT_VERSION).unwrap(),
+        )
+        .unwrap();
+
+        let schema = token_output_schema();
+        for field in schema["required"].as_array().unwrap() {
+            assert!(output.get(field.as_str().unwrap()).is_some(), "{field}");
+        }
+        assert!(VersionedTokenOutput::new(&token, None, 99).is_err());
+    }
+
+    #[test]
+    fn test_executable_error_shape() {
+        let json =
+            serde_json::to_value(ExecutableResponse::error("401", "Login required")).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "version": 1,
+                "success": false,
+                "code": "401",
+                "message": "Login required",
+            })
+        );
+    }
+
+    #[test]
+    fn test_credential_process_shape() {
+        let token = TokenOutput {
+            access_token: "access",
+            id_token: Some("id"),
+            token_expiry: "2025-01-01T00:00:00Z".parse().unwrap(),
+        };
+        let json =
+            serde_json::to_value(CredentialProcessOutput::new(&token, TokenKind::Id).unwrap())
+                .unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "Version": 1,
+                "AccessToken": "id",
+                "Expiration": "2025-01-01T00:00:00Z",
+            })
+        );
+
+        let access_only = TokenOutput {
+            id_token: None,
+            ..token
+        };
+        let err = CredentialProcessOutput::new(&access_only, TokenKind::Id).unwrap_err();
+        assert!(err.to_string().starts_with("No ID token was issued"));
+        assert!(ExecutableResponse::success(&access_only).is_err());
+    }
+}
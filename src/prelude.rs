@@ -0,0 +1,26 @@
+//! The types most integrations need, in one import.
+//!
+//! ```rust,no_run
+//! use gcloud_identity_token::prelude::*;
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let manager = TokenManager::new(load_creds()?);
+//! let token = manager.id_token().await?;
+//! let claims = verify_id_token(&token, &VerifyOptions::for_audience("client-id")).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub use crate::adc::{TokenSource, default_token_source};
+pub use crate::auth::{
+    CancellationToken, get_access_token, get_id_token, get_token, get_token_for_account,
+    get_token_for_service_account, get_token_with_options, impersonate,
+};
+pub use crate::cache::{KeychainAccess, StoragePolicy};
+pub use crate::config::{
+    Creds, LoginFlow, LoginOptions, ServiceAccountCreds, TokenOutput, load_creds,
+    load_service_account_creds,
+};
+pub use crate::error::AuthError;
+pub use crate::manager::TokenManager;
+pub use crate::verify::{Assurance, IdTokenClaims, VerifyOptions, verify_id_token};
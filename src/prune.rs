@@ -0,0 +1,195 @@
+//! Removal of keyring entries that can no longer produce tokens or that
+//! nobody uses any more.
+//!
+//! Only keyring entries are considered; a file cache selected with
+//! `GCLOUD_IDENTITY_TOKEN_PATH` is left alone.
+
+use crate::auth::request_refresh;
+use crate::cache::{CachedAccount, cached_accounts, delete_stored_account, load_any_account};
+use crate::config::{Creds, SavedToken};
+use crate::error::AuthError;
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::fmt;
+
+/// What [`prune_cache`] removes.
+#[derive(Debug, Clone, Default)]
+pub struct PruneOptions {
+    /// Remove accounts not used for this long; accounts of unknown age are kept
+    pub max_idle: Option<Duration>,
+    /// Ask Google whether each stored refresh token still works
+    ///
+    /// Costs one token endpoint request per account. The refreshed tokens
+    /// are discarded.
+    pub check_refresh: bool,
+}
+
+/// Why an account was removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PruneReason {
+    /// The index listed the account but the keyring had no entry for it
+    Missing,
+    /// The entry has no refresh token and its access token has expired
+    Expired,
+    /// Google rejected the refresh token as revoked or expired, while
+    /// pruning or on the account's last refresh
+    Revoked,
+    /// The account was not used within [`PruneOptions::max_idle`]
+    Idle,
+}
+
+impl fmt::Display for PruneReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PruneReason::Missing => "no keyring entry",
+            PruneReason::Expired => "expired, no refresh token",
+            PruneReason::Revoked => "refresh token rejected",
+            PruneReason::Idle => "unused",
+        })
+    }
+}
+
+/// An account removed by [`prune_cache`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PrunedAccount {
+    /// Name the account was stored under, see [`CachedAccount::user`]
+    pub user: String,
+    /// Email of the account, if known
+    pub email: Option<String>,
+    /// Why it was removed
+    pub reason: PruneReason,
+}
+
+/// Delete cached accounts that are unusable or idle, returning what was removed.
+pub async fn prune_cache(creds: &Creds, opts: &PruneOptions) -> Result<Vec<PrunedAccount>> {
+    let now = Utc::now();
+    let mut pruned = Vec::new();
+    for account in cached_accounts() {
+        let saved = load_any_account(&account.user, &creds.client_id);
+        if saved.is_none()
+            && account
+                .clients
+                .iter()
+                .any(|other| *other != creds.client_id)
+        {
+            // Cached for other OAuth clients only, which cannot be checked here.
+            continue;
+        }
+        let mut reason = local_prune_reason(&account, saved.as_ref(), opts.max_idle, now);
+        if let (None, Some(saved), true) = (reason, &saved, opts.check_refresh) {
+            reason = match request_refresh(creds, saved).await {
+                Ok(_) => None,
+                Err(err) if matches!(err.downcast_ref(), Some(AuthError::LoginRequired)) => {
+                    Some(PruneReason::Revoked)
+                }
+                Err(err) => return Err(err),
+            };
+        }
+
+        if let Some(reason) = reason {
+            delete_stored_account(&account.user)?;
+            pruned.push(PrunedAccount {
+                user: account.user,
+                email: account.email,
+                reason,
+            });
+        }
+    }
+    Ok(pruned)
+}
+
+/// The reason to prune `account` that can be decided without the network.
+fn local_prune_reason(
+    account: &CachedAccount,
+    saved: Option<&SavedToken>,
+    max_idle: Option<Duration>,
+    now: DateTime<Utc>,
+) -> Option<PruneReason> {
+    let Some(saved) = saved else {
+        return Some(PruneReason::Missing);
+    };
+    if account.refresh_rejected.is_some() {
+        return Some(PruneReason::Revoked);
+    }
+    if saved.refresh_token.is_empty() && saved.token_expiry <= now {
+        return Some(PruneReason::Expired);
+    }
+    match (account.last_used, max_idle) {
+        (Some(last_used), Some(max_idle)) if now - last_used > max_idle => Some(PruneReason::Idle),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn saved(refresh_token: &str, token_expiry: DateTime<Utc>) -> SavedToken {
+        SavedToken {
+            refresh_token: refresh_token.into(),
+            access_token: "access".into(),
+            id_token: Some("id".into()),
+            token_expiry,
+            refresh_token_issued_at: None,
+            granted_scopes: Vec::new(),
+            requested_scopes: Vec::new(),
+            client_id: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_local_prune_reason() {
+        let now = Utc::now();
+        let account = |last_used| CachedAccount {
+            user: "me@example.com".into(),
+            email: None,
+            last_used,
+            clients: Vec::new(),
+            refresh_rejected: None,
+        };
+        let idle = Some(Duration::days(30));
+        let fresh = saved("refresh", now - Duration::hours(1));
+
+        assert_eq!(
+            local_prune_reason(&account(None), None, idle, now),
+            Some(PruneReason::Missing)
+        );
+        assert_eq!(
+            local_prune_reason(&account(None), Some(&saved("", now)), idle, now),
+            Some(PruneReason::Expired)
+        );
+        assert_eq!(
+            local_prune_reason(
+                &account(Some(now - Duration::days(31))),
+                Some(&fresh),
+                idle,
+                now
+            ),
+            Some(PruneReason::Idle)
+        );
+        assert_eq!(
+            local_prune_reason(
+                &account(Some(now - Duration::days(2))),
+                Some(&fresh),
+                idle,
+                now
+            ),
+            None
+        );
+        assert_eq!(
+            local_prune_reason(&account(None), Some(&fresh), idle, now),
+            None
+        );
+
+        let rejected = CachedAccount {
+            refresh_rejected: Some(now - Duration::hours(2)),
+            ..account(Some(now))
+        };
+        assert_eq!(
+            local_prune_reason(&rejected, Some(&fresh), idle, now),
+            Some(PruneReason::Revoked)
+        );
+    }
+}
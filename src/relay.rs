@@ -0,0 +1,213 @@
+//! Hand-off of a browser login to a machine that cannot open a browser.
+//!
+//! The headless machine creates a [`Receiver`] and shows its pairing code. On
+//! a workstation, [`login_for_remote`] runs the browser login and encrypts the
+//! tokens to that code; the reply is pasted back into the receiver, which
+//! decrypts and caches them. Only the two endpoints ever see the tokens, so
+//! the reply can travel over chat, a ticket, or a terminal multiplexer.
+//!
+//! Keys are ephemeral X25519 pairs, and replies are AES-256-GCM encrypted under
+//! an HKDF-SHA256 key bound to both public keys.
+
+use crate::auth::{DEFAULT_SCOPES, browser_login};
+use crate::cache::save_token;
+use crate::config::{Creds, LoginOptions, SavedToken};
+use anyhow::{Result, anyhow};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use ring::aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use ring::agreement::{EphemeralPrivateKey, UnparsedPublicKey, X25519, agree_ephemeral};
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+/// Format version leading every reply.
+const REPLY_VERSION: u8 = 1;
+
+/// HKDF info string separating relay keys from any other use.
+const RELAY_INFO: &[u8] = b"gcloud-identity-token login relay v1";
+
+const PUBLIC_KEY_LEN: usize = 32;
+
+/// What a reply carries.
+#[derive(Serialize, Deserialize)]
+struct Handoff {
+    /// OAuth client the tokens were issued to; refreshing needs the same one
+    client_id: String,
+    token: SavedToken,
+}
+
+/// The headless end of a relayed login.
+pub struct Receiver {
+    private: EphemeralPrivateKey,
+    public: Vec<u8>,
+}
+
+impl Receiver {
+    /// Generate a fresh key pair. Each receiver accepts a single reply.
+    pub fn new() -> Result<Self> {
+        let rng = SystemRandom::new();
+        let private = EphemeralPrivateKey::generate(&X25519, &rng)
+            .map_err(|_| anyhow!("Failed to generate a relay key"))?;
+        let public = private
+            .compute_public_key()
+            .map_err(|_| anyhow!("Failed to generate a relay key"))?
+            .as_ref()
+            .to_vec();
+        Ok(Self { private, public })
+    }
+
+    /// The code to pass to [`login_for_remote`] on the workstation.
+    pub fn pairing_code(&self) -> String {
+        URL_SAFE_NO_PAD.encode(&self.public)
+    }
+
+    /// Decrypt a reply from [`login_for_remote`] and cache its tokens.
+    ///
+    /// Fails if the reply was not made for this receiver, was altered, or
+    /// holds tokens of an OAuth client other than `creds`, which could not
+    /// refresh them.
+    pub fn accept(self, reply: &str, creds: &Creds) -> Result<SavedToken> {
+        let handoff: Handoff = serde_json::from_slice(&self.open(reply)?)?;
+        if handoff.client_id != creds.client_id {
+            return Err(anyhow!(
+                "The remote login used OAuth client {}, but this machine uses {}; \
+                 both need the same application default credentials",
+                handoff.client_id,
+                creds.client_id
+            ));
+        }
+        save_token(&handoff.token)?;
+        Ok(handoff.token)
+    }
+
+    fn open(self, reply: &str) -> Result<Vec<u8>> {
+        let reply = URL_SAFE_NO_PAD
+            .decode(reply.trim())
+            .map_err(|_| anyhow!("The reply is not a relay reply"))?;
+        let (&version, rest) = reply
+            .split_first()
+            .ok_or_else(|| anyhow!("The reply is empty"))?;
+        if version != REPLY_VERSION || rest.len() < PUBLIC_KEY_LEN + NONCE_LEN {
+            return Err(anyhow!("The reply is not a relay reply"));
+        }
+        let (sender, rest) = rest.split_at(PUBLIC_KEY_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+        let key = agree_ephemeral(
+            self.private,
+            &UnparsedPublicKey::new(&X25519, sender),
+            |shared| derive_key(shared, &self.public, sender),
+        )
+        .map_err(|_| anyhow!("The reply carries an invalid key"))?;
+        let nonce = Nonce::try_assume_unique_for_key(nonce)
+            .map_err(|_| anyhow!("The reply carries an invalid nonce"))?;
+        let mut in_out = ciphertext.to_vec();
+        let plaintext = key
+            .open_in_place(nonce, Aad::empty(), &mut in_out)
+            .map_err(|_| {
+                anyhow!(
+                    "The reply cannot be decrypted; it was made for another pairing code or altered"
+                )
+            })?;
+        Ok(plaintext.to_vec())
+    }
+}
+
+/// Log in with the browser here and encrypt the tokens for the machine that
+/// showed `pairing_code`, returning the reply to paste there.
+///
+/// Nothing is cached on this machine.
+pub async fn login_for_remote(
+    creds: &Creds,
+    opts: &LoginOptions,
+    pairing_code: &str,
+) -> Result<String> {
+    // Reject a mistyped code before sending the user through the browser.
+    let receiver = decode_pairing_code(pairing_code)?;
+    let token = browser_login(creds, DEFAULT_SCOPES, opts).await?;
+    let handoff = Handoff {
+        client_id: creds.client_id.clone(),
+        token,
+    };
+    seal_for(&receiver, &serde_json::to_vec(&handoff)?)
+}
+
+fn decode_pairing_code(pairing_code: &str) -> Result<Vec<u8>> {
+    URL_SAFE_NO_PAD
+        .decode(pairing_code.trim())
+        .ok()
+        .filter(|key| key.len() == PUBLIC_KEY_LEN)
+        .ok_or_else(|| anyhow!("{pairing_code:?} is not a pairing code"))
+}
+
+/// Encrypt `plaintext` to the receiver public key `receiver`.
+fn seal_for(receiver: &[u8], plaintext: &[u8]) -> Result<String> {
+    let rng = SystemRandom::new();
+    let private = EphemeralPrivateKey::generate(&X25519, &rng)
+        .map_err(|_| anyhow!("Failed to generate a relay key"))?;
+    let sender = private
+        .compute_public_key()
+        .map_err(|_| anyhow!("Failed to generate a relay key"))?;
+    let key = agree_ephemeral(
+        private,
+        &UnparsedPublicKey::new(&X25519, receiver),
+        |shared| derive_key(shared, receiver, sender.as_ref()),
+    )
+    .map_err(|_| anyhow!("The pairing code is not a valid key"))?;
+
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill(&mut nonce)
+        .map_err(|_| anyhow!("No randomness available to encrypt the reply"))?;
+    let mut in_out = plaintext.to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::empty(),
+        &mut in_out,
+    )
+    .map_err(|_| anyhow!("Failed to encrypt the reply"))?;
+
+    let reply = [&[REPLY_VERSION][..], sender.as_ref(), &nonce, &in_out].concat();
+    Ok(URL_SAFE_NO_PAD.encode(reply))
+}
+
+/// The AES-256-GCM key for one receiver/sender pair.
+fn derive_key(shared: &[u8], receiver: &[u8], sender: &[u8]) -> LessSafeKey {
+    let salt = hkdf::Salt::new(hkdf::HKDF_SHA256, &[receiver, sender].concat());
+    let prk = salt.extract(shared);
+    let okm = prk
+        .expand(&[RELAY_INFO], &AES_256_GCM)
+        .expect("AES-256 key length is a valid HKDF output length");
+    LessSafeKey::new(UnboundKey::from(okm))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reply_opens_only_for_its_receiver() {
+        let receiver = Receiver::new().unwrap();
+        let code = receiver.pairing_code();
+        let reply = seal_for(&decode_pairing_code(&code).unwrap(), b"tokens").unwrap();
+        assert_eq!(receiver.open(&reply).unwrap(), b"tokens");
+
+        let other = Receiver::new().unwrap();
+        assert!(other.open(&reply).is_err());
+
+        let receiver = Receiver::new().unwrap();
+        let reply = seal_for(
+            &decode_pairing_code(&receiver.pairing_code()).unwrap(),
+            b"x",
+        )
+        .unwrap();
+        let mut tampered = URL_SAFE_NO_PAD.decode(&reply).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(receiver.open(&URL_SAFE_NO_PAD.encode(tampered)).is_err());
+    }
+
+    #[test]
+    fn test_decode_pairing_code_rejects_garbage() {
+        assert!(decode_pairing_code("not a code").is_err());
+        assert!(decode_pairing_code("c2hvcnQ").is_err());
+    }
+}
@@ -0,0 +1,355 @@
+//! Encryption and integrity protection of the file cache.
+//!
+//! A sealed cache file is a small JSON envelope holding an AES-256-GCM
+//! ciphertext. The key is derived from something that does not travel with
+//! the file: the machine's identifier, so a copied file is useless, or a
+//! passphrase stretched with PBKDF2 under a random salt kept in the envelope.
+//!
+//! Unsealed files carry the token in the clear next to a MAC, so a truncated
+//! write or an edit is detected on load instead of trusted. The MAC is an
+//! HMAC when a cache secret is configured and a plain SHA-256 checksum
+//! otherwise, which catches corruption but not deliberate edits.
+
+use anyhow::{Context, Result, anyhow};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use ring::aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use ring::digest::{SHA256, digest};
+use ring::hmac;
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
+
+/// Domain separation prefix for machine-bound keys.
+const MACHINE_KEY_CONTEXT: &[u8] = b"gcloud-identity-token machine-bound cache v1\0";
+
+/// PBKDF2-HMAC-SHA256 rounds for passphrase keys, per OWASP's recommendation.
+const PASSPHRASE_ITERATIONS: u32 = 600_000;
+
+/// Length of the random salt of passphrase keys.
+const SALT_LEN: usize = 16;
+
+/// Integrity scheme keyed with the configured cache secret.
+const HMAC_SHA256: &str = "hmac-sha256";
+
+/// Integrity scheme used without a cache secret.
+const SHA256_CHECKSUM: &str = "sha256";
+
+/// On-disk form of a sealed cache file.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    /// What the key is bound to, e.g. `machine`; also authenticated as AAD
+    sealed: String,
+    /// Salt and rounds of a passphrase key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    salt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    iterations: Option<u32>,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// On-disk form of an integrity-protected, unencrypted cache file.
+#[derive(Serialize, Deserialize)]
+struct Protected {
+    integrity: String,
+    token: serde_json::Value,
+    mac: String,
+}
+
+/// A key for sealing cache files, with the name of what it is bound to.
+pub(crate) struct SealKey {
+    binding: &'static str,
+    key: LessSafeKey,
+    /// Salt and rounds a passphrase key was derived with
+    stretch: Option<(Vec<u8>, u32)>,
+}
+
+impl SealKey {
+    fn new(binding: &'static str, material: &[u8]) -> Self {
+        let key = UnboundKey::new(&AES_256_GCM, material).expect("SHA-256 output is a valid key");
+        Self {
+            binding,
+            key: LessSafeKey::new(key),
+            stretch: None,
+        }
+    }
+
+    /// A key derived from `passphrase` under a fresh random salt.
+    pub(crate) fn passphrase(passphrase: &[u8]) -> Result<Self> {
+        let mut salt = vec![0u8; SALT_LEN];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| anyhow!("No randomness available to salt the cache passphrase"))?;
+        Ok(Self::stretched(passphrase, salt, PASSPHRASE_ITERATIONS))
+    }
+
+    fn stretched(passphrase: &[u8], salt: Vec<u8>, iterations: u32) -> Self {
+        let rounds = NonZeroU32::new(iterations).unwrap_or(NonZeroU32::MIN);
+        let mut material = [0u8; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            rounds,
+            &salt,
+            passphrase,
+            &mut material,
+        );
+        Self {
+            stretch: Some((salt, rounds.get())),
+            ..Self::new("passphrase", &material)
+        }
+    }
+
+    /// The key that opens the envelope `data`, given the configured passphrase.
+    pub(crate) fn for_envelope(data: &[u8], passphrase: Option<&[u8]>) -> Result<Self> {
+        let envelope: Envelope = serde_json::from_slice(data)?;
+        match (envelope.sealed.as_str(), passphrase) {
+            ("machine", _) => Self::machine(),
+            ("passphrase", Some(passphrase)) => {
+                let salt = STANDARD.decode(envelope.salt.as_deref().unwrap_or_default())?;
+                let iterations = envelope
+                    .iterations
+                    .ok_or_else(|| anyhow!("Token cache does not record its PBKDF2 rounds"))?;
+                Ok(Self::stretched(passphrase, salt, iterations))
+            }
+            ("passphrase", None) => Err(anyhow!(
+                "Token cache is encrypted with a passphrase, but none is configured"
+            )),
+            (other, _) => Err(anyhow!("Token cache is sealed with unknown key {other:?}")),
+        }
+    }
+
+    /// A key derived from this machine's identifier.
+    pub(crate) fn machine() -> Result<Self> {
+        let id = machine_id()?;
+        let material = digest(&SHA256, &[MACHINE_KEY_CONTEXT, id.as_bytes()].concat());
+        Ok(Self::new("machine", material.as_ref()))
+    }
+}
+
+/// Whether `data` is a sealed envelope rather than plaintext JSON.
+pub(crate) fn is_sealed(data: &[u8]) -> bool {
+    serde_json::from_slice::<Envelope>(data).is_ok()
+}
+
+/// Encrypt `plaintext` under `key`, returning the envelope to write.
+pub(crate) fn seal(plaintext: &[u8], key: &SealKey) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| anyhow!("No randomness available to seal the token cache"))?;
+
+    let mut in_out = plaintext.to_vec();
+    key.key
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(key.binding.as_bytes()),
+            &mut in_out,
+        )
+        .map_err(|_| anyhow!("Failed to seal the token cache"))?;
+
+    Ok(serde_json::to_vec_pretty(&Envelope {
+        sealed: key.binding.to_string(),
+        salt: key.stretch.as_ref().map(|(salt, _)| STANDARD.encode(salt)),
+        iterations: key.stretch.as_ref().map(|(_, iterations)| *iterations),
+        nonce: STANDARD.encode(nonce),
+        ciphertext: STANDARD.encode(in_out),
+    })?)
+}
+
+/// Decrypt an envelope written by [`seal`].
+///
+/// Fails when the file was sealed with another key, for a machine-bound file
+/// meaning it was copied from another host and for a passphrase-sealed one a
+/// wrong passphrase, or when it was modified.
+pub(crate) fn open(data: &[u8], key: &SealKey) -> Result<Vec<u8>> {
+    let envelope: Envelope = serde_json::from_slice(data)?;
+    if envelope.sealed != key.binding {
+        return Err(anyhow!(
+            "Token cache is bound to {:?}, not {:?}",
+            envelope.sealed,
+            key.binding
+        ));
+    }
+    let nonce = STANDARD.decode(&envelope.nonce)?;
+    let nonce = Nonce::try_assume_unique_for_key(&nonce)
+        .map_err(|_| anyhow!("Token cache has a malformed nonce"))?;
+    let mut in_out = STANDARD.decode(&envelope.ciphertext)?;
+    let plaintext = key
+        .key
+        .open_in_place(nonce, Aad::from(key.binding.as_bytes()), &mut in_out)
+        .map_err(|_| {
+            anyhow!(
+                "Token cache cannot be decrypted with this {} key; it was sealed with another or modified",
+                key.binding
+            )
+        })?;
+    Ok(plaintext.to_vec())
+}
+
+/// Whether `data` is an integrity-protected envelope written by [`protect`].
+pub(crate) fn is_protected(data: &[u8]) -> bool {
+    serde_json::from_slice::<Protected>(data).is_ok()
+}
+
+/// Wrap the JSON document `payload` with a MAC over its contents, keyed
+/// with `secret` when one is given.
+pub(crate) fn protect(payload: &[u8], secret: Option<&[u8]>) -> Result<Vec<u8>> {
+    let token: serde_json::Value = serde_json::from_slice(payload)?;
+    let canonical = serde_json::to_vec(&token)?;
+    let (integrity, mac) = match secret {
+        Some(secret) => (
+            HMAC_SHA256,
+            hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, secret), &canonical)
+                .as_ref()
+                .to_vec(),
+        ),
+        None => (
+            SHA256_CHECKSUM,
+            digest(&SHA256, &canonical).as_ref().to_vec(),
+        ),
+    };
+    Ok(serde_json::to_vec_pretty(&Protected {
+        integrity: integrity.to_string(),
+        token,
+        mac: STANDARD.encode(mac),
+    })?)
+}
+
+/// Check an envelope written by [`protect`] and return the JSON it protects.
+///
+/// An HMAC-protected envelope needs the `secret` it was written with.
+pub(crate) fn verify(data: &[u8], secret: Option<&[u8]>) -> Result<Vec<u8>> {
+    let protected: Protected = serde_json::from_slice(data)?;
+    let canonical = serde_json::to_vec(&protected.token)?;
+    let mac = STANDARD.decode(&protected.mac)?;
+    let intact = match (protected.integrity.as_str(), secret) {
+        (HMAC_SHA256, Some(secret)) => {
+            hmac::verify(&hmac::Key::new(hmac::HMAC_SHA256, secret), &canonical, &mac).is_ok()
+        }
+        (HMAC_SHA256, None) => {
+            return Err(anyhow!(
+                "Token cache is protected with a secret, but none is configured"
+            ));
+        }
+        (SHA256_CHECKSUM, _) => digest(&SHA256, &canonical).as_ref() == mac.as_slice(),
+        (other, _) => {
+            return Err(anyhow!(
+                "Token cache uses unknown integrity scheme {other:?}"
+            ));
+        }
+    };
+    if !intact {
+        return Err(anyhow!(
+            "Token cache failed its integrity check; it was modified or corrupted"
+        ));
+    }
+    Ok(canonical)
+}
+
+/// A stable identifier of this machine, never written anywhere.
+///
+/// `/etc/machine-id` on Linux, the IOPlatformUUID on macOS, and the
+/// `MachineGuid` registry value on Windows.
+fn machine_id() -> Result<String> {
+    #[cfg(target_os = "macos")]
+    {
+        let out = std::process::Command::new("ioreg")
+            .args(["-rd1", "-c", "IOPlatformExpertDevice"])
+            .output()
+            .context("Failed to run ioreg")?;
+        let out = String::from_utf8_lossy(&out.stdout);
+        out.lines()
+            .find(|line| line.contains("\"IOPlatformUUID\""))
+            .and_then(|line| line.split('"').nth(3))
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("ioreg reported no IOPlatformUUID"))
+    }
+
+    #[cfg(windows)]
+    {
+        let out = std::process::Command::new("reg")
+            .args([
+                "query",
+                r"HKLM\SOFTWARE\Microsoft\Cryptography",
+                "/v",
+                "MachineGuid",
+            ])
+            .output()
+            .context("Failed to query MachineGuid")?;
+        String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .find(|line| line.contains("MachineGuid"))
+            .and_then(|line| line.split_whitespace().last())
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("No MachineGuid in the registry"))
+    }
+
+    #[cfg(not(any(target_os = "macos", windows)))]
+    {
+        ["/etc/machine-id", "/var/lib/dbus/machine-id"]
+            .iter()
+            .find_map(|path| {
+                let id = std::fs::read_to_string(path).ok()?;
+                Some(id.trim().to_string()).filter(|id| !id.is_empty())
+            })
+            .context("No machine ID in /etc/machine-id or /var/lib/dbus/machine-id")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_round_trip_and_wrong_key() {
+        let key = SealKey::new("machine", &[7; 32]);
+        let sealed = seal(br#"{"refresh_token":"r"}"#, &key).unwrap();
+        assert!(is_sealed(&sealed));
+        assert!(!is_sealed(br#"{"refresh_token":"r"}"#));
+        assert_eq!(open(&sealed, &key).unwrap(), br#"{"refresh_token":"r"}"#);
+
+        let other = SealKey::new("machine", &[8; 32]);
+        assert!(open(&sealed, &other).is_err());
+    }
+
+    #[test]
+    fn test_passphrase_key_from_envelope() {
+        let key = SealKey::stretched(b"hunter2", vec![1; SALT_LEN], 2);
+        let sealed = seal(br#"{"refresh_token":"r"}"#, &key).unwrap();
+        assert!(!String::from_utf8_lossy(&sealed).contains("refresh_token"));
+
+        let reopened = SealKey::for_envelope(&sealed, Some(b"hunter2")).unwrap();
+        assert_eq!(
+            open(&sealed, &reopened).unwrap(),
+            br#"{"refresh_token":"r"}"#
+        );
+
+        let wrong = SealKey::for_envelope(&sealed, Some(b"hunter3")).unwrap();
+        assert!(open(&sealed, &wrong).is_err());
+        assert!(SealKey::for_envelope(&sealed, None).is_err());
+    }
+
+    #[test]
+    fn test_protect_detects_modification() {
+        for secret in [None, Some(&b"s3cret"[..])] {
+            let protected = protect(br#"{"refresh_token":"r"}"#, secret).unwrap();
+            assert!(is_protected(&protected));
+            assert!(!is_sealed(&protected));
+            assert_eq!(
+                verify(&protected, secret).unwrap(),
+                br#"{"refresh_token":"r"}"#
+            );
+
+            let tampered = String::from_utf8(protected.clone())
+                .unwrap()
+                .replace("\"r\"", "\"x\"");
+            assert!(verify(tampered.as_bytes(), secret).is_err());
+            assert!(verify(&protected[..protected.len() / 2], secret).is_err());
+        }
+
+        let protected = protect(br#"{"refresh_token":"r"}"#, Some(b"s3cret")).unwrap();
+        assert!(verify(&protected, Some(b"other")).is_err());
+        assert!(verify(&protected, None).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+//! Process-wide counters of token cache and token endpoint activity.
+//!
+//! Useful when tuning refresh-ahead and clock skew settings: a good setting
+//! shows mostly hits and few refreshes per process lifetime.
+
+use serde::Serialize;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+static REFRESHES: AtomicU64 = AtomicU64::new(0);
+static LOGINS: AtomicU64 = AtomicU64::new(0);
+static FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/// A snapshot of the counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Stats {
+    /// Requests served by a cached, unexpired token
+    pub hits: u64,
+    /// Requests that found no usable cached token
+    pub misses: u64,
+    /// Successful refresh-token exchanges with the token endpoint
+    pub refreshes: u64,
+    /// Completed interactive browser logins
+    pub logins: u64,
+    /// Token requests that ended in an error
+    pub failures: u64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "hits={} misses={} refreshes={} logins={} failures={}",
+            self.hits, self.misses, self.refreshes, self.logins, self.failures
+        )
+    }
+}
+
+/// Something worth counting.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Event {
+    Hit,
+    Miss,
+    Refresh,
+    Login,
+    Failure,
+}
+
+/// Count one occurrence of `event`.
+pub(crate) fn record(event: Event) {
+    let counter = match event {
+        Event::Hit => &HITS,
+        Event::Miss => &MISSES,
+        Event::Refresh => &REFRESHES,
+        Event::Login => &LOGINS,
+        Event::Failure => &FAILURES,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Current counter values for this process.
+pub fn stats() -> Stats {
+    Stats {
+        hits: HITS.load(Ordering::Relaxed),
+        misses: MISSES.load(Ordering::Relaxed),
+        refreshes: REFRESHES.load(Ordering::Relaxed),
+        logins: LOGINS.load(Ordering::Relaxed),
+        failures: FAILURES.load(Ordering::Relaxed),
+    }
+}
+
+/// Reset all counters to zero.
+pub fn reset_stats() {
+    for counter in [&HITS, &MISSES, &REFRESHES, &LOGINS, &FAILURES] {
+        counter.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_increments_counter() {
+        let before = stats().refreshes;
+        record(Event::Refresh);
+        assert!(stats().refreshes > before);
+    }
+}
@@ -0,0 +1,166 @@
+//! OAuth 2.0 token exchange (RFC 8693) at Google's Security Token Service.
+//!
+//! This is the step underneath `external_account` credentials, exposed on
+//! its own for callers that hold a token from their own OIDC issuer and want
+//! to choose every exchange parameter themselves.
+//!
+//! ```rust,no_run
+//! use gcloud_identity_token::sts::{ExchangeOptions, exchange};
+//!
+//! # async fn run(oidc_token: &str) -> anyhow::Result<()> {
+//! let audience = "//iam.googleapis.com/projects/1/locations/global/workloadIdentityPools/p/providers/oidc";
+//! let token = exchange(oidc_token, audience, &ExchangeOptions::default()).await?;
+//! println!("{}", token.access_token);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::auth::CLOUD_PLATFORM_SCOPE;
+use crate::config::TokenErrorResponse;
+use crate::debug;
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Duration, Utc};
+use reqwest::Client;
+use serde::Deserialize;
+
+/// Google's Security Token Service token exchange endpoint.
+pub const STS_TOKEN_URL: &str = "https://sts.googleapis.com/v1/token";
+
+/// Grant type of token exchanges.
+const TOKEN_EXCHANGE_GRANT: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+
+/// Token type of OIDC ID tokens and other JWTs.
+pub const JWT_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:jwt";
+
+/// Token type of OAuth access tokens, the only kind Google's STS issues.
+pub const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+
+/// Parameters of a token exchange beyond the subject token and audience.
+#[derive(Debug, Clone)]
+pub struct ExchangeOptions {
+    /// Type of the subject token
+    pub subject_token_type: String,
+    /// Type of token asked for
+    pub requested_token_type: String,
+    /// Scopes of the issued token; `cloud-platform` when empty
+    pub scopes: Vec<String>,
+    /// Endpoint the exchange is posted to
+    pub token_url: String,
+    /// Token of the party acting on the subject's behalf, with its type
+    pub actor_token: Option<(String, String)>,
+    /// Google-specific `options` object, e.g. `{"userProject": "..."}` for
+    /// workforce pools
+    pub options: Option<serde_json::Value>,
+}
+
+impl Default for ExchangeOptions {
+    fn default() -> Self {
+        Self {
+            subject_token_type: JWT_TOKEN_TYPE.to_string(),
+            requested_token_type: ACCESS_TOKEN_TYPE.to_string(),
+            scopes: Vec::new(),
+            token_url: STS_TOKEN_URL.to_string(),
+            actor_token: None,
+            options: None,
+        }
+    }
+}
+
+/// Successful response of a token exchange.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExchangeResponse {
+    /// The issued token
+    pub access_token: String,
+    /// Type of the issued token
+    pub issued_token_type: String,
+    /// How the token is presented, usually `Bearer`
+    pub token_type: String,
+    /// Seconds until the token expires, if the service says
+    #[serde(default)]
+    pub expires_in: Option<i64>,
+}
+
+impl ExchangeResponse {
+    /// When the token expires, counted from now; `None` if unknown.
+    pub fn expiry(&self) -> Option<DateTime<Utc>> {
+        self.expires_in
+            .map(|secs| Utc::now() + Duration::seconds(secs))
+    }
+}
+
+/// Exchange `subject_token` for a Google token, with `audience` naming the
+/// workload or workforce identity pool provider that trusts its issuer.
+///
+/// # Errors
+///
+/// Returns an error if the request fails or the service refuses the exchange,
+/// in which case its OAuth error is included.
+pub async fn exchange(
+    subject_token: &str,
+    audience: &str,
+    opts: &ExchangeOptions,
+) -> Result<ExchangeResponse> {
+    let form = exchange_form(subject_token, audience, opts);
+    let res = debug::send(Client::new().post(&opts.token_url).form(&form)).await?;
+    if !res.status().is_success() {
+        let err = res.json::<TokenErrorResponse>().await?;
+        return Err(anyhow!("Security Token Service exchange failed: {err}"));
+    }
+    Ok(res.json::<ExchangeResponse>().await?)
+}
+
+/// The form fields of an exchange request.
+fn exchange_form(
+    subject_token: &str,
+    audience: &str,
+    opts: &ExchangeOptions,
+) -> Vec<(&'static str, String)> {
+    let scope = if opts.scopes.is_empty() {
+        CLOUD_PLATFORM_SCOPE.to_string()
+    } else {
+        opts.scopes.join(" ")
+    };
+    let mut form = vec![
+        ("grant_type", TOKEN_EXCHANGE_GRANT.to_string()),
+        ("audience", audience.to_string()),
+        ("scope", scope),
+        ("requested_token_type", opts.requested_token_type.clone()),
+        ("subject_token", subject_token.to_string()),
+        ("subject_token_type", opts.subject_token_type.clone()),
+    ];
+    if let Some((token, token_type)) = &opts.actor_token {
+        form.push(("actor_token", token.clone()));
+        form.push(("actor_token_type", token_type.clone()));
+    }
+    if let Some(options) = &opts.options {
+        form.push(("options", options.to_string()));
+    }
+    form
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exchange_form() {
+        let form = exchange_form("oidc", "aud", &ExchangeOptions::default());
+        let field = |form: &[(&str, String)], name| {
+            form.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.clone())
+        };
+        assert_eq!(field(&form, "scope").unwrap(), CLOUD_PLATFORM_SCOPE);
+        assert_eq!(field(&form, "subject_token_type").unwrap(), JWT_TOKEN_TYPE);
+        assert_eq!(field(&form, "options"), None);
+
+        let opts = ExchangeOptions {
+            scopes: vec!["a".into(), "b".into()],
+            options: Some(serde_json::json!({ "userProject": "p" })),
+            ..ExchangeOptions::default()
+        };
+        let form = exchange_form("oidc", "aud", &opts);
+        assert_eq!(field(&form, "scope").unwrap(), "a b");
+        assert_eq!(field(&form, "options").unwrap(), r#"{"userProject":"p"}"#);
+    }
+}
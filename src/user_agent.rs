@@ -0,0 +1,74 @@
+//! Identification of the HTTP requests this crate sends to Google.
+//!
+//! Every request carries a `User-Agent` naming this crate and its version,
+//! followed by the embedding application's product identifier when one is
+//! set, so egress proxies and Google support can tell callers apart.
+
+use anyhow::{Result, anyhow};
+use reqwest::header::HeaderValue;
+use std::sync::Mutex;
+
+/// Environment variable holding a product identifier to append to the
+/// `User-Agent`, e.g. `deploy-bot/2.3`.
+pub const USER_AGENT_PRODUCT_ENV: &str = "GCLOUD_IDENTITY_TOKEN_USER_AGENT_PRODUCT";
+
+/// This crate's own `User-Agent` product token.
+const CRATE_PRODUCT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+static PRODUCT: Mutex<Option<String>> = Mutex::new(None);
+
+/// Append `product`, such as `"my-tool/1.4 (build 77)"`, to the `User-Agent`
+/// of every later request, overriding `GCLOUD_IDENTITY_TOKEN_USER_AGENT_PRODUCT`.
+/// `None` restores the environment setting.
+///
+/// Fails if `product` cannot appear in an HTTP header.
+pub fn set_user_agent_product(product: Option<String>) -> Result<()> {
+    if let Some(product) = &product {
+        HeaderValue::from_str(product)
+            .map_err(|_| anyhow!("{product:?} is not a valid User-Agent product"))?;
+    }
+    if let Ok(mut current) = PRODUCT.lock() {
+        *current = product;
+    }
+    Ok(())
+}
+
+/// The `User-Agent` sent with every request.
+pub fn user_agent() -> String {
+    let product = PRODUCT
+        .lock()
+        .ok()
+        .and_then(|product| product.clone())
+        .or_else(|| std::env::var(USER_AGENT_PRODUCT_ENV).ok())
+        .filter(|product| !product.trim().is_empty());
+    compose(product.as_deref())
+}
+
+fn compose(product: Option<&str>) -> String {
+    match product {
+        Some(product) => format!("{CRATE_PRODUCT} {}", product.trim()),
+        None => CRATE_PRODUCT.to_string(),
+    }
+}
+
+/// [`user_agent`] as a header value.
+pub(crate) fn user_agent_header() -> HeaderValue {
+    // An invalid environment value falls back to the crate's own product.
+    HeaderValue::from_str(&user_agent()).unwrap_or_else(|_| HeaderValue::from_static(CRATE_PRODUCT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_agent_appends_product() {
+        assert_eq!(compose(None), CRATE_PRODUCT);
+        assert!(CRATE_PRODUCT.starts_with("gcloud-identity-token/"));
+        assert_eq!(
+            compose(Some(" deploy-bot/2.3 ")),
+            format!("{CRATE_PRODUCT} deploy-bot/2.3")
+        );
+        assert!(set_user_agent_product(Some("bad\nproduct".into())).is_err());
+    }
+}
@@ -0,0 +1,544 @@
+//! ID token verification against Google's published signing keys.
+//!
+//! Besides the tokens this crate mints, the same machinery validates tokens
+//! Google sends *to* a service: Identity-Aware Proxy assertions (ES256, signed
+//! with IAP's own keys) and Pub/Sub push OIDC tokens (ordinary ID tokens).
+//!
+//! Google's JWKS is cached in memory according to the `Cache-Control: max-age`
+//! of the certificate response. Once that expires, the cache revalidates with a
+//! conditional request (`If-None-Match`), and if Google cannot be reached the
+//! stale keys keep being served for a bounded grace period so that brief
+//! network blips do not break verification.
+
+use anyhow::{Result, anyhow};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode_header};
+use reqwest::header::{CACHE_CONTROL, ETAG, IF_NONE_MATCH};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Google's JWKS endpoint for ID token signing keys.
+const GOOGLE_JWKS_URL: &str = "https://www.googleapis.com/oauth2/v3/certs";
+
+/// Issuers Google uses for ID tokens.
+const GOOGLE_ISSUERS: &[&str] = &["https://accounts.google.com", "accounts.google.com"];
+
+/// JWKS endpoint for Identity-Aware Proxy signing keys.
+const IAP_JWKS_URL: &str = "https://www.gstatic.com/iap/verify/public_key-jwk";
+
+/// Issuer of Identity-Aware Proxy assertions.
+pub const IAP_ISSUER: &str = "https://cloud.google.com/iap";
+
+/// Request header carrying the Identity-Aware Proxy assertion.
+pub const IAP_ASSERTION_HEADER: &str = "x-goog-iap-jwt-assertion";
+
+/// Freshness used when the response carries no usable `max-age`.
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// How long expired keys may still be served while Google is unreachable.
+const MAX_STALE: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Claims of a verified Google ID token.
+#[derive(Debug, Deserialize)]
+pub struct IdTokenClaims {
+    /// Issuer, `https://accounts.google.com`
+    pub iss: String,
+    /// Stable Google user ID
+    pub sub: String,
+    /// OAuth client ID the token was issued to
+    pub aud: String,
+    /// Expiry as seconds since the Unix epoch
+    pub exp: i64,
+    /// Issue time as seconds since the Unix epoch
+    pub iat: i64,
+    /// Account email, when the `email` scope was granted
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Whether Google has verified the email address
+    #[serde(default)]
+    pub email_verified: Option<bool>,
+    /// Hosted (Workspace) domain of the account
+    #[serde(default)]
+    pub hd: Option<String>,
+    /// Time of the last interactive authentication, when Google includes it
+    #[serde(default)]
+    pub auth_time: Option<i64>,
+    /// Authentication context class reference
+    #[serde(default)]
+    pub acr: Option<String>,
+    /// Authentication methods used, e.g. `pwd` and `mfa`
+    #[serde(default)]
+    pub amr: Vec<String>,
+    /// The `nonce` of the authorization request that issued the token
+    #[serde(default)]
+    pub nonce: Option<String>,
+}
+
+/// Authentication strength an ID token must show, e.g. multi-factor sign-in.
+///
+/// The default requires nothing.
+#[derive(Debug, Clone, Default)]
+pub struct Assurance {
+    /// Accepted `acr` values, any one of which suffices
+    pub acr_values: Vec<String>,
+    /// `amr` values that must all be present, e.g. `mfa`
+    pub amr_values: Vec<String>,
+}
+
+impl Assurance {
+    /// Whether no requirement is set.
+    pub fn is_empty(&self) -> bool {
+        self.acr_values.is_empty() && self.amr_values.is_empty()
+    }
+
+    /// Check a token's `acr` and `amr` claims against the requirements.
+    pub fn check(&self, acr: Option<&str>, amr: &[String]) -> Result<()> {
+        if !self.acr_values.is_empty()
+            && !acr.is_some_and(|acr| self.acr_values.iter().any(|value| value == acr))
+        {
+            return Err(anyhow!(
+                "ID token acr {:?} is not one of {:?}",
+                acr.unwrap_or(""),
+                self.acr_values
+            ));
+        }
+        if let Some(missing) = self.amr_values.iter().find(|value| !amr.contains(value)) {
+            return Err(anyhow!(
+                "ID token amr {amr:?} lacks the required method {missing:?}"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check an ID token this process received from Google's token endpoint.
+    ///
+    /// The signature is not verified, which is sound only for such tokens.
+    pub(crate) fn check_id_token(&self, id_token: Option<&str>) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let id_token = id_token
+            .ok_or_else(|| anyhow!("No ID token to check the required acr and amr claims in"))?;
+        let claims = decode_unverified(id_token)?;
+        let acr = claims.get("acr").and_then(|acr| acr.as_str());
+        let amr: Vec<String> = claims
+            .get("amr")
+            .and_then(|amr| serde_json::from_value(amr.clone()).ok())
+            .unwrap_or_default();
+        self.check(acr, &amr)
+    }
+}
+
+/// Rules an ID token must satisfy to pass verification.
+///
+/// The defaults accept Google's issuers with a one minute clock skew; at least
+/// one audience must always be supplied.
+#[derive(Debug, Clone)]
+pub struct VerifyOptions {
+    /// Accepted `aud` values, usually OAuth client IDs
+    pub audiences: Vec<String>,
+    /// Accepted `iss` values
+    pub issuers: Vec<String>,
+    /// Hosted domain the account must belong to
+    pub required_hd: Option<String>,
+    /// Reject tokens whose email address Google has not verified
+    pub require_email_verified: bool,
+    /// Required `acr` and `amr` claims
+    pub assurance: Assurance,
+    /// Tolerance applied to `exp`, `nbf`, and `iat` checks
+    pub clock_skew: Duration,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        Self {
+            audiences: Vec::new(),
+            issuers: GOOGLE_ISSUERS.iter().map(|iss| iss.to_string()).collect(),
+            required_hd: None,
+            require_email_verified: false,
+            assurance: Assurance::default(),
+            clock_skew: Duration::from_secs(60),
+        }
+    }
+}
+
+impl VerifyOptions {
+    /// Default options accepting a single audience.
+    pub fn for_audience(audience: impl Into<String>) -> Self {
+        Self {
+            audiences: vec![audience.into()],
+            ..Self::default()
+        }
+    }
+
+    /// Options for an Identity-Aware Proxy assertion.
+    ///
+    /// `audience` is `/projects/PROJECT_NUMBER/global/backendServices/SERVICE_ID`
+    /// behind a load balancer, or `/projects/PROJECT_NUMBER/apps/PROJECT_ID` on
+    /// App Engine.
+    pub fn for_iap(audience: impl Into<String>) -> Self {
+        Self {
+            audiences: vec![audience.into()],
+            issuers: vec![IAP_ISSUER.to_string()],
+            ..Self::default()
+        }
+    }
+
+    /// Apply the checks `jsonwebtoken` cannot express.
+    fn check_claims(&self, claims: &IdTokenClaims) -> Result<()> {
+        if let Some(required) = &self.required_hd {
+            if claims.hd.as_ref() != Some(required) {
+                return Err(anyhow!("ID token is not from hosted domain {required}"));
+            }
+        }
+        if self.require_email_verified && claims.email_verified != Some(true) {
+            return Err(anyhow!("ID token email address is not verified"));
+        }
+        self.assurance.check(claims.acr.as_deref(), &claims.amr)
+    }
+}
+
+/// A cached copy of the JWKS along with its HTTP validators.
+struct CachedKeys {
+    keys: Arc<JwkSet>,
+    etag: Option<String>,
+    fresh_until: Instant,
+}
+
+/// An in-memory cache of Google's signing keys.
+///
+/// Share one cache (e.g. behind an `Arc`) between verifications; the free
+/// [`verify_id_token`] function uses a process-wide instance.
+pub struct JwksCache {
+    client: Client,
+    url: String,
+    algorithm: Algorithm,
+    state: Mutex<Option<CachedKeys>>,
+}
+
+impl Default for JwksCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JwksCache {
+    /// Create an empty cache for Google's JWKS endpoint.
+    pub fn new() -> Self {
+        Self::with_endpoint(GOOGLE_JWKS_URL, Algorithm::RS256)
+    }
+
+    /// Create an empty cache for Identity-Aware Proxy's JWKS endpoint.
+    pub fn iap() -> Self {
+        Self::with_endpoint(IAP_JWKS_URL, Algorithm::ES256)
+    }
+
+    fn with_endpoint(url: &str, algorithm: Algorithm) -> Self {
+        Self {
+            client: Client::new(),
+            url: url.to_string(),
+            algorithm,
+            state: Mutex::new(None),
+        }
+    }
+
+    /// Verify an ID token's signature and claims against `opts`.
+    pub async fn verify_id_token(
+        &self,
+        id_token: &str,
+        opts: &VerifyOptions,
+    ) -> Result<IdTokenClaims> {
+        self.decode(id_token, opts).await
+    }
+
+    /// Verify an ID token and deserialize its claims into a caller-supplied type.
+    ///
+    /// The standard claims are still checked against `opts`; `T` only needs to
+    /// declare the claims the caller is interested in.
+    pub async fn decode<T: DeserializeOwned>(
+        &self,
+        id_token: &str,
+        opts: &VerifyOptions,
+    ) -> Result<T> {
+        if opts.audiences.is_empty() {
+            return Err(anyhow!("VerifyOptions must list at least one audience"));
+        }
+
+        let header = decode_header(id_token)?;
+        let kid = header
+            .kid
+            .ok_or_else(|| anyhow!("ID token header has no key ID"))?;
+
+        // An unknown key ID usually means Google rotated keys; revalidate once.
+        let mut keys = self.keys(false).await?;
+        if keys.find(&kid).is_none() {
+            keys = self.keys(true).await?;
+        }
+        let jwk = keys
+            .find(&kid)
+            .ok_or_else(|| anyhow!("No Google signing key matches key ID {kid}"))?;
+
+        let mut validation = Validation::new(self.algorithm);
+        validation.set_audience(&opts.audiences);
+        validation.set_issuer(&opts.issuers);
+        validation.leeway = opts.clock_skew.as_secs();
+
+        let data = jsonwebtoken::decode::<serde_json::Value>(
+            id_token,
+            &DecodingKey::from_jwk(jwk)?,
+            &validation,
+        )?;
+        let standard: IdTokenClaims = serde_json::from_value(data.claims.clone())?;
+        opts.check_claims(&standard)?;
+        Ok(serde_json::from_value(data.claims)?)
+    }
+
+    /// Return the cached keys, revalidating them when expired or when `force` is set.
+    async fn keys(&self, force: bool) -> Result<Arc<JwkSet>> {
+        let mut state = self.state.lock().await;
+        let now = Instant::now();
+
+        if let Some(cached) = state.as_ref() {
+            if !force && now < cached.fresh_until {
+                return Ok(cached.keys.clone());
+            }
+        }
+
+        let etag = state.as_ref().and_then(|cached| cached.etag.clone());
+        match self.fetch(etag.as_deref()).await {
+            Ok(Fetched::NotModified(max_age)) => {
+                let cached = state
+                    .as_mut()
+                    .ok_or_else(|| anyhow!("JWKS not modified but nothing is cached"))?;
+                cached.fresh_until = now + max_age;
+                Ok(cached.keys.clone())
+            }
+            Ok(Fetched::Keys(keys, etag, max_age)) => {
+                let keys = Arc::new(keys);
+                *state = Some(CachedKeys {
+                    keys: keys.clone(),
+                    etag,
+                    fresh_until: now + max_age,
+                });
+                Ok(keys)
+            }
+            Err(err) => match state.as_ref() {
+                Some(cached) if now < cached.fresh_until + MAX_STALE => Ok(cached.keys.clone()),
+                _ => Err(err),
+            },
+        }
+    }
+
+    /// Fetch the JWKS, sending `etag` as a conditional request validator.
+    async fn fetch(&self, etag: Option<&str>) -> Result<Fetched> {
+        let mut req = self.client.get(&self.url);
+        if let Some(etag) = etag {
+            req = req.header(IF_NONE_MATCH, etag);
+        }
+        let res = crate::debug::send(req).await?;
+
+        let max_age = res
+            .headers()
+            .get(CACHE_CONTROL)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_max_age)
+            .unwrap_or(DEFAULT_MAX_AGE);
+
+        if res.status() == StatusCode::NOT_MODIFIED {
+            return Ok(Fetched::NotModified(max_age));
+        }
+
+        let res = res.error_for_status()?;
+        let etag = res
+            .headers()
+            .get(ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        Ok(Fetched::Keys(res.json().await?, etag, max_age))
+    }
+}
+
+/// Result of a JWKS fetch.
+enum Fetched {
+    NotModified(Duration),
+    Keys(JwkSet, Option<String>, Duration),
+}
+
+/// Extract the `max-age` directive from a `Cache-Control` header value.
+fn parse_max_age(cache_control: &str) -> Option<Duration> {
+    cache_control
+        .split(',')
+        .filter_map(|directive| directive.trim().strip_prefix("max-age="))
+        .find_map(|secs| secs.trim_matches('"').parse().ok())
+        .map(Duration::from_secs)
+}
+
+/// The process-wide JWKS cache used by the free functions.
+fn shared_cache() -> &'static JwksCache {
+    static CACHE: OnceLock<JwksCache> = OnceLock::new();
+    CACHE.get_or_init(JwksCache::new)
+}
+
+/// The process-wide IAP key cache.
+fn shared_iap_cache() -> &'static JwksCache {
+    static CACHE: OnceLock<JwksCache> = OnceLock::new();
+    CACHE.get_or_init(JwksCache::iap)
+}
+
+/// Verify a Google ID token against `opts` using the process-wide JWKS cache.
+pub async fn verify_id_token(id_token: &str, opts: &VerifyOptions) -> Result<IdTokenClaims> {
+    shared_cache().verify_id_token(id_token, opts).await
+}
+
+/// Decode an ID token's claims without checking its signature or expiry.
+///
+/// Only suitable for tokens this process obtained from Google itself, e.g. to
+/// display them; use [`verify_id_token`] for anything received from elsewhere.
+pub fn decode_unverified(id_token: &str) -> Result<serde_json::Value> {
+    let payload = id_token
+        .split('.')
+        .nth(1)
+        .ok_or_else(|| anyhow!("ID token is not a JWT"))?;
+    Ok(serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload)?)?)
+}
+
+/// Verify a Google ID token and decode its claims into `T`.
+///
+/// ```rust,no_run
+/// use gcloud_identity_token::verify::{VerifyOptions, decode};
+/// use serde::Deserialize;
+///
+/// #[derive(Deserialize)]
+/// struct MyClaims {
+///     email: String,
+///     #[serde(default)]
+///     groups: Vec<String>,
+/// }
+///
+/// # async fn run(token: &str) -> anyhow::Result<()> {
+/// let claims: MyClaims = decode(token, &VerifyOptions::for_audience("client-id")).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn decode<T: DeserializeOwned>(id_token: &str, opts: &VerifyOptions) -> Result<T> {
+    shared_cache().decode(id_token, opts).await
+}
+
+/// Verify the `x-goog-iap-jwt-assertion` header Identity-Aware Proxy adds to
+/// requests it lets through, for the audience described in [`VerifyOptions::for_iap`].
+pub async fn verify_iap_assertion(assertion: &str, audience: &str) -> Result<IdTokenClaims> {
+    shared_iap_cache()
+        .verify_id_token(assertion, &VerifyOptions::for_iap(audience))
+        .await
+}
+
+/// Verify the bearer token of a Pub/Sub push request.
+///
+/// `audience` is the audience configured on the push subscription (the push
+/// endpoint URL by default) and `service_account` the email of the account the
+/// subscription pushes as.
+pub async fn verify_pubsub_push(
+    token: &str,
+    audience: &str,
+    service_account: &str,
+) -> Result<IdTokenClaims> {
+    let opts = VerifyOptions {
+        require_email_verified: true,
+        ..VerifyOptions::for_audience(audience)
+    };
+    let claims = verify_id_token(token, &opts).await?;
+    if claims.email.as_deref() != Some(service_account) {
+        return Err(anyhow!(
+            "Push token was not issued to service account {service_account}"
+        ));
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_max_age() {
+        let header = "public, max-age=19204, must-revalidate, no-transform";
+        assert_eq!(parse_max_age(header), Some(Duration::from_secs(19204)));
+    }
+
+    #[test]
+    fn test_parse_max_age_missing() {
+        assert_eq!(parse_max_age("no-cache"), None);
+    }
+
+    #[test]
+    fn test_decode_unverified() {
+        let payload = URL_SAFE_NO_PAD.encode(r#"{"email":"me@example.com"}"#);
+        let claims = decode_unverified(&format!("e30.{payload}.sig")).unwrap();
+        assert_eq!(claims["email"], "me@example.com");
+        assert!(decode_unverified("garbage").is_err());
+    }
+
+    #[test]
+    fn test_for_iap_accepts_only_iap_issuer() {
+        let opts = VerifyOptions::for_iap("/projects/123/apps/my-app");
+        assert_eq!(opts.issuers, [IAP_ISSUER]);
+        assert_eq!(opts.audiences, ["/projects/123/apps/my-app"]);
+    }
+
+    #[test]
+    fn test_required_hd_and_email_verified() {
+        let claims = IdTokenClaims {
+            iss: "https://accounts.google.com".into(),
+            sub: "123".into(),
+            aud: "client".into(),
+            exp: 0,
+            iat: 0,
+            email: Some("me@example.com".into()),
+            email_verified: Some(false),
+            hd: Some("example.com".into()),
+            auth_time: None,
+            acr: None,
+            amr: vec!["pwd".into()],
+            nonce: None,
+        };
+
+        let mut opts = VerifyOptions::for_audience("client");
+        opts.required_hd = Some("example.com".into());
+        assert!(opts.check_claims(&claims).is_ok());
+
+        opts.require_email_verified = true;
+        assert!(opts.check_claims(&claims).is_err());
+
+        opts.require_email_verified = false;
+        opts.required_hd = Some("other.com".into());
+        assert!(opts.check_claims(&claims).is_err());
+    }
+
+    #[test]
+    fn test_assurance_requires_acr_and_amr() {
+        let mfa = Assurance {
+            acr_values: Vec::new(),
+            amr_values: vec!["mfa".into()],
+        };
+        assert!(mfa.check(None, &["pwd".into()]).is_err());
+        assert!(mfa.check(None, &["pwd".into(), "mfa".into()]).is_ok());
+
+        let acr = Assurance {
+            acr_values: vec!["gold".into(), "silver".into()],
+            amr_values: Vec::new(),
+        };
+        assert!(acr.check(Some("silver"), &[]).is_ok());
+        assert!(acr.check(Some("bronze"), &[]).is_err());
+        assert!(acr.check(None, &[]).is_err());
+        assert!(Assurance::default().check(None, &[]).is_ok());
+
+        let err = acr.check_id_token(None).unwrap_err();
+        assert!(err.to_string().starts_with("No ID token"));
+        assert!(Assurance::default().check_id_token(None).is_ok());
+    }
+}
@@ -0,0 +1,232 @@
+//! Keep a token file continuously up to date.
+//!
+//! Some programs (Prometheus remote-write, Thanos, Vector sinks) can only read
+//! a bearer token from disk. [`watch`] refreshes the token ahead of expiry and
+//! rewrites the file atomically, so readers never observe a partial token.
+
+use crate::auth::{get_owned_token, renew_with_options};
+use crate::config::{Creds, LoginOptions, TokenOutput};
+use crate::error::AuthError;
+use anyhow::{Result, anyhow};
+use chrono::{Duration, Utc};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Shortest pause between refresh attempts, also used as the retry delay.
+const RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Longest single sleep while waiting for the next refresh.
+///
+/// Timers count monotonic time, which may stop during suspend, so long
+/// waits are split up and the wall clock is consulted in between.
+const WALL_CLOCK_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Which token to write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// OAuth 2.0 access token
+    Access,
+    /// OpenID Connect ID token
+    Id,
+}
+
+impl FromStr for TokenKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "access" => Ok(TokenKind::Access),
+            "id" => Ok(TokenKind::Id),
+            _ => Err(anyhow!("Unknown token kind {s:?}; expected access or id")),
+        }
+    }
+}
+
+/// How the token is written to the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    /// The bare token with no trailing newline
+    Raw,
+    /// The JSON token output, including both tokens and the expiry
+    Json,
+}
+
+impl FromStr for FileFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "raw" => Ok(FileFormat::Raw),
+            "json" => Ok(FileFormat::Json),
+            _ => Err(anyhow!("Unknown file format {s:?}; expected raw or json")),
+        }
+    }
+}
+
+/// Settings for [`watch`].
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    /// File to keep updated
+    pub output: PathBuf,
+    /// Token written in [`FileFormat::Raw`] mode
+    pub token: TokenKind,
+    /// File contents format
+    pub format: FileFormat,
+    /// How long before expiry the token is refreshed
+    pub refresh_margin: Duration,
+}
+
+/// Keep `opts.output` updated with a fresh token until `login.cancel` fires.
+///
+/// The first iteration may run an interactive login. Refresh failures are
+/// reported on stderr and retried, leaving the previous file in place.
+pub async fn watch(creds: &Creds, login: &LoginOptions, opts: &WatchOptions) -> Result<()> {
+    loop {
+        let retry_at = Utc::now() + Duration::from_std(RETRY_INTERVAL)?;
+        let next = match write_fresh_token(creds, login, opts).await {
+            Ok(expiry) => (expiry - opts.refresh_margin).max(retry_at),
+            Err(err) if matches!(err.downcast_ref(), Some(AuthError::Cancelled)) => {
+                return Err(err);
+            }
+            Err(err) => {
+                eprintln!("Failed to update {}: {err:#}", opts.output.display());
+                retry_at
+            }
+        };
+
+        while let Ok(pause) = (next - Utc::now()).to_std() {
+            tokio::select! {
+                _ = tokio::time::sleep(pause.min(WALL_CLOCK_CHECK_INTERVAL)) => {}
+                _ = login.cancel.cancelled() => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Refresh if needed, write the token file, and return the token's expiry.
+async fn write_fresh_token(
+    creds: &Creds,
+    login: &LoginOptions,
+    opts: &WatchOptions,
+) -> Result<chrono::DateTime<Utc>> {
+    match renew_with_options(creds, login, opts.refresh_margin).await {
+        Ok(_) => {}
+        // Nothing usable is cached; get_token below falls back to a browser login.
+        Err(err) if matches!(err.downcast_ref(), Some(AuthError::LoginRequired)) => {}
+        Err(err) => return Err(err),
+    }
+
+    let token = get_owned_token(creds, login).await?;
+    write_atomic(&opts.output, render(&token.as_output(), opts)?.as_bytes())?;
+    Ok(token.token_expiry)
+}
+
+/// Render the file contents for `token`.
+fn render(token: &TokenOutput<'_>, opts: &WatchOptions) -> Result<String> {
+    Ok(match (opts.format, opts.token) {
+        (FileFormat::Json, _) => serde_json::to_string_pretty(token)?,
+        (FileFormat::Raw, TokenKind::Access) => token.access_token.to_string(),
+        (FileFormat::Raw, TokenKind::Id) => token.require_id_token()?.to_string(),
+    })
+}
+
+/// Replace `path` with `contents` via a synced temp file and a rename.
+///
+/// The file is created readable by the owner only. On Unix the directory is
+/// synced too, so the rename itself survives a crash. Each call uses its own
+/// temp file, so concurrent writers never interleave; the last rename wins.
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a file path", path.display()))?;
+    let tmp = dir.join(format!(
+        ".{}.{}.{}.tmp",
+        name.to_string_lossy(),
+        std::process::id(),
+        TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })?;
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Distinguishes the temp files of concurrent [`write_atomic`] calls.
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opts(format: FileFormat, token: TokenKind) -> WatchOptions {
+        WatchOptions {
+            output: PathBuf::from("token"),
+            token,
+            format,
+            refresh_margin: Duration::minutes(5),
+        }
+    }
+
+    #[test]
+    fn test_render_formats() {
+        let token = TokenOutput {
+            access_token: "access",
+            id_token: Some("id"),
+            token_expiry: Utc::now(),
+        };
+        assert_eq!(
+            render(&token, &opts(FileFormat::Raw, TokenKind::Id)).unwrap(),
+            "id"
+        );
+        let json = render(&token, &opts(FileFormat::Json, TokenKind::Access)).unwrap();
+        assert!(json.contains("\"access_token\": \"access\""));
+    }
+
+    #[test]
+    fn test_write_atomic_replaces_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        write_atomic(&path, b"first").unwrap();
+        write_atomic(&path, b"second").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_write_atomic_concurrent_writers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        std::thread::scope(|scope| {
+            for n in 0..8 {
+                let path = &path;
+                scope.spawn(move || write_atomic(path, format!("{{\"n\":{n}}}").as_bytes()));
+            }
+        });
+
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert!(json["n"].is_u64());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}